@@ -0,0 +1,102 @@
+use crate::endpoint::Endpoint;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime};
+
+/// One finished TCP connection, proxy session or UDP session.
+pub struct AccessRecord<'a> {
+    pub forwarder: &'a str,
+    /// `tcp`, `socks5`, `http` or `udp`.
+    pub protocol: &'a str,
+    pub client: &'a Endpoint,
+    pub pattern: Option<&'a str>,
+    pub upstream: Option<&'a str>,
+    pub started: Instant,
+    pub up_bytes: u64,
+    pub down_bytes: u64,
+    pub close_reason: &'a str,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn output() -> &'static Mutex<Option<File>> {
+    static OUTPUT: OnceLock<Mutex<Option<File>>> = OnceLock::new();
+    OUTPUT.get_or_init(|| Mutex::new(None))
+}
+
+/// Append records to `path` as JSON lines from now on.
+pub fn open(path: &str) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *output().lock().unwrap() = Some(file);
+    ENABLED.store(true, Ordering::Relaxed);
+    log::info!("write access log to {}", path);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn to_line(record: &AccessRecord, now: SystemTime) -> String {
+    let start = now - record.started.elapsed();
+    let mut line = serde_json::json!({
+        "forwarder": record.forwarder,
+        "protocol": record.protocol,
+        "client": record.client.to_string(),
+        "pattern": record.pattern,
+        "upstream": record.upstream,
+        "start": humantime::format_rfc3339_millis(start).to_string(),
+        "end": humantime::format_rfc3339_millis(now).to_string(),
+        "up_bytes": record.up_bytes,
+        "down_bytes": record.down_bytes,
+        "close_reason": record.close_reason,
+    })
+    .to_string();
+    line.push('\n');
+    line
+}
+
+pub fn write(record: &AccessRecord) {
+    if !enabled() {
+        return;
+    }
+    let line = to_line(record, SystemTime::now());
+    if let Some(file) = output().lock().unwrap().as_mut() {
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::warn!("fail to write access log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn record_as_json_line() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let record = AccessRecord {
+            forwarder: "0.0.0.0:8808",
+            protocol: "tcp",
+            client: &"127.0.0.1:40000".parse().unwrap(),
+            pattern: Some("[ssh]"),
+            upstream: None,
+            started: Instant::now(),
+            up_bytes: 3,
+            down_bytes: 4,
+            close_reason: "closed",
+        };
+        let line = to_line(&record, now);
+        assert!(line.ends_with('\n'));
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["client"], "127.0.0.1:40000");
+        assert_eq!(v["pattern"], "[ssh]");
+        assert!(v["upstream"].is_null());
+        assert_eq!(v["end"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(v["up_bytes"], 3);
+        assert_eq!(v["close_reason"], "closed");
+    }
+}
@@ -1,52 +1,310 @@
 extern crate ipnet;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
+use std::time::{Duration, SystemTime};
 
-#[derive(Clone)]
-pub struct IpAddrMatcher {
-    nets: Vec<IpNet>,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
 }
 
-impl IpAddrMatcher {
-    pub fn from(addr_vec: &Vec<String>) -> IpAddrMatcher {
-        let mut nets = vec![];
-        for addr in addr_vec {
-            if let Ok(ipv4) = Ipv4Net::from_str(addr.as_str()) {
-                nets.push(IpNet::from(ipv4));
+/// One entry of an ordered access list, either a single network or a file
+/// with one network per line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AclRule {
+    Net(AclAction, String),
+    File(AclAction, String),
+}
+
+/// Parse a CIDR network, a bare IP address is treated as /32 or /128.
+pub fn parse_net(addr: &str) -> Result<IpNet, String> {
+    let addr = addr.trim();
+    if let Ok(ipv4) = Ipv4Net::from_str(addr) {
+        return Ok(IpNet::from(ipv4));
+    }
+    if let Ok(ipv6) = Ipv6Net::from_str(addr) {
+        return Ok(IpNet::from(ipv6));
+    }
+    if let Ok(ip) = IpAddr::from_str(addr) {
+        return Ok(IpNet::from(ip));
+    }
+    Err(format!("invalid network '{}'", addr))
+}
+
+/// Read a list of networks from a file, empty lines and `#` comments are skipped.
+pub fn load_net_file(path: &str) -> Result<Vec<IpNet>, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("failed to read '{}': {}", path, e))?;
+    let mut nets = vec![];
+    for (lineno, line) in content.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(idx) => &line[..idx],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        nets.push(parse_net(line).map_err(|e| format!("{}:{}: {}", path, lineno + 1, e))?);
+    }
+    Ok(nets)
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: [Option<Box<TrieNode>>; 2],
+    rule: Option<(usize, AclAction)>,
+}
+
+/// Binary prefix trie over IPv4 and IPv6 networks. Every network carries the
+/// index of the rule it came from, so lookups keep first-match semantics.
+#[derive(Default)]
+pub struct NetTrie {
+    v4: TrieNode,
+    v6: TrieNode,
+    len: usize,
+}
+
+fn ip_bits(ip: &IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(v4) => ((u32::from(*v4) as u128) << 96, 32),
+        IpAddr::V6(v6) => (u128::from(*v6), 128),
+    }
+}
+
+impl NetTrie {
+    pub fn insert(&mut self, net: &IpNet, index: usize, action: AclAction) {
+        let (bits, _) = ip_bits(&net.network());
+        let mut node = match net {
+            IpNet::V4(_) => &mut self.v4,
+            IpNet::V6(_) => &mut self.v6,
+        };
+        for i in 0..net.prefix_len() {
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            node = node.children[bit].get_or_insert_with(Default::default);
+        }
+        match node.rule {
+            Some((old, _)) if old <= index => {}
+            Some(_) => node.rule = Some((index, action)),
+            None => {
+                node.rule = Some((index, action));
+                self.len += 1;
             }
-            if let Ok(ipv6) = Ipv6Net::from_str(addr.as_str()) {
-                nets.push(IpNet::from(ipv6));
+        }
+    }
+
+    /// The action of the lowest indexed rule containing `ip`.
+    pub fn lookup(&self, ip: &IpAddr) -> Option<AclAction> {
+        let (bits, width) = ip_bits(ip);
+        let mut node = match ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        let mut best = node.rule;
+        for i in 0..width {
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            node = match &node.children[bit] {
+                Some(child) => child,
+                None => break,
+            };
+            if let Some((index, action)) = node.rule {
+                if best.is_none_or(|(b, _)| index < b) {
+                    best = Some((index, action));
+                }
             }
         }
-        IpAddrMatcher { nets }
+        best.map(|(_, action)| action)
     }
 
-    pub fn testipaddr(self: &Self, ipaddr: &IpAddr) -> bool {
-        if self.nets.is_empty() {
-            return true;
+    /// The number of distinct networks.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+type FileStamp = Option<(SystemTime, u64, u64)>;
+
+/// Modification time, size and a hash of the content, which catches a rewrite
+/// of the same size within the resolution of the modification time.
+fn file_stamp(path: &str) -> FileStamp {
+    let meta = fs::metadata(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    fs::read(path).ok()?.hash(&mut hasher);
+    Some((meta.modified().ok()?, meta.len(), hasher.finish()))
+}
+
+fn build_trie(acl: &[AclRule]) -> Result<NetTrie, String> {
+    let mut trie = NetTrie::default();
+    for (index, rule) in acl.iter().enumerate() {
+        match rule {
+            AclRule::Net(action, addr) => trie.insert(&parse_net(addr)?, index, *action),
+            AclRule::File(action, path) => {
+                for net in load_net_file(path)? {
+                    trie.insert(&net, index, *action);
+                }
+            }
         }
+    }
+    Ok(trie)
+}
 
-        for net in &self.nets {
-            match net {
-                IpNet::V4(n4) => {
-                    if let IpAddr::V4(v4) = ipaddr {
-                        if n4.contains(v4) {
-                            return true;
-                        }
-                    }
+struct AclState {
+    acl: Vec<AclRule>,
+    trie: RwLock<Arc<NetTrie>>,
+    files: Mutex<Vec<(String, FileStamp)>>,
+}
+
+impl AclState {
+    /// Record the current stamp of `path`, whether it differs from the last one.
+    fn mark(&self, path: &str, current: &FileStamp) -> bool {
+        let mut changed = false;
+        for (p, stamp) in self.files.lock().unwrap().iter_mut() {
+            if p == path && stamp != current {
+                *stamp = *current;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn rebuild(&self) -> Result<(), String> {
+        let trie = build_trie(&self.acl)?;
+        log::info!("reload access list, {} networks", trie.len());
+        *self.trie.write().unwrap() = Arc::new(trie);
+        Ok(())
+    }
+}
+
+/// The matchers using each list file, all served by one watcher thread per file.
+fn watches() -> &'static Mutex<HashMap<String, Vec<Weak<AclState>>>> {
+    static WATCHES: OnceLock<Mutex<HashMap<String, Vec<Weak<AclState>>>>> = OnceLock::new();
+    WATCHES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Reload `state` whenever `path` changes. The watcher of a file stops once
+/// no matcher uses it anymore.
+fn watch(path: &str, state: &Arc<AclState>) {
+    let mut registry = watches().lock().unwrap();
+    if let Some(states) = registry.get_mut(path) {
+        states.push(Arc::downgrade(state));
+        return;
+    }
+    registry.insert(path.to_string(), vec![Arc::downgrade(state)]);
+    let path = path.to_string();
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(RELOAD_INTERVAL);
+            let states: Vec<Arc<AclState>> = {
+                let mut registry = watches().lock().unwrap();
+                let states = registry.get_mut(&path).unwrap();
+                states.retain(|state| state.strong_count() > 0);
+                if states.is_empty() {
+                    registry.remove(&path);
+                    break;
                 }
-                IpNet::V6(n6) => {
-                    if let IpAddr::V6(v6) = ipaddr {
-                        if n6.contains(v6) {
-                            return true;
-                        }
+                states.iter().filter_map(Weak::upgrade).collect()
+            };
+            let current = file_stamp(&path);
+            for state in states {
+                if state.mark(&path, &current) {
+                    if let Err(e) = state.rebuild() {
+                        log::warn!("keep previous access list: {}", e);
                     }
                 }
             }
         }
+    });
+}
+
+#[derive(Clone)]
+pub struct IpAddrMatcher {
+    state: Arc<AclState>,
+    default_action: AclAction,
+}
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+impl IpAddrMatcher {
+    /// Build an allow list, reporting the first invalid entry.
+    pub fn from(addr_vec: &[String]) -> Result<IpAddrMatcher, String> {
+        let rules: Vec<AclRule> = addr_vec
+            .iter()
+            .map(|addr| AclRule::Net(AclAction::Allow, addr.clone()))
+            .collect();
+        IpAddrMatcher::from_rules(&rules)
+    }
+
+    /// Build a matcher with first-match semantics over `rules`. When nothing
+    /// matches, the address is denied if any allow rule exists, otherwise allowed.
+    /// Files referenced by the rules are watched and reloaded when they change.
+    pub fn from_rules(acl: &[AclRule]) -> Result<IpAddrMatcher, String> {
+        let files: Vec<_> = acl
+            .iter()
+            .filter_map(|r| match r {
+                AclRule::File(_, path) => Some((path.clone(), file_stamp(path))),
+                AclRule::Net(..) => None,
+            })
+            .collect();
+        let trie = build_trie(acl)?;
+        let default_action = if acl.iter().any(|r| match r {
+            AclRule::Net(action, _) | AclRule::File(action, _) => *action == AclAction::Allow,
+        }) {
+            AclAction::Deny
+        } else {
+            AclAction::Allow
+        };
+        let mut paths: Vec<String> = files.iter().map(|(path, _)| path.clone()).collect();
+        paths.sort();
+        paths.dedup();
+        let state = Arc::new(AclState {
+            acl: acl.to_vec(),
+            trie: RwLock::new(Arc::new(trie)),
+            files: Mutex::new(files),
+        });
+        for path in &paths {
+            watch(path, &state);
+        }
+        Ok(IpAddrMatcher {
+            state,
+            default_action,
+        })
+    }
+
+    /// Rebuild the access list if any referenced file changed since the last load.
+    pub fn reload(&self) -> Result<bool, String> {
+        let paths: Vec<String> = self
+            .state
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut changed = false;
+        for path in paths {
+            changed |= self.state.mark(&path, &file_stamp(&path));
+        }
+        if !changed {
+            return Ok(false);
+        }
+        self.state.rebuild()?;
+        Ok(true)
+    }
 
-        false
+    pub fn testipaddr(self: &Self, ipaddr: &IpAddr) -> bool {
+        let trie = self.state.trie.read().unwrap().clone();
+        trie.lookup(ipaddr).unwrap_or(self.default_action) == AclAction::Allow
     }
 
     #[allow(dead_code)]
@@ -71,3 +329,132 @@ impl IpAddrMatcher {
         self.testipaddr(&ipaddr_opt.unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AclAction, AclRule, IpAddrMatcher, NetTrie, parse_net, watches};
+    use std::net::IpAddr;
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let matcher = IpAddrMatcher::from_rules(&[
+            AclRule::Net(AclAction::Deny, "10.1.0.0/16".to_string()),
+            AclRule::Net(AclAction::Allow, "10.0.0.0/8".to_string()),
+            AclRule::Net(AclAction::Allow, "192.168.1.5".to_string()),
+        ])
+        .unwrap();
+
+        assert!(!matcher.testaddr("10.1.2.3"));
+        assert!(matcher.testaddr("10.2.2.3"));
+        assert!(matcher.testaddr("192.168.1.5"));
+        assert!(!matcher.testaddr("192.168.1.6"));
+    }
+
+    #[test]
+    fn deny_only_list_allows_the_rest() {
+        let matcher =
+            IpAddrMatcher::from_rules(&[AclRule::Net(AclAction::Deny, "::1".to_string())]).unwrap();
+
+        assert!(!matcher.testaddr("::1"));
+        assert!(matcher.testaddr("::2"));
+        assert!(matcher.testaddr("127.0.0.1"));
+    }
+
+    #[test]
+    fn invalid_entries_are_reported() {
+        assert!(parse_net("10.0.0.0/33").is_err());
+        assert!(parse_net("localhost").is_err());
+        assert_eq!(parse_net("::1").unwrap().prefix_len(), 128);
+        assert!(
+            IpAddrMatcher::from_rules(&[AclRule::Net(AclAction::Allow, "1.2.3".to_string())])
+                .is_err()
+        );
+        assert!(IpAddrMatcher::from(&["10.0.0.0/8".to_string(), "1.2.3".to_string()]).is_err());
+    }
+
+    #[test]
+    fn trie_agrees_with_linear_scan() {
+        let nets = [
+            (AclAction::Deny, "10.1.2.0/24"),
+            (AclAction::Allow, "10.1.0.0/16"),
+            (AclAction::Deny, "10.0.0.0/8"),
+            (AclAction::Allow, "0.0.0.0/0"),
+            (AclAction::Deny, "2001:db8::/32"),
+            (AclAction::Allow, "2001:db8:1::1"),
+        ];
+        let mut trie = NetTrie::default();
+        for (i, (action, net)) in nets.iter().enumerate() {
+            trie.insert(&parse_net(net).unwrap(), i, *action);
+        }
+
+        for addr in [
+            "10.1.2.3",
+            "10.1.3.3",
+            "10.2.0.1",
+            "8.8.8.8",
+            "2001:db8:1::1",
+            "2001:db9::1",
+        ] {
+            let ip: IpAddr = addr.parse().unwrap();
+            let linear = nets
+                .iter()
+                .find(|(_, net)| parse_net(net).unwrap().contains(&ip))
+                .map(|(action, _)| *action);
+            assert_eq!(trie.lookup(&ip), linear, "{}", addr);
+        }
+
+        trie.insert(&parse_net("10.0.0.0/8").unwrap(), 9, AclAction::Allow);
+        assert_eq!(trie.len(), nets.len());
+    }
+
+    #[test]
+    fn reload_list_file_when_changed() {
+        let path = std::env::temp_dir().join(format!("portfd-acl-{}.txt", std::process::id()));
+        std::fs::write(&path, "# blocklist\n192.0.2.1\n").unwrap();
+        let matcher = IpAddrMatcher::from_rules(&[AclRule::File(
+            AclAction::Deny,
+            path.to_string_lossy().to_string(),
+        )])
+        .unwrap();
+        assert!(!matcher.testaddr("192.0.2.1"));
+        assert!(matcher.testaddr("192.0.2.2"));
+
+        // the watcher may pick a change up first, so only the outcome is checked
+        std::fs::write(&path, "192.0.2.0/30 # whole range\n").unwrap();
+        matcher.reload().unwrap();
+        assert!(!matcher.testaddr("192.0.2.2"));
+
+        // same size, likely within the same modification time
+        std::fs::write(&path, "192.0.2.4/30 # whole range\n").unwrap();
+        matcher.reload().unwrap();
+        assert!(matcher.testaddr("192.0.2.2"));
+        assert!(!matcher.testaddr("192.0.2.5"));
+
+        std::fs::write(&path, "not-a-network\n").unwrap();
+        let _ = matcher.reload();
+        assert!(!matcher.testaddr("192.0.2.5"));
+        assert!(matcher.testaddr("192.0.2.2"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn list_file_has_one_watcher() {
+        let path = std::env::temp_dir().join(format!("portfd-watch-{}.txt", std::process::id()));
+        std::fs::write(&path, "192.0.2.1\n").unwrap();
+        let path = path.to_string_lossy().to_string();
+        let other = std::env::temp_dir().join(format!("portfd-other-{}.txt", std::process::id()));
+        std::fs::write(&other, "192.0.2.2\n").unwrap();
+        let other = other.to_string_lossy().to_string();
+        let rules = [
+            AclRule::File(AclAction::Deny, path.clone()),
+            AclRule::File(AclAction::Deny, other.clone()),
+            AclRule::File(AclAction::Deny, path.clone()),
+        ];
+        let tcp = IpAddrMatcher::from_rules(&rules).unwrap();
+        let udp = IpAddrMatcher::from_rules(&rules).unwrap();
+        assert_eq!(watches().lock().unwrap()[&path].len(), 2);
+        assert!(!tcp.testaddr("192.0.2.1") && !udp.testaddr("192.0.2.1"));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&other).unwrap();
+    }
+}
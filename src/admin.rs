@@ -0,0 +1,431 @@
+use crate::connection_plugin::ConnectionPlugin;
+use crate::endpoint::Endpoint;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+/// Token the forwarder loops register their admin waker with.
+pub const WAKER_TOKEN: mio::Token = mio::Token(usize::MAX);
+
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_DISABLE_SECONDS: u64 = 300;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A unique id for a forwarder or a session, shared by all forwarders.
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    /// `tcp`, `proxy` or `udp`.
+    pub kind: &'static str,
+    pub client: Endpoint,
+    pub upstream: Option<String>,
+    pub rule: Option<String>,
+    /// `Socks5SessionState` of a proxy session.
+    pub state: Option<String>,
+    pub up_bytes: u64,
+    pub down_bytes: u64,
+    pub age_secs: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RuleInfo {
+    pub index: usize,
+    pub pattern: String,
+    /// Seconds until a disabled rule is enabled again.
+    pub disabled_secs: Option<f64>,
+}
+
+/// A rule as configured, its pattern and its remote, which stays the same
+/// when a reload moves the rule to another index.
+type RuleKey = (String, String);
+
+/// The pattern and the remote of all rules of `plugin`, in order.
+pub fn rule_keys(plugin: &dyn ConnectionPlugin) -> Vec<RuleKey> {
+    (0..)
+        .map_while(|i| {
+            let pattern = plugin.rulePattern(i)?;
+            let remote = plugin.ruleRemote(i).unwrap_or_default();
+            Some((pattern.to_string(), remote.to_string()))
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct HandleState {
+    generation: u64,
+    sessions: Vec<SessionInfo>,
+    rules: Vec<RuleKey>,
+    close_requests: Vec<u64>,
+    disabled_rules: HashMap<RuleKey, Instant>,
+}
+
+/// The admin side of a running forwarder loop.
+///
+/// The loop owns its sessions, so a listing asks it for a snapshot
+/// through the waker and waits until it is published.
+pub struct ForwarderHandle {
+    pub id: u64,
+    pub local: Endpoint,
+    /// `tcp`, `socks5` or `udp`.
+    pub protocol: &'static str,
+    waker: mio::Waker,
+    wanted: AtomicBool,
+    state: Mutex<HandleState>,
+    published: Condvar,
+}
+
+fn registry() -> &'static Mutex<Vec<Weak<ForwarderHandle>>> {
+    static REGISTRY: OnceLock<Mutex<Vec<Weak<ForwarderHandle>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(vec![]))
+}
+
+fn handles() -> Vec<Arc<ForwarderHandle>> {
+    let mut reg = registry().lock().unwrap();
+    reg.retain(|h| h.strong_count() > 0);
+    reg.iter().filter_map(|h| h.upgrade()).collect()
+}
+
+impl ForwarderHandle {
+    /// Register a forwarder loop, it stays listed while the handle is alive.
+    pub fn register(
+        local: Endpoint,
+        protocol: &'static str,
+        poll: &mio::Poll,
+    ) -> io::Result<Arc<ForwarderHandle>> {
+        let handle = Arc::new(ForwarderHandle {
+            id: next_id(),
+            local,
+            protocol,
+            waker: mio::Waker::new(poll.registry(), WAKER_TOKEN)?,
+            wanted: AtomicBool::new(false),
+            state: Mutex::new(HandleState::default()),
+            published: Condvar::new(),
+        });
+        registry().lock().unwrap().push(Arc::downgrade(&handle));
+        Ok(handle)
+    }
+
+    /// Whether an admin request is waiting for the loop to `publish`.
+    pub fn pending(&self) -> bool {
+        self.wanted.load(Ordering::Acquire)
+    }
+
+    pub fn publish(&self, sessions: Vec<SessionInfo>) {
+        let mut state = self.state.lock().unwrap();
+        self.wanted.store(false, Ordering::Release);
+        state.sessions = sessions;
+        state.generation += 1;
+        self.published.notify_all();
+    }
+
+    /// The rules the loop routes with, `rule_keys` of its plugin; call it
+    /// again after every reload so rule indices refer to the current rules.
+    pub fn set_rules(&self, rules: Vec<RuleKey>) {
+        self.state.lock().unwrap().rules = rules;
+    }
+
+    /// Session ids the loop should close.
+    pub fn take_close_requests(&self) -> Vec<u64> {
+        std::mem::take(&mut self.state.lock().unwrap().close_requests)
+    }
+
+    pub fn rule_enabled(&self, index: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.disabled_rules.is_empty() {
+            return true;
+        }
+        let key = match state.rules.get(index) {
+            Some(key) => key.clone(),
+            None => return true,
+        };
+        match state.disabled_rules.get(&key) {
+            Some(until) if *until > Instant::now() => false,
+            Some(_) => {
+                state.disabled_rules.remove(&key);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn snapshot(&self) -> io::Result<(Vec<SessionInfo>, Vec<RuleInfo>)> {
+        let state = self.state.lock().unwrap();
+        let generation = state.generation;
+        self.wanted.store(true, Ordering::Release);
+        self.waker.wake()?;
+        let (state, timeout) = self
+            .published
+            .wait_timeout_while(state, SNAPSHOT_TIMEOUT, |s| s.generation == generation)
+            .unwrap();
+        if timeout.timed_out() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("forwarder {} did not respond", self.local),
+            ));
+        }
+        let now = Instant::now();
+        let rules = state
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, key)| RuleInfo {
+                index,
+                pattern: key.0.clone(),
+                disabled_secs: state
+                    .disabled_rules
+                    .get(key)
+                    .filter(|until| **until > now)
+                    .map(|until| (*until - now).as_secs_f64()),
+            })
+            .collect();
+        Ok((state.sessions.clone(), rules))
+    }
+
+    fn request_close(&self, id: u64) -> io::Result<()> {
+        self.state.lock().unwrap().close_requests.push(id);
+        self.wanted.store(true, Ordering::Release);
+        self.waker.wake()
+    }
+
+    /// Returns false if there is no rule at `index`.
+    fn disable_rule(&self, index: usize, d: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.rules.get(index).cloned() {
+            Some(key) => {
+                state.disabled_rules.insert(key, Instant::now() + d);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns false if there is no rule at `index`.
+    fn enable_rule(&self, index: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.rules.get(index).cloned() {
+            Some(key) => {
+                state.disabled_rules.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ForwarderInfo {
+    id: u64,
+    local: Endpoint,
+    protocol: &'static str,
+    rules: Vec<RuleInfo>,
+    sessions: Vec<SessionInfo>,
+}
+
+fn json<T: Serialize>(v: &T) -> (u16, String) {
+    (200, serde_json::to_string_pretty(v).unwrap())
+}
+
+fn error(status: u16, msg: &str) -> (u16, String) {
+    (status, serde_json::json!({ "error": msg }).to_string())
+}
+
+fn forwarder_infos() -> Vec<ForwarderInfo> {
+    handles()
+        .into_iter()
+        .map(|h| {
+            let (sessions, rules) = match h.snapshot() {
+                Ok(s) => s,
+                Err(e) => {
+                    log::warn!("admin: {}", e);
+                    (vec![], vec![])
+                }
+            };
+            ForwarderInfo {
+                id: h.id,
+                local: h.local.clone(),
+                protocol: h.protocol,
+                rules,
+                sessions,
+            }
+        })
+        .collect()
+}
+
+fn find_forwarder(id: &str) -> Option<Arc<ForwarderHandle>> {
+    let id: u64 = id.parse().ok()?;
+    handles().into_iter().find(|h| h.id == id)
+}
+
+/// Answer one request, returns the status code and the JSON body.
+fn route(method: &str, target: &str) -> (u16, String) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+    match (method, parts.as_slice()) {
+        ("GET", ["forwarders"]) => json(&forwarder_infos()),
+        ("GET", ["sessions"]) => {
+            let sessions: Vec<SessionInfo> = forwarder_infos()
+                .into_iter()
+                .flat_map(|f| f.sessions)
+                .collect();
+            json(&sessions)
+        }
+        ("POST", ["sessions", id, "close"]) | ("DELETE", ["sessions", id]) => {
+            let id: u64 = match id.parse() {
+                Ok(id) => id,
+                Err(_) => return error(400, "invalid session id"),
+            };
+            for f in forwarder_infos() {
+                if f.sessions.iter().any(|s| s.id == id) {
+                    let h = find_forwarder(&f.id.to_string()).unwrap();
+                    return match h.request_close(id) {
+                        Ok(_) => json(&serde_json::json!({ "closed": id })),
+                        Err(e) => error(500, &e.to_string()),
+                    };
+                }
+            }
+            error(404, "no such session")
+        }
+        ("POST", ["forwarders", id, "rules", index, action]) => {
+            let h = match find_forwarder(id) {
+                Some(h) => h,
+                None => return error(404, "no such forwarder"),
+            };
+            let index: usize = match index.parse() {
+                Ok(i) => i,
+                Err(_) => return error(400, "invalid rule index"),
+            };
+            match *action {
+                "disable" => {
+                    let mut seconds = DEFAULT_DISABLE_SECONDS;
+                    for kv in query.split('&') {
+                        if let Some(v) = kv.strip_prefix("seconds=") {
+                            match v.parse() {
+                                Ok(s) => seconds = s,
+                                Err(_) => return error(400, "invalid seconds"),
+                            }
+                        }
+                    }
+                    if !h.disable_rule(index, Duration::from_secs(seconds)) {
+                        return error(404, "no such rule");
+                    }
+                    log::info!(
+                        "admin: disable rule {} of {} for {}s",
+                        index,
+                        h.local,
+                        seconds
+                    );
+                    json(&serde_json::json!({ "disabled": index, "seconds": seconds }))
+                }
+                "enable" => {
+                    if !h.enable_rule(index) {
+                        return error(404, "no such rule");
+                    }
+                    log::info!("admin: enable rule {} of {}", index, h.local);
+                    json(&serde_json::json!({ "enabled": index }))
+                }
+                _ => error(404, "not found"),
+            }
+        }
+        _ => error(404, "not found"),
+    }
+}
+
+fn handle_connection<S: Read + Write>(mut stream: S) -> io::Result<()> {
+    let mut reader = BufReader::new(&mut stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut words = request_line.split_whitespace();
+    let method = words.next().unwrap_or("");
+    let target = words.next().unwrap_or("");
+    let (status, body) = route(method, target);
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )
+}
+
+/// Serve the admin API at `addr`, a TCP address or `unix:/path/to/socket`.
+pub fn serve(addr: &str) -> io::Result<std::thread::JoinHandle<()>> {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        let _ = std::fs::remove_file(path);
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        log::info!("serve admin API at unix:{}", path);
+        return Ok(std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                let _ = handle_connection(stream);
+            }
+        }));
+    }
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    if !local.ip().is_loopback() {
+        log::warn!("admin API at {} is reachable from other hosts", local);
+    }
+    log::info!("serve admin API at http://{}", local);
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let _ = handle_connection(stream);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_rule_expires() {
+        let poll = mio::Poll::new().unwrap();
+        let h = ForwarderHandle::register("127.0.0.1:1".parse().unwrap(), "tcp", &poll).unwrap();
+        let a = ("a".to_string(), "127.0.0.1:2".to_string());
+        let b = ("b".to_string(), "127.0.0.1:3".to_string());
+        h.set_rules(vec![a.clone(), b.clone()]);
+        assert!(h.rule_enabled(0));
+        assert!(h.disable_rule(0, Duration::from_secs(60)));
+        assert!(h.disable_rule(1, Duration::ZERO));
+        assert!(!h.disable_rule(2, Duration::ZERO));
+        assert!(!h.rule_enabled(0));
+        assert!(h.rule_enabled(1));
+        // a reload that reorders the rules keeps the same rule disabled
+        h.set_rules(vec![b, a]);
+        assert!(h.rule_enabled(0));
+        assert!(!h.rule_enabled(1));
+        assert!(h.enable_rule(1));
+        assert!(h.rule_enabled(1));
+    }
+
+    #[test]
+    fn unknown_routes() {
+        assert_eq!(route("GET", "/nothing").0, 404);
+        assert_eq!(route("POST", "/sessions/abc/close").0, 400);
+        assert_eq!(route("POST", "/forwarders/0/rules/0/disable").0, 404);
+    }
+}
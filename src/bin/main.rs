@@ -1,43 +1,215 @@
 #![allow(non_snake_case)]
 extern crate portforwarder;
 
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
 use colored::Colorize;
-use portforwarder::forward_config::{ForwardSessionConfig, TcpMode};
-use portforwarder::tcp_udp_forwarder::TcpUdpForwarder;
-use regex::Regex;
+use portforwarder::address_matcher::parse_net;
+use portforwarder::config::{
+    ConfigFile, convert_to_bytes, convert_to_duration, load_file, to_yaml,
+};
+use portforwarder::forward_config::{
+    ForwardSessionConfig, TcpMode, expand_local, substitute_port, validate_forwarders,
+};
+use portforwarder::connection_plugin::RegexMultiplexer;
+use portforwarder::endpoint::unix_name;
+use portforwarder::rule_tester;
+use portforwarder::systemd;
+use portforwarder::tcp_udp_forwarder::{Draining, TcpUdpForwarder};
+use portforwarder::upstream;
+use std::error::Error;
 use std::fs;
 use std::panic;
 use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
-use yaml_rust::{Yaml, YamlLoader};
+use std::sync::mpsc;
+use std::time::Duration;
 
-fn usage() {
-    let args: Vec<String> = std::env::args().collect();
-    println!(
-        "usage:
-    {} [-htu] <bind-address> <forward-address>
-    {} [--socks5] <bind-address>
-    {} -c <yaml-config-file>
-
-    -t    disable tcp
-    -u    disable udp
-    -s    connection buffer size, default value: 2MB.
-          support UNITs: KB MB
-    -w    network whitelist, eg. 127.0.0.1/24
-    -m    max connections
-    -c    config file (a yaml file)
-    --socks5  run tcp listener as a SOCKS5 server (CONNECT only)
-              when enabled, <forward-address> is not required
-    -e    show an example of config file
-    -h    show help",
-        args[0], args[0], args[0]
-    );
+const TEST_RULES_USAGE: &str = "usage: portfd test-rules -c <config-file> [--hex <hex>] [--pcap <file>] [<payload-file>...]
+
+test-rules prints which rule of each forwarder matches every payload, given as a raw file, a hex
+string or the first client packets of a pcap/pcapng file, and why the rules before it do not";
+
+/// A TCP/UDP port multiplexer which forwards connections based on the content of their first packet.
+#[derive(Parser, Debug)]
+#[command(name = "portfd", version, after_help = TEST_RULES_USAGE)]
+struct Cli {
+    /// Address to listen on, eg. 0.0.0.0:8808, a port range, eg. 0.0.0.0:10000-10100,
+    /// or a unix socket, eg. unix:/run/portfd.sock
+    #[arg(value_parser = parse_local, required_unless_present_any = ["config", "example"])]
+    bind_address: Option<String>,
+    /// Where connections that match no --rule go, same as a last '.*=<address>' rule.
+    /// '${port}' or '${port+N}' stand for the port a connection came in on
+    #[arg(value_parser = parse_remote)]
+    forward_address: Option<String>,
+    /// Send connections whose first packet matches PATTERN to REMOTE, tried in order,
+    /// eg. '[ssh]=10.0.0.1:22'
+    #[arg(short, long = "rule", value_name = "PATTERN=REMOTE", value_parser = parse_rule)]
+    rules: Vec<(String, String)>,
+    /// What the tcp listener does with connections
+    #[arg(long, value_enum, default_value_t = Mode::Forward)]
+    mode: Mode,
+    /// Same as --mode socks5
+    #[arg(long, conflicts_with = "mode")]
+    socks5: bool,
+    /// Disable tcp
+    #[arg(short = 't', long)]
+    no_tcp: bool,
+    /// Disable udp, always so in socks5 mode
+    #[arg(short = 'u', long)]
+    no_udp: bool,
+    /// Connection buffer size, eg. 64KB, 2MB
+    #[arg(short = 's', long, value_name = "SIZE", value_parser = parse_size, default_value = "2MB")]
+    bufsize: usize,
+    /// Networks allowed to connect, repeatable or comma separated, eg. 127.0.0.1/24
+    #[arg(short = 'w', long, value_name = "NETS", value_delimiter = ',', value_parser = parse_network)]
+    allow: Vec<String>,
+    /// Networks refused, checked before --allow
+    #[arg(short = 'd', long, value_name = "NETS", value_delimiter = ',', value_parser = parse_network)]
+    deny: Vec<String>,
+    /// Limit of simultaneous connections
+    #[arg(short = 'm', long, value_name = "N", value_parser = clap::value_parser!(i64).range(1..))]
+    max_connections: Option<i64>,
+    /// File mode of a unix:<path> listening socket, in octal, eg. 0660
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    unix_mode: Option<u32>,
+    /// On ctrl-c, stop accepting and let live connections finish for up to this long, eg. 30s
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "0s")]
+    drain_timeout: Duration,
+    /// Close a UDP session after it has been idle this long, eg. 10s
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "3m")]
+    udp_idle_timeout: Duration,
+    /// When --max-connections UDP sessions are open, close the least recently used for a new client
+    #[arg(long)]
+    udp_evict_lru: bool,
+    /// Config file (yaml, toml or json by extension) instead of the options above, reloaded on SIGHUP
+    #[arg(short, long, value_name = "FILE", conflicts_with_all = [
+        "bind_address", "forward_address", "rules", "mode", "socks5", "no_tcp", "no_udp", "bufsize",
+        "allow", "deny", "max_connections", "unix_mode", "drain_timeout", "udp_idle_timeout",
+        "udp_evict_lru",
+    ])]
+    config: Option<String>,
+    /// Reload the config file when it changes
+    #[arg(long, requires = "config")]
+    watch: bool,
+    /// Validate the configuration, report every problem and exit
+    #[arg(long)]
+    check: bool,
+    /// Print the configuration as a config file, with includes and defaults applied, and exit
+    #[arg(long, conflicts_with = "check")]
+    dump_config: bool,
+    /// Serve prometheus metrics at http://<ADDRESS>/metrics
+    #[arg(long, value_name = "ADDRESS")]
+    metrics: Option<String>,
+    /// Serve the admin API at <ADDRESS> or unix:<path>
+    #[arg(long, value_name = "ADDRESS")]
+    admin: Option<String>,
+    /// Append one JSON line per finished connection to this file
+    #[arg(long, value_name = "FILE")]
+    access_log: Option<String>,
+    /// Show an example of config file
+    #[arg(short, long)]
+    example: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    /// Forward by --rule and <FORWARD_ADDRESS>
+    Forward,
+    /// Run a SOCKS5 (CONNECT only) and HTTP proxy server
+    Socks5,
+}
+
+/// A `host:port` address, resolved later with the rest of the configuration.
+fn parse_address(arg: &str) -> Result<String, String> {
+    if let Some(name) = unix_name(arg) {
+        if name.is_empty() || name == "@" {
+            return Err("expect unix:<path> or unix:@<name>".to_string());
+        }
+        return Ok(arg.to_string());
+    }
+    let (host, port) = arg
+        .rsplit_once(':')
+        .ok_or_else(|| "expect <host>:<port>, eg. 127.0.0.1:22".to_string())?;
+    if host.is_empty() {
+        return Err("missing host, eg. 0.0.0.0:8808".to_string());
+    }
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        return Err("put IPv6 addresses in brackets, eg. [::1]:22".to_string());
+    }
+    port.parse::<u16>()
+        .map_err(|_| format!("invalid port '{}'", port))?;
+    Ok(arg.to_string())
+}
+
+/// A `local` address, whose port may be a range.
+fn parse_local(arg: &str) -> Result<String, String> {
+    for address in expand_local(arg)? {
+        parse_address(&address)?;
+    }
+    Ok(arg.to_string())
+}
+
+/// A remote address, which may use the local port as `${port}`, or the name
+/// of an upstream group, which like in a config file has no `:`.
+fn parse_remote(arg: &str) -> Result<String, String> {
+    if !arg.is_empty() && !arg.contains(':') {
+        return Ok(arg.to_string());
+    }
+    parse_address(&substitute_port(arg, 32768)?)?;
+    Ok(arg.to_string())
+}
+
+fn parse_rule(arg: &str) -> Result<(String, String), String> {
+    let (pattern, remote) = arg
+        .rsplit_once('=')
+        .ok_or_else(|| "expect PATTERN=REMOTE, eg. '[ssh]=127.0.0.1:22'".to_string())?;
+    RegexMultiplexer::checkPattern(pattern)?;
+    let remote = parse_remote(remote).map_err(|e| format!("remote '{}': {}", remote, e))?;
+    Ok((pattern.to_string(), remote))
+}
+
+fn parse_size(arg: &str) -> Result<usize, String> {
+    convert_to_bytes(arg).ok_or_else(|| "expect a size, eg. 2MB".to_string())
+}
+
+fn parse_duration(arg: &str) -> Result<Duration, String> {
+    convert_to_duration(arg).ok_or_else(|| "expect a duration, eg. 30s".to_string())
+}
+
+fn parse_mode(arg: &str) -> Result<u32, String> {
+    u32::from_str_radix(arg, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| "expect an octal file mode, eg. 0660".to_string())
+}
+
+fn parse_network(arg: &str) -> Result<String, String> {
+    parse_net(arg).map(|_| arg.to_string())
 }
 
 fn print_example_of_config_file() {
     println!(
-        "forwarders:
+        "metrics_listen: 127.0.0.1:9100 # optional, serve prometheus metrics
+admin_listen: 127.0.0.1:9101 # optional, or unix:/run/portfd.sock
+access_log: /var/log/portfd/access.log # optional, JSON lines
+include: conf.d # optional, files or directories with more forwarders
+upstreams: # optional, server groups a 'remote' can name
+  ssh_pool:
+    servers:
+      - 192.168.44.43:22
+      - address: 192.168.44.44:22
+        weight: 2 # optional, default 1
+    health_check: # optional, or true for the defaults below
+      interval: 5s
+      timeout: 2s
+      fall: 3 # failed checks in a row to take a server out
+      rise: 2 # passed checks in a row to put it back
+    max_fails: 1 # skip a server after this many failed connects, 0 never
+    fail_timeout: 10s # for this long
+    upstream_socket: # optional, options of the connections to the servers
+      keepalive: 60s
+forwarders:
   - local: 0.0.0.0:8808
     # specify either 'remoteMap' or 'remote'
     remoteMap:
@@ -53,284 +225,408 @@ fn print_example_of_config_file() {
         remote: 192.168.100.46:3389
       - pattern: .*
         remote: 192.168.100.46:23
-    remote: <remote-address/127.0.0.1:2233>
+    remote: <remote-address/127.0.0.1:2233> # or an upstream group, eg. ssh_pool
     tcp_mode: forward # support: forward, socks5
     enable_tcp: true # default is true
     enable_udp: true # default is true
     conn_bufsize: 2MB
     max_connections: 10000 # optional
+    drain_timeout: 30s # optional, let connections finish on shutdown
+    udp_idle_timeout: 3m # optional, also per rule in remoteMap
+    udp_evict_lru: false # optional, make room for new udp clients when max_connections is met
+    udp_queue: # optional, datagrams waiting for a busy socket
+      session_packets: 256
+      session_bytes: 256KB
+      packets: 4096 # back to clients, all sessions together
+      bytes: 4MB
+      drop: newest # or oldest
+    fd_name: https # optional, take the socket systemd passes with this name
+    ipv6_only: false # optional, an IPv6 socket takes no IPv4 clients
+    unix_mode: \"0660\" # optional, file mode when local is unix:<path>
     allow_nets: # optional
-      - 127.0.0.0/24"
+      - 127.0.0.0/24
+    deny_nets: # optional, checked before allow_nets
+      - 127.0.0.100
+    acl: # optional, first match wins, checked before deny_nets and allow_nets
+      - deny: 10.1.0.0/16
+      - allow: 10.0.0.0/8
+      - deny_file: /etc/portfd/blocklist.txt
+    capture: # optional, or just a file name to capture everything
+      file: /tmp/portfd-8808.pcapng
+      rules: [\"[ssh]\"] # optional, patterns of remoteMap
+      clients: [10.0.0.0/8] # optional
+    unmatched_log: # optional, or just a file name
+      file: /var/log/portfd/unmatched.log
+      bytes: 64 # optional, dumped bytes of the first packet
+      per_minute: 30 # optional, rate limit
+      dedup_prefix: 8 # optional, packets with the same leading bytes are logged once
+      dedup_window: 10m # optional
+    listen_socket: # optional, options of the listener and accepted connections
+      nodelay: true
+      keepalive: 60s
+    upstream_socket: # optional, also per rule in remoteMap
+      bind_address: 192.168.100.2
+      interface: eth1
+      fwmark: 100
+      tos: 16"
     );
 }
 
-pub fn convert_to_bytes(input: &str) -> Option<usize> {
-    let units = vec!["B", "KB", "MB", "GB", "TB"];
-    let mut num_str = String::new();
-    let mut unit_str = String::new();
+enum ControlEvent {
+    Shutdown,
+    Reload,
+}
+
+struct RunningForwarder {
+    config: ForwardSessionConfig<String>,
+    forwarder: TcpUdpForwarder,
+}
 
-    for c in input.chars() {
-        if c.is_digit(10) {
-            num_str.push(c);
+impl RunningForwarder {
+    /// Bind the listeners of `config` and start forwarding.
+    fn start(config: ForwardSessionConfig<String>) -> Result<RunningForwarder, Box<dyn Error>> {
+        let mut forwarder = TcpUdpForwarder::from(&config)?;
+        forwarder.listen();
+        Ok(RunningForwarder { config, forwarder })
+    }
+}
+
+/// Diff forwarders by `local` address: changed ones are updated in place or
+/// get new listeners, new ones start listening and removed ones serve their
+/// connections until these end. A forwarder that can't be changed keeps
+/// running as it was.
+fn reload(
+    running: &mut Vec<RunningForwarder>,
+    configs: Vec<ForwardSessionConfig<String>>,
+    draining: &mut Vec<Draining>,
+) {
+    let mut kept = vec![];
+    for r in running.drain(..) {
+        if configs.iter().any(|c| c.local == r.config.local) {
+            kept.push(r);
         } else {
-            unit_str.push(c);
+            log::info!("reload: remove forwarder {}", r.config.local);
+            draining.push(r.forwarder.retire());
         }
     }
 
-    let num = match num_str.parse::<usize>() {
-        Ok(n) => n,
-        Err(_) => return None,
-    };
+    for config in configs {
+        match kept.iter_mut().find(|r| r.config.local == config.local) {
+            Some(r) if r.config == config => {}
+            Some(r) => match r.forwarder.update(&config) {
+                Ok(replaced) => {
+                    log::info!("reload: update forwarder {}", config.local);
+                    let kept = listener_changes(&r.config, &config);
+                    if !kept.is_empty() {
+                        log::warn!(
+                            "reload: {} of {} change on restart, the listener keeps the old ones",
+                            kept.join(", "),
+                            config.local
+                        );
+                    }
+                    if let Some(old) = replaced {
+                        log::info!("reload: restart listeners of {}", config.local);
+                        draining.push(old);
+                    }
+                    r.config = config;
+                }
+                Err(e) => log::warn!("reload: keep forwarder {}: {}", config.local, e),
+            },
+            None => {
+                log::info!("reload: add forwarder {}", config.local);
+                match RunningForwarder::start(config.clone()) {
+                    Ok(r) => kept.push(r),
+                    Err(e) => log::warn!("reload: fail to start {}: {}", config.local, e),
+                }
+            }
+        }
+    }
+    *running = kept;
+}
 
-    let unit_index = units
+/// The settings of the listening socket that differ, a listener keeps those
+/// it was bound with.
+fn listener_changes(
+    old: &ForwardSessionConfig<String>,
+    new: &ForwardSessionConfig<String>,
+) -> Vec<&'static str> {
+    let changes = [
+        ("listen_socket", old.listen_socket != new.listen_socket),
+        ("ipv6_only", old.ipv6_only != new.ipv6_only),
+        ("unix_mode", old.unix_mode != new.unix_mode),
+        ("fd_name", old.fd_name != new.fd_name),
+    ];
+    changes
         .iter()
-        .position(|&u| u == unit_str.trim().to_uppercase());
+        .filter(|(_, changed)| *changed)
+        .map(|(key, _)| *key)
+        .collect()
+}
 
-    if let Some(index) = unit_index {
-        Some(num * (1024 as usize).pow(index as u32))
-    } else {
-        None
+fn notify_systemd(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        log::warn!("fail to notify systemd: {}", e);
     }
 }
 
-pub trait FromYaml: Sized {
-    fn run(&self, sync_pair: Arc<(Mutex<bool>, Condvar)>) -> std::thread::JoinHandle<()>;
-    fn fromYaml(yaml: &Yaml) -> Result<Self, &'static str>;
+fn status(running: &[RunningForwarder]) -> String {
+    format!("forwarding on {} listeners", running.len())
 }
 
-impl FromYaml for ForwardSessionConfig<String> {
-    fn run(&self, sync_pair: Arc<(Mutex<bool>, Condvar)>) -> std::thread::JoinHandle<()> {
-        let cp = self.clone();
-        std::thread::spawn(move || {
-            let forwarder = TcpUdpForwarder::from(&cp).unwrap();
-            let close_handler = forwarder.listen();
-
-            let (lock, cvar) = &*sync_pair;
-            let mut closed = lock.lock().unwrap();
-            while !*closed {
-                closed = cvar.wait(closed).unwrap();
-            }
-            close_handler();
-        })
-    }
-
-    fn fromYaml(yaml: &Yaml) -> Result<Self, &'static str> {
-        let local = match yaml["local"].as_str() {
-            Some(s) => String::from(s),
-            None => return Err("missing local"),
-        };
-        let enable_tcp = yaml["enable_tcp"].as_bool().unwrap_or(true);
-        let enable_udp = yaml["enable_udp"].as_bool().unwrap_or(true);
-        let tcp_mode = match yaml["tcp_mode"].as_str().unwrap_or("forward") {
-            "forward" => TcpMode::Forward,
-            "socks5" => TcpMode::Socks5Server,
-            _ => return Err("invalid tcp_mode, support values: forward, socks5"),
-        };
-        let allow_nets_opt = yaml["allow_nets"].as_vec();
-        let mut allow_nets = vec![];
-        let conn_bufsize =
-            convert_to_bytes(yaml["conn_bufsize"].as_str().unwrap_or("2MB")).unwrap();
-
-        if let Some(nets) = allow_nets_opt {
-            for _net in nets {
-                if let Some(_str) = _net.as_str() {
-                    allow_nets.push(String::from(_str));
-                }
+#[cfg(unix)]
+fn watch_reload_signal(tx: mpsc::Sender<ControlEvent>) {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP]).unwrap();
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            log::info!("SIGHUP received, reloading configuration");
+            if tx.send(ControlEvent::Reload).is_err() {
+                break;
             }
         }
+    });
+}
 
-        let max_connections = if let Some(mc) = yaml["max_connections"].as_i64() {
-            mc
-        } else {
-            -1
-        };
+#[cfg(not(unix))]
+fn watch_reload_signal(_tx: mpsc::Sender<ControlEvent>) {}
 
-        let mut remoteMap: Vec<(String, String)> = vec![];
-        if let Some(pairs) = yaml["remoteMap"].as_vec() {
-            for pair in pairs {
-                let pattern = pair["pattern"].as_str();
-                let remote = pair["remote"].as_str();
-                if pattern.is_some() && remote.is_some() {
-                    remoteMap.push((pattern.unwrap().to_string(), remote.unwrap().to_string()));
-                }
-            }
+/// Shut down on SIGTERM like on ctrl-c, which is how service managers stop portfd.
+#[cfg(unix)]
+fn watch_terminate_signal(tx: mpsc::Sender<ControlEvent>) {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGTERM]).unwrap();
+    std::thread::spawn(move || {
+        let mut signals = signals.forever();
+        if signals.next().is_some() {
+            log::info!("SIGTERM received, exiting (send again to force) ...");
+            tx.send(ControlEvent::Shutdown).unwrap_or(());
         }
-        match yaml["remote"].as_str() {
-            Some(s) => {
-                remoteMap.push((".*".to_string(), s.to_string()));
-            }
-            None => {}
-        };
-
-        Ok(Self {
-            local,
-            remoteMap,
-            enable_tcp,
-            enable_udp,
-            allow_nets,
-            max_connections,
-            conn_bufsize,
-            tcp_mode,
-        })
-    }
+        if signals.next().is_some() {
+            std::process::exit(0);
+        }
+    });
 }
 
-fn main() {
-    env_logger::init_from_env(
-        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
-    );
-    let mut bind_addr = None;
-    let mut forward_addr = None;
-    let mut enable_tcp = true;
-    let mut enable_udp = true;
-    let mut max_connections = -1;
-    let mut tcp_mode = TcpMode::Forward;
-    let mut args: Vec<String> = std::env::args().collect();
-    let mut config_file: Option<String> = None;
-    let mut whitelist: Vec<String> = vec![];
-    let mut conn_bufsize = 2 * 1024 * 1024;
-    args.remove(0);
-    let valid_ipv4_port = Regex::new(
-        r"^(([0-9]{1,3}.){3}[0-9]{1,3}|0-9]{1}|(([0-9]{1}[a-zA-Z]{1})|([a-zA-Z0-9][a-zA-Z0-9-_]{1,61}[a-zA-Z0-9]))\.([a-zA-Z]{2,6}|[a-zA-Z0-9-]{2,30}\.[a-zA-Z]{2,3})|([a-f0-9:]+:+)+[a-f0-9]+|::|localhost):[0-9]{1,5}$").unwrap();
-    let mut skipnext = false;
-    for i in 0..args.len() {
-        if skipnext {
-            skipnext = false;
-            continue;
-        }
+#[cfg(not(unix))]
+fn watch_terminate_signal(_tx: mpsc::Sender<ControlEvent>) {}
 
-        let s = args[i].as_str();
-        match s {
-            "-h" => {
-                usage();
-                std::process::exit(0);
-            }
-            "-u" => {
-                enable_udp = false;
-            }
-            "-t" => {
-                enable_tcp = false;
-            }
-            "-e" => {
-                print_example_of_config_file();
-                std::process::exit(0);
-            }
-            "-s" => {
-                if i + 1 < args.len() {
-                    conn_bufsize = convert_to_bytes(args[i + 1].as_str()).unwrap();
-                    skipnext = true;
-                } else {
-                    usage();
-                    std::process::exit(1);
+fn watch_config_file(file: String, tx: mpsc::Sender<ControlEvent>) {
+    let stamp = |f: &str| fs::metadata(f).and_then(|m| m.modified()).ok();
+    let mut last = stamp(&file);
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(Duration::from_secs(2));
+            let current = stamp(&file);
+            if current != last {
+                last = current;
+                log::info!("{} changed, reloading configuration", file);
+                if tx.send(ControlEvent::Reload).is_err() {
+                    break;
                 }
             }
-            "-w" => {
-                if i + 1 < args.len() {
-                    whitelist = args[i + 1].split(",").map(|s| String::from(s)).collect();
-                    skipnext = true;
-                } else {
-                    usage();
-                    std::process::exit(1);
-                }
+        }
+    });
+}
+
+/// `portfd test-rules`: classify sample payloads with the rules of a config file.
+fn test_rules(args: &[String]) -> i32 {
+    let mut config_file = None;
+    let mut payloads = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        let loaded = match (args[i].as_str(), value) {
+            ("-c", Some(file)) => {
+                config_file = Some(file.clone());
+                Ok(vec![])
             }
-            "-m" => {
-                if i + 1 < args.len() {
-                    max_connections = args[i + 1].parse().unwrap();
-                    skipnext = true;
-                } else {
-                    usage();
-                    std::process::exit(1);
-                }
+            ("--hex", Some(text)) => rule_tester::from_hex(text, text).map(|p| vec![p]),
+            ("--pcap", Some(file)) => fs::read(file)
+                .map_err(|e| format!("fail to read {}: {}", file, e))
+                .and_then(|bytes| rule_tester::from_pcap(file, &bytes)),
+            ("-c", None) | ("--hex", None) | ("--pcap", None) => {
+                println!("{}", TEST_RULES_USAGE);
+                return 1;
             }
-            "-c" => {
-                if i + 1 < args.len() {
-                    config_file = Some(args[i + 1].clone());
-                    break;
-                } else {
-                    usage();
-                    std::process::exit(1);
+            (file, _) => {
+                i += 1;
+                let payload = fs::read(file).map(|data| rule_tester::Payload {
+                    source: file.to_string(),
+                    data,
+                });
+                match payload {
+                    Ok(p) => payloads.push(p),
+                    Err(e) => {
+                        println!("fail to read {}: {}", file, e);
+                        return 1;
+                    }
                 }
+                continue;
             }
-            "--socks5" => {
-                tcp_mode = TcpMode::Socks5Server;
-                enable_udp = false;
+        };
+        match loaded {
+            Ok(mut p) => payloads.append(&mut p),
+            Err(e) => {
+                println!("{}", e);
+                return 1;
             }
-            _ => {
-                if valid_ipv4_port.is_match(s) {
-                    if bind_addr.is_none() {
-                        bind_addr = Some(String::from(s));
-                    } else if forward_addr.is_none() {
-                        forward_addr = Some(String::from(s));
-                    } else {
-                        usage();
-                        std::process::exit(1);
-                    }
-                } else {
-                    usage();
-                    std::process::exit(1);
-                }
+        }
+        i += 2;
+    }
+
+    let config_file = match config_file {
+        Some(file) => file,
+        None => {
+            println!("{}", TEST_RULES_USAGE);
+            return 1;
+        }
+    };
+    let configs = match load_file(&config_file) {
+        Ok(c) => c,
+        Err(problems) => {
+            for p in problems {
+                println!("{}", p);
             }
+            return 1;
         }
+    };
+    upstream::configure(&configs.upstreams);
+    print!("{}", rule_tester::report(&configs.forwarders, &payloads));
+    0
+}
+
+fn main() {
+    env_logger::init_from_env(
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
+    );
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("test-rules") {
+        std::process::exit(test_rules(&args[2..]));
+    }
+    let cli = Cli::parse();
+    if cli.example {
+        print_example_of_config_file();
+        std::process::exit(0);
     }
+    let tcp_mode = if cli.socks5 || cli.mode == Mode::Socks5 {
+        TcpMode::Socks5Server
+    } else {
+        TcpMode::Forward
+    };
+    let config_file = cli.config;
+    let mut metrics_listen = cli.metrics;
+    let mut admin_listen = cli.admin;
+    let mut access_log = cli.access_log;
 
     let mut forwarder_configs = vec![];
-    if config_file.is_some() {
-        let file = &config_file.unwrap();
-        if let Ok(file_content) = fs::read_to_string(file) {
-            if let Ok(config) = YamlLoader::load_from_str(file_content.as_str()) {
-                if config.is_empty() {
-                    println!("do nothing");
-                    std::process::exit(0);
-                }
-                let config = &config[0];
-
-                let forwarders = &config["forwarders"];
-                if !forwarders.is_array() {
-                    println!(
-                        "invalid config file, expect an array but get {:?}",
-                        forwarders
-                    );
-                    std::process::exit(1);
-                }
-
-                let fflist = forwarders.as_vec().unwrap();
-                for ff in fflist {
-                    match ForwardSessionConfig::fromYaml(ff) {
-                        Ok(c) => forwarder_configs.push(c),
-                        Err(e) => {
-                            println!("invalid config file: {e}\n{:?}", ff);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-            } else {
-                println!("invalid config file, should be a valid yaml file");
-                std::process::exit(1);
+    let mut upstreams = vec![];
+    let mut problems = vec![];
+    if let Some(file) = &config_file {
+        match load_file(file) {
+            Ok(configs) => {
+                forwarder_configs = configs.forwarders;
+                upstreams = configs.upstreams;
+                metrics_listen = metrics_listen.or(configs.metrics_listen);
+                admin_listen = admin_listen.or(configs.admin_listen);
+                access_log = access_log.or(configs.access_log);
             }
-        } else {
-            println!("open file {} failed", file);
-            std::process::exit(1);
+            Err(p) => problems = p,
         }
     } else {
-        if bind_addr.is_none() || (forward_addr.is_none() && tcp_mode == TcpMode::Forward) {
-            usage();
-            std::process::exit(1);
+        let mut remoteMap = cli.rules;
+        if let Some(remote) = cli.forward_address {
+            remoteMap.push((".*".to_string(), remote));
         }
-
-        let mut remoteMap: Vec<(String, String)> = vec![];
-        if tcp_mode == TcpMode::Forward {
-            remoteMap.push((".*".to_string(), forward_addr.unwrap()));
+        if tcp_mode == TcpMode::Forward && remoteMap.is_empty() {
+            Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "forward mode needs <FORWARD_ADDRESS> or at least one --rule",
+                )
+                .exit();
         }
-        forwarder_configs.push(ForwardSessionConfig {
-            local: bind_addr.unwrap(),
-            remoteMap,
-            enable_tcp,
-            enable_udp,
-            allow_nets: whitelist,
-            max_connections,
-            conn_bufsize,
+        let config = ForwardSessionConfig {
+            local: String::new(),
+            remoteMap: vec![],
+            enable_tcp: !cli.no_tcp,
+            enable_udp: !cli.no_udp && tcp_mode == TcpMode::Forward,
+            allow_nets: cli.allow,
+            deny_nets: cli.deny,
+            acl: vec![],
+            max_connections: cli.max_connections.unwrap_or(-1),
+            conn_bufsize: cli.bufsize,
             tcp_mode,
-        });
+            drain_timeout: cli.drain_timeout,
+            capture: None,
+            unmatched_log: None,
+            fd_name: None,
+            ipv6_only: false,
+            unix_mode: cli.unix_mode,
+            udp_idle_timeout: cli.udp_idle_timeout,
+            udp_evict_lru: cli.udp_evict_lru,
+            ..Default::default()
+        };
+        // both were checked by the parser
+        for local in expand_local(&cli.bind_address.unwrap()).unwrap() {
+            // a unix socket has no port to substitute
+            let port = local.rsplit(':').next().unwrap().parse().unwrap_or(0);
+            let remoteMap = remoteMap
+                .iter()
+                .map(|(pattern, remote)| Ok((pattern.clone(), substitute_port(remote, port)?)))
+                .collect::<Result<Vec<_>, String>>();
+            let remoteMap = match remoteMap {
+                Ok(rules) => rules,
+                Err(e) => {
+                    problems.push(format!("{}: {}", local, e));
+                    continue;
+                }
+            };
+            // udp has no unix sockets, so leave it out rather than refuse the command
+            let unix = unix_name(&local).is_some()
+                || remoteMap.iter().any(|(_, remote)| unix_name(remote).is_some());
+            forwarder_configs.push(ForwardSessionConfig {
+                local,
+                remoteMap,
+                enable_udp: config.enable_udp && !unix,
+                ..config.clone()
+            });
+        }
+        // the listeners of a range share their problems
+        for e in validate_forwarders(forwarder_configs.iter().map(|c| (0, c)), &[]) {
+            let e = e.to_string();
+            if !problems.contains(&e) {
+                problems.push(e);
+            }
+        }
+    }
+
+    for p in &problems {
+        println!("{}", p);
+    }
+    if cli.check {
+        if problems.is_empty() {
+            println!("configuration is valid, {} forwarders", forwarder_configs.len());
+            std::process::exit(0);
+        }
+        println!("{} problems found", problems.len());
+    }
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+    if cli.dump_config {
+        print!(
+            "{}",
+            to_yaml(&ConfigFile {
+                forwarders: forwarder_configs,
+                upstreams,
+                metrics_listen,
+                admin_listen,
+                access_log,
+            })
+        );
+        std::process::exit(0);
+    }
+    if forwarder_configs.is_empty() {
+        println!("do nothing");
+        std::process::exit(0);
     }
 
     panic::set_hook(Box::new(|panic_info| {
@@ -338,28 +634,160 @@ fn main() {
         process::exit(1); // Exit the process with a non-zero status code
     }));
 
-    let mut handlers = vec![];
-    let sync_pair = Arc::new((Mutex::new(false), Condvar::new()));
-    let pair2 = sync_pair.clone();
+    let (tx, rx) = mpsc::channel();
+    let ctrlc_tx = tx.clone();
     let ctrlcPressed = AtomicBool::new(false);
     ctrlc::set_handler(move || {
         if ctrlcPressed.load(Ordering::SeqCst) {
             std::process::exit(0);
         }
         ctrlcPressed.store(true, Ordering::SeqCst);
-        println!("ctrl-c pressed, exiting ...");
-        let (lock, cvar) = &*pair2;
-        let mut close = lock.lock().unwrap();
-        *close = true;
-        cvar.notify_all();
+        println!("ctrl-c pressed, exiting (press again to force) ...");
+        ctrlc_tx.send(ControlEvent::Shutdown).unwrap_or(());
     })
     .unwrap();
+    watch_terminate_signal(tx.clone());
+    if let Some(file) = &config_file {
+        watch_reload_signal(tx.clone());
+        if cli.watch {
+            watch_config_file(file.clone(), tx.clone());
+        }
+    }
+
+    if let Some(addr) = &metrics_listen {
+        if let Err(e) = portforwarder::metrics::serve(addr.as_str()) {
+            println!("fail to serve metrics at {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &access_log {
+        if let Err(e) = portforwarder::access_log::open(path) {
+            println!("fail to open access log {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(addr) = &admin_listen {
+        if let Err(e) = portforwarder::admin::serve(addr) {
+            println!("fail to serve admin API at {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    }
 
+    upstream::configure(&upstreams);
+    upstream::start_health_checks();
+    let mut running = vec![];
     for cc in forwarder_configs {
-        handlers.push(cc.run(sync_pair.clone()));
+        match RunningForwarder::start(cc) {
+            Ok(r) => running.push(r),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // every listener is bound by now, a failing bind has exited above
+    notify_systemd(&format!("READY=1\nSTATUS={}", status(&running)));
+
+    let watchdog = systemd::watchdog_interval();
+    if let Some(interval) = watchdog {
+        log::info!("systemd watchdog every {:?}", interval);
+    }
+    let mut draining = vec![];
+    loop {
+        let event = match watchdog {
+            Some(interval) => rx.recv_timeout(interval / 2),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Ok(ControlEvent::Reload) => {
+                notify_systemd(&systemd::reloading());
+                match load_file(config_file.as_ref().unwrap()) {
+                    Ok(configs) => {
+                        upstream::configure(&configs.upstreams);
+                        reload(&mut running, configs.forwarders, &mut draining)
+                    }
+                    Err(problems) => {
+                        for p in problems {
+                            log::warn!("{}", p);
+                        }
+                        log::warn!("keep running configuration, reload failed");
+                    }
+                }
+                notify_systemd(&format!("READY=1\nSTATUS={}", status(&running)));
+            }
+            Ok(ControlEvent::Shutdown) => break,
+        }
+        // a stopped forwarder loop withholds the ping, so systemd restarts us
+        if watchdog.is_some() && running.iter().all(|r| r.forwarder.is_alive()) {
+            notify_systemd("WATCHDOG=1");
+        }
     }
 
-    for h in handlers {
+    notify_systemd("STOPPING=1\nSTATUS=draining connections");
+
+    let closing: Vec<_> = running
+        .into_iter()
+        .map(|r| std::thread::spawn(r.forwarder.close()))
+        .collect();
+    // what reloads left draining gets `drain_timeout` too
+    for d in &draining {
+        d.bound();
+    }
+    for d in draining {
+        d.join();
+    }
+    for h in closing {
         h.join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from([
+            "portfd",
+            "0.0.0.0:8808",
+            "-r",
+            "[ssh]=127.0.0.1:22",
+            "--rule",
+            "^GET /a=b=[::1]:80",
+            "-r",
+            "[http]=web_pool",
+            "--allow",
+            "10.0.0.0/8,127.0.0.1",
+            "-w",
+            "::1",
+            "--mode",
+            "socks5",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.rules,
+            [
+                ("[ssh]".to_string(), "127.0.0.1:22".to_string()),
+                ("^GET /a=b".to_string(), "[::1]:80".to_string()),
+                ("[http]".to_string(), "web_pool".to_string()),
+            ]
+        );
+        assert_eq!(cli.allow, ["10.0.0.0/8", "127.0.0.1", "::1"]);
+        assert_eq!(cli.mode, Mode::Socks5);
+
+        for args in [
+            &["portfd", "0.0.0.0"][..],
+            &["portfd", "0.0.0.0:1", "-r", "[ssh]"],
+            &["portfd", "0.0.0.0:1", "-r", "(=127.0.0.1:1"],
+            &["portfd", "0.0.0.0:1", "-m", "0"],
+            &["portfd", "-c", "a.yaml", "-t"],
+        ] {
+            assert!(Cli::try_parse_from(args).is_err(), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use crate::address_matcher::parse_net;
+use crate::endpoint::Endpoint;
+use crate::forward_config::CaptureConfig;
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LINKTYPE_RAW: u16 = 101;
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const MAX_SEGMENT: usize = 32 * 1024;
+
+/// A pcapng file with a single raw-IP interface, shared by every forwarder
+/// loop that captures into the same path.
+pub struct CaptureFile {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+fn open_files() -> &'static Mutex<HashMap<String, Weak<CaptureFile>>> {
+    static FILES: OnceLock<Mutex<HashMap<String, Weak<CaptureFile>>>> = OnceLock::new();
+    FILES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let padded = (body.len() + 3) & !3;
+    let total = (12 + padded) as u32;
+    let mut out = Vec::with_capacity(total as usize);
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&total.to_le_bytes());
+    out.extend_from_slice(body);
+    out.resize(8 + padded, 0);
+    out.extend_from_slice(&total.to_le_bytes());
+    out
+}
+
+impl CaptureFile {
+    fn new(mut out: Box<dyn Write + Send>) -> io::Result<CaptureFile> {
+        let mut shb = vec![];
+        shb.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        out.write_all(&block(0x0A0D_0D0A, &shb))?;
+        let mut idb = vec![];
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        out.write_all(&block(1, &idb))?;
+        out.flush()?;
+        Ok(CaptureFile {
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Open `path` for capturing, or share the writer if it is already open.
+    pub fn open(path: &str) -> io::Result<Arc<CaptureFile>> {
+        let mut files = open_files().lock().unwrap();
+        if let Some(file) = files.get(path).and_then(|f| f.upgrade()) {
+            return Ok(file);
+        }
+        let file = Arc::new(CaptureFile::new(Box::new(File::create(path)?))?);
+        files.insert(path.to_string(), Arc::downgrade(&file));
+        log::info!("capture forwarded traffic to {}", path);
+        Ok(file)
+    }
+
+    fn write_packet(&self, packet: &[u8]) {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut epb = Vec::with_capacity(20 + packet.len());
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(packet);
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out.write_all(&block(6, &epb)).and_then(|_| out.flush()) {
+            log::warn!("fail to write capture: {}", e);
+        }
+    }
+}
+
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in chunks {
+        let mut words = chunk.chunks_exact(2);
+        for w in &mut words {
+            sum += u16::from_be_bytes([w[0], w[1]]) as u32;
+        }
+        if let [last] = words.remainder() {
+            sum += (*last as u32) << 8;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Both addresses in the same family, IPv4 is mapped into IPv6 when they differ.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (IpAddr, IpAddr) {
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (IpAddr::V4(a), IpAddr::V4(b)),
+        (a, b) => {
+            let v6 = |ip: IpAddr| -> Ipv6Addr {
+                match ip {
+                    IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                    IpAddr::V6(v6) => v6,
+                }
+            };
+            (IpAddr::V6(v6(a)), IpAddr::V6(v6(b)))
+        }
+    }
+}
+
+/// Wrap a transport segment into an IP packet, filling in the transport checksum
+/// at `checksum_at`.
+fn ip_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    proto: u8,
+    mut segment: Vec<u8>,
+    checksum_at: usize,
+) -> Vec<u8> {
+    let (src_ip, dst_ip) = same_family(src, dst);
+    let len = segment.len();
+    let mut packet = vec![];
+    match (src_ip, dst_ip) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let sum = checksum(&[
+                &s.octets(),
+                &d.octets(),
+                &[0, proto],
+                &(len as u16).to_be_bytes(),
+                &segment,
+            ]);
+            segment[checksum_at..checksum_at + 2].copy_from_slice(&sum.to_be_bytes());
+            let mut header = vec![0x45, 0];
+            header.extend_from_slice(&((20 + len) as u16).to_be_bytes());
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, proto, 0, 0]);
+            header.extend_from_slice(&s.octets());
+            header.extend_from_slice(&d.octets());
+            let sum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+            packet.extend_from_slice(&header);
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            let sum = checksum(&[
+                &s.octets(),
+                &d.octets(),
+                &(len as u32).to_be_bytes(),
+                &[0, 0, 0, proto],
+                &segment,
+            ]);
+            // a zero UDP checksum means "none", which IPv6 doesn't allow
+            let sum = if sum == 0 && proto == 17 { 0xffff } else { sum };
+            segment[checksum_at..checksum_at + 2].copy_from_slice(&sum.to_be_bytes());
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(len as u16).to_be_bytes());
+            packet.extend_from_slice(&[proto, 64]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+        }
+        _ => unreachable!(),
+    }
+    packet.extend_from_slice(&segment);
+    packet
+}
+
+fn tcp_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(20 + data.len());
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    segment.extend_from_slice(data);
+    ip_packet(src, dst, 6, segment, 16)
+}
+
+fn udp_packet(src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(8 + data.len());
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(data);
+    ip_packet(src, dst, 17, segment, 6)
+}
+
+/// The `capture` option of a forwarder: where to write and what to keep.
+pub struct Capture {
+    file: Arc<CaptureFile>,
+    rules: Vec<String>,
+    clients: Vec<IpNet>,
+}
+
+impl Capture {
+    pub fn from(config: &CaptureConfig) -> Result<Capture, String> {
+        let mut clients = vec![];
+        for net in &config.clients {
+            clients.push(parse_net(net)?);
+        }
+        let file = CaptureFile::open(&config.file)
+            .map_err(|e| format!("fail to open capture file {}: {}", config.file, e))?;
+        Ok(Capture {
+            file,
+            rules: config.rules.clone(),
+            clients,
+        })
+    }
+
+    /// Whether a session of `client` routed by the rule `rule` is captured.
+    /// Unix clients have no address and only match when `clients` is empty.
+    pub fn wants(&self, rule: &str, client: &Endpoint) -> bool {
+        let client = client.inet();
+        (self.rules.is_empty() || self.rules.iter().any(|r| r == rule))
+            && (self.clients.is_empty()
+                || self
+                    .clients
+                    .iter()
+                    .any(|n| client.is_some_and(|c| n.contains(&c.ip()))))
+    }
+
+    /// Start a synthetic TCP stream, the handshake is written right away. Unix
+    /// sockets show up as 127.0.0.1, port 1 for the client and 2 upstream.
+    pub fn tcp(&self, client: &Endpoint, upstream: &Endpoint) -> TcpCapture {
+        let stand_in = |e: &Endpoint, port: u16| {
+            e.inet()
+                .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+        };
+        let (client, upstream) = (stand_in(client, 1), stand_in(upstream, 2));
+        let stream = TcpCapture {
+            file: self.file.clone(),
+            client,
+            upstream,
+            client_seq: 1,
+            upstream_seq: 1,
+        };
+        let file = &stream.file;
+        file.write_packet(&tcp_packet(client, upstream, 0, 0, TCP_SYN, &[]));
+        file.write_packet(&tcp_packet(upstream, client, 0, 1, TCP_SYN | TCP_ACK, &[]));
+        file.write_packet(&tcp_packet(client, upstream, 1, 1, TCP_ACK, &[]));
+        stream
+    }
+
+    pub fn udp(&self, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        self.file.write_packet(&udp_packet(src, dst, data));
+    }
+}
+
+/// One relayed TCP connection, closed with FINs when dropped.
+pub struct TcpCapture {
+    file: Arc<CaptureFile>,
+    client: SocketAddr,
+    upstream: SocketAddr,
+    client_seq: u32,
+    upstream_seq: u32,
+}
+
+impl TcpCapture {
+    pub fn client_data(&mut self, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            let (src, dst) = (self.client, self.upstream);
+            let packet = tcp_packet(
+                src,
+                dst,
+                self.client_seq,
+                self.upstream_seq,
+                TCP_PSH | TCP_ACK,
+                chunk,
+            );
+            self.file.write_packet(&packet);
+            self.client_seq = self.client_seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+
+    pub fn upstream_data(&mut self, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            let (src, dst) = (self.upstream, self.client);
+            let packet = tcp_packet(
+                src,
+                dst,
+                self.upstream_seq,
+                self.client_seq,
+                TCP_PSH | TCP_ACK,
+                chunk,
+            );
+            self.file.write_packet(&packet);
+            self.upstream_seq = self.upstream_seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+}
+
+impl Drop for TcpCapture {
+    fn drop(&mut self) {
+        let (c, u) = (self.client, self.upstream);
+        let (cs, us) = (self.client_seq, self.upstream_seq);
+        let (cs1, us1) = (cs.wrapping_add(1), us.wrapping_add(1));
+        self.file
+            .write_packet(&tcp_packet(c, u, cs, us, TCP_FIN | TCP_ACK, &[]));
+        self.file
+            .write_packet(&tcp_packet(u, c, us, cs1, TCP_FIN | TCP_ACK, &[]));
+        self.file
+            .write_packet(&tcp_packet(c, u, cs1, us1, TCP_ACK, &[]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packets_have_valid_checksums() {
+        let client: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let upstream: SocketAddr = "192.168.1.2:22".parse().unwrap();
+        let packet = tcp_packet(client, upstream, 1, 1, TCP_PSH | TCP_ACK, b"SSH-2.0-x\r\n");
+        assert_eq!(checksum(&[&packet[..20]]), 0);
+        let pseudo = [
+            &packet[12..20],
+            &[0, 6],
+            &((packet.len() - 20) as u16).to_be_bytes()[..],
+        ];
+        assert_eq!(
+            checksum(&[pseudo[0], pseudo[1], pseudo[2], &packet[20..]]),
+            0
+        );
+
+        let mixed: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        let packet = udp_packet(client, mixed, b"odd");
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(
+            &packet[8..24],
+            &Ipv6Addr::from([0, 0, 0, 0, 0, 0xffff, 0x0a00, 1]).octets()
+        );
+        let len = (packet.len() - 40) as u32;
+        let sum = checksum(&[
+            &packet[8..40],
+            &len.to_be_bytes(),
+            &[0, 0, 0, 17],
+            &packet[40..],
+        ]);
+        assert_eq!(sum, 0);
+    }
+
+    #[test]
+    fn pcapng_blocks() {
+        let sink = Sink::default();
+        let file = CaptureFile::new(Box::new(sink.clone())).unwrap();
+        file.write_packet(b"abcde");
+        let bytes = sink.0.lock().unwrap().clone();
+        assert_eq!(&bytes[0..4], &0x0A0D_0D0Au32.to_le_bytes());
+        assert_eq!(&bytes[8..12], &0x1A2B_3C4Du32.to_le_bytes());
+        let shb_len = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let idb_len =
+            u32::from_le_bytes(bytes[shb_len + 4..shb_len + 8].try_into().unwrap()) as usize;
+        let epb = &bytes[shb_len + idb_len..];
+        assert_eq!(&epb[0..4], &6u32.to_le_bytes());
+        assert_eq!(epb.len(), 32 + 8);
+        assert_eq!(&epb[28..33], b"abcde");
+        assert_eq!(&epb[epb.len() - 4..], &40u32.to_le_bytes());
+    }
+
+    #[test]
+    fn filter_by_rule_and_client() {
+        let capture = Capture {
+            file: Arc::new(CaptureFile::new(Box::new(Sink::default())).unwrap()),
+            rules: vec!["[ssh]".to_string()],
+            clients: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        assert!(capture.wants("[ssh]", &"10.1.2.3:1".parse().unwrap()));
+        assert!(!capture.wants("[rdp]", &"10.1.2.3:1".parse().unwrap()));
+        assert!(!capture.wants("[ssh]", &"11.1.2.3:1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,2538 @@
+use crate::address_matcher::{AclAction, AclRule};
+use crate::forward_config::{
+    CaptureConfig, ConfigError, DropPolicy, ForwardSessionConfig, HealthCheckConfig, MAX_LISTENERS,
+    SocketConfig, TcpMode, UdpQueueConfig, UnmatchedLogConfig, UpstreamConfig, UpstreamServer,
+    expand_local, substitute_port, validate_forwarders,
+};
+use crate::utils::resolveSockAddr;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use serde_json::Value;
+use serde_yaml::{Mapping, Value as Yaml};
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+/// Syntax of a config document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// `.toml` and `.json` files by their extension, anything else is YAML.
+    pub fn from_path(path: &str) -> Format {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".toml") {
+            Format::Toml
+        } else if lower.ends_with(".json") {
+            Format::Json
+        } else {
+            Format::Yaml
+        }
+    }
+}
+
+/// A config document: the forwarders and the process-wide services.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigFile {
+    pub forwarders: Vec<ForwardSessionConfig<String>>,
+    pub upstreams: Vec<UpstreamConfig>,
+    pub metrics_listen: Option<String>,
+    pub admin_listen: Option<String>,
+    pub access_log: Option<String>,
+}
+
+pub fn convert_to_bytes(input: &str) -> Option<usize> {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut num_str = String::new();
+    let mut unit_str = String::new();
+
+    for c in input.chars() {
+        if c.is_ascii_digit() {
+            num_str.push(c);
+        } else {
+            unit_str.push(c);
+        }
+    }
+
+    let num = match num_str.parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => return None,
+    };
+
+    units
+        .iter()
+        .position(|&u| u == unit_str.trim().to_uppercase())
+        .and_then(|index| 1024_usize.checked_pow(index as u32)?.checked_mul(num))
+}
+
+pub fn convert_to_duration(input: &str) -> Option<Duration> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let num = match input[..split].parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => return None,
+    };
+
+    match input[split..].trim().to_lowercase().as_str() {
+        "ns" => Some(Duration::from_nanos(num)),
+        "us" => Some(Duration::from_micros(num)),
+        "ms" => Some(Duration::from_millis(num)),
+        "" | "s" => Some(Duration::from_secs(num)),
+        "m" => num.checked_mul(60).map(Duration::from_secs),
+        "h" => num.checked_mul(60 * 60).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+/// Problems found while reading the configuration, as key and message.
+type Problems = Vec<(String, String)>;
+
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("'{}'", s),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Null => "nothing".to_string(),
+        Value::Array(_) => "a list".to_string(),
+        Value::Object(_) => "a mapping".to_string(),
+    }
+}
+
+fn check_keys(value: &Value, prefix: &str, known: &[&str], problems: &mut Problems) {
+    if let Some(map) = value.as_object() {
+        for key in map.keys() {
+            if !known.contains(&key.as_str()) {
+                problems.push((format!("{}{}", prefix, key), "unknown key".to_string()));
+            }
+        }
+    }
+}
+
+fn parse_string_list(value: &Value, key: &str, problems: &mut Problems) -> Vec<String> {
+    let mut list = vec![];
+    let entries = match value {
+        Value::Null => return list,
+        Value::Array(v) => v,
+        v => {
+            problems.push((
+                key.to_string(),
+                format!("expect a list, got {}", value_text(v)),
+            ));
+            return list;
+        }
+    };
+    for (i, entry) in entries.iter().enumerate() {
+        match entry.as_str() {
+            Some(s) => list.push(String::from(s)),
+            None => problems.push((
+                format!("{}[{}]", key, i),
+                format!("expect a string, got {}", value_text(entry)),
+            )),
+        }
+    }
+    list
+}
+
+/// `capture` is either a file name or a mapping with `file`, `rules` and `clients`.
+fn parse_capture(value: &Value, problems: &mut Problems) -> Option<CaptureConfig> {
+    match value {
+        Value::Null => None,
+        Value::String(file) => Some(CaptureConfig {
+            file: file.clone(),
+            ..Default::default()
+        }),
+        Value::Object(_) => {
+            check_keys(value, "capture.", &["file", "rules", "clients"], problems);
+            let file = match value["file"].as_str() {
+                Some(f) => f.to_string(),
+                None => {
+                    problems.push(("capture.file".to_string(), "missing".to_string()));
+                    String::new()
+                }
+            };
+            Some(CaptureConfig {
+                file,
+                rules: parse_string_list(&value["rules"], "capture.rules", problems),
+                clients: parse_string_list(&value["clients"], "capture.clients", problems),
+            })
+        }
+        v => {
+            problems.push((
+                "capture".to_string(),
+                format!("expect a file name or a mapping, got {}", value_text(v)),
+            ));
+            None
+        }
+    }
+}
+
+/// `unmatched_log` is either a file name or a mapping with `file`, `bytes`,
+/// `per_minute`, `dedup_prefix` and `dedup_window`.
+fn parse_unmatched_log(value: &Value, problems: &mut Problems) -> Option<UnmatchedLogConfig> {
+    let mut config = UnmatchedLogConfig::default();
+    match value {
+        Value::Null => return None,
+        Value::String(file) => config.file = file.clone(),
+        Value::Object(_) => {
+            check_keys(
+                value,
+                "unmatched_log.",
+                &[
+                    "file",
+                    "bytes",
+                    "per_minute",
+                    "dedup_prefix",
+                    "dedup_window",
+                ],
+                problems,
+            );
+            match value["file"].as_str() {
+                Some(f) => config.file = f.to_string(),
+                None => problems.push(("unmatched_log.file".to_string(), "missing".to_string())),
+            };
+            let mut count = |key: &str, default: usize| match &value[key] {
+                Value::Null => default,
+                v => match v.as_u64() {
+                    Some(n) => n as usize,
+                    None => {
+                        problems.push((
+                            format!("unmatched_log.{}", key),
+                            format!("expect a count, got {}", value_text(v)),
+                        ));
+                        default
+                    }
+                },
+            };
+            config.per_minute = count("per_minute", config.per_minute as usize) as u32;
+            config.dedup_prefix = count("dedup_prefix", config.dedup_prefix);
+            if let Some(n) = parse_size(&value["bytes"], "unmatched_log.bytes", problems) {
+                config.bytes = n;
+            }
+            if let Some(d) = parse_duration(
+                &value["dedup_window"],
+                "unmatched_log.dedup_window",
+                problems,
+            ) {
+                config.dedup_window = d;
+            }
+        }
+        v => problems.push((
+            "unmatched_log".to_string(),
+            format!("expect a file name or a mapping, got {}", value_text(v)),
+        )),
+    }
+    Some(config)
+}
+
+/// `udp_queue` is a mapping of limits and the `drop` policy, each optional.
+fn parse_udp_queue(value: &Value, problems: &mut Problems) -> UdpQueueConfig {
+    let mut config = UdpQueueConfig::default();
+    match value {
+        Value::Null => {}
+        Value::Object(_) => {
+            check_keys(
+                value,
+                "udp_queue.",
+                &[
+                    "session_packets",
+                    "session_bytes",
+                    "packets",
+                    "bytes",
+                    "drop",
+                ],
+                problems,
+            );
+            let mut count = |key: &str, default: usize| {
+                let full = format!("udp_queue.{}", key);
+                parse_count(&value[key], &full, default as u32, problems) as usize
+            };
+            config.session_packets = count("session_packets", config.session_packets);
+            config.packets = count("packets", config.packets);
+            let mut size = |key: &str, default: usize| {
+                let full = format!("udp_queue.{}", key);
+                parse_size(&value[key], &full, problems).unwrap_or(default)
+            };
+            config.session_bytes = size("session_bytes", config.session_bytes);
+            config.bytes = size("bytes", config.bytes);
+            config.drop = match &value["drop"] {
+                Value::Null => config.drop,
+                v if v.as_str() == Some("oldest") => DropPolicy::Oldest,
+                v if v.as_str() == Some("newest") => DropPolicy::Newest,
+                v => {
+                    problems.push((
+                        "udp_queue.drop".to_string(),
+                        format!("invalid {}, support values: oldest, newest", value_text(v)),
+                    ));
+                    config.drop
+                }
+            };
+        }
+        v => problems.push((
+            "udp_queue".to_string(),
+            format!("expect a mapping, got {}", value_text(v)),
+        )),
+    }
+    config
+}
+
+const SOCKET_KEYS: &[&str] = &[
+    "bind_address",
+    "interface",
+    "fwmark",
+    "nodelay",
+    "keepalive",
+    "keepalive_interval",
+    "keepalive_count",
+    "user_timeout",
+    "send_buffer",
+    "recv_buffer",
+    "fast_open",
+    "tos",
+];
+
+/// Socket options under `key`, a mapping of `SOCKET_KEYS`.
+fn parse_socket(value: &Value, key: &str, problems: &mut Problems) -> SocketConfig {
+    let mut options = SocketConfig::default();
+    match value {
+        Value::Null => return options,
+        Value::Object(_) => {}
+        v => {
+            problems.push((
+                key.to_string(),
+                format!("expect a mapping, got {}", value_text(v)),
+            ));
+            return options;
+        }
+    }
+    let prefix = format!("{}.", key);
+    check_keys(value, &prefix, SOCKET_KEYS, problems);
+    let name = |k: &str| format!("{}{}", prefix, k);
+    let number = |k: &str, max: u64, problems: &mut Problems| match &value[k] {
+        Value::Null => None,
+        v => match v.as_u64().filter(|n| *n <= max) {
+            Some(n) => Some(n),
+            None => {
+                problems.push((
+                    name(k),
+                    format!("expect a number up to {}, got {}", max, value_text(v)),
+                ));
+                None
+            }
+        },
+    };
+    let flag = |k: &str, problems: &mut Problems| match &value[k] {
+        Value::Null => None,
+        v => Some(parse_bool(v, &name(k), false, problems)),
+    };
+    options.bind_address = match &value["bind_address"] {
+        Value::Null => None,
+        v => match v.as_str().and_then(|s| s.parse().ok()) {
+            Some(ip) => Some(ip),
+            None => {
+                problems.push((
+                    name("bind_address"),
+                    format!("expect an IP address, got {}", value_text(v)),
+                ));
+                None
+            }
+        },
+    };
+    options.interface = match &value["interface"] {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        v => {
+            problems.push((
+                name("interface"),
+                format!("expect a string, got {}", value_text(v)),
+            ));
+            None
+        }
+    };
+    options.fwmark = number("fwmark", u32::MAX as u64, problems).map(|n| n as u32);
+    options.nodelay = flag("nodelay", problems);
+    options.keepalive = parse_duration(&value["keepalive"], &name("keepalive"), problems);
+    options.keepalive_interval = parse_duration(
+        &value["keepalive_interval"],
+        &name("keepalive_interval"),
+        problems,
+    );
+    options.keepalive_count =
+        number("keepalive_count", u32::MAX as u64, problems).map(|n| n as u32);
+    options.user_timeout = parse_duration(&value["user_timeout"], &name("user_timeout"), problems);
+    options.send_buffer = parse_size(&value["send_buffer"], &name("send_buffer"), problems);
+    options.recv_buffer = parse_size(&value["recv_buffer"], &name("recv_buffer"), problems);
+    options.fast_open = flag("fast_open", problems);
+    options.tos = number("tos", u8::MAX as u64, problems).map(|n| n as u8);
+    options
+}
+
+/// Entries of `acl` have exactly one of `allow`, `deny`, `allow_file` or `deny_file`.
+fn parse_acl(value: &Value, problems: &mut Problems) -> Vec<AclRule> {
+    let mut rules = vec![];
+    let entries = match value {
+        Value::Null => return rules,
+        Value::Array(v) => v,
+        v => {
+            problems.push((
+                "acl".to_string(),
+                format!("expect a list, got {}", value_text(v)),
+            ));
+            return rules;
+        }
+    };
+    for (i, entry) in entries.iter().enumerate() {
+        let key = format!("acl[{}]", i);
+        let map = match entry.as_object() {
+            Some(m) if m.len() == 1 => m,
+            _ => {
+                problems.push((
+                    key,
+                    "expect one of allow, deny, allow_file or deny_file".to_string(),
+                ));
+                continue;
+            }
+        };
+        let (kind, value) = map.iter().next().unwrap();
+        let value = match value.as_str() {
+            Some(v) => String::from(v),
+            None => {
+                problems.push((key, format!("expect a string, got {}", value_text(value))));
+                continue;
+            }
+        };
+        let rule = match kind.as_str() {
+            "allow" => AclRule::Net(AclAction::Allow, value),
+            "deny" => AclRule::Net(AclAction::Deny, value),
+            "allow_file" => AclRule::File(AclAction::Allow, value),
+            "deny_file" => AclRule::File(AclAction::Deny, value),
+            _ => {
+                problems.push((
+                    key,
+                    format!(
+                        "unknown key '{}', expect one of allow, deny, allow_file or deny_file",
+                        kind
+                    ),
+                ));
+                continue;
+            }
+        };
+        rules.push(rule);
+    }
+    rules
+}
+
+/// A size like `2MB`, or a number of bytes.
+fn parse_size(value: &Value, key: &str, problems: &mut Problems) -> Option<usize> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => match convert_to_bytes(s) {
+            Some(n) => Some(n),
+            None => {
+                problems.push((key.to_string(), format!("invalid size '{}', eg. 64KB", s)));
+                None
+            }
+        },
+        v => match v.as_u64() {
+            Some(n) => Some(n as usize),
+            None => {
+                problems.push((
+                    key.to_string(),
+                    format!("expect a size, got {}", value_text(v)),
+                ));
+                None
+            }
+        },
+    }
+}
+
+/// A duration like `30s`, or a number of seconds.
+fn parse_duration(value: &Value, key: &str, problems: &mut Problems) -> Option<Duration> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => match convert_to_duration(s) {
+            Some(d) => Some(d),
+            None => {
+                problems.push((
+                    key.to_string(),
+                    format!("invalid duration '{}', eg. 30s", s),
+                ));
+                None
+            }
+        },
+        v => match v.as_u64() {
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => {
+                problems.push((
+                    key.to_string(),
+                    format!("expect a duration, got {}", value_text(v)),
+                ));
+                None
+            }
+        },
+    }
+}
+
+fn parse_bool(value: &Value, key: &str, default: bool, problems: &mut Problems) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::Null => default,
+        v => {
+            problems.push((
+                key.to_string(),
+                format!("expect true or false, got {}", value_text(v)),
+            ));
+            default
+        }
+    }
+}
+
+const FORWARDER_KEYS: &[&str] = &[
+    "local",
+    "remoteMap",
+    "remote",
+    "tcp_mode",
+    "enable_tcp",
+    "enable_udp",
+    "conn_bufsize",
+    "max_connections",
+    "drain_timeout",
+    "allow_nets",
+    "deny_nets",
+    "acl",
+    "capture",
+    "unmatched_log",
+    "fd_name",
+    "ipv6_only",
+    "unix_mode",
+    "listen_socket",
+    "upstream_socket",
+    "udp_idle_timeout",
+    "udp_evict_lru",
+    "udp_queue",
+];
+
+const TOP_LEVEL_KEYS: &[&str] = &[
+    "forwarders",
+    "include",
+    "upstreams",
+    "metrics_listen",
+    "admin_listen",
+    "access_log",
+];
+
+/// Read a forwarder as far as possible along with its problems, so that the
+/// values which were read can still be validated. Missing strings are left empty.
+fn parse_forwarder(value: &Value) -> (Option<ForwardSessionConfig<String>>, Problems) {
+    let mut problems = vec![];
+    if !value.is_object() {
+        problems.push((
+            String::new(),
+            format!("expect a mapping, got {}", value_text(value)),
+        ));
+        return (None, problems);
+    }
+    check_keys(value, "", FORWARDER_KEYS, &mut problems);
+    let local = match value["local"].as_str() {
+        Some(s) => String::from(s),
+        None => {
+            problems.push(("local".to_string(), "missing".to_string()));
+            String::new()
+        }
+    };
+    let enable_tcp = parse_bool(&value["enable_tcp"], "enable_tcp", true, &mut problems);
+    let enable_udp = parse_bool(&value["enable_udp"], "enable_udp", true, &mut problems);
+    let tcp_mode = match &value["tcp_mode"] {
+        Value::Null => TcpMode::Forward,
+        v if v.as_str() == Some("forward") => TcpMode::Forward,
+        v if v.as_str() == Some("socks5") => TcpMode::Socks5Server,
+        v => {
+            problems.push((
+                "tcp_mode".to_string(),
+                format!("invalid {}, support values: forward, socks5", value_text(v)),
+            ));
+            TcpMode::Forward
+        }
+    };
+    let conn_bufsize = parse_size(&value["conn_bufsize"], "conn_bufsize", &mut problems)
+        .unwrap_or(2 * 1024 * 1024);
+    let allow_nets = parse_string_list(&value["allow_nets"], "allow_nets", &mut problems);
+    let deny_nets = parse_string_list(&value["deny_nets"], "deny_nets", &mut problems);
+    let acl = parse_acl(&value["acl"], &mut problems);
+    let capture = parse_capture(&value["capture"], &mut problems);
+    let unmatched_log = parse_unmatched_log(&value["unmatched_log"], &mut problems);
+    let drain_timeout = parse_duration(&value["drain_timeout"], "drain_timeout", &mut problems)
+        .unwrap_or(Duration::ZERO);
+    let fd_name = optional_string(value, "fd_name", &mut problems);
+    let ipv6_only = parse_bool(&value["ipv6_only"], "ipv6_only", false, &mut problems);
+    let unix_mode = parse_mode(&value["unix_mode"], "unix_mode", &mut problems);
+    let listen_socket = parse_socket(&value["listen_socket"], "listen_socket", &mut problems);
+    let upstream_socket = parse_socket(&value["upstream_socket"], "upstream_socket", &mut problems);
+    let udp_idle_timeout = parse_duration(
+        &value["udp_idle_timeout"],
+        "udp_idle_timeout",
+        &mut problems,
+    )
+    .unwrap_or(Duration::from_secs(180));
+    let udp_evict_lru = parse_bool(
+        &value["udp_evict_lru"],
+        "udp_evict_lru",
+        false,
+        &mut problems,
+    );
+    let udp_queue = parse_udp_queue(&value["udp_queue"], &mut problems);
+
+    let max_connections = match &value["max_connections"] {
+        Value::Null => -1,
+        v => match v.as_i64() {
+            Some(mc) => mc,
+            None => {
+                problems.push((
+                    "max_connections".to_string(),
+                    format!("expect a number, got {}", value_text(v)),
+                ));
+                -1
+            }
+        },
+    };
+
+    let mut remoteMap: Vec<(String, String)> = vec![];
+    let mut rule_sockets = vec![];
+    let mut rule_idle_timeouts = vec![];
+    match &value["remoteMap"] {
+        Value::Array(pairs) => {
+            for (i, pair) in pairs.iter().enumerate() {
+                let prefix = format!("remoteMap[{}].", i);
+                check_keys(
+                    pair,
+                    &prefix,
+                    &["pattern", "remote", "upstream_socket", "udp_idle_timeout"],
+                    &mut problems,
+                );
+                let key = format!("{}upstream_socket", prefix);
+                let options = parse_socket(&pair["upstream_socket"], &key, &mut problems);
+                if options != SocketConfig::default() {
+                    rule_sockets.push((i, options));
+                }
+                let key = format!("{}udp_idle_timeout", prefix);
+                if let Some(timeout) =
+                    parse_duration(&pair["udp_idle_timeout"], &key, &mut problems)
+                {
+                    rule_idle_timeouts.push((i, timeout));
+                }
+                let mut field = |key: &str| match &pair[key] {
+                    Value::String(s) => s.clone(),
+                    v => {
+                        problems.push((
+                            format!("{}{}", prefix, key),
+                            match v {
+                                Value::Null => "missing".to_string(),
+                                v => format!("expect a string, got {}", value_text(v)),
+                            },
+                        ));
+                        String::new()
+                    }
+                };
+                remoteMap.push((field("pattern"), field("remote")));
+            }
+        }
+        Value::Null => {}
+        v => problems.push((
+            "remoteMap".to_string(),
+            format!("expect a list, got {}", value_text(v)),
+        )),
+    }
+    match &value["remote"] {
+        Value::String(s) => {
+            remoteMap.push((".*".to_string(), s.to_string()));
+        }
+        Value::Null => {}
+        v => problems.push((
+            "remote".to_string(),
+            format!("expect an address, got {}", value_text(v)),
+        )),
+    };
+
+    let config = ForwardSessionConfig {
+        local,
+        remoteMap,
+        enable_tcp,
+        enable_udp,
+        allow_nets,
+        deny_nets,
+        acl,
+        max_connections,
+        conn_bufsize,
+        tcp_mode,
+        drain_timeout,
+        capture,
+        unmatched_log,
+        fd_name,
+        ipv6_only,
+        unix_mode,
+        listen_socket,
+        upstream_socket,
+        rule_sockets,
+        group_sockets: vec![],
+        udp_idle_timeout,
+        rule_idle_timeouts,
+        udp_evict_lru,
+        udp_queue,
+    };
+    (Some(config), problems)
+}
+
+fn parse_count(value: &Value, key: &str, default: u32, problems: &mut Problems) -> u32 {
+    match value {
+        Value::Null => default,
+        v => match v.as_u64() {
+            Some(n) if n <= u32::MAX as u64 => n as u32,
+            _ => {
+                problems.push((
+                    key.to_string(),
+                    format!("expect a number, got {}", value_text(v)),
+                ));
+                default
+            }
+        },
+    }
+}
+
+fn parse_health_check(value: &Value, problems: &mut Problems) -> Option<HealthCheckConfig> {
+    let mut check = HealthCheckConfig::default();
+    match value {
+        Value::Null | Value::Bool(false) => return None,
+        Value::Bool(true) => return Some(check),
+        Value::Object(_) => {}
+        v => {
+            problems.push((
+                "health_check".to_string(),
+                format!("expect true or a mapping, got {}", value_text(v)),
+            ));
+            return None;
+        }
+    }
+    check_keys(
+        value,
+        "health_check.",
+        &["interval", "timeout", "fall", "rise"],
+        problems,
+    );
+    if let Some(d) = parse_duration(&value["interval"], "health_check.interval", problems) {
+        check.interval = d;
+    }
+    if let Some(d) = parse_duration(&value["timeout"], "health_check.timeout", problems) {
+        check.timeout = d;
+    }
+    check.fall = parse_count(&value["fall"], "health_check.fall", check.fall, problems);
+    check.rise = parse_count(&value["rise"], "health_check.rise", check.rise, problems);
+    Some(check)
+}
+
+/// Read an upstream group, its servers are addresses or mappings with a weight.
+fn parse_upstream(name: &str, value: &Value) -> (UpstreamConfig, Problems) {
+    let mut problems = vec![];
+    let mut config = UpstreamConfig {
+        name: name.to_string(),
+        ..Default::default()
+    };
+    if !value.is_object() {
+        problems.push((
+            String::new(),
+            format!("expect a mapping, got {}", value_text(value)),
+        ));
+        return (config, problems);
+    }
+    check_keys(
+        value,
+        "",
+        &[
+            "servers",
+            "health_check",
+            "max_fails",
+            "fail_timeout",
+            "upstream_socket",
+        ],
+        &mut problems,
+    );
+    match &value["servers"] {
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                let key = format!("servers[{}]", i);
+                let server = match item {
+                    Value::String(s) => UpstreamServer {
+                        address: s.clone(),
+                        weight: 1,
+                    },
+                    Value::Object(_) => {
+                        check_keys(
+                            item,
+                            &format!("{}.", key),
+                            &["address", "weight"],
+                            &mut problems,
+                        );
+                        let weight_key = format!("{}.weight", key);
+                        let weight = parse_count(&item["weight"], &weight_key, 1, &mut problems);
+                        match item["address"].as_str() {
+                            Some(s) => UpstreamServer {
+                                address: s.to_string(),
+                                weight,
+                            },
+                            None => {
+                                problems.push((format!("{}.address", key), "missing".to_string()));
+                                continue;
+                            }
+                        }
+                    }
+                    v => {
+                        problems.push((key, format!("expect an address, got {}", value_text(v))));
+                        continue;
+                    }
+                };
+                config.servers.push(server);
+            }
+        }
+        Value::Null => problems.push(("servers".to_string(), "missing".to_string())),
+        v => problems.push((
+            "servers".to_string(),
+            format!("expect a list, got {}", value_text(v)),
+        )),
+    }
+    config.health_check = parse_health_check(&value["health_check"], &mut problems);
+    config.max_fails = parse_count(
+        &value["max_fails"],
+        "max_fails",
+        config.max_fails,
+        &mut problems,
+    );
+    if let Some(d) = parse_duration(&value["fail_timeout"], "fail_timeout", &mut problems) {
+        config.fail_timeout = d;
+    }
+    config.upstream_socket =
+        parse_socket(&value["upstream_socket"], "upstream_socket", &mut problems);
+    (config, problems)
+}
+
+/// Replace `${port}` in every string of `value` but `local`.
+fn substitute_port_in(value: &mut Value, path: &str, port: u16, problems: &mut Problems) {
+    match value {
+        Value::String(s) => match substitute_port(s, port) {
+            Ok(text) => *s = text,
+            Err(e) => problems.push((path.to_string(), e)),
+        },
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                substitute_port_in(item, &format!("{}[{}]", path, i), port, problems);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                if !(path.is_empty() && key == "local") {
+                    substitute_port_in(item, &join_path(path, key), port, problems);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// One copy of a forwarder per address of its `local`, which may be a list
+/// and have port ranges. An IPv6 wildcard listening next to IPv4 on the same
+/// port is made `ipv6_only`.
+fn expand_forwarder(value: &Value, problems: &mut Problems) -> Vec<Value> {
+    let locals = match &value["local"] {
+        Value::String(s) => vec![("local".to_string(), Ok(s.as_str()))],
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(j, item)| {
+                let local = item
+                    .as_str()
+                    .ok_or_else(|| format!("expect an address, got {}", value_text(item)));
+                (format!("local[{}]", j), local)
+            })
+            .collect(),
+        Value::Null => return vec![value.clone()],
+        v => {
+            problems.push((
+                "local".to_string(),
+                format!("expect an address or a list, got {}", value_text(v)),
+            ));
+            return vec![];
+        }
+    };
+    let mut addresses = vec![];
+    for (key, local) in locals {
+        match local.map(expand_local) {
+            Ok(Ok(list)) => addresses.extend(list),
+            Ok(Err(e)) | Err(e) => problems.push((key, e)),
+        }
+    }
+    if addresses.len() > MAX_LISTENERS {
+        problems.push((
+            "local".to_string(),
+            format!(
+                "expands to {} listeners, at most {} are allowed",
+                addresses.len(),
+                MAX_LISTENERS
+            ),
+        ));
+    }
+    if !problems.is_empty() {
+        return vec![];
+    }
+    let parsed: Vec<Option<SocketAddr>> = addresses.iter().map(|a| a.parse().ok()).collect();
+    let ipv4_ports: Vec<u16> = parsed
+        .iter()
+        .flatten()
+        .filter(|a| a.is_ipv4())
+        .map(|a| a.port())
+        .collect();
+    let mut listeners = vec![];
+    for (address, addr) in addresses.into_iter().zip(parsed) {
+        let mut listener = value.clone();
+        let port = address
+            .rsplit(':')
+            .next()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(0);
+        substitute_port_in(&mut listener, "", port, problems);
+        if addr.is_some_and(|a| {
+            a.is_ipv6() && a.ip().is_unspecified() && ipv4_ports.contains(&a.port())
+        }) && listener["ipv6_only"].is_null()
+        {
+            listener["ipv6_only"] = Value::Bool(true);
+        }
+        listener["local"] = Value::String(address);
+        listeners.push(listener);
+    }
+    listeners
+}
+
+/// A file mode, written as an octal string like `"0660"` so that no format
+/// reads it as a decimal number.
+fn parse_mode(value: &Value, key: &str, problems: &mut Problems) -> Option<u32> {
+    let problem = match value {
+        Value::Null => return None,
+        Value::String(s) => match u32::from_str_radix(s, 8) {
+            Ok(mode) if mode <= 0o7777 => return Some(mode),
+            _ => format!("invalid mode '{}', expect octal digits like \"0660\"", s),
+        },
+        v => format!(
+            "expect an octal string like \"0660\", got {}",
+            value_text(v)
+        ),
+    };
+    problems.push((key.to_string(), problem));
+    None
+}
+
+fn optional_string(value: &Value, key: &str, problems: &mut Problems) -> Option<String> {
+    match &value[key] {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        v => {
+            problems.push((
+                key.to_string(),
+                format!("expect a string, got {}", value_text(v)),
+            ));
+            None
+        }
+    }
+}
+
+/// Lines of the values of a document by their key path, eg. `forwarders[0].local`.
+type LineMap = HashMap<String, usize>;
+
+fn join_path(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+/// The line of `path`, or of the closest enclosing value that has one.
+fn line_of(lines: &LineMap, path: &str) -> Option<usize> {
+    let mut path = path;
+    loop {
+        if let Some(line) = lines.get(path) {
+            return Some(*line);
+        }
+        path = &path[..path.rfind(['.', '['])?];
+    }
+}
+
+struct YamlFrame {
+    path: String,
+    is_map: bool,
+    /// The key whose value comes next in a mapping.
+    key: Option<String>,
+    /// The index of the next item in a sequence.
+    index: usize,
+}
+
+/// Collects the lines of keys and items from the events of the YAML parser.
+#[derive(Default)]
+struct YamlLines {
+    lines: LineMap,
+    stack: Vec<YamlFrame>,
+}
+
+impl YamlLines {
+    fn next_path(&self) -> String {
+        match self.stack.last() {
+            None => String::new(),
+            Some(f) if f.is_map => join_path(&f.path, f.key.as_deref().unwrap_or("")),
+            Some(f) => format!("{}[{}]", f.path, f.index),
+        }
+    }
+
+    fn value_at(&mut self, line: usize) -> String {
+        let path = self.next_path();
+        self.lines.entry(path.clone()).or_insert(line);
+        if let Some(f) = self.stack.last_mut() {
+            if f.is_map {
+                f.key = None;
+            } else {
+                f.index += 1;
+            }
+        }
+        path
+    }
+}
+
+impl MarkedEventReceiver for YamlLines {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        match ev {
+            Event::Scalar(key, ..)
+                if self
+                    .stack
+                    .last()
+                    .is_some_and(|f| f.is_map && f.key.is_none()) =>
+            {
+                let f = self.stack.last_mut().unwrap();
+                let path = join_path(&f.path, &key);
+                f.key = Some(key);
+                self.lines.entry(path).or_insert(mark.line());
+            }
+            Event::Scalar(..) | Event::Alias(_) => {
+                self.value_at(mark.line());
+            }
+            Event::MappingStart(_) | Event::SequenceStart(_) => {
+                let is_map = matches!(ev, Event::MappingStart(_));
+                let path = self.value_at(mark.line());
+                self.stack.push(YamlFrame {
+                    path,
+                    is_map,
+                    key: None,
+                    index: 0,
+                });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn line_at(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+fn add_line(lines: &mut LineMap, text: &str, path: &str, span: Option<Range<usize>>) {
+    if let Some(span) = span {
+        lines
+            .entry(path.to_string())
+            .or_insert(line_at(text, span.start));
+    }
+}
+
+fn toml_table_lines(text: &str, table: &dyn toml_edit::TableLike, path: &str, lines: &mut LineMap) {
+    for (key, _) in table.iter() {
+        if let Some((k, v)) = table.get_key_value(key) {
+            let child = join_path(path, key);
+            add_line(lines, text, &child, k.span());
+            toml_lines(text, v, &child, lines);
+        }
+    }
+}
+
+fn toml_value_lines(text: &str, value: &toml_edit::Value, path: &str, lines: &mut LineMap) {
+    add_line(lines, text, path, value.span());
+    match value {
+        toml_edit::Value::Array(array) => {
+            for (i, v) in array.iter().enumerate() {
+                toml_value_lines(text, v, &format!("{}[{}]", path, i), lines);
+            }
+        }
+        toml_edit::Value::InlineTable(table) => toml_table_lines(text, table, path, lines),
+        _ => {}
+    }
+}
+
+fn toml_lines(text: &str, item: &toml_edit::Item, path: &str, lines: &mut LineMap) {
+    match item {
+        toml_edit::Item::Table(table) => {
+            add_line(lines, text, path, table.span());
+            toml_table_lines(text, table, path, lines);
+        }
+        toml_edit::Item::ArrayOfTables(tables) => {
+            for (i, table) in tables.iter().enumerate() {
+                let child = format!("{}[{}]", path, i);
+                add_line(lines, text, &child, table.span());
+                toml_table_lines(text, table, &child, lines);
+            }
+        }
+        toml_edit::Item::Value(value) => toml_value_lines(text, value, path, lines),
+        toml_edit::Item::None => {}
+    }
+}
+
+/// Lines of the keys and items in `text`, empty if it does not parse.
+fn line_map(text: &str, format: Format) -> LineMap {
+    match format {
+        // JSON is read as YAML here, which it is a subset of
+        Format::Yaml | Format::Json => {
+            let mut receiver = YamlLines::default();
+            let _ = Parser::new(text.chars()).load(&mut receiver, false);
+            receiver.lines
+        }
+        Format::Toml => {
+            let mut lines = LineMap::new();
+            if let Ok(document) = toml_edit::ImDocument::parse(text) {
+                toml_table_lines(text, document.as_table(), "", &mut lines);
+            }
+            lines
+        }
+    }
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` in `text` with the environment,
+/// the default is used when `VAR` is unset or empty. `$${` stands for `${`.
+fn interpolate_str(text: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if rest.starts_with("$${") {
+            out.push_str("${");
+            rest = &rest[3..];
+            continue;
+        }
+        if !rest.starts_with("${") {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        }
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => return Err(format!("unclosed '${{' in '{}'", text)),
+        };
+        let expr = &rest[2..end];
+        // `${port}` is the local port, substituted per listener
+        if expr == "port" || expr.starts_with("port+") || expr.starts_with("port-") {
+            out.push_str(&rest[..end + 1]);
+            rest = &rest[end + 1..];
+            continue;
+        }
+        let (name, default) = match expr.find(":-") {
+            Some(i) => (&expr[..i], Some(&expr[i + 2..])),
+            None => (expr, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid variable name '{}' in '{}'", name, text));
+        }
+        let value = match std::env::var(name) {
+            Ok(v) if !v.is_empty() || default.is_none() => v,
+            _ => match default {
+                Some(d) => d.to_string(),
+                None => return Err(format!("environment variable {} is not set", name)),
+            },
+        };
+        out.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Interpolate every string in `value`, problems are keyed by path from `path`.
+fn interpolate(value: &mut Value, path: &str, problems: &mut Problems) {
+    match value {
+        Value::String(s) => match interpolate_str(s) {
+            Ok(v) => *s = v,
+            Err(e) => problems.push((path.to_string(), e)),
+        },
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate(item, &format!("{}[{}]", path, i), problems);
+            }
+        }
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                interpolate(v, &join_path(path, key), problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The document in `text` as a generic value, an empty document is `Null`.
+/// Errors come with the line they were found at.
+fn to_value(text: &str, format: Format) -> Result<Value, (String, Option<usize>)> {
+    if text.trim().is_empty() {
+        return Ok(Value::Null);
+    }
+    // messages of serde_yaml and serde_json end with the location
+    let strip = |message: String, location: Option<(usize, usize)>| match location {
+        Some((line, column)) => {
+            let suffix = format!(" at line {} column {}", line, column);
+            (message.trim_end_matches(&suffix).to_string(), Some(line))
+        }
+        None => (message, None),
+    };
+    match format {
+        Format::Yaml => serde_yaml::from_str(text).map_err(|e| {
+            let location = e.location().map(|l| (l.line(), l.column()));
+            strip(format!("invalid yaml: {}", e), location)
+        }),
+        Format::Toml => toml::from_str(text).map_err(|e| {
+            (
+                format!("invalid toml: {}", e.message()),
+                e.span().map(|span| line_at(text, span.start)),
+            )
+        }),
+        Format::Json => serde_json::from_str(text).map_err(|e| {
+            let location = Some((e.line(), e.column()));
+            strip(format!("invalid json: {}", e), location)
+        }),
+    }
+}
+
+/// A file or text the configuration was read from.
+struct Source {
+    file: Option<String>,
+    lines: LineMap,
+}
+
+const INCLUDED_KEYS: &[&str] = &["forwarders", "include"];
+const INCLUDED_EXTENSIONS: &[&str] = &["yaml", "yml", "toml", "json"];
+
+/// Gathers the forwarders of a document and the files it includes.
+#[derive(Default)]
+struct Loader {
+    sources: Vec<Source>,
+    /// Forwarders with their source and their path in it.
+    forwarders: Vec<(Value, usize, String)>,
+    errors: Vec<ConfigError>,
+    /// Canonical paths of the files being read, to catch include cycles.
+    reading: Vec<PathBuf>,
+}
+
+impl Loader {
+    fn error(&mut self, source: usize, path: &str, key: &str, message: String) {
+        let source = &self.sources[source];
+        self.errors.push(ConfigError {
+            forwarder: None,
+            key: key.to_string(),
+            message,
+            file: source.file.clone(),
+            line: line_of(&source.lines, path),
+        });
+    }
+
+    /// Report a problem at `key` of the forwarder with global index `i`, once
+    /// for all the listeners it expands to.
+    fn forwarder_error(&mut self, i: usize, key: String, message: String) {
+        if self
+            .errors
+            .iter()
+            .any(|e| e.forwarder == Some(i) && e.key == key)
+        {
+            return;
+        }
+        let (_, source, prefix) = &self.forwarders[i];
+        let source = &self.sources[*source];
+        let line = line_of(&source.lines, &join_path(prefix, &key));
+        self.errors.push(ConfigError {
+            forwarder: Some(i),
+            key,
+            message,
+            file: source.file.clone(),
+            line,
+        });
+    }
+
+    /// Read a document, its forwarders and includes, and return what is left
+    /// of its top-level mapping.
+    fn add_document(
+        &mut self,
+        document: Value,
+        file: Option<&Path>,
+        lines: LineMap,
+        known: &[&str],
+    ) -> serde_json::Map<String, Value> {
+        let source = self.sources.len();
+        self.sources.push(Source {
+            file: file.map(|f| f.display().to_string()),
+            lines,
+        });
+        let mut problems = vec![];
+        check_keys(&document, "", known, &mut problems);
+        let mut map = match document {
+            Value::Null => return serde_json::Map::new(),
+            Value::Object(map) => map,
+            v => {
+                let message = format!("expect a mapping, got {}", value_text(&v));
+                self.error(source, "", "", message);
+                return serde_json::Map::new();
+            }
+        };
+        for (key, value) in map.iter_mut() {
+            if key != "forwarders" {
+                interpolate(value, key, &mut problems);
+            }
+        }
+        for (key, message) in problems {
+            self.error(source, &key, &key, message);
+        }
+
+        match map.remove("forwarders") {
+            Some(Value::Array(items)) => {
+                for (j, mut item) in items.into_iter().enumerate() {
+                    let mut problems = vec![];
+                    interpolate(&mut item, "", &mut problems);
+                    let i = self.forwarders.len();
+                    self.forwarders
+                        .push((item, source, format!("forwarders[{}]", j)));
+                    for (key, message) in problems {
+                        self.forwarder_error(i, key, message);
+                    }
+                }
+            }
+            Some(Value::Null) | None if map.contains_key("include") => {}
+            v => {
+                let message = format!(
+                    "expect a list, got {}",
+                    value_text(v.as_ref().unwrap_or(&Value::Null))
+                );
+                self.error(source, "forwarders", "forwarders", message);
+            }
+        }
+
+        let includes = match map.remove("include") {
+            None | Some(Value::Null) => vec![],
+            Some(Value::String(s)) => vec![("include".to_string(), s)],
+            Some(Value::Array(items)) => {
+                let mut includes = vec![];
+                for (i, item) in items.into_iter().enumerate() {
+                    let key = format!("include[{}]", i);
+                    match item {
+                        Value::String(s) => includes.push((key, s)),
+                        v => {
+                            let message = format!("expect a file name, got {}", value_text(&v));
+                            self.error(source, &key, &key, message);
+                        }
+                    }
+                }
+                includes
+            }
+            Some(v) => {
+                let message = format!("expect a file name or a list, got {}", value_text(&v));
+                self.error(source, "include", "include", message);
+                vec![]
+            }
+        };
+        for (key, name) in includes {
+            let base = file
+                .and_then(|f| f.parent())
+                .unwrap_or_else(|| Path::new(""));
+            self.include(source, &key, &base.join(name));
+        }
+        map
+    }
+
+    /// Read a file or every config file of a directory, in name order.
+    fn include(&mut self, source: usize, key: &str, path: &Path) {
+        let is_dir = match fs::metadata(path) {
+            Ok(m) => m.is_dir(),
+            Err(e) => {
+                let message = format!("can't read '{}': {}", path.display(), e);
+                return self.error(source, key, key, message);
+            }
+        };
+        if !is_dir {
+            return self.read_file(source, key, path);
+        }
+        let mut files = match fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| {
+                    p.extension()
+                        .and_then(|e| e.to_str())
+                        .is_some_and(|e| INCLUDED_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+                })
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                let message = format!("can't read '{}': {}", path.display(), e);
+                return self.error(source, key, key, message);
+            }
+        };
+        files.sort();
+        for file in files {
+            self.read_file(source, key, &file);
+        }
+    }
+
+    fn read_file(&mut self, source: usize, key: &str, path: &Path) {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if self.reading.contains(&canonical) {
+            let message = format!("include cycle through '{}'", path.display());
+            return self.error(source, key, key, message);
+        }
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                let message = format!("can't read '{}': {}", path.display(), e);
+                return self.error(source, key, key, message);
+            }
+        };
+        let format = Format::from_path(&path.to_string_lossy());
+        let document = match to_value(&text, format) {
+            Ok(v) => v,
+            Err((message, line)) => {
+                self.errors.push(ConfigError {
+                    message,
+                    file: Some(path.display().to_string()),
+                    line,
+                    ..Default::default()
+                });
+                return;
+            }
+        };
+        self.reading.push(canonical);
+        let lines = line_map(&text, format);
+        self.add_document(document, Some(path), lines, INCLUDED_KEYS);
+        self.reading.pop();
+    }
+
+    /// Read the forwarders and top-level keys gathered, then validate them.
+    fn finish(
+        mut self,
+        top: serde_json::Map<String, Value>,
+    ) -> Result<ConfigFile, Vec<ConfigError>> {
+        let top = Value::Object(top);
+        let mut configs = ConfigFile::default();
+        let mut problems = vec![];
+        configs.metrics_listen = optional_string(&top, "metrics_listen", &mut problems);
+        configs.admin_listen = optional_string(&top, "admin_listen", &mut problems);
+        configs.access_log = optional_string(&top, "access_log", &mut problems);
+        for (key, addr) in [
+            ("metrics_listen", &configs.metrics_listen),
+            ("admin_listen", &configs.admin_listen),
+        ] {
+            match addr {
+                Some(addr) if !addr.starts_with("unix:") => {
+                    if let Err(e) = resolveSockAddr(addr.as_str()) {
+                        problems.push((key.to_string(), format!("{}: {}", addr, e)));
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (key, message) in problems {
+            self.error(0, &key, &key, message);
+        }
+
+        match &top["upstreams"] {
+            Value::Object(groups) => {
+                for (name, value) in groups {
+                    let (config, mut problems) = parse_upstream(name, value);
+                    if problems.is_empty() {
+                        problems = config.validate();
+                    }
+                    for (key, message) in problems {
+                        let key = join_path(&format!("upstreams.{}", name), &key);
+                        self.error(0, &key, &key, message);
+                    }
+                    configs.upstreams.push(config);
+                }
+            }
+            Value::Null => {}
+            v => {
+                let message = format!("expect a mapping, got {}", value_text(v));
+                self.error(0, "upstreams", "upstreams", message);
+            }
+        }
+
+        let mut parsed = vec![];
+        for i in 0..self.forwarders.len() {
+            let mut problems = vec![];
+            let listeners = expand_forwarder(&self.forwarders[i].0, &mut problems);
+            for listener in listeners {
+                let (config, more) = parse_forwarder(&listener);
+                problems.extend(more);
+                if let Some(c) = config {
+                    parsed.push((i, c));
+                }
+            }
+            for (key, message) in problems {
+                self.forwarder_error(i, key, message);
+            }
+        }
+        for (_, c) in parsed.iter_mut() {
+            c.apply_upstreams(&configs.upstreams);
+        }
+        for e in validate_forwarders(parsed.iter().map(|(i, c)| (*i, c)), &configs.upstreams) {
+            let i = e.forwarder.unwrap_or(0);
+            // `remote` is appended to `remoteMap` as the last rule
+            let ff = &self.forwarders[i].0;
+            let remote_index = ff["remoteMap"].as_array().map_or(0, |v| v.len());
+            let key = if ff["remote"].is_string()
+                && e.key.starts_with(&format!("remoteMap[{}].", remote_index))
+            {
+                "remote".to_string()
+            } else {
+                e.key
+            };
+            // a value that could not be read is already reported
+            self.forwarder_error(i, key, e.message);
+        }
+
+        if !self.errors.is_empty() {
+            self.errors.sort_by_key(|e| (e.forwarder, e.line));
+            return Err(self.errors);
+        }
+        configs.forwarders = parsed.into_iter().map(|(_, c)| c).collect();
+        Ok(configs)
+    }
+}
+
+fn problems_message(problems: &[ConfigError]) -> String {
+    problems
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+impl<'de> Deserialize<'de> for ForwardSessionConfig<String> {
+    /// Accepts the same keys, defaults and units as a forwarder of a config file.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        match parse_forwarder(&value) {
+            (Some(config), problems) if problems.is_empty() => Ok(config),
+            (_, problems) => Err(de::Error::custom(
+                problems
+                    .iter()
+                    .map(|(key, message)| format!("{}: {}", key, message))
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigFile {
+    /// Like `parse`, includes are relative to the working directory.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let mut loader = Loader::default();
+        let top = loader.add_document(value, None, LineMap::new(), TOP_LEVEL_KEYS);
+        loader
+            .finish(top)
+            .map_err(|errors| de::Error::custom(problems_message(&errors)))
+    }
+}
+
+/// `size` in the largest unit it is a whole number of, or in bytes.
+fn size_yaml(size: usize) -> Yaml {
+    for (unit, scale) in [("GB", 1 << 30), ("MB", 1 << 20), ("KB", 1 << 10)] {
+        if size >= scale && size.is_multiple_of(scale) {
+            return Yaml::from(format!("{}{}", size / scale, unit));
+        }
+    }
+    Yaml::from(size as u64)
+}
+
+/// `d` in the largest unit it is a whole number of, at least milliseconds.
+fn duration_yaml(d: Duration) -> Yaml {
+    let nanos = d.subsec_nanos();
+    if !nanos.is_multiple_of(1000) {
+        return Yaml::from(format!("{}ns", d.as_nanos()));
+    }
+    if !nanos.is_multiple_of(1_000_000) {
+        return Yaml::from(format!("{}us", d.as_micros()));
+    }
+    if nanos != 0 {
+        return Yaml::from(format!("{}ms", d.as_millis()));
+    }
+    let secs = d.as_secs();
+    let text = if secs >= 3600 && secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs >= 60 && secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    };
+    Yaml::from(text)
+}
+
+/// `text` escaped so that loading it back does not interpolate it again.
+fn text_yaml(text: &str) -> Yaml {
+    Yaml::from(text.replace("${", "$${"))
+}
+
+fn strings_yaml(list: &[String]) -> Yaml {
+    Yaml::Sequence(list.iter().map(|s| text_yaml(s)).collect())
+}
+
+fn mapping(pairs: Vec<(&str, Yaml)>) -> Yaml {
+    Yaml::Mapping(
+        pairs
+            .into_iter()
+            .map(|(key, value)| (Yaml::from(key), value))
+            .collect(),
+    )
+}
+
+/// The options that are set, `None` when none is.
+fn socket_yaml(options: &SocketConfig) -> Option<Yaml> {
+    let mut pairs = vec![];
+    if let Some(ip) = options.bind_address {
+        pairs.push(("bind_address", Yaml::from(ip.to_string())));
+    }
+    if let Some(name) = &options.interface {
+        pairs.push(("interface", text_yaml(name)));
+    }
+    if let Some(mark) = options.fwmark {
+        pairs.push(("fwmark", Yaml::from(mark)));
+    }
+    if let Some(nodelay) = options.nodelay {
+        pairs.push(("nodelay", Yaml::from(nodelay)));
+    }
+    let durations = [
+        ("keepalive", options.keepalive),
+        ("keepalive_interval", options.keepalive_interval),
+    ];
+    for (key, d) in durations {
+        if let Some(d) = d {
+            pairs.push((key, duration_yaml(d)));
+        }
+    }
+    if let Some(count) = options.keepalive_count {
+        pairs.push(("keepalive_count", Yaml::from(count)));
+    }
+    if let Some(d) = options.user_timeout {
+        pairs.push(("user_timeout", duration_yaml(d)));
+    }
+    for (key, size) in [
+        ("send_buffer", options.send_buffer),
+        ("recv_buffer", options.recv_buffer),
+    ] {
+        if let Some(size) = size {
+            pairs.push((key, size_yaml(size)));
+        }
+    }
+    if let Some(fast_open) = options.fast_open {
+        pairs.push(("fast_open", Yaml::from(fast_open)));
+    }
+    if let Some(tos) = options.tos {
+        pairs.push(("tos", Yaml::from(tos)));
+    }
+    if pairs.is_empty() {
+        None
+    } else {
+        Some(mapping(pairs))
+    }
+}
+
+/// A forwarder with every key set, the last `.*` rule is written as `remote`.
+fn forwarder_yaml(config: &ForwardSessionConfig<String>) -> Yaml {
+    let mut rules: &[(String, String)] = &config.remoteMap;
+    let rule_socket = |i: usize| {
+        config
+            .rule_sockets
+            .iter()
+            .find(|(j, _)| *j == i)
+            .map(|r| &r.1)
+    };
+    let rule_idle_timeout = |i: usize| {
+        config
+            .rule_idle_timeouts
+            .iter()
+            .find(|(j, _)| *j == i)
+            .map(|r| r.1)
+    };
+    let mut remote = None;
+    if let Some(((pattern, last), rest)) = rules.split_last() {
+        // `remote` can't have options of its own
+        if pattern == ".*"
+            && rule_socket(rest.len()).is_none()
+            && rule_idle_timeout(rest.len()).is_none()
+        {
+            remote = Some(last);
+            rules = rest;
+        }
+    }
+    let mut pairs = vec![("local", text_yaml(&config.local))];
+    if let Some(name) = &config.fd_name {
+        pairs.push(("fd_name", text_yaml(name)));
+    }
+    pairs.push(("ipv6_only", Yaml::from(config.ipv6_only)));
+    if let Some(mode) = config.unix_mode {
+        pairs.push(("unix_mode", Yaml::from(format!("{:04o}", mode).as_str())));
+    }
+    if !rules.is_empty() {
+        let rules = rules
+            .iter()
+            .enumerate()
+            .map(|(i, (pattern, remote))| {
+                let mut rule = vec![
+                    ("pattern", text_yaml(pattern)),
+                    ("remote", text_yaml(remote)),
+                ];
+                if let Some(options) = rule_socket(i).and_then(socket_yaml) {
+                    rule.push(("upstream_socket", options));
+                }
+                if let Some(timeout) = rule_idle_timeout(i) {
+                    rule.push(("udp_idle_timeout", duration_yaml(timeout)));
+                }
+                mapping(rule)
+            })
+            .collect();
+        pairs.push(("remoteMap", Yaml::Sequence(rules)));
+    }
+    if let Some(remote) = remote {
+        pairs.push(("remote", text_yaml(remote)));
+    }
+    let tcp_mode = match config.tcp_mode {
+        TcpMode::Forward => "forward",
+        TcpMode::Socks5Server => "socks5",
+    };
+    let acl = config
+        .acl
+        .iter()
+        .map(|rule| {
+            let (key, value) = match rule {
+                AclRule::Net(AclAction::Allow, net) => ("allow", net),
+                AclRule::Net(AclAction::Deny, net) => ("deny", net),
+                AclRule::File(AclAction::Allow, file) => ("allow_file", file),
+                AclRule::File(AclAction::Deny, file) => ("deny_file", file),
+            };
+            mapping(vec![(key, text_yaml(value))])
+        })
+        .collect();
+    let queue = &config.udp_queue;
+    let drop = match queue.drop {
+        DropPolicy::Oldest => "oldest",
+        DropPolicy::Newest => "newest",
+    };
+    pairs.extend([
+        ("tcp_mode", Yaml::from(tcp_mode)),
+        ("enable_tcp", Yaml::from(config.enable_tcp)),
+        ("enable_udp", Yaml::from(config.enable_udp)),
+        ("conn_bufsize", size_yaml(config.conn_bufsize)),
+        ("max_connections", Yaml::from(config.max_connections)),
+        ("drain_timeout", duration_yaml(config.drain_timeout)),
+        ("udp_idle_timeout", duration_yaml(config.udp_idle_timeout)),
+        ("udp_evict_lru", Yaml::from(config.udp_evict_lru)),
+        (
+            "udp_queue",
+            mapping(vec![
+                ("session_packets", Yaml::from(queue.session_packets as u64)),
+                ("session_bytes", size_yaml(queue.session_bytes)),
+                ("packets", Yaml::from(queue.packets as u64)),
+                ("bytes", size_yaml(queue.bytes)),
+                ("drop", Yaml::from(drop)),
+            ]),
+        ),
+        ("allow_nets", strings_yaml(&config.allow_nets)),
+        ("deny_nets", strings_yaml(&config.deny_nets)),
+        ("acl", Yaml::Sequence(acl)),
+    ]);
+    for (key, options) in [
+        ("listen_socket", &config.listen_socket),
+        ("upstream_socket", &config.upstream_socket),
+    ] {
+        if let Some(options) = socket_yaml(options) {
+            pairs.push((key, options));
+        }
+    }
+    if let Some(capture) = &config.capture {
+        pairs.push((
+            "capture",
+            mapping(vec![
+                ("file", text_yaml(&capture.file)),
+                ("rules", strings_yaml(&capture.rules)),
+                ("clients", strings_yaml(&capture.clients)),
+            ]),
+        ));
+    }
+    if let Some(log) = &config.unmatched_log {
+        pairs.push((
+            "unmatched_log",
+            mapping(vec![
+                ("file", text_yaml(&log.file)),
+                ("bytes", Yaml::from(log.bytes as u64)),
+                ("per_minute", Yaml::from(log.per_minute)),
+                ("dedup_prefix", Yaml::from(log.dedup_prefix as u64)),
+                ("dedup_window", duration_yaml(log.dedup_window)),
+            ]),
+        ));
+    }
+    mapping(pairs)
+}
+
+fn upstream_yaml(config: &UpstreamConfig) -> Yaml {
+    let servers = config
+        .servers
+        .iter()
+        .map(|server| {
+            mapping(vec![
+                ("address", text_yaml(&server.address)),
+                ("weight", Yaml::from(server.weight)),
+            ])
+        })
+        .collect();
+    let mut pairs = vec![("servers", Yaml::Sequence(servers))];
+    if let Some(check) = &config.health_check {
+        pairs.push((
+            "health_check",
+            mapping(vec![
+                ("interval", duration_yaml(check.interval)),
+                ("timeout", duration_yaml(check.timeout)),
+                ("fall", Yaml::from(check.fall)),
+                ("rise", Yaml::from(check.rise)),
+            ]),
+        ));
+    }
+    pairs.push(("max_fails", Yaml::from(config.max_fails)));
+    pairs.push(("fail_timeout", duration_yaml(config.fail_timeout)));
+    if let Some(options) = socket_yaml(&config.upstream_socket) {
+        pairs.push(("upstream_socket", options));
+    }
+    mapping(pairs)
+}
+
+fn config_file_yaml(config: &ConfigFile) -> Yaml {
+    let mut pairs = vec![];
+    for (key, value) in [
+        ("metrics_listen", &config.metrics_listen),
+        ("admin_listen", &config.admin_listen),
+        ("access_log", &config.access_log),
+    ] {
+        if let Some(value) = value {
+            pairs.push((key, text_yaml(value)));
+        }
+    }
+    if !config.upstreams.is_empty() {
+        let upstreams: Mapping = config
+            .upstreams
+            .iter()
+            .map(|u| (Yaml::from(u.name.as_str()), upstream_yaml(u)))
+            .collect();
+        pairs.push(("upstreams", Yaml::Mapping(upstreams)));
+    }
+    let forwarders = config.forwarders.iter().map(forwarder_yaml).collect();
+    pairs.push(("forwarders", Yaml::Sequence(forwarders)));
+    mapping(pairs)
+}
+
+impl Serialize for ForwardSessionConfig<String> {
+    /// Every key with its value, in the units a config file takes.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        forwarder_yaml(self).serialize(serializer)
+    }
+}
+
+impl Serialize for ConfigFile {
+    /// A document that reads back the same, without `include`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        config_file_yaml(self).serialize(serializer)
+    }
+}
+
+/// `config` as a YAML document that `load_file` reads back the same, with the
+/// defaults spelled out.
+pub fn to_yaml(config: &ConfigFile) -> String {
+    serde_yaml::to_string(config).unwrap()
+}
+
+/// Read and validate a config document, every problem is reported with the
+/// forwarder index and key it was found at. Includes are relative to the
+/// working directory.
+pub fn parse(text: &str, format: Format) -> Result<ConfigFile, Vec<ConfigError>> {
+    let document = to_value(text, format).map_err(|(message, line)| {
+        vec![ConfigError {
+            message,
+            line,
+            ..Default::default()
+        }]
+    })?;
+    let mut loader = Loader::default();
+    let top = loader.add_document(document, None, line_map(text, format), TOP_LEVEL_KEYS);
+    loader.finish(top)
+}
+
+/// Read and validate a config file, whose format is told by its extension.
+/// Problems are prefixed with the file and line they were found at.
+pub fn load_file(file: &str) -> Result<ConfigFile, Vec<String>> {
+    let text = match fs::read_to_string(file) {
+        Ok(c) => c,
+        Err(e) => return Err(vec![format!("open file {} failed: {}", file, e)]),
+    };
+    let path = Path::new(file);
+    let format = Format::from_path(file);
+    let located = |(message, line)| ConfigError {
+        message,
+        line,
+        file: Some(file.to_string()),
+        ..Default::default()
+    };
+    let result = to_value(&text, format)
+        .map_err(|e| vec![located(e)])
+        .and_then(|document| {
+            let mut loader = Loader {
+                reading: vec![fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())],
+                ..Default::default()
+            };
+            let top = loader.add_document(
+                document,
+                Some(path),
+                line_map(&text, format),
+                TOP_LEVEL_KEYS,
+            );
+            loader.finish(top)
+        });
+    result.map_err(|errors| errors.iter().map(|e| e.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "
+forwarders:
+  - local: 127.0.0.1:8808
+    remoteMap:
+      - pattern: \"[ssh]\"
+        remote: 127.0.0.1:22
+    remote: 127.0.0.1:80
+    conn_bufsize: 64KB
+    drain_timeout: 30s
+    acl:
+      - deny: 10.1.0.0/16
+    unmatched_log:
+      file: /tmp/unmatched.log
+      per_minute: 5
+";
+
+    const TOML: &str = "
+[[forwarders]]
+local = \"127.0.0.1:8808\"
+remote = \"127.0.0.1:80\"
+conn_bufsize = \"64KB\"
+drain_timeout = \"30s\"
+acl = [{ deny = \"10.1.0.0/16\" }]
+unmatched_log = { file = \"/tmp/unmatched.log\", per_minute = 5 }
+
+[[forwarders.remoteMap]]
+pattern = \"[ssh]\"
+remote = \"127.0.0.1:22\"
+";
+
+    const JSON: &str = r#"{"forwarders": [{
+        "local": "127.0.0.1:8808",
+        "remoteMap": [{"pattern": "[ssh]", "remote": "127.0.0.1:22"}],
+        "remote": "127.0.0.1:80",
+        "conn_bufsize": "64KB",
+        "drain_timeout": "30s",
+        "acl": [{"deny": "10.1.0.0/16"}],
+        "unmatched_log": {"file": "/tmp/unmatched.log", "per_minute": 5}
+    }]}"#;
+
+    #[test]
+    fn formats_read_the_same() {
+        let expected = ForwardSessionConfig {
+            local: "127.0.0.1:8808".to_string(),
+            remoteMap: vec![
+                ("[ssh]".to_string(), "127.0.0.1:22".to_string()),
+                (".*".to_string(), "127.0.0.1:80".to_string()),
+            ],
+            acl: vec![AclRule::Net(AclAction::Deny, "10.1.0.0/16".to_string())],
+            conn_bufsize: 64 * 1024,
+            drain_timeout: Duration::from_secs(30),
+            unmatched_log: Some(UnmatchedLogConfig {
+                file: "/tmp/unmatched.log".to_string(),
+                per_minute: 5,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        for (text, format) in [
+            (YAML, Format::Yaml),
+            (TOML, Format::Toml),
+            (JSON, Format::Json),
+        ] {
+            let configs = parse(text, format).unwrap();
+            assert_eq!(configs.forwarders, vec![expected.clone()], "{:?}", format);
+        }
+
+        let from_serde: ConfigFile = serde_json::from_str(JSON).unwrap();
+        assert_eq!(from_serde.forwarders, vec![expected.clone()]);
+        let single: ForwardSessionConfig<String> =
+            serde_yaml::from_str("{local: 127.0.0.1:8808, remote: 127.0.0.1:80}").unwrap();
+        assert_eq!(single.conn_bufsize, 2 * 1024 * 1024);
+        assert_eq!(single.max_connections, -1);
+    }
+
+    #[test]
+    fn problems_are_located() {
+        let errors: Vec<String> = parse(
+            r#"{"forwarders": [{"local": "127.0.0.1:8808", "remote": "127.0.0.1:80",
+                "conn_bufsize": "2XB", "tcp_mod": "socks5"}]}"#,
+            Format::Json,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 2: forwarders[0].tcp_mod: unknown key",
+                "line 2: forwarders[0].conn_bufsize: invalid size '2XB', eg. 64KB",
+            ]
+        );
+
+        let err = serde_json::from_str::<ForwardSessionConfig<String>>(r#"{"remote": 1}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("local: missing; remote: expect an address, got 1"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn overflowing_values_are_invalid() {
+        assert_eq!(convert_to_bytes("4GB"), Some(4 << 30));
+        assert_eq!(convert_to_bytes("99999999999999TB"), None);
+        assert_eq!(convert_to_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(convert_to_duration("999999999999999999m"), None);
+        assert_eq!(convert_to_duration("9999999999999999h"), None);
+
+        let errors: Vec<String> = parse(
+            r#"{"forwarders": [{"local": "127.0.0.1:8808", "remote": "127.0.0.1:80",
+                "conn_bufsize": "99999999999999TB", "drain_timeout": "9999999999999999h"}]}"#,
+            Format::Json,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 2: forwarders[0].conn_bufsize: invalid size '99999999999999TB', eg. 64KB",
+                "line 2: forwarders[0].drain_timeout: invalid duration '9999999999999999h', eg. 30s",
+            ]
+        );
+    }
+
+    #[test]
+    fn interpolation() {
+        std::env::set_var("PORTFD_TEST_HOST", "10.0.0.1");
+        std::env::set_var("PORTFD_TEST_EMPTY", "");
+        assert_eq!(
+            interpolate_str("${PORTFD_TEST_HOST}:22").unwrap(),
+            "10.0.0.1:22"
+        );
+        assert_eq!(
+            interpolate_str("${PORTFD_TEST_UNSET:-127.0.0.1}:${PORTFD_TEST_EMPTY:-80}").unwrap(),
+            "127.0.0.1:80"
+        );
+        assert_eq!(interpolate_str("^a$ $${X}").unwrap(), "^a$ ${X}");
+        assert_eq!(
+            interpolate_str("${PORTFD_TEST_UNSET}").unwrap_err(),
+            "environment variable PORTFD_TEST_UNSET is not set"
+        );
+    }
+
+    #[test]
+    fn includes_point_at_their_file() {
+        let dir = std::env::temp_dir().join(format!("portfd-include-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(
+            dir.join("main.yaml"),
+            "forwarders:\n  - local: 127.0.0.1:8808\n    remote: 127.0.0.1:22\ninclude: conf.d\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("conf.d/a.toml"),
+            "[[forwarders]]\nlocal = \"127.0.0.1:8809\"\nremote = \"127.0.0.1:80\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("conf.d/b.yaml"),
+            "forwarders:\n  - local: 127.0.0.1:8810\n\n    remote: 127.0.0.1:81\n    conn_bufsize: 2XB\ninclude: ../main.yaml\n",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/notes.txt"), "not a config").unwrap();
+
+        let main = dir.join("main.yaml").display().to_string();
+        let b = dir.join("conf.d").join("b.yaml").display().to_string();
+        assert_eq!(
+            load_file(&main).unwrap_err(),
+            vec![
+                format!(
+                    "{}:6: include: include cycle through '{}'",
+                    b,
+                    dir.join("conf.d").join("../main.yaml").display()
+                ),
+                format!(
+                    "{}:5: forwarders[2].conn_bufsize: invalid size '2XB', eg. 64KB",
+                    b
+                ),
+            ]
+        );
+
+        fs::write(
+            dir.join("conf.d/b.yaml"),
+            "forwarders:\n  - local: 127.0.0.1:8810\n    remote: 127.0.0.1:81\n",
+        )
+        .unwrap();
+        let locals: Vec<String> = load_file(&main)
+            .unwrap()
+            .forwarders
+            .into_iter()
+            .map(|c| c.local)
+            .collect();
+        assert_eq!(
+            locals,
+            vec!["127.0.0.1:8808", "127.0.0.1:8809", "127.0.0.1:8810"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn upstream_groups() {
+        let configs = parse(
+            "
+upstreams:
+  pool:
+    servers: [127.0.0.1:22, {address: 127.0.0.1:2222, weight: 3}]
+    health_check: {interval: 1s}
+    max_fails: 0
+forwarders:
+  - local: 127.0.0.1:8808
+    remote: pool
+",
+            Format::Yaml,
+        )
+        .unwrap();
+        assert_eq!(
+            configs.upstreams,
+            vec![UpstreamConfig {
+                name: "pool".to_string(),
+                servers: vec![
+                    UpstreamServer {
+                        address: "127.0.0.1:22".to_string(),
+                        weight: 1,
+                    },
+                    UpstreamServer {
+                        address: "127.0.0.1:2222".to_string(),
+                        weight: 3,
+                    },
+                ],
+                health_check: Some(HealthCheckConfig {
+                    interval: Duration::from_secs(1),
+                    ..Default::default()
+                }),
+                max_fails: 0,
+                ..Default::default()
+            }]
+        );
+        assert_eq!(configs.forwarders[0].remoteMap[0].1, "pool");
+
+        let errors: Vec<String> = parse(
+            "
+upstreams:
+  pool:
+    servers: [{address: 127.0.0.1:22, weight: 0}]
+forwarders:
+  - local: 127.0.0.1:8808
+    remote: poll
+",
+            Format::Yaml,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 4: upstreams.pool.servers[0].weight: must be at least 1",
+                "line 7: forwarders[0].remote: no upstream group or address 'poll'",
+            ]
+        );
+    }
+
+    #[test]
+    fn local_lists_and_ranges() {
+        let configs = parse(
+            "
+forwarders:
+  - local: [\":5300-5301\", \"[::]:5300\"]
+    remote: 10.0.0.1:${port+100}
+",
+            Format::Yaml,
+        )
+        .unwrap();
+        let listeners: Vec<(&str, &str, bool)> = configs
+            .forwarders
+            .iter()
+            .map(|c| (c.local.as_str(), c.remoteMap[0].1.as_str(), c.ipv6_only))
+            .collect();
+        assert_eq!(
+            listeners,
+            vec![
+                ("0.0.0.0:5300", "10.0.0.1:5400", false),
+                ("0.0.0.0:5301", "10.0.0.1:5401", false),
+                ("[::]:5300", "10.0.0.1:5400", true),
+            ]
+        );
+
+        let configs = parse(
+            "
+forwarders:
+  - local: \":10000-10100\"
+    remote: 10.0.0.1:${port+10000}
+",
+            Format::Yaml,
+        )
+        .unwrap();
+        assert_eq!(configs.forwarders.len(), 101);
+        assert_eq!(configs.forwarders[100].local, "0.0.0.0:10100");
+        assert_eq!(configs.forwarders[100].remoteMap[0].1, "10.0.0.1:20100");
+
+        let errors: Vec<String> = parse(
+            "
+forwarders:
+  - local: 127.0.0.1:1-2
+    remote: 10.0.0.1:${port-1}
+",
+            Format::Yaml,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            vec!["line 4: forwarders[0].remote: '${port-1}' is 0 for port 1, not a port"]
+        );
+
+        let errors: Vec<String> = parse(
+            "
+forwarders:
+  - local: [\":1000-2023\", \":3000\"]
+    remote: 10.0.0.1:${port}
+",
+            Format::Yaml,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            vec!["line 3: forwarders[0].local: expands to 1025 listeners, at most 1024 are allowed"]
+        );
+    }
+
+    #[test]
+    fn unix_sockets() {
+        let configs = parse(
+            "
+forwarders:
+  - local: unix:/run/portfd.sock
+    remote: unix:@backend
+    enable_udp: false
+    unix_mode: \"0660\"
+",
+            Format::Yaml,
+        )
+        .unwrap();
+        assert_eq!(configs.forwarders[0].unix_mode, Some(0o660));
+        assert!(to_yaml(&configs).contains("  unix_mode: '0660'\n"));
+
+        let errors: Vec<String> = parse(
+            "
+forwarders:
+  - local: unix:@portfd
+    remote: unix:/run/backend.sock
+    unix_mode: \"0660\"
+    allow_nets: [10.0.0.0/8]
+  - local: 127.0.0.1:8808
+    remote: 127.0.0.1:22
+    enable_udp: false
+    unix_mode: \"rw\"
+",
+            Format::Yaml,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 3: forwarders[0].local: udp can't listen on a unix socket, set enable_udp: false",
+                "line 4: forwarders[0].remote: udp can't forward to a unix socket, set enable_udp: false",
+                "line 5: forwarders[0].unix_mode: an abstract socket has no file to set the mode of",
+                "line 6: forwarders[0].allow_nets: unix clients have no address, guard a unix:<path> with unix_mode",
+                "line 10: forwarders[1].unix_mode: invalid mode 'rw', expect octal digits like \"0660\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn upstream_group_sockets() {
+        let configs = parse(
+            "
+upstreams:
+  pool:
+    servers: [10.0.0.1:22]
+    upstream_socket: {fwmark: 7, tos: 8}
+forwarders:
+  - local: 127.0.0.1:8808
+    upstream_socket: {fwmark: 1, keepalive: 1m}
+    remoteMap:
+      - pattern: \"[ssh]\"
+        remote: pool
+        upstream_socket: {tos: 16}
+      - pattern: .*
+        remote: 10.0.0.1:80
+",
+            Format::Yaml,
+        )
+        .unwrap();
+        let forwarder = &configs.forwarders[0];
+        let sockets = forwarder.upstream_sockets();
+        assert_eq!(sockets[0].fwmark, Some(7));
+        assert_eq!(sockets[0].tos, Some(16));
+        assert_eq!(sockets[0].keepalive, Some(Duration::from_secs(60)));
+        assert_eq!(sockets[1], forwarder.upstream_socket);
+        assert_eq!(parse(&to_yaml(&configs), Format::Yaml).unwrap(), configs);
+
+        let errors: Vec<String> = parse(
+            "
+upstreams:
+  pool:
+    servers: [10.0.0.1:22]
+    upstream_socket: {bind_address: \"::1\"}
+forwarders:
+  - local: 127.0.0.1:8808
+    remote: pool
+",
+            Format::Yaml,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            ["line 5: upstreams.pool.upstream_socket.bind_address: ::1 can't send to 10.0.0.1:22, another address family"]
+        );
+    }
+
+    #[test]
+    fn socket_options() {
+        let configs = parse(
+            "
+forwarders:
+  - local: 127.0.0.1:8808
+    listen_socket:
+      nodelay: true
+      fast_open: true
+    upstream_socket:
+      bind_address: 10.0.0.2
+      fwmark: 100
+      keepalive: 1m
+      keepalive_count: 3
+    remoteMap:
+      - pattern: \"[ssh]\"
+        remote: 10.0.0.1:22
+        upstream_socket:
+          bind_address: 10.0.0.3
+          tos: 16
+      - pattern: .*
+        remote: 10.0.0.1:80
+",
+            Format::Yaml,
+        )
+        .unwrap();
+        let forwarder = &configs.forwarders[0];
+        assert_eq!(forwarder.listen_socket.nodelay, Some(true));
+        let sockets = forwarder.upstream_sockets();
+        assert_eq!(sockets[0].bind_address, Some("10.0.0.3".parse().unwrap()));
+        assert_eq!(sockets[0].fwmark, Some(100));
+        assert_eq!(sockets[0].tos, Some(16));
+        assert_eq!(sockets[1], forwarder.upstream_socket);
+        assert_eq!(sockets[1].keepalive, Some(Duration::from_secs(60)));
+        assert_eq!(parse(&to_yaml(&configs), Format::Yaml).unwrap(), configs);
+
+        let errors: Vec<String> = parse(
+            "
+forwarders:
+  - local: 127.0.0.1:8808
+    remote: 10.0.0.1:80
+    listen_socket:
+      bind_address: 10.0.0.2
+      keepalive: 0s
+    upstream_socket:
+      bind_address: host
+      tos: 256
+      nagle: false
+",
+            Format::Yaml,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 6: forwarders[0].listen_socket.bind_address: the listener is bound to local",
+                "line 7: forwarders[0].listen_socket.keepalive: must not be 0",
+                "line 9: forwarders[0].upstream_socket.bind_address: expect an IP address, got 'host'",
+                "line 10: forwarders[0].upstream_socket.tos: expect a number up to 255, got 256",
+                "line 11: forwarders[0].upstream_socket.nagle: unknown key",
+            ]
+        );
+    }
+
+    #[test]
+    fn udp_sessions() {
+        let configs = parse(
+            "
+forwarders:
+  - local: 127.0.0.1:53
+    udp_idle_timeout: 5m
+    udp_evict_lru: true
+    udp_queue:
+      session_packets: 16
+      bytes: 1MB
+      drop: oldest
+    remoteMap:
+      - pattern: .*
+        remote: 10.0.0.1:53
+        udp_idle_timeout: 10s
+  - local: 127.0.0.1:51820
+    remote: 10.0.0.1:51820
+",
+            Format::Yaml,
+        )
+        .unwrap();
+        let dns = &configs.forwarders[0];
+        assert!(dns.udp_evict_lru);
+        assert_eq!(
+            dns.udp_queue,
+            UdpQueueConfig {
+                session_packets: 16,
+                bytes: 1024 * 1024,
+                drop: DropPolicy::Oldest,
+                ..Default::default()
+            }
+        );
+        assert_eq!(dns.udp_idle_timeout, Duration::from_secs(300));
+        assert_eq!(dns.udp_idle_timeouts(), vec![Duration::from_secs(10)]);
+        assert_eq!(
+            configs.forwarders[1].udp_idle_timeouts(),
+            vec![Duration::from_secs(180)]
+        );
+        assert_eq!(configs.forwarders[1].udp_queue, UdpQueueConfig::default());
+        assert_eq!(parse(&to_yaml(&configs), Format::Yaml).unwrap(), configs);
+
+        let errors: Vec<String> = parse(
+            "
+forwarders:
+  - local: 127.0.0.1:53
+    udp_idle_timeout: 0s
+    remoteMap:
+      - pattern: .*
+        remote: 10.0.0.1:53
+        udp_idle_timeout: soon
+    udp_queue:
+      packets: 0
+      drop: tail
+",
+            Format::Yaml,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 4: forwarders[0].udp_idle_timeout: must not be 0",
+                "line 8: forwarders[0].remoteMap[0].udp_idle_timeout: invalid duration 'soon', eg. 30s",
+                "line 10: forwarders[0].udp_queue.packets: must not be 0",
+                "line 11: forwarders[0].udp_queue.drop: invalid 'tail', support values: oldest, newest",
+            ]
+        );
+    }
+
+    #[test]
+    fn dump_reads_back_the_same() {
+        let mut configs = parse(YAML, Format::Yaml).unwrap();
+        configs.metrics_listen = Some("127.0.0.1:9100".to_string());
+        configs.upstreams.push(UpstreamConfig {
+            name: "pool".to_string(),
+            servers: vec![UpstreamServer {
+                address: "127.0.0.1:22".to_string(),
+                weight: 2,
+            }],
+            health_check: Some(HealthCheckConfig::default()),
+            fail_timeout: Duration::from_micros(1500),
+            ..Default::default()
+        });
+        let forwarder = &mut configs.forwarders[0];
+        forwarder.conn_bufsize = 1000;
+        forwarder.drain_timeout = Duration::from_nanos(30_000_000_001);
+        forwarder.capture = Some(CaptureConfig {
+            // as if written `$${HOME}`, loading the dump must not read the variable
+            file: "/tmp/${HOME}.pcapng".to_string(),
+            rules: vec!["[ssh]".to_string()],
+            clients: vec![],
+        });
+
+        let text = to_yaml(&configs);
+        assert!(text.contains("  drain_timeout: 30000000001ns\n"), "{}", text);
+        assert!(text.contains("  fail_timeout: 1500us\n"), "{}", text);
+        assert!(text.contains("/tmp/$${HOME}.pcapng"), "{}", text);
+        assert!(text.contains("  remote: 127.0.0.1:80\n"), "{}", text);
+        assert_eq!(parse(&text, Format::Yaml).unwrap(), configs);
+    }
+}
@@ -1,19 +1,64 @@
 use crate::address_matcher::IpAddrMatcher;
+use crate::endpoint::{Endpoint, ToEndpoint};
+use crate::upstream::{self, UpstreamGroup};
 use hex;
 use regex::Regex;
 use std::collections::HashMap;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 pub trait ConnectionPlugin {
-    fn onlySingleTarget(&self) -> Option<SocketAddr>;
-    fn decideTarget(&self, buf: &[u8], addr: SocketAddr) -> Option<SocketAddr>;
+    fn onlySingleTarget(&self) -> Option<Endpoint>;
+    fn decideTarget(&self, buf: &[u8], addr: SocketAddr) -> Option<Endpoint>;
+    /// Like `decideTarget`, but skips rules for which `enabled` is false and
+    /// also returns the index of the matching rule.
+    fn matchRule(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+        enabled: &dyn Fn(usize) -> bool,
+    ) -> Option<(usize, Endpoint)>;
+    /// The pattern the rule at `index` was configured with.
+    fn rulePattern(&self, index: usize) -> Option<&str>;
+    /// The remote the rule at `index` was configured with.
+    fn ruleRemote(&self, index: usize) -> Option<&str>;
     fn testipaddr(&self, addr: &SocketAddr) -> bool;
     fn transform(&mut self, buf: &[u8]) -> Option<Vec<u8>>;
 }
 
+/// Checks the first packet against one rule. Given a string, a mismatch
+/// writes why into it, which only the rule tester asks for.
+type RuleCheck = Box<dyn Fn(&[u8], Option<&mut String>) -> bool + Send + Sync>;
+
+/// A failed check, with `reason` written to `why` if it was asked for.
+fn mismatch(why: Option<&mut String>, reason: impl FnOnce() -> String) -> bool {
+    if let Some(why) = why {
+        *why = reason();
+    }
+    false
+}
+
+/// Where a rule sends its connections: one address or an upstream group.
+enum Target {
+    Addr(Endpoint),
+    Group(Arc<UpstreamGroup>),
+}
+
+impl Target {
+    fn pick(&self) -> Option<Endpoint> {
+        match self {
+            Target::Addr(addr) => Some(addr.clone()),
+            Target::Group(group) => group.pick().map(Endpoint::Inet),
+        }
+    }
+}
+
 pub struct RegexMultiplexer {
-    utarget: Option<SocketAddr>,
-    rules: Vec<(Box<dyn Fn(&[u8]) -> bool + Send + Sync>, SocketAddr)>,
+    utarget: Option<usize>,
+    rules: Vec<(RuleCheck, Target)>,
+    patterns: Vec<String>,
+    remotes: Vec<String>,
     ip_matcher: IpAddrMatcher,
 }
 
@@ -69,166 +114,248 @@ fn kmp_search(text: &[u8], pattern: &[u8]) -> Option<usize> {
     None
 }
 
-impl From<(Vec<(String, String)>, Vec<String>)> for RegexMultiplexer {
-    fn from(regexPlusAllowed: (Vec<(String, String)>, Vec<String>)) -> Self {
-        let proto2regex: HashMap<&str, &str> = vec![
-            ("[ssh]", "^SSH-2\\.0-.+"),
-            (
-                "[http]",
-                "^(GET|POST|PUT|DELETE|OPTIONS|HEAD|CONNECT|TRACE).*HTTP.*",
-            ),
-        ]
-        .into_iter()
-        .collect();
-
-        let rules = regexPlusAllowed
-            .0
-            .iter()
-            .map(|pair| {
-                let gexp = match proto2regex.get(&pair.0.as_str()) {
-                    Some(re) => *re,
-                    None => &pair.0,
-                };
-
-                let addr = pair.1.to_socket_addrs().unwrap().next().unwrap();
-                if gexp == "[socks5]" {
-                    let func: Box<dyn Fn(&[u8]) -> bool + Send + Sync> =
-                        Box::new(|buf: &[u8]| {
-                            if buf.len() < 3
-                                || buf[0] != 0x05
-                                || buf.len() != usize::from(buf[1]) + 2
-                            {
-                                false
-                            } else {
-                                let mut is_valid = true;
-                                for octet_ in &buf[2..] {
-                                    let octet = *octet_;
-                                    if octet != 0
-                                        && octet != 1
-                                        && octet != 2
-                                        && octet != 3
-                                        && octet != 0x80
-                                        && octet != 0xFF
-                                    {
-                                        is_valid = false;
-                                        break;
-                                    }
-                                }
-                                is_valid
-                            }
-                        });
-                    return (func, addr);
-                } else if gexp == "[rdp]" {
-                    let func: Box<dyn Fn(&[u8]) -> bool + Send + Sync> =
-                        Box::new(|buf: &[u8]| {
-                            if buf.len() < 11 || buf[0] != 0x03 {
-                                return false;
-                            }
-
-                            let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
-                            if length != buf.len() {
-                                return false;
-                            }
-
-                            if (buf[4] as usize + 5) != buf.len() {
-                                return false;
-                            }
-
-                            // connection request
-                            if buf[5] & 0xE0 != 0xE0 {
-                                return false;
-                            }
-
-                            // DST-REF
-                            if buf[6] != 0 || buf[7] != 0 {
-                                return false;
-                            }
-
-                            true
-                        });
-                    return (func, addr);
-                } else if gexp.starts_with("[https:") && gexp.ends_with("]") {
-                    let domain_name = gexp[7..gexp.len() - 1].to_string();
-                    let func: Box<dyn Fn(&[u8]) -> bool + Send + Sync> =
-                        Box::new(move |buf: &[u8]| {
-                            if buf.len() < 3 + domain_name.len() {
-                                return false;
-                            }
-                            // TLS handshake record with major version 0x03 and minor 0x00..0x04.
-                            // This covers SSLv3/TLS1.0/1.1/1.2 and TLS1.3-compatible records.
-                            if buf[0] != 0x16 || buf[1] != 0x03 || buf[2] > 0x04 {
-                                return false;
-                            }
-                            return kmp_search(buf, domain_name.as_bytes()).is_some();
-                        });
-                    (func, addr)
-                } else {
-                    let exp = if gexp.starts_with("[http:") && gexp.ends_with("]") {
-                        let domain_name = &gexp[6..gexp.len() - 1];
-                        "^(GET|POST|PUT|DELETE|OPTIONS|HEAD|CONNECT|TRACE).*HTTP.*(.\r\n.*)*"
-                            .to_string()
-                            + domain_name
-                    } else {
-                        gexp.to_string()
-                    };
-
-                    let regex = Regex::new(&exp).unwrap();
-                    let func: Box<dyn Fn(&[u8]) -> bool + Send + Sync> =
-                        Box::new(move |buf: &[u8]| {
-                            let s1 = hex::encode(buf);
-                            if regex.is_match(&s1) {
-                                return true;
-                            }
-                            let s2 = String::from_utf8_lossy(buf);
-                            if regex.is_match(&s2) {
-                                return true;
-                            }
-
-                            return false;
-                        });
-                    return (func, addr);
+impl TryFrom<(Vec<(String, String)>, Vec<String>)> for RegexMultiplexer {
+    type Error = String;
+
+    fn try_from(regexPlusAllowed: (Vec<(String, String)>, Vec<String>)) -> Result<Self, String> {
+        let ip_matcher = IpAddrMatcher::from(&regexPlusAllowed.1)?;
+        RegexMultiplexer::new(regexPlusAllowed.0, ip_matcher)
+    }
+}
+
+/// The check a `remoteMap` pattern stands for, or why the pattern is invalid.
+fn ruleCheck(pattern: &str) -> Result<RuleCheck, String> {
+    let proto2regex: HashMap<&str, &str> = vec![
+        ("[ssh]", "^SSH-2\\.0-.+"),
+        (
+            "[http]",
+            "^(GET|POST|PUT|DELETE|OPTIONS|HEAD|CONNECT|TRACE).*HTTP.*",
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    let gexp = match proto2regex.get(pattern) {
+        Some(re) => *re,
+        None => pattern,
+    };
+
+    if gexp == "[socks5]" {
+        let func: RuleCheck = Box::new(|buf: &[u8], why: Option<&mut String>| {
+            if buf.len() < 3 {
+                return mismatch(why, || "shorter than a SOCKS5 greeting".to_string());
+            }
+            if buf[0] != 0x05 {
+                return mismatch(why, || format!("version 0x{:02x} is not SOCKS5", buf[0]));
+            }
+            if buf.len() != usize::from(buf[1]) + 2 {
+                return mismatch(why, || {
+                    format!("{} bytes but {} methods announced", buf.len(), buf[1])
+                });
+            }
+            for octet_ in &buf[2..] {
+                let octet = *octet_;
+                if octet != 0
+                    && octet != 1
+                    && octet != 2
+                    && octet != 3
+                    && octet != 0x80
+                    && octet != 0xFF
+                {
+                    return mismatch(why, || format!("unknown SOCKS5 method 0x{:02x}", octet));
                 }
-            })
-            .collect();
-        let ip_matcher = IpAddrMatcher::from(&regexPlusAllowed.1);
-        let utarget =
-            if regexPlusAllowed.0.len() == 1 && regexPlusAllowed.0.get(0).unwrap().0 == ".*" {
-                Some(
-                    regexPlusAllowed
-                        .0
-                        .get(0)
-                        .unwrap()
-                        .1
-                        .to_socket_addrs()
-                        .unwrap()
-                        .next()
-                        .unwrap(),
+            }
+            true
+        });
+        return Ok(func);
+    } else if gexp == "[rdp]" {
+        let func: RuleCheck = Box::new(|buf: &[u8], why: Option<&mut String>| {
+            if buf.len() < 11 {
+                return mismatch(why, || "shorter than an RDP connection request".to_string());
+            }
+            if buf[0] != 0x03 {
+                return mismatch(why, || format!("version 0x{:02x} is not TPKT", buf[0]));
+            }
+
+            let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            if length != buf.len() {
+                return mismatch(why, || {
+                    format!("TPKT length {} but {} bytes received", length, buf.len())
+                });
+            }
+
+            if (buf[4] as usize + 5) != buf.len() {
+                return mismatch(why, || format!("X.224 length {} does not fit", buf[4]));
+            }
+
+            // connection request
+            if buf[5] & 0xE0 != 0xE0 {
+                return mismatch(why, || "not an X.224 connection request".to_string());
+            }
+
+            // DST-REF
+            if buf[6] != 0 || buf[7] != 0 {
+                return mismatch(why, || "X.224 DST-REF is not zero".to_string());
+            }
+
+            true
+        });
+        return Ok(func);
+    } else if gexp.starts_with("[https:") && gexp.ends_with("]") {
+        let domain_name = gexp[7..gexp.len() - 1].to_string();
+        let func: RuleCheck = Box::new(move |buf: &[u8], why: Option<&mut String>| {
+            if buf.len() < 3 + domain_name.len() {
+                return mismatch(why, || "too short to carry the server name".to_string());
+            }
+            // TLS handshake record with major version 0x03 and minor 0x00..0x04.
+            // This covers SSLv3/TLS1.0/1.1/1.2 and TLS1.3-compatible records.
+            if buf[0] != 0x16 || buf[1] != 0x03 || buf[2] > 0x04 {
+                return mismatch(why, || {
+                    format!(
+                        "starts with {} instead of a TLS handshake record",
+                        hex::encode(&buf[..3])
+                    )
+                });
+            }
+            match kmp_search(buf, domain_name.as_bytes()) {
+                Some(_) => true,
+                None => mismatch(why, || format!("server name '{}' not found", domain_name)),
+            }
+        });
+        Ok(func)
+    } else {
+        let exp = if gexp.starts_with("[http:") && gexp.ends_with("]") {
+            let domain_name = &gexp[6..gexp.len() - 1];
+            "^(GET|POST|PUT|DELETE|OPTIONS|HEAD|CONNECT|TRACE).*HTTP.*(.\r\n.*)*".to_string()
+                + domain_name
+        } else {
+            gexp.to_string()
+        };
+
+        let regex = Regex::new(&exp).map_err(|e| format!("invalid regex: {}", e))?;
+        let func: RuleCheck = Box::new(move |buf: &[u8], why: Option<&mut String>| {
+            let s1 = hex::encode(buf);
+            if regex.is_match(&s1) {
+                return true;
+            }
+            let s2 = String::from_utf8_lossy(buf);
+            if regex.is_match(&s2) {
+                return true;
+            }
+
+            mismatch(why, || {
+                format!(
+                    "regex '{}' matches neither the hex nor the text",
+                    regex.as_str().replace('\r', "\\r").replace('\n', "\\n")
                 )
-            } else {
-                None
-            };
-        RegexMultiplexer {
+            })
+        });
+        return Ok(func);
+    }
+}
+
+impl TryFrom<(Vec<(String, String)>, IpAddrMatcher)> for RegexMultiplexer {
+    type Error = String;
+
+    fn try_from(regexPlusAllowed: (Vec<(String, String)>, IpAddrMatcher)) -> Result<Self, String> {
+        RegexMultiplexer::new(regexPlusAllowed.0, regexPlusAllowed.1)
+    }
+}
+
+fn resolve(remote: &str) -> Result<Target, String> {
+    if let Some(group) = upstream::group(remote) {
+        return Ok(Target::Group(group));
+    }
+    remote
+        .to_endpoint()
+        .map(Target::Addr)
+        .map_err(|e| format!("{}: {}", remote, e))
+}
+
+impl RegexMultiplexer {
+    /// Build the rules of `remoteMap`, reporting the first invalid pattern or
+    /// unresolvable remote instead of panicking. A remote naming a configured
+    /// upstream group picks one of its servers on every match.
+    pub fn new(
+        remoteMap: Vec<(String, String)>,
+        ip_matcher: IpAddrMatcher,
+    ) -> Result<RegexMultiplexer, String> {
+        let mut rules = vec![];
+        for (i, (pattern, remote)) in remoteMap.iter().enumerate() {
+            let check =
+                ruleCheck(pattern).map_err(|e| format!("remoteMap[{}].pattern: {}", i, e))?;
+            let target =
+                resolve(remote).map_err(|e| format!("remoteMap[{}].remote: {}", i, e))?;
+            rules.push((check, target));
+        }
+        let utarget = if rules.len() == 1 && remoteMap[0].0 == ".*" {
+            Some(0)
+        } else {
+            None
+        };
+        let (patterns, remotes) = remoteMap.into_iter().unzip();
+        Ok(RegexMultiplexer {
             utarget,
             rules,
+            patterns,
+            remotes,
             ip_matcher,
+        })
+    }
+
+    /// Whether `pattern` is a valid `remoteMap` pattern.
+    pub fn checkPattern(pattern: &str) -> Result<(), String> {
+        ruleCheck(pattern).map(|_| ())
+    }
+
+    pub fn ruleCount(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Why the rule at `index` does not match `buf`, `None` if it does.
+    pub fn explainMismatch(&self, index: usize, buf: &[u8]) -> Option<String> {
+        let rule = self.rules.get(index)?;
+        let mut why = String::new();
+        if rule.0(buf, Some(&mut why)) {
+            None
+        } else {
+            Some(why)
         }
     }
 }
 
 impl ConnectionPlugin for RegexMultiplexer {
-    fn onlySingleTarget(&self) -> Option<SocketAddr> {
-        return self.utarget;
+    fn onlySingleTarget(&self) -> Option<Endpoint> {
+        self.utarget.and_then(|i| self.rules[i].1.pick())
+    }
+
+    fn decideTarget(&self, buf: &[u8], addr: SocketAddr) -> Option<Endpoint> {
+        self.matchRule(buf, addr, &|_| true)
+            .map(|(_, target)| target)
     }
 
-    fn decideTarget(&self, buf: &[u8], _addr: SocketAddr) -> Option<SocketAddr> {
-        for rule in &self.rules {
-            if rule.0(&buf) {
-                return Some(rule.1);
+    fn matchRule(
+        &self,
+        buf: &[u8],
+        _addr: SocketAddr,
+        enabled: &dyn Fn(usize) -> bool,
+    ) -> Option<(usize, Endpoint)> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if enabled(i) && rule.0(buf, None) {
+                return rule.1.pick().map(|target| (i, target));
             }
         }
         None
     }
 
+    fn rulePattern(&self, index: usize) -> Option<&str> {
+        self.patterns.get(index).map(|p| p.as_str())
+    }
+
+    fn ruleRemote(&self, index: usize) -> Option<&str> {
+        self.remotes.get(index).map(|r| r.as_str())
+    }
+
     fn testipaddr(&self, addr: &SocketAddr) -> bool {
         self.ip_matcher.testipaddr(&addr.ip())
     }
@@ -241,16 +368,18 @@ impl ConnectionPlugin for RegexMultiplexer {
 #[cfg(test)]
 mod tests {
     use super::{ConnectionPlugin, RegexMultiplexer};
+    use std::convert::TryFrom;
 
     #[test]
     fn https_matcher_requires_valid_tls_prefix() {
-        let mux = RegexMultiplexer::from((
+        let mux = RegexMultiplexer::try_from((
             vec![(
                 "[https:example.com]".to_string(),
                 "127.0.0.1:443".to_string(),
             )],
             vec!["127.0.0.1/8".to_string()],
-        ));
+        ))
+        .unwrap();
 
         // Contains domain bytes but does not have a valid TLS prefix.
         let bad = b"\x16\x99\x99....example.com....";
@@ -0,0 +1,472 @@
+//! Addresses that are either TCP or Unix domain sockets (`unix:/path`, or
+//! `unix:@name` for the Linux abstract namespace), with streams and listeners
+//! for both.
+use crate::forward_config::SocketConfig;
+use crate::socket_options;
+use crate::utils::{canonical, resolveSockAddr};
+use mio::event::Source;
+use mio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use mio::net::{UnixListener, UnixStream};
+use mio::{Interest, Registry, Token};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{AddrParseError, Shutdown, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Inet(SocketAddr),
+    /// A path, or `@name` for an abstract socket.
+    Unix(String),
+}
+
+/// The socket name of a `unix:` address.
+pub fn unix_name(addr: &str) -> Option<&str> {
+    addr.strip_prefix("unix:")
+}
+
+impl Endpoint {
+    pub fn inet(&self) -> Option<SocketAddr> {
+        match self {
+            Endpoint::Inet(addr) => Some(*addr),
+            Endpoint::Unix(_) => None,
+        }
+    }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self, Endpoint::Unix(_))
+    }
+
+    /// For logs, eg. `tcp://0.0.0.0:8808` or `unix:/run/portfd.sock`.
+    pub fn url(&self, scheme: &str) -> String {
+        match self {
+            Endpoint::Inet(addr) => format!("{}://{}", scheme, addr),
+            Endpoint::Unix(_) => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Inet(addr) => addr.fmt(f),
+            Endpoint::Unix(name) => write!(f, "unix:{}", name),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match unix_name(s) {
+            Some(name) => Ok(Endpoint::Unix(name.to_string())),
+            None => s.parse().map(Endpoint::Inet),
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Inet(addr)
+    }
+}
+
+impl Serialize for Endpoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// What a forwarder can listen on: anything `ToSocketAddrs`, and strings may
+/// also be `unix:` addresses.
+pub trait ToEndpoint: ToSocketAddrs {
+    fn to_endpoint(&self) -> io::Result<Endpoint>;
+}
+
+impl ToEndpoint for str {
+    fn to_endpoint(&self) -> io::Result<Endpoint> {
+        match unix_name(self) {
+            Some("") => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expect unix:<path> or unix:@<name>",
+            )),
+            Some(name) => Ok(Endpoint::Unix(name.to_string())),
+            None => resolveSockAddr(&self).map(Endpoint::Inet),
+        }
+    }
+}
+
+impl ToEndpoint for String {
+    fn to_endpoint(&self) -> io::Result<Endpoint> {
+        self.as_str().to_endpoint()
+    }
+}
+
+impl ToEndpoint for SocketAddr {
+    fn to_endpoint(&self) -> io::Result<Endpoint> {
+        Ok(Endpoint::Inet(*self))
+    }
+}
+
+impl<T: ToEndpoint + ?Sized> ToEndpoint for &T {
+    fn to_endpoint(&self) -> io::Result<Endpoint> {
+        (**self).to_endpoint()
+    }
+}
+
+#[cfg(unix)]
+fn unix_addr(name: &str) -> io::Result<std::os::unix::net::SocketAddr> {
+    match name.strip_prefix('@') {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(name) => {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+        }
+        _ => std::os::unix::net::SocketAddr::from_pathname(name),
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn unix_endpoint(addr: &std::os::unix::net::SocketAddr) -> Option<Endpoint> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+        if let Some(name) = addr.as_abstract_name() {
+            return Some(Endpoint::Unix(format!(
+                "@{}",
+                String::from_utf8_lossy(name)
+            )));
+        }
+    }
+    let path = addr.as_pathname()?;
+    Some(Endpoint::Unix(path.to_string_lossy().into_owned()))
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets are not supported on this platform",
+    )
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+macro_rules! each {
+    ($stream:expr, $s:ident => $e:expr) => {
+        match $stream {
+            Stream::Tcp($s) => $e,
+            #[cfg(unix)]
+            Stream::Unix($s) => $e,
+        }
+    };
+}
+
+impl Stream {
+    /// Start connecting without blocking, like `TcpStream::connect`. A unix
+    /// socket has none of the `options`.
+    pub fn connect(to: &Endpoint, options: &SocketConfig) -> io::Result<Stream> {
+        match to {
+            Endpoint::Inet(addr) => socket_options::connect(*addr, options).map(Stream::Tcp),
+            #[cfg(unix)]
+            Endpoint::Unix(name) => UnixStream::connect_addr(&unix_addr(name)?).map(Stream::Unix),
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(unsupported()),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<Endpoint> {
+        match self {
+            Stream::Tcp(s) => s.peer_addr().map(Endpoint::Inet),
+            #[cfg(unix)]
+            Stream::Unix(s) => unix_endpoint(&s.peer_addr()?).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "unnamed unix socket")
+            }),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        each!(self, s => s.shutdown(how))
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        each!(self, s => s.take_error())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        each!(self, s => s.read(buf))
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        each!(self, s => s.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        each!(self, s => s.flush())
+    }
+}
+
+impl Source for Stream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        each!(self, s => s.register(registry, token, interests))
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        each!(self, s => s.reregister(registry, token, interests))
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        each!(self, s => s.deregister(registry))
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        name: String,
+        /// Device and inode of the socket file this listener created, it is
+        /// removed on drop unless something else took its place.
+        created: Option<(u64, u64)>,
+    },
+}
+
+impl Listener {
+    /// Listen at `unix:<name>`, a stale socket file left by an earlier run is
+    /// replaced and the new one has `mode` from the start.
+    #[cfg(unix)]
+    pub fn bind_unix(name: &str, mode: Option<u32>) -> io::Result<Listener> {
+        use std::os::unix::fs::MetadataExt;
+        let is_file = !name.starts_with('@');
+        if is_file {
+            remove_stale_socket(name)?;
+        }
+        let listener = match mode {
+            Some(mode) if is_file => bind_private(name, mode)?,
+            _ => std::os::unix::net::UnixListener::bind_addr(&unix_addr(name)?)?,
+        };
+        let created = if is_file {
+            let meta = std::fs::metadata(name)?;
+            Some((meta.dev(), meta.ino()))
+        } else {
+            None
+        };
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix {
+            listener: UnixListener::from_std(listener),
+            name: name.to_string(),
+            created,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind_unix(_name: &str, _mode: Option<u32>) -> io::Result<Listener> {
+        Err(unsupported())
+    }
+
+    /// A listener systemd passed, its socket file is left to systemd.
+    #[cfg(unix)]
+    pub fn inherited_unix(
+        listener: std::os::unix::net::UnixListener,
+        name: &str,
+    ) -> io::Result<Listener> {
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix {
+            listener: UnixListener::from_std(listener),
+            name: name.to_string(),
+            created: None,
+        })
+    }
+
+    /// The next client, a Unix one is named after the listener since its own
+    /// socket usually has no name, an IPv4 one of `[::]` by its IPv4 address.
+    pub fn accept(&self) -> io::Result<(Stream, Endpoint)> {
+        match self {
+            Listener::Tcp(l) => l
+                .accept()
+                .map(|(stream, addr)| (Stream::Tcp(stream), Endpoint::Inet(canonical(addr)))),
+            #[cfg(unix)]
+            Listener::Unix { listener, name, .. } => listener
+                .accept()
+                .map(|(stream, _)| (Stream::Unix(stream), Endpoint::Unix(name.clone()))),
+        }
+    }
+}
+
+/// Bind `path` in a directory only this process can enter, apply `mode` and
+/// link it into place, so that it is never reachable with other permissions.
+/// Like binding, linking fails if something is in the way.
+#[cfg(unix)]
+fn bind_private(path: &str, mode: u32) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let parent = Path::new(path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let dir = parent.join(format!(
+        ".portfd-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let temp = dir.join("s");
+    let bound = std::os::unix::net::UnixListener::bind(&temp).and_then(|listener| {
+        std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(mode))?;
+        std::fs::hard_link(&temp, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&temp);
+    let _ = std::fs::remove_dir(&dir);
+    bound
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("another process listens at {}", path),
+                )),
+                Err(_) => {
+                    log::info!("remove stale socket {}", path);
+                    std::fs::remove_file(path)
+                }
+            }
+        }
+        // binding reports anything else in the way
+        _ => Ok(()),
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix {
+            name,
+            created: Some(created),
+            ..
+        } = self
+        {
+            use std::os::unix::fs::MetadataExt;
+            let same = std::fs::symlink_metadata(&name)
+                .is_ok_and(|meta| (meta.dev(), meta.ino()) == *created);
+            if same {
+                let _ = std::fs::remove_file(&name);
+            }
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Listener::Tcp(l) => l.register(registry, token, interests),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Listener::Tcp(l) => l.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Listener::Tcp(l) => l.deregister(registry),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener.deregister(registry),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_listener_replaces_stale_socket_and_cleans_up() {
+        let path =
+            std::env::temp_dir().join(format!("portfd-endpoint-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        // left over by a process that is gone
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let listener = Listener::bind_unix(&path, Some(0o600)).unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let endpoint: Endpoint = format!("unix:{}", path).parse().unwrap();
+        let mut client = Stream::connect(&endpoint, &SocketConfig::default()).unwrap();
+        client.write_all(b"hi").unwrap();
+        let (mut server, peer) = loop {
+            match listener.accept() {
+                Ok(accepted) => break accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(peer, endpoint);
+        let mut buf = [0; 2];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+        assert_eq!(
+            Listener::bind_unix(&path, None).err().map(|e| e.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+
+        drop(listener);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
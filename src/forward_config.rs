@@ -1,19 +1,859 @@
-use std::net::ToSocketAddrs;
+use crate::address_matcher::{AclAction, AclRule, load_net_file, parse_net};
+use crate::connection_plugin::RegexMultiplexer;
+use crate::endpoint::{Endpoint, ToEndpoint, unix_name};
+use crate::utils::resolveSockAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
 pub enum TcpMode {
+    #[default]
     Forward,
     Socks5Server,
 }
 
-#[derive(Clone, Debug)]
-pub struct ForwardSessionConfig<T: ToSocketAddrs> {
+/// Where to write captured sessions, optionally limited to some rules or clients.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct CaptureConfig {
+    pub file: String,
+    pub rules: Vec<String>,
+    pub clients: Vec<String>,
+}
+
+/// Where to log first packets that match no rule, and how much of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnmatchedLogConfig {
+    pub file: String,
+    /// Bytes of each packet to dump.
+    pub bytes: usize,
+    /// At most this many samples per minute are written.
+    pub per_minute: u32,
+    /// Packets sharing this many leading bytes are logged once per `dedup_window`.
+    pub dedup_prefix: usize,
+    pub dedup_window: Duration,
+}
+
+impl Default for UnmatchedLogConfig {
+    fn default() -> Self {
+        Self {
+            file: String::new(),
+            bytes: 64,
+            per_minute: 30,
+            dedup_prefix: 8,
+            dedup_window: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Which datagram a full UDP queue gives up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// The longest queued one, so that fresh datagrams get through.
+    Oldest,
+    /// The one that would be queued.
+    #[default]
+    Newest,
+}
+
+/// Bounds of the datagrams waiting for a writable UDP socket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UdpQueueConfig {
+    /// Datagrams a session may queue to its upstream, and their bytes.
+    pub session_packets: usize,
+    pub session_bytes: usize,
+    /// Datagrams queued back to clients by all sessions together, and their bytes.
+    pub packets: usize,
+    pub bytes: usize,
+    pub drop: DropPolicy,
+}
+
+impl Default for UdpQueueConfig {
+    fn default() -> Self {
+        Self {
+            session_packets: 256,
+            session_bytes: 256 * 1024,
+            packets: 4096,
+            bytes: 4 * 1024 * 1024,
+            drop: DropPolicy::Newest,
+        }
+    }
+}
+
+/// A server of an upstream group, picked in proportion to its weight.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamServer {
+    pub address: String,
+    pub weight: u32,
+}
+
+/// Periodic TCP connect checks of the servers of an upstream group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheckConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Failed checks in a row before a server is taken out.
+    pub fall: u32,
+    /// Passed checks in a row before it is put back.
+    pub rise: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            fall: 3,
+            rise: 2,
+        }
+    }
+}
+
+/// Named servers that any `remote` can refer to instead of an address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamConfig {
+    pub name: String,
+    pub servers: Vec<UpstreamServer>,
+    pub health_check: Option<HealthCheckConfig>,
+    /// Failed connects in a row after which a server is skipped for
+    /// `fail_timeout`, 0 never skips.
+    pub max_fails: u32,
+    pub fail_timeout: Duration,
+    /// Options of the sockets to the servers, over the `upstream_socket` of
+    /// a forwarder and under those of a rule.
+    pub upstream_socket: SocketConfig,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            servers: vec![],
+            health_check: None,
+            max_fails: 1,
+            fail_timeout: Duration::from_secs(10),
+            upstream_socket: SocketConfig::default(),
+        }
+    }
+}
+
+impl UpstreamConfig {
+    /// Every problem of this group, as key and message.
+    pub fn validate(&self) -> Vec<(String, String)> {
+        let mut problems = vec![];
+        if self.name.is_empty() || self.name.contains(':') {
+            problems.push((
+                String::new(),
+                format!("invalid name '{}', it must not look like an address", self.name),
+            ));
+        }
+        if self.servers.is_empty() {
+            problems.push(("servers".to_string(), "no servers".to_string()));
+        }
+        for (i, server) in self.servers.iter().enumerate() {
+            if let Err(e) = resolveSockAddr(server.address.as_str()) {
+                problems.push((
+                    format!("servers[{}]", i),
+                    format!("{}: {}", server.address, e),
+                ));
+            }
+            if server.weight == 0 {
+                problems.push((
+                    format!("servers[{}].weight", i),
+                    "must be at least 1".to_string(),
+                ));
+            }
+        }
+        if let Some(check) = &self.health_check {
+            if check.interval.is_zero() {
+                problems.push((
+                    "health_check.interval".to_string(),
+                    "must not be 0".to_string(),
+                ));
+            }
+            if check.timeout.is_zero() {
+                problems.push((
+                    "health_check.timeout".to_string(),
+                    "must not be 0".to_string(),
+                ));
+            }
+        }
+        self.upstream_socket.validate("upstream_socket", false, &mut problems);
+        if let Some(source) = self.upstream_socket.bind_address {
+            for server in &self.servers {
+                match resolveSockAddr(server.address.as_str()) {
+                    Ok(target) if source.is_ipv4() != target.is_ipv4() => problems.push((
+                        "upstream_socket.bind_address".to_string(),
+                        format!(
+                            "{} can't send to {}, another address family",
+                            source, server.address
+                        ),
+                    )),
+                    _ => {}
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// Options of a socket, unset ones keep the system's default.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct SocketConfig {
+    /// Source address of upstream sockets.
+    pub bind_address: Option<IpAddr>,
+    /// Only send through this network interface (`SO_BINDTODEVICE`).
+    pub interface: Option<String>,
+    /// `SO_MARK`, for policy routing.
+    pub fwmark: Option<u32>,
+    pub nodelay: Option<bool>,
+    /// Idle time before keepalive probes are sent, turns keepalive on.
+    pub keepalive: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_count: Option<u32>,
+    /// `TCP_USER_TIMEOUT`, how long sent data may stay unacknowledged.
+    pub user_timeout: Option<Duration>,
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    /// TCP Fast Open on connects, or the listener's queue for it.
+    pub fast_open: Option<bool>,
+    /// `IP_TOS`, or the traffic class of an IPv6 socket.
+    pub tos: Option<u8>,
+}
+
+impl SocketConfig {
+    /// The options of `over` where it sets them, ours otherwise.
+    pub fn merged(&self, over: &SocketConfig) -> SocketConfig {
+        SocketConfig {
+            bind_address: over.bind_address.or(self.bind_address),
+            interface: over.interface.clone().or_else(|| self.interface.clone()),
+            fwmark: over.fwmark.or(self.fwmark),
+            nodelay: over.nodelay.or(self.nodelay),
+            keepalive: over.keepalive.or(self.keepalive),
+            keepalive_interval: over.keepalive_interval.or(self.keepalive_interval),
+            keepalive_count: over.keepalive_count.or(self.keepalive_count),
+            user_timeout: over.user_timeout.or(self.user_timeout),
+            send_buffer: over.send_buffer.or(self.send_buffer),
+            recv_buffer: over.recv_buffer.or(self.recv_buffer),
+            fast_open: over.fast_open.or(self.fast_open),
+            tos: over.tos.or(self.tos),
+        }
+    }
+
+    /// Problems of the options under `key`, `listen` for a listening socket.
+    fn validate(&self, key: &str, listen: bool, problems: &mut Vec<(String, String)>) {
+        let mut check = |name: &str, bad: bool, message: &str| {
+            if bad {
+                problems.push((format!("{}.{}", key, name), message.to_string()));
+            }
+        };
+        check(
+            "bind_address",
+            listen && self.bind_address.is_some(),
+            "the listener is bound to local",
+        );
+        check(
+            "keepalive",
+            self.keepalive.is_some_and(|d| d.is_zero()),
+            "must not be 0",
+        );
+        check(
+            "keepalive_interval",
+            self.keepalive_interval.is_some_and(|d| d.is_zero()),
+            "must not be 0",
+        );
+        check(
+            "keepalive_count",
+            self.keepalive_count == Some(0),
+            "must not be 0",
+        );
+        check(
+            "interface",
+            self.interface.as_deref() == Some(""),
+            "must not be empty",
+        );
+        let linux_only = [
+            ("interface", self.interface.is_some()),
+            ("fwmark", self.fwmark.is_some()),
+            ("user_timeout", self.user_timeout.is_some()),
+            ("fast_open", self.fast_open.is_some()),
+        ];
+        for (name, set) in linux_only {
+            check(
+                name,
+                set && !cfg!(target_os = "linux"),
+                "only supported on Linux",
+            );
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForwardSessionConfig<T: ToEndpoint> {
     pub local: T,
     pub remoteMap: Vec<(String, String)>,
     pub allow_nets: Vec<String>,
+    pub deny_nets: Vec<String>,
+    pub acl: Vec<AclRule>,
     pub enable_tcp: bool,
     pub enable_udp: bool,
     pub conn_bufsize: usize,
     pub max_connections: i64,
     pub tcp_mode: TcpMode,
+    /// How long live connections may keep relaying after shutdown is requested.
+    pub drain_timeout: Duration,
+    pub capture: Option<CaptureConfig>,
+    pub unmatched_log: Option<UnmatchedLogConfig>,
+    /// `FileDescriptorName=` of the sockets systemd passes for this forwarder,
+    /// otherwise passed sockets are matched by `local`.
+    pub fd_name: Option<String>,
+    /// An IPv6 `local` does not accept IPv4 connections, so that the same port
+    /// can be listened on for IPv4 separately.
+    pub ipv6_only: bool,
+    /// Permissions of the socket file when `local` is `unix:/path`.
+    pub unix_mode: Option<u32>,
+    /// Options of the listening socket and the connections it accepts.
+    pub listen_socket: SocketConfig,
+    /// Options of the sockets to upstreams.
+    pub upstream_socket: SocketConfig,
+    /// Upstream socket options of single rules, by index in `remoteMap`,
+    /// over `upstream_socket`.
+    pub rule_sockets: Vec<(usize, SocketConfig)>,
+    /// `upstream_socket` of the groups rules name, by index in `remoteMap`,
+    /// between `upstream_socket` and `rule_sockets`. Set by `apply_upstreams`.
+    pub group_sockets: Vec<(usize, SocketConfig)>,
+    /// How long a UDP session lives without a datagram either way.
+    pub udp_idle_timeout: Duration,
+    /// `udp_idle_timeout` of single rules, by index in `remoteMap`.
+    pub rule_idle_timeouts: Vec<(usize, Duration)>,
+    /// When `max_connections` UDP sessions are open, close the least recently
+    /// used one for a new client instead of dropping its datagrams.
+    pub udp_evict_lru: bool,
+    pub udp_queue: UdpQueueConfig,
+}
+
+impl<T: ToEndpoint + Default> Default for ForwardSessionConfig<T> {
+    fn default() -> Self {
+        Self {
+            local: T::default(),
+            remoteMap: vec![],
+            allow_nets: vec![],
+            deny_nets: vec![],
+            acl: vec![],
+            enable_tcp: true,
+            enable_udp: true,
+            conn_bufsize: 2 * 1024 * 1024,
+            max_connections: -1,
+            tcp_mode: TcpMode::Forward,
+            drain_timeout: Duration::ZERO,
+            capture: None,
+            unmatched_log: None,
+            fd_name: None,
+            ipv6_only: false,
+            unix_mode: None,
+            listen_socket: SocketConfig::default(),
+            upstream_socket: SocketConfig::default(),
+            rule_sockets: vec![],
+            group_sockets: vec![],
+            udp_idle_timeout: Duration::from_secs(180),
+            rule_idle_timeouts: vec![],
+            udp_evict_lru: false,
+            udp_queue: UdpQueueConfig::default(),
+        }
+    }
+}
+
+impl<T: ToEndpoint> ForwardSessionConfig<T> {
+    /// Ordered access rules: `acl` first, then `deny_nets`, then `allow_nets`.
+    pub fn acl_rules(&self) -> Vec<AclRule> {
+        let mut rules = self.acl.clone();
+        for net in &self.deny_nets {
+            rules.push(AclRule::Net(AclAction::Deny, net.clone()));
+        }
+        for net in &self.allow_nets {
+            rules.push(AclRule::Net(AclAction::Allow, net.clone()));
+        }
+        rules
+    }
+
+    /// The upstream socket options of every rule in `remoteMap`.
+    pub fn upstream_sockets(&self) -> Vec<SocketConfig> {
+        let over = |layer: &[(usize, SocketConfig)], i: usize, base: SocketConfig| {
+            match layer.iter().find(|(j, _)| *j == i) {
+                Some((_, over)) => base.merged(over),
+                None => base,
+            }
+        };
+        (0..self.remoteMap.len())
+            .map(|i| {
+                let group = over(&self.group_sockets, i, self.upstream_socket.clone());
+                over(&self.rule_sockets, i, group)
+            })
+            .collect()
+    }
+
+    /// Take the `upstream_socket` of the groups in `upstreams` that rules name.
+    pub fn apply_upstreams(&mut self, upstreams: &[UpstreamConfig]) {
+        self.group_sockets = self
+            .remoteMap
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, remote))| {
+                let group = upstreams.iter().find(|u| &u.name == remote)?;
+                Some((i, group.upstream_socket.clone()))
+            })
+            .filter(|(_, options)| *options != SocketConfig::default())
+            .collect();
+    }
+
+    /// The UDP idle timeout of every rule in `remoteMap`.
+    pub fn udp_idle_timeouts(&self) -> Vec<Duration> {
+        (0..self.remoteMap.len())
+            .map(|i| match self.rule_idle_timeouts.iter().find(|(j, _)| *j == i) {
+                Some((_, timeout)) => *timeout,
+                None => self.udp_idle_timeout,
+            })
+            .collect()
+    }
+
+    /// Every problem that would keep this forwarder from starting, as key and
+    /// message. A `remote` may name one of `upstreams`.
+    pub fn validate(&self, upstreams: &[UpstreamConfig]) -> Vec<(String, String)> {
+        let mut problems = vec![];
+        let mut check = |key: String, result: Result<(), String>| {
+            if let Err(e) = result {
+                problems.push((key, e));
+            }
+        };
+        let local = self.local.to_endpoint();
+        check(
+            "local".to_string(),
+            local.as_ref().map(|_| ()).map_err(|e| format!("can't resolve: {}", e)),
+        );
+        if let Ok(Endpoint::Unix(name)) = &local {
+            if self.enable_udp {
+                check(
+                    "local".to_string(),
+                    Err("udp can't listen on a unix socket, set enable_udp: false".to_string()),
+                );
+            }
+            if self.unix_mode.is_some() && name.starts_with('@') {
+                check(
+                    "unix_mode".to_string(),
+                    Err("an abstract socket has no file to set the mode of".to_string()),
+                );
+            }
+            let access = [
+                ("allow_nets", !self.allow_nets.is_empty()),
+                ("deny_nets", !self.deny_nets.is_empty()),
+                ("acl", !self.acl.is_empty()),
+            ];
+            for (key, set) in access {
+                if set {
+                    check(
+                        key.to_string(),
+                        Err("unix clients have no address, guard a unix:<path> with unix_mode"
+                            .to_string()),
+                    );
+                }
+            }
+        } else if self.unix_mode.is_some() {
+            check(
+                "unix_mode".to_string(),
+                Err("only applies when local is unix:<path>".to_string()),
+            );
+        }
+        if !self.enable_tcp && !self.enable_udp {
+            check(
+                "enable_udp".to_string(),
+                Err("both enable_tcp and enable_udp are false".to_string()),
+            );
+        }
+        if self.tcp_mode == TcpMode::Forward && self.remoteMap.is_empty() {
+            check(
+                "remoteMap".to_string(),
+                Err("no rules, specify remoteMap or remote".to_string()),
+            );
+        }
+        for (i, (pattern, remote)) in self.remoteMap.iter().enumerate() {
+            check(
+                format!("remoteMap[{}].pattern", i),
+                RegexMultiplexer::checkPattern(pattern),
+            );
+            if upstreams.iter().any(|u| &u.name == remote) {
+                continue;
+            }
+            if let Some(name) = unix_name(remote) {
+                let result = if name.is_empty() {
+                    Err("expect unix:<path> or unix:@<name>".to_string())
+                } else if self.enable_udp {
+                    Err("udp can't forward to a unix socket, set enable_udp: false".to_string())
+                } else {
+                    Ok(())
+                };
+                check(format!("remoteMap[{}].remote", i), result);
+                continue;
+            }
+            check(
+                format!("remoteMap[{}].remote", i),
+                resolveSockAddr(remote.as_str())
+                    .map(|_| ())
+                    .map_err(|e| {
+                        if remote.contains(':') {
+                            format!("{}: {}", remote, e)
+                        } else {
+                            format!("no upstream group or address '{}'", remote)
+                        }
+                    }),
+            );
+        }
+        for (key, nets) in [("allow_nets", &self.allow_nets), ("deny_nets", &self.deny_nets)] {
+            for (i, net) in nets.iter().enumerate() {
+                check(format!("{}[{}]", key, i), parse_net(net).map(|_| ()));
+            }
+        }
+        for (i, rule) in self.acl.iter().enumerate() {
+            let result = match rule {
+                AclRule::Net(_, net) => parse_net(net).map(|_| ()),
+                AclRule::File(_, path) => load_net_file(path).map(|_| ()),
+            };
+            check(format!("acl[{}]", i), result);
+        }
+        if let Some(capture) = &self.capture {
+            for (i, net) in capture.clients.iter().enumerate() {
+                check(format!("capture.clients[{}]", i), parse_net(net).map(|_| ()));
+            }
+        }
+        let sockets = self.upstream_sockets();
+        for (i, (_, remote)) in self.remoteMap.iter().enumerate() {
+            let (source, target) = match (sockets[i].bind_address, resolveSockAddr(remote.as_str())) {
+                (Some(source), Ok(target)) => (source, target),
+                _ => continue,
+            };
+            if source.is_ipv4() != target.is_ipv4() {
+                let own = self
+                    .rule_sockets
+                    .iter()
+                    .any(|(j, o)| *j == i && o.bind_address.is_some());
+                let key = if own {
+                    format!("remoteMap[{}].upstream_socket.bind_address", i)
+                } else {
+                    "upstream_socket.bind_address".to_string()
+                };
+                check(
+                    key,
+                    Err(format!("{} can't send to {}, another address family", source, remote)),
+                );
+            }
+        }
+        if self.udp_idle_timeout.is_zero() {
+            check("udp_idle_timeout".to_string(), Err("must not be 0".to_string()));
+        }
+        for (i, timeout) in &self.rule_idle_timeouts {
+            if timeout.is_zero() {
+                check(
+                    format!("remoteMap[{}].udp_idle_timeout", i),
+                    Err("must not be 0".to_string()),
+                );
+            }
+        }
+        let queue = &self.udp_queue;
+        for (key, limit) in [
+            ("session_packets", queue.session_packets),
+            ("session_bytes", queue.session_bytes),
+            ("packets", queue.packets),
+            ("bytes", queue.bytes),
+        ] {
+            if limit == 0 {
+                check(format!("udp_queue.{}", key), Err("must not be 0".to_string()));
+            }
+        }
+        self.listen_socket.validate("listen_socket", true, &mut problems);
+        self.upstream_socket.validate("upstream_socket", false, &mut problems);
+        for (i, options) in &self.rule_sockets {
+            let key = format!("remoteMap[{}].upstream_socket", i);
+            options.validate(&key, false, &mut problems);
+        }
+        problems
+    }
+}
+
+/// A problem in the configuration, located by forwarder index and key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigError {
+    /// Index in `forwarders`, `None` for top-level keys.
+    pub forwarder: Option<usize>,
+    pub key: String,
+    pub message: String,
+    /// The file and line the value was read from, when known.
+    pub file: Option<String>,
+    pub line: Option<usize>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: ", file, line)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            (None, Some(line)) => write!(f, "line {}: ", line)?,
+            (None, None) => {}
+        }
+        match self.forwarder {
+            Some(i) => write!(f, "forwarders[{}].{}: {}", i, self.key, self.message),
+            None if self.key.is_empty() => write!(f, "{}", self.message),
+            None => write!(f, "{}: {}", self.key, self.message),
+        }
+    }
+}
+
+/// Whether listening on `a` and `b` conflicts, `0.0.0.0` and `[::]` take the
+/// whole port unless `*_v6only` tell that an IPv6 address leaves IPv4 alone.
+fn overlaps(a: &SocketAddr, a_v6only: bool, b: &SocketAddr, b_v6only: bool) -> bool {
+    let covers = |x: &SocketAddr, x_v6only: bool, y: &SocketAddr| {
+        x.ip().is_unspecified() && (x.is_ipv4() == y.is_ipv4() || (x.is_ipv6() && !x_v6only))
+    };
+    a.port() == b.port() && (a.ip() == b.ip() || covers(a, a_v6only, b) || covers(b, b_v6only, a))
+}
+
+/// Most listeners one forwarder definition may expand to, each of them runs
+/// its own TCP and UDP loop.
+pub const MAX_LISTENERS: usize = 1024;
+
+/// The addresses of `local`, which is `host:port`, `host:first-last`, `:port`
+/// for every IPv4 interface or a `unix:` socket.
+pub fn expand_local(local: &str) -> Result<Vec<String>, String> {
+    if unix_name(local).is_some() {
+        return Ok(vec![local.to_string()]);
+    }
+    let (host, ports) = local
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expect <host>:<port>, got '{}'", local))?;
+    let host = if host.is_empty() { "0.0.0.0" } else { host };
+    let port = |p: &str| {
+        p.parse::<u16>()
+            .map_err(|_| format!("invalid port '{}' in '{}'", p, local))
+    };
+    let (first, last) = match ports.split_once('-') {
+        Some((first, last)) => (port(first)?, port(last)?),
+        None => (port(ports)?, port(ports)?),
+    };
+    if first > last {
+        return Err(format!("empty port range {}", ports));
+    }
+    if usize::from(last - first) >= MAX_LISTENERS {
+        return Err(format!(
+            "port range {} has more than {} ports",
+            ports, MAX_LISTENERS
+        ));
+    }
+    Ok((first..=last).map(|p| format!("{}:{}", host, p)).collect())
+}
+
+/// `text` with `${port}`, `${port+N}` and `${port-N}` replaced by `port` and
+/// its offsets, so that a range of listeners maps to a range of remotes.
+pub fn substitute_port(text: &str, port: u16) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(pos) = rest.find("${port") {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => return Err(format!("unclosed '${{' in '{}'", text)),
+        };
+        let expr = &rest["${port".len()..end];
+        let offset = match expr.chars().next() {
+            None => Some(0),
+            Some('+') | Some('-') => expr[1..]
+                .parse::<i64>()
+                .ok()
+                .map(|n| if expr.starts_with('-') { -n } else { n }),
+            // `${portal}` and the like are not ours
+            Some(_) => {
+                out.push_str("${port");
+                rest = &rest["${port".len()..];
+                continue;
+            }
+        };
+        let value = match offset {
+            Some(offset) => i64::from(port) + offset,
+            None => return Err(format!("invalid port offset in '${{port{}}}'", expr)),
+        };
+        if !(1..=65535).contains(&value) {
+            return Err(format!(
+                "'${{port{}}}' is {} for port {}, not a port",
+                expr, value, port
+            ));
+        }
+        out.push_str(&value.to_string());
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Validate forwarders given with their index, and that no two of them listen
+/// on the same address with the same protocol.
+pub fn validate_forwarders<'a, T: ToEndpoint + 'a>(
+    configs: impl IntoIterator<Item = (usize, &'a ForwardSessionConfig<T>)>,
+    upstreams: &[UpstreamConfig],
+) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    let mut listening: Vec<(usize, Endpoint, bool, bool, bool)> = vec![];
+    for (i, config) in configs {
+        for (key, message) in config.validate(upstreams) {
+            errors.push(ConfigError {
+                forwarder: Some(i),
+                key,
+                message,
+                ..Default::default()
+            });
+        }
+        let local = match config.local.to_endpoint() {
+            Ok(addr) => addr,
+            Err(_) => continue,
+        };
+        for (j, other, tcp, udp, v6only) in &listening {
+            let protocol = if config.enable_tcp && *tcp {
+                "tcp"
+            } else if config.enable_udp && *udp {
+                "udp"
+            } else {
+                continue;
+            };
+            let conflict = match (&local, other) {
+                (Endpoint::Inet(a), Endpoint::Inet(b)) => {
+                    overlaps(a, config.ipv6_only, b, *v6only)
+                }
+                (a, b) => a == b,
+            };
+            if conflict {
+                errors.push(ConfigError {
+                    forwarder: Some(i),
+                    key: "local".to_string(),
+                    message: format!(
+                        "{} {} conflicts with forwarders[{}] on {}",
+                        protocol, local, j, other
+                    ),
+                    ..Default::default()
+                });
+            }
+        }
+        listening.push((
+            i,
+            local,
+            config.enable_tcp,
+            config.enable_udp,
+            config.ipv6_only,
+        ));
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let configs = [
+            ForwardSessionConfig {
+                local: "127.0.0.1:8808",
+                remoteMap: vec![
+                    ("(unclosed".to_string(), "127.0.0.1:22".to_string()),
+                    ("[ssh]".to_string(), "nowhere.invalid:22".to_string()),
+                ],
+                allow_nets: vec!["10.0.0.0/33".to_string()],
+                ..Default::default()
+            },
+            ForwardSessionConfig {
+                local: "0.0.0.0:8808",
+                remoteMap: vec![(".*".to_string(), "127.0.0.1:22".to_string())],
+                enable_tcp: false,
+                ..Default::default()
+            },
+            ForwardSessionConfig {
+                local: "127.0.0.1:8809",
+                tcp_mode: TcpMode::Socks5Server,
+                enable_udp: false,
+                ..Default::default()
+            },
+        ];
+        let errors: Vec<String> = validate_forwarders(configs.iter().enumerate(), &[])
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert!(errors[0].starts_with("forwarders[0].remoteMap[0].pattern: invalid regex"));
+        assert!(errors[1].starts_with("forwarders[0].remoteMap[1].remote: nowhere.invalid:22: can't resolve"));
+        assert_eq!(
+            errors[2],
+            "forwarders[0].allow_nets[0]: invalid network '10.0.0.0/33'"
+        );
+        assert_eq!(
+            errors[3],
+            "forwarders[1].local: udp 0.0.0.0:8808 conflicts with forwarders[0] on 127.0.0.1:8808"
+        );
+    }
+
+    #[test]
+    fn source_address_family_must_reach_the_remote() {
+        let config = ForwardSessionConfig {
+            local: "127.0.0.1:8808",
+            remoteMap: vec![
+                ("[ssh]".to_string(), "[::1]:22".to_string()),
+                (".*".to_string(), "127.0.0.1:80".to_string()),
+            ],
+            upstream_socket: SocketConfig {
+                bind_address: Some("127.0.0.2".parse().unwrap()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(&[]),
+            vec![(
+                "upstream_socket.bind_address".to_string(),
+                "127.0.0.2 can't send to [::1]:22, another address family".to_string()
+            )]
+        );
+
+        let over = SocketConfig {
+            bind_address: Some("::1".parse().unwrap()),
+            ..Default::default()
+        };
+        let config = ForwardSessionConfig {
+            rule_sockets: vec![(0, over)],
+            ..config
+        };
+        assert!(config.validate(&[]).is_empty());
+    }
+
+    #[test]
+    fn port_ranges() {
+        assert_eq!(
+            expand_local(":8808-8810").unwrap(),
+            vec!["0.0.0.0:8808", "0.0.0.0:8809", "0.0.0.0:8810"]
+        );
+        assert_eq!(expand_local("[::1]:53").unwrap(), vec!["[::1]:53"]);
+        assert_eq!(expand_local(":9-8").unwrap_err(), "empty port range 9-8");
+        assert_eq!(expand_local(":10000-10100").unwrap().len(), 101);
+        assert!(expand_local(":1-1024").is_ok());
+        assert!(expand_local(":1-1025").is_err());
+        assert!(expand_local("localhost").is_err());
+
+        assert_eq!(
+            substitute_port("10.0.0.1:${port+100}", 8808).unwrap(),
+            "10.0.0.1:8908"
+        );
+        assert_eq!(substitute_port("/tmp/${port}.pcap", 53).unwrap(), "/tmp/53.pcap");
+        assert_eq!(substitute_port("${portal}", 53).unwrap(), "${portal}");
+        assert!(substitute_port("host:${port-100}", 53).is_err());
+        assert!(substitute_port("host:${port+x}", 53).is_err());
+
+        let v4: SocketAddr = "0.0.0.0:53".parse().unwrap();
+        let v6: SocketAddr = "[::]:53".parse().unwrap();
+        assert!(overlaps(&v4, false, &v6, false));
+        assert!(!overlaps(&v4, false, &v6, true));
+    }
 }
@@ -1,9 +1,21 @@
 #![allow(non_snake_case)]
 
-mod address_matcher;
+pub mod access_log;
+pub mod address_matcher;
+pub mod admin;
+pub mod capture;
+pub mod config;
 pub mod connection_plugin;
+pub mod endpoint;
 pub mod forward_config;
+pub mod metrics;
+pub mod rule_tester;
+pub mod socket_options;
+pub mod systemd;
 pub mod tcp_forwarder;
 pub mod tcp_udp_forwarder;
+pub mod udp_batch;
 pub mod udp_forwarder;
+pub mod upstream;
+pub mod unmatched_log;
 mod utils;
@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+pub const CONNECTIONS_ACTIVE: &str = "portfd_connections_active";
+pub const CONNECTIONS_TOTAL: &str = "portfd_connections_total";
+pub const CONNECT_FAILURES_TOTAL: &str = "portfd_connect_failures_total";
+pub const CONNECT_DURATION_SECONDS: &str = "portfd_connect_duration_seconds";
+pub const BYTES_IN_TOTAL: &str = "portfd_bytes_in_total";
+pub const BYTES_OUT_TOTAL: &str = "portfd_bytes_out_total";
+pub const UDP_SESSIONS_ACTIVE: &str = "portfd_udp_sessions_active";
+pub const UDP_SESSIONS_TOTAL: &str = "portfd_udp_sessions_total";
+pub const UDP_DROPPED_TOTAL: &str = "portfd_udp_dropped_total";
+pub const UDP_DROPPED_BYTES_TOTAL: &str = "portfd_udp_dropped_bytes_total";
+pub const SNIFF_TOTAL: &str = "portfd_sniff_total";
+pub const UPSTREAM_AVAILABLE: &str = "portfd_upstream_available";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+const FAMILIES: &[(&str, Kind, &str)] = &[
+    (
+        CONNECTIONS_ACTIVE,
+        Kind::Gauge,
+        "TCP connections currently relayed to an upstream.",
+    ),
+    (
+        CONNECTIONS_TOTAL,
+        Kind::Counter,
+        "TCP connections relayed to an upstream.",
+    ),
+    (
+        CONNECT_FAILURES_TOTAL,
+        Kind::Counter,
+        "Failed connection attempts to an upstream.",
+    ),
+    (
+        CONNECT_DURATION_SECONDS,
+        Kind::Histogram,
+        "Time to establish a TCP connection to an upstream.",
+    ),
+    (
+        BYTES_IN_TOTAL,
+        Kind::Counter,
+        "Bytes received from clients.",
+    ),
+    (BYTES_OUT_TOTAL, Kind::Counter, "Bytes sent to clients."),
+    (
+        UDP_SESSIONS_ACTIVE,
+        Kind::Gauge,
+        "UDP sessions currently alive.",
+    ),
+    (
+        UDP_SESSIONS_TOTAL,
+        Kind::Counter,
+        "UDP sessions forwarded to an upstream.",
+    ),
+    (
+        UDP_DROPPED_TOTAL,
+        Kind::Counter,
+        "Datagrams dropped because a UDP queue was full.",
+    ),
+    (
+        UDP_DROPPED_BYTES_TOTAL,
+        Kind::Counter,
+        "Bytes of datagrams dropped because a UDP queue was full.",
+    ),
+    (
+        SNIFF_TOTAL,
+        Kind::Counter,
+        "First packets classified by the routing rules.",
+    ),
+    (
+        UPSTREAM_AVAILABLE,
+        Kind::Gauge,
+        "Servers of an upstream group currently picked for new connections.",
+    ),
+];
+
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// One recorded series; handles share it so updates never take the registry lock.
+enum Value {
+    Scalar(Arc<AtomicI64>),
+    Histogram(Arc<HistogramCell>),
+}
+
+struct HistogramCell {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_ns: AtomicU64,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn registry() -> &'static Mutex<BTreeMap<(&'static str, String), Value>> {
+    static REGISTRY: OnceLock<Mutex<BTreeMap<(&'static str, String), Value>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// Whether metrics are collected, recording is a no-op until `enable` is called.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    for (i, (k, v)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let v = v
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(out, "{}=\"{}\"", k, v);
+    }
+    out
+}
+
+/// A counter or gauge series, looked up once and then updated lock free.
+#[derive(Clone, Default)]
+pub struct Series(Option<Arc<AtomicI64>>);
+
+impl Series {
+    pub fn add(&self, delta: i64) {
+        if let Some(v) = &self.0 {
+            v.fetch_add(delta, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A histogram series, looked up once and then updated lock free.
+#[derive(Clone, Default)]
+pub struct Histogram(Option<Arc<HistogramCell>>);
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        if let Some(h) = &self.0 {
+            let secs = d.as_secs_f64();
+            for (i, le) in BUCKETS.iter().enumerate() {
+                if secs <= *le {
+                    h.buckets[i].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            h.count.fetch_add(1, Ordering::Relaxed);
+            h.sum_ns.fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The counter or gauge `name` with `labels`, a no-op handle while metrics are disabled.
+pub fn series(name: &'static str, labels: &[(&str, &str)]) -> Series {
+    if !enabled() {
+        return Series(None);
+    }
+    let mut reg = registry().lock().unwrap();
+    let value = reg
+        .entry((name, render_labels(labels)))
+        .or_insert_with(|| Value::Scalar(Arc::default()));
+    match value {
+        Value::Scalar(v) => Series(Some(v.clone())),
+        Value::Histogram(_) => Series(None),
+    }
+}
+
+/// The histogram `name` with `labels`, a no-op handle while metrics are disabled.
+pub fn histogram(name: &'static str, labels: &[(&str, &str)]) -> Histogram {
+    if !enabled() {
+        return Histogram(None);
+    }
+    let mut reg = registry().lock().unwrap();
+    let value = reg
+        .entry((name, render_labels(labels)))
+        .or_insert_with(|| {
+            Value::Histogram(Arc::new(HistogramCell {
+                buckets: Default::default(),
+                count: AtomicU64::new(0),
+                sum_ns: AtomicU64::new(0),
+            }))
+        });
+    match value {
+        Value::Histogram(h) => Histogram(Some(h.clone())),
+        Value::Scalar(_) => Histogram(None),
+    }
+}
+
+/// Add to a counter once, use a `series` handle where it is updated often.
+pub fn counter_add(name: &'static str, labels: &[(&str, &str)], v: u64) {
+    series(name, labels).add(v as i64);
+}
+
+pub fn gauge_add(name: &'static str, labels: &[(&str, &str)], delta: i64) {
+    series(name, labels).add(delta);
+}
+
+pub fn observe(name: &'static str, labels: &[(&str, &str)], d: Duration) {
+    histogram(name, labels).observe(d);
+}
+
+/// Render every recorded series in the Prometheus text exposition format.
+pub fn render() -> String {
+    let reg = registry().lock().unwrap();
+    let mut out = String::new();
+    for (name, kind, help) in FAMILIES {
+        let kind_name = match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        };
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind_name);
+        for ((_, labels), value) in reg.iter().filter(|((n, _), _)| n == name) {
+            match value {
+                Value::Scalar(v) => {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, v.load(Ordering::Relaxed));
+                }
+                Value::Histogram(h) => {
+                    let count = h.count.load(Ordering::Relaxed);
+                    let sum = h.sum_ns.load(Ordering::Relaxed) as f64 / 1e9;
+                    let sep = if labels.is_empty() { "" } else { "," };
+                    for (i, le) in BUCKETS.iter().enumerate() {
+                        let _ = writeln!(
+                            out,
+                            "{}_bucket{{{}{}le=\"{}\"}} {}",
+                            name, labels, sep, le, h.buckets[i].load(Ordering::Relaxed)
+                        );
+                    }
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+                        name, labels, sep, count
+                    );
+                    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+                    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+                }
+            }
+        }
+    }
+    out
+}
+
+fn respond(mut stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+    let mut buf = [0u8; 4096];
+    let n = match stream.read(&mut buf) {
+        Ok(n) => n,
+        Err(_) => return,
+    };
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let response = if request.starts_with("GET ") && path == "/metrics" {
+        let body = render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    let _ = stream.write_all(response.as_bytes());
+}
+
+/// Enable collection and serve `GET /metrics` on `addr` from a background
+/// thread, each scrape on its own thread so a slow client delays no other.
+pub fn serve<T: ToSocketAddrs>(addr: T) -> io::Result<std::thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!("serve metrics at http://{}/metrics", listener.local_addr()?);
+    enable();
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            std::thread::spawn(move || respond(stream));
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_text_format() {
+        enable();
+        let labels = [("forwarder", "127.0.0.1:1"), ("upstream", "a\"b")];
+        counter_add(CONNECT_FAILURES_TOTAL, &labels, 2);
+        counter_add(CONNECT_FAILURES_TOTAL, &labels, 1);
+        observe(CONNECT_DURATION_SECONDS, &labels, Duration::from_millis(30));
+        let text = render();
+        assert!(text.contains("# TYPE portfd_connect_failures_total counter\n"));
+        assert!(text.contains(
+            "portfd_connect_failures_total{forwarder=\"127.0.0.1:1\",upstream=\"a\\\"b\"} 3\n"
+        ));
+        assert!(text.contains(
+            "portfd_connect_duration_seconds_bucket{forwarder=\"127.0.0.1:1\",upstream=\"a\\\"b\",le=\"0.025\"} 0\n"
+        ));
+        assert!(text.contains(
+            "portfd_connect_duration_seconds_bucket{forwarder=\"127.0.0.1:1\",upstream=\"a\\\"b\",le=\"0.05\"} 1\n"
+        ));
+        assert!(text.contains(
+            "portfd_connect_duration_seconds_count{forwarder=\"127.0.0.1:1\",upstream=\"a\\\"b\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn handles_share_a_series() {
+        enable();
+        let labels = [("forwarder", "127.0.0.1:2")];
+        let a = series(UDP_SESSIONS_ACTIVE, &labels);
+        let b = series(UDP_SESSIONS_ACTIVE, &labels);
+        a.add(2);
+        b.add(-1);
+        assert!(render().contains("portfd_udp_sessions_active{forwarder=\"127.0.0.1:2\"} 1\n"));
+    }
+}
@@ -0,0 +1,430 @@
+use crate::address_matcher::IpAddrMatcher;
+use crate::connection_plugin::{ConnectionPlugin, RegexMultiplexer};
+use crate::endpoint::ToEndpoint;
+use crate::forward_config::{ForwardSessionConfig, TcpMode};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// A first packet to classify and where it came from.
+pub struct Payload {
+    pub source: String,
+    pub data: Vec<u8>,
+}
+
+/// Hex digits, whitespace and `0x`/`\x` prefixes are ignored.
+pub fn from_hex(source: &str, text: &str) -> Result<Payload, String> {
+    let digits: String = text
+        .replace("0x", "")
+        .replace("\\x", "")
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    match hex::decode(&digits) {
+        Ok(data) => Ok(Payload {
+            source: source.to_string(),
+            data,
+        }),
+        Err(e) => Err(format!("invalid hex payload '{}': {}", text, e)),
+    }
+}
+
+fn u16_at(buf: &[u8], at: usize, le: bool) -> Option<u16> {
+    let b = [*buf.get(at)?, *buf.get(at + 1)?];
+    Some(if le {
+        u16::from_le_bytes(b)
+    } else {
+        u16::from_be_bytes(b)
+    })
+}
+
+fn u32_at(buf: &[u8], at: usize, le: bool) -> Option<u32> {
+    let b = [
+        *buf.get(at)?,
+        *buf.get(at + 1)?,
+        *buf.get(at + 2)?,
+        *buf.get(at + 3)?,
+    ];
+    Some(if le {
+        u32::from_le_bytes(b)
+    } else {
+        u32::from_be_bytes(b)
+    })
+}
+
+/// Link-layer frames of a pcap or pcapng file, with their link type.
+fn read_frames(file: &[u8]) -> Result<Vec<(u16, &[u8])>, String> {
+    let truncated = || "truncated capture file".to_string();
+    let mut frames = vec![];
+    match u32_at(file, 0, true) {
+        Some(0xa1b2_c3d4) | Some(0xa1b2_3c4d) | Some(0xd4c3_b2a1) | Some(0x4d3c_b2a1) => {
+            let le = matches!(u32_at(file, 0, true), Some(0xa1b2_c3d4) | Some(0xa1b2_3c4d));
+            let linktype = u32_at(file, 20, le).ok_or_else(truncated)? as u16;
+            let mut offset = 24;
+            while offset + 16 <= file.len() {
+                let caplen = u32_at(file, offset + 8, le).ok_or_else(truncated)? as usize;
+                let data = file
+                    .get(offset + 16..offset + 16 + caplen)
+                    .ok_or_else(truncated)?;
+                frames.push((linktype, data));
+                offset += 16 + caplen;
+            }
+        }
+        Some(0x0a0d_0d0a) => {
+            let mut le = true;
+            let mut interfaces: Vec<u16> = vec![];
+            let mut offset = 0;
+            while offset + 12 <= file.len() {
+                let kind = u32_at(file, offset, le).ok_or_else(truncated)?;
+                if kind == 0x0a0d_0d0a {
+                    le = u32_at(file, offset + 8, true) == Some(0x1a2b_3c4d);
+                    interfaces.clear();
+                }
+                let len = u32_at(file, offset + 4, le).ok_or_else(truncated)? as usize;
+                if len < 12 {
+                    return Err(format!("invalid pcapng block length {}", len));
+                }
+                let body = file
+                    .get(offset + 8..offset + len - 4)
+                    .ok_or_else(truncated)?;
+                match kind {
+                    1 => interfaces.push(u16_at(body, 0, le).ok_or_else(truncated)?),
+                    6 => {
+                        let interface = u32_at(body, 0, le).ok_or_else(truncated)? as usize;
+                        let caplen = u32_at(body, 12, le).ok_or_else(truncated)? as usize;
+                        let data = body.get(20..20 + caplen).ok_or_else(truncated)?;
+                        match interfaces.get(interface) {
+                            Some(linktype) => frames.push((*linktype, data)),
+                            None => return Err(format!("unknown pcapng interface {}", interface)),
+                        }
+                    }
+                    3 => {
+                        let origlen = u32_at(body, 0, le).ok_or_else(truncated)? as usize;
+                        let data = body
+                            .get(4..body.len().min(4 + origlen))
+                            .ok_or_else(truncated)?;
+                        frames.push((*interfaces.first().ok_or_else(truncated)?, data));
+                    }
+                    _ => {}
+                }
+                offset += len;
+            }
+        }
+        _ => return Err("not a pcap or pcapng file".to_string()),
+    }
+    Ok(frames)
+}
+
+/// The IP packet inside a link-layer frame.
+fn ip_packet(linktype: u16, frame: &[u8]) -> Option<&[u8]> {
+    match linktype {
+        // BSD loopback, the family is in host byte order
+        0 => frame.get(4..),
+        1 => {
+            let mut offset = 12;
+            let mut ethertype = u16_at(frame, offset, false)?;
+            while ethertype == 0x8100 || ethertype == 0x88a8 {
+                offset += 4;
+                ethertype = u16_at(frame, offset, false)?;
+            }
+            frame.get(offset + 2..)
+        }
+        101 | 228 | 229 => Some(frame),
+        113 => frame.get(16..),
+        276 => frame.get(20..),
+        _ => None,
+    }
+}
+
+struct Segment<'a> {
+    udp: bool,
+    src: SocketAddr,
+    dst: SocketAddr,
+    /// A TCP SYN without ACK, which tells who the client is.
+    syn: bool,
+    payload: &'a [u8],
+}
+
+fn segment(packet: &[u8]) -> Option<Segment<'_>> {
+    let (proto, src, dst, l4) = match packet.first()? >> 4 {
+        4 => {
+            let ihl = ((packet[0] & 0x0f) as usize) * 4;
+            let total = (u16_at(packet, 2, false)? as usize).min(packet.len());
+            // only the first fragment carries the transport header
+            if u16_at(packet, 6, false)? & 0x1fff != 0 {
+                return None;
+            }
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                packet[9],
+                IpAddr::from(Ipv4Addr::from(src)),
+                IpAddr::from(Ipv4Addr::from(dst)),
+                packet.get(ihl..total)?,
+            )
+        }
+        6 => {
+            let total = (40 + u16_at(packet, 4, false)? as usize).min(packet.len());
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                packet[6],
+                IpAddr::from(Ipv6Addr::from(src)),
+                IpAddr::from(Ipv6Addr::from(dst)),
+                packet.get(40..total)?,
+            )
+        }
+        _ => return None,
+    };
+    let sport = u16_at(l4, 0, false)?;
+    let dport = u16_at(l4, 2, false)?;
+    let (udp, syn, payload) = match proto {
+        6 => {
+            let offset = ((*l4.get(12)? >> 4) as usize) * 4;
+            let flags = *l4.get(13)?;
+            (false, flags & 0x12 == 0x02, l4.get(offset..)?)
+        }
+        17 => (true, false, l4.get(8..)?),
+        _ => return None,
+    };
+    Some(Segment {
+        udp,
+        src: SocketAddr::new(src, sport),
+        dst: SocketAddr::new(dst, dport),
+        syn,
+        payload,
+    })
+}
+
+/// The first payload the client sent in every TCP connection and UDP flow of a
+/// pcap or pcapng file. The client of a TCP connection is the side that sent the
+/// SYN, or the first one to send data if the handshake was not captured.
+pub fn from_pcap(name: &str, file: &[u8]) -> Result<Vec<Payload>, String> {
+    let mut flows: HashMap<(bool, SocketAddr, SocketAddr), (Option<SocketAddr>, bool)> =
+        HashMap::new();
+    let mut payloads = vec![];
+    for (index, (linktype, frame)) in read_frames(file)?.into_iter().enumerate() {
+        let seg = match ip_packet(linktype, frame).and_then(segment) {
+            Some(seg) => seg,
+            None => continue,
+        };
+        let key = (seg.udp, seg.src.min(seg.dst), seg.src.max(seg.dst));
+        let (client, done) = flows.entry(key).or_insert((None, false));
+        if seg.syn {
+            *client = Some(seg.src);
+        }
+        if *done || seg.payload.is_empty() || client.is_some_and(|c| c != seg.src) {
+            continue;
+        }
+        *done = true;
+        payloads.push(Payload {
+            source: format!(
+                "{} #{} {} {} -> {}",
+                name,
+                index + 1,
+                if seg.udp { "udp" } else { "tcp" },
+                seg.src,
+                seg.dst
+            ),
+            data: seg.payload.to_vec(),
+        });
+    }
+    Ok(payloads)
+}
+
+fn shorten(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        text.to_string()
+    } else {
+        text.chars().take(max).collect::<String>() + "..."
+    }
+}
+
+/// Which rule of which forwarder every payload matches, and why the rules before
+/// it do not. Matching is done by the same `RegexMultiplexer` the forwarders use.
+pub fn report<T: ToEndpoint>(
+    configs: &[ForwardSessionConfig<T>],
+    payloads: &[Payload],
+) -> String {
+    let locals: Vec<String> = configs
+        .iter()
+        .map(
+            |c| match c.local.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+                Some(addr) => addr.to_string(),
+                None => "?".to_string(),
+            },
+        )
+        .collect();
+    let plugins: Vec<Result<RegexMultiplexer, String>> = configs
+        .iter()
+        .map(|c| match c.tcp_mode {
+            TcpMode::Forward => IpAddrMatcher::from(&[])
+                .and_then(|ip_matcher| RegexMultiplexer::new(c.remoteMap.clone(), ip_matcher)),
+            TcpMode::Socks5Server => Err("proxy mode, no rules".to_string()),
+        })
+        .collect();
+    let anyone: SocketAddr = (Ipv4Addr::UNSPECIFIED, 0).into();
+
+    let mut out = String::new();
+    for payload in payloads {
+        let _ = writeln!(out, "{} ({} bytes)", payload.source, payload.data.len());
+        let _ = writeln!(out, "  hex:  {}", shorten(&hex::encode(&payload.data), 96));
+        let _ = writeln!(
+            out,
+            "  text: {:?}",
+            shorten(&String::from_utf8_lossy(&payload.data), 64)
+        );
+        for (i, plugin) in plugins.iter().enumerate() {
+            let plugin = match plugin {
+                Ok(p) => p,
+                Err(e) => {
+                    let _ = writeln!(out, "  forwarder {} {}: {}", i, locals[i], e);
+                    continue;
+                }
+            };
+            let matched = plugin.matchRule(&payload.data, anyone, &|_| true);
+            let tried = match matched {
+                Some((index, target)) => {
+                    let _ = writeln!(
+                        out,
+                        "  forwarder {} {}: rule {} {:?} -> {}",
+                        i,
+                        locals[i],
+                        index,
+                        plugin.rulePattern(index).unwrap_or(""),
+                        target
+                    );
+                    index
+                }
+                None => {
+                    let _ = writeln!(out, "  forwarder {} {}: no rule matches", i, locals[i]);
+                    plugin.ruleCount()
+                }
+            };
+            for index in 0..tried {
+                let _ = writeln!(
+                    out,
+                    "    rule {} {:?}: {}",
+                    index,
+                    plugin.rulePattern(index).unwrap_or(""),
+                    plugin
+                        .explainMismatch(index, &payload.data)
+                        .unwrap_or_default()
+                );
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Capture;
+    use crate::forward_config::CaptureConfig;
+
+    #[test]
+    fn payloads_from_own_capture() {
+        let path = std::env::temp_dir().join(format!("portfd-rules-{}.pcapng", std::process::id()));
+        let capture = Capture::from(&CaptureConfig {
+            file: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let client: SocketAddr = "10.0.0.7:51234".parse().unwrap();
+        let upstream: SocketAddr = "[2001:db8::1]:22".parse().unwrap();
+        {
+            let mut tcp = capture.tcp(&client.into(), &upstream.into());
+            tcp.upstream_data(b"SSH-2.0-server\r\n");
+            tcp.client_data(b"SSH-2.0-client\r\n");
+            tcp.client_data(b"more");
+        }
+        capture.udp(client, "10.0.0.1:53".parse().unwrap(), b"\x12\x34query");
+        capture.udp("10.0.0.1:53".parse().unwrap(), client, b"\x12\x34answer");
+        drop(capture);
+
+        let file = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let payloads = from_pcap("x.pcapng", &file).unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].data, b"SSH-2.0-client\r\n");
+        assert!(
+            payloads[0]
+                .source
+                .contains("tcp [::ffff:10.0.0.7]:51234 -> [2001:db8::1]:22")
+        );
+        assert_eq!(payloads[1].data, b"\x12\x34query");
+        assert!(
+            payloads[1]
+                .source
+                .ends_with("udp 10.0.0.7:51234 -> 10.0.0.1:53")
+        );
+    }
+
+    #[test]
+    fn classic_pcap_with_ethernet() {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        let mut ip = vec![
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1,
+        ];
+        ip.extend_from_slice(&[0x30, 0x39, 0, 53, 0, 12, 0, 0]);
+        ip.extend_from_slice(b"ping");
+        let total = ip.len() as u16;
+        ip[2..4].copy_from_slice(&total.to_be_bytes());
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&[0; 6]); // ethernet padding
+
+        let mut file = vec![];
+        file.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        file.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0]);
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        file.extend_from_slice(&frame);
+
+        let payloads = from_pcap("x.pcap", &file).unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].data, b"ping");
+        assert!(from_pcap("x", b"garbage!").is_err());
+    }
+
+    #[test]
+    fn report_explains_earlier_rules() {
+        let config = ForwardSessionConfig {
+            local: "127.0.0.1:8808",
+            remoteMap: vec![
+                ("[ssh]".to_string(), "127.0.0.1:22".to_string()),
+                (
+                    "[https:example.com]".to_string(),
+                    "127.0.0.1:443".to_string(),
+                ),
+                ("^47455420".to_string(), "127.0.0.1:80".to_string()),
+            ],
+            ..Default::default()
+        };
+        let payloads = vec![
+            Payload {
+                source: "get".to_string(),
+                data: b"GET /index.html HTTP/1.1\r\n".to_vec(),
+            },
+            from_hex(
+                "tls",
+                "0x16 0x03 0x01 0x00 0x0c \\x01 00 00 08 00 00 00 00 00 00 00 00",
+            )
+            .unwrap(),
+            from_hex("short", "05").unwrap(),
+        ];
+        let text = report(&[config], &payloads);
+        assert!(text.contains("forwarder 0 127.0.0.1:8808: rule 2 \"^47455420\" -> 127.0.0.1:80"));
+        assert!(text.contains("rule 0 \"[ssh]\": regex '^SSH-2\\.0-.+' matches neither"));
+        assert!(text.contains("rule 1 \"[https:example.com]\": starts with 474554"));
+        assert!(text.contains("tls (17 bytes)"));
+        assert!(text.contains("server name 'example.com' not found"));
+        assert!(text.contains("forwarder 0 127.0.0.1:8808: no rule matches"));
+        assert!(text.contains("too short to carry the server name"));
+        assert!(from_hex("bad", "zz").is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Applying `SocketConfig` to listening, accepted and upstream sockets.
+use crate::forward_config::SocketConfig;
+use mio::net::{TcpStream, UdpSocket};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Connections a listener with `fast_open` queues before the handshake is done.
+#[cfg(target_os = "linux")]
+const FAST_OPEN_QUEUE: libc::c_int = 256;
+
+#[cfg(target_os = "linux")]
+fn set_int(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if r == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// `IP_TOS`, or `IPV6_TCLASS` where IPv6 has it.
+fn set_tos(socket: &Socket, tos: u8, ipv6: bool) -> io::Result<()> {
+    if !ipv6 {
+        return socket.set_tos_v4(tos.into());
+    }
+    #[cfg(unix)]
+    socket.set_tclass_v6(tos.into())?;
+    Ok(())
+}
+
+/// The options of any socket: interface, mark, TOS and buffer sizes.
+fn apply(socket: &Socket, options: &SocketConfig, ipv6: bool) -> io::Result<()> {
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(tos) = options.tos {
+        set_tos(socket, tos, ipv6)?;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(name) = &options.interface {
+            socket.bind_device(Some(name.as_bytes()))?;
+        }
+        if let Some(mark) = options.fwmark {
+            socket.set_mark(mark)?;
+        }
+    }
+    Ok(())
+}
+
+/// Nagle, keepalive and the user timeout of a TCP socket.
+fn apply_tcp(socket: &Socket, options: &SocketConfig) -> io::Result<()> {
+    if let Some(nodelay) = options.nodelay {
+        socket.set_tcp_nodelay(nodelay)?;
+    }
+    if options.keepalive.is_some()
+        || options.keepalive_interval.is_some()
+        || options.keepalive_count.is_some()
+    {
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(time) = options.keepalive {
+            keepalive = keepalive.with_time(time);
+        }
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            if let Some(interval) = options.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            if let Some(count) = options.keepalive_count {
+                keepalive = keepalive.with_retries(count);
+            }
+        }
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(timeout) = options.user_timeout {
+        socket.set_tcp_user_timeout(Some(timeout))?;
+    }
+    Ok(())
+}
+
+/// Set up a TCP listener before it is bound, accepted connections take most
+/// options over from it.
+pub fn tcp_listener(socket: &Socket, options: &SocketConfig, ipv6: bool) -> io::Result<()> {
+    apply(socket, options, ipv6)?;
+    apply_tcp(socket, options)?;
+    #[cfg(target_os = "linux")]
+    if options.fast_open == Some(true) {
+        set_int(
+            socket,
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            FAST_OPEN_QUEUE,
+        )?;
+    }
+    Ok(())
+}
+
+/// Set up a UDP listener before it is bound.
+pub fn udp_listener(socket: &Socket, options: &SocketConfig, ipv6: bool) -> io::Result<()> {
+    apply(socket, options, ipv6)
+}
+
+/// Set the options an accepted connection may not have taken over from the listener.
+pub fn accepted(stream: &TcpStream, options: &SocketConfig) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    if let Some(tos) = options.tos {
+        set_tos(&socket, tos, stream.local_addr()?.is_ipv6())?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(mark) = options.fwmark {
+        socket.set_mark(mark)?;
+    }
+    apply_tcp(&socket, options)
+}
+
+/// Start connecting to `addr` without blocking, like `TcpStream::connect`.
+pub fn connect(addr: SocketAddr, options: &SocketConfig) -> io::Result<TcpStream> {
+    if *options == SocketConfig::default() {
+        return TcpStream::connect(addr);
+    }
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    apply(&socket, options, addr.is_ipv6())?;
+    apply_tcp(&socket, options)?;
+    if let Some(ip) = options.bind_address {
+        // the port is picked at connect, so that many upstreams can share it
+        #[cfg(target_os = "linux")]
+        let _ = set_int(&socket, libc::IPPROTO_IP, libc::IP_BIND_ADDRESS_NO_PORT, 1);
+        socket.bind(&SocketAddr::new(ip, 0).into())?;
+    }
+    #[cfg(target_os = "linux")]
+    if options.fast_open == Some(true) {
+        set_int(&socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)?;
+    }
+    match socket.connect(&addr.into()) {
+        Ok(()) => {}
+        #[cfg(unix)]
+        Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        Err(e) => return Err(e),
+    }
+    Ok(TcpStream::from_std(socket.into()))
+}
+
+/// Have a UDP listener on an unspecified address report the local address of
+/// each datagram, so that replies can be sent from it.
+pub fn recv_pktinfo(socket: &UdpSocket, ipv6: bool) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let socket = SockRef::from(socket);
+        if ipv6 {
+            set_int(&socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
+        } else {
+            set_int(&socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)?;
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (socket, ipv6);
+    Ok(())
+}
+
+/// A socket to send datagrams to upstreams from, bound once the upstream
+/// and so its options are known.
+pub fn udp_socket(ipv6: bool) -> io::Result<UdpSocket> {
+    let domain = if ipv6 { Domain::IPV6 } else { Domain::IPV4 };
+    let socket = Socket::new(domain, Type::DGRAM, None)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into()))
+}
+
+/// Apply `options` to a socket of `udp_socket` and bind it.
+pub fn bind_udp(socket: &UdpSocket, options: &SocketConfig, ipv6: bool) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    apply(&socket, options, ipv6)?;
+    let unspecified = if ipv6 {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    };
+    let ip = options.bind_address.unwrap_or(unspecified);
+    socket.bind(&SocketAddr::new(ip, 0).into())
+}
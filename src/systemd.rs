@@ -0,0 +1,259 @@
+//! systemd socket activation (`LISTEN_FDS`) and service notifications (`sd_notify`).
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::time::Duration;
+
+#[cfg(unix)]
+mod imp {
+    use crate::endpoint::{Endpoint, unix_endpoint};
+    use std::io;
+    use std::mem::ManuallyDrop;
+    use std::net::{SocketAddr, TcpListener, UdpSocket};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::os::unix::net::{UnixDatagram, UnixListener};
+    use std::sync::{Mutex, OnceLock};
+
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    /// A socket passed by systemd, not yet taken by a forwarder.
+    struct Inherited {
+        fd: RawFd,
+        name: String,
+        stream: bool,
+        addr: Option<Endpoint>,
+    }
+
+    fn inherited() -> &'static Mutex<Vec<Inherited>> {
+        static SOCKETS: OnceLock<Mutex<Vec<Inherited>>> = OnceLock::new();
+        SOCKETS.get_or_init(|| Mutex::new(listen_fds()))
+    }
+
+    fn socket_type(fd: RawFd) -> Option<libc::c_int> {
+        let mut kind: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_TYPE,
+                &mut kind as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if r == 0 { Some(kind) } else { None }
+    }
+
+    fn listen_fds() -> Vec<Inherited> {
+        let env = |key: &str| std::env::var(key).ok();
+        if env("LISTEN_PID").and_then(|p| p.parse::<u32>().ok()) != Some(std::process::id()) {
+            return vec![];
+        }
+        let count = env("LISTEN_FDS")
+            .and_then(|n| n.parse::<RawFd>().ok())
+            .unwrap_or(0);
+        let names = env("LISTEN_FDNAMES").unwrap_or_default();
+        let mut names = names.split(':');
+        let mut sockets = vec![];
+        for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count.max(0) {
+            let name = names.next().unwrap_or("unknown").to_string();
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let stream = match socket_type(fd) {
+                Some(libc::SOCK_STREAM) => true,
+                Some(libc::SOCK_DGRAM) => false,
+                _ => {
+                    log::warn!(
+                        "systemd passed fd {} ({}), not a socket portfd can use",
+                        fd,
+                        name
+                    );
+                    continue;
+                }
+            };
+            // only borrowed to ask for the address, it is closed when taken
+            let socket = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(fd) });
+            let addr = socket.local_addr().map(Endpoint::Inet).ok().or_else(|| {
+                let socket = ManuallyDrop::new(unsafe { UnixListener::from_raw_fd(fd) });
+                unix_endpoint(&socket.local_addr().ok()?)
+            });
+            log::info!(
+                "systemd passed {} socket {} at {}",
+                if stream { "stream" } else { "datagram" },
+                name,
+                addr.as_ref()
+                    .map_or("an unknown address".to_string(), |a| a.to_string())
+            );
+            sockets.push(Inherited {
+                fd,
+                name,
+                stream,
+                addr,
+            });
+        }
+        sockets
+    }
+
+    fn same_address(a: &Endpoint, b: &Endpoint) -> bool {
+        match (a, b) {
+            (Endpoint::Inet(a), Endpoint::Inet(b)) => {
+                a == b
+                    || (a.port() == b.port() && a.ip().is_unspecified() && b.ip().is_unspecified())
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// The passed socket named `name`, or else the one at `addr`.
+    fn take(addr: &Endpoint, name: Option<&str>, stream: bool) -> Option<RawFd> {
+        let mut sockets = inherited().lock().unwrap();
+        let by_name = name.and_then(|name| {
+            let found = sockets
+                .iter()
+                .position(|s| s.stream == stream && s.name == name);
+            if found.is_none() {
+                log::warn!("systemd passed no socket named {} for {}", name, addr);
+            }
+            found
+        });
+        let i = by_name.or_else(|| {
+            sockets
+                .iter()
+                .position(|s| s.stream == stream && s.addr.as_ref().is_some_and(|a| same_address(a, addr)))
+        })?;
+        Some(sockets.remove(i).fd)
+    }
+
+    pub fn take_tcp_listener(addr: SocketAddr, name: Option<&str>) -> Option<TcpListener> {
+        take(&Endpoint::Inet(addr), name, true).map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+    }
+
+    pub fn take_udp_socket(addr: SocketAddr, name: Option<&str>) -> Option<UdpSocket> {
+        take(&Endpoint::Inet(addr), name, false).map(|fd| unsafe { UdpSocket::from_raw_fd(fd) })
+    }
+
+    pub fn take_unix_listener(path: &str, name: Option<&str>) -> Option<UnixListener> {
+        take(&Endpoint::Unix(path.to_string()), name, true)
+            .map(|fd| unsafe { UnixListener::from_raw_fd(fd) })
+    }
+
+    pub fn notify(state: &str) -> io::Result<bool> {
+        let path = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(p) => p,
+            None => return Ok(false),
+        };
+        let socket = UnixDatagram::unbound()?;
+        match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            _ => {
+                socket.send_to(state.as_bytes(), &path)?;
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn monotonic_usec() -> u64 {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000
+    }
+}
+
+/// The listening socket systemd passed for a forwarder at `addr`, preferring
+/// the one named `name` by `FileDescriptorName=`.
+pub fn take_tcp_listener(addr: SocketAddr, name: Option<&str>) -> Option<TcpListener> {
+    #[cfg(unix)]
+    return imp::take_tcp_listener(addr, name);
+    #[cfg(not(unix))]
+    {
+        let _ = (addr, name);
+        None
+    }
+}
+
+/// Like `take_tcp_listener`, for datagram sockets.
+pub fn take_udp_socket(addr: SocketAddr, name: Option<&str>) -> Option<UdpSocket> {
+    #[cfg(unix)]
+    return imp::take_udp_socket(addr, name);
+    #[cfg(not(unix))]
+    {
+        let _ = (addr, name);
+        None
+    }
+}
+
+/// Like `take_tcp_listener`, for a `unix:` socket at `path`.
+#[cfg(unix)]
+pub fn take_unix_listener(
+    path: &str,
+    name: Option<&str>,
+) -> Option<std::os::unix::net::UnixListener> {
+    imp::take_unix_listener(path, name)
+}
+
+/// Send `state`, eg. `READY=1`, to the service manager. `false` when not
+/// started by one that listens for notifications.
+pub fn notify(state: &str) -> io::Result<bool> {
+    #[cfg(unix)]
+    return imp::notify(state);
+    #[cfg(not(unix))]
+    {
+        let _ = state;
+        Ok(false)
+    }
+}
+
+/// The message telling the service manager a reload starts.
+pub fn reloading() -> String {
+    #[cfg(unix)]
+    return format!("RELOADING=1\nMONOTONIC_USEC={}", imp::monotonic_usec());
+    #[cfg(not(unix))]
+    return "RELOADING=1".to_string();
+}
+
+/// How often the service manager expects `WATCHDOG=1`, if it does.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    match std::env::var("WATCHDOG_PID") {
+        Ok(pid) if pid.parse::<u32>().ok() != Some(std::process::id()) => None,
+        _ if usec == 0 => None,
+        _ => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn notifications() {
+        let path = std::env::temp_dir().join(format!("portfd-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        assert!(notify("READY=1").unwrap());
+        let mut buf = [0; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        assert!(reloading().starts_with("RELOADING=1\nMONOTONIC_USEC="));
+        std::env::remove_var("NOTIFY_SOCKET");
+        assert!(!notify("READY=1").unwrap());
+        let _ = std::fs::remove_file(&path);
+
+        std::env::set_var("WATCHDOG_USEC", "2000000");
+        std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        assert_eq!(watchdog_interval(), Some(Duration::from_secs(2)));
+        std::env::set_var("WATCHDOG_PID", "1");
+        assert_eq!(watchdog_interval(), None);
+        std::env::remove_var("WATCHDOG_PID");
+        std::env::remove_var("WATCHDOG_USEC");
+    }
+}
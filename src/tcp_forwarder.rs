@@ -1,7 +1,16 @@
+use crate::access_log;
 use crate::address_matcher::IpAddrMatcher;
+use crate::admin;
+use crate::capture::{Capture, TcpCapture};
 use crate::connection_plugin::{ConnectionPlugin, RegexMultiplexer};
-use crate::forward_config::{ForwardSessionConfig, TcpMode};
-use crate::utils::toSockAddr;
+use crate::endpoint::{Endpoint, Listener, Stream, ToEndpoint};
+use crate::forward_config::{ForwardSessionConfig, SocketConfig, TcpMode};
+use crate::metrics;
+use crate::socket_options;
+use crate::systemd;
+use crate::unmatched_log::UnmatchedLog;
+use crate::upstream;
+use crate::utils::{Shared, bind_tcp, inherited_tcp};
 use log::info;
 use mio::net::{TcpListener, TcpStream};
 use mio::{Events, Interest, Poll, Token};
@@ -10,7 +19,7 @@ use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{self, ErrorKind, Read, Write};
 use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, ToSocketAddrs};
 use std::rc::Rc;
-use std::sync::{Arc, atomic::AtomicBool};
+use std::sync::{Arc, Mutex, atomic::AtomicBool, mpsc};
 use std::time;
 
 enum TcpForwarderMode {
@@ -18,14 +27,202 @@ enum TcpForwarderMode {
     Socks5Server(IpAddrMatcher),
 }
 
-pub struct TcpForwarder {
-    local_addr: SocketAddr,
+struct TcpSettings {
     mode: TcpForwarderMode,
     max_connections: Option<u64>,
     cache_size: usize,
+    drain_timeout: time::Duration,
+    capture: Option<Capture>,
+    unmatched_log: Option<UnmatchedLog>,
+    listen_socket: SocketConfig,
+    upstream_socket: SocketConfig,
+    /// `upstream_socket` with the options of each rule over it.
+    rule_sockets: Vec<SocketConfig>,
+}
+
+/// Handles on the series of one relayed connection, looked up once.
+#[derive(Default)]
+struct ConnMetrics {
+    active: metrics::Series,
+    bytes_in: metrics::Series,
+    bytes_out: metrics::Series,
+}
+
+impl ConnMetrics {
+    fn new(fwd: &str, rule: &str, upstream: &str) -> Self {
+        let labels = [("forwarder", fwd), ("rule", rule), ("upstream", upstream)];
+        let bytes = |name| {
+            metrics::series(
+                name,
+                &[
+                    ("forwarder", fwd),
+                    ("protocol", "tcp"),
+                    ("rule", rule),
+                    ("upstream", upstream),
+                ],
+            )
+        };
+        ConnMetrics {
+            active: metrics::series(metrics::CONNECTIONS_ACTIVE, &labels),
+            bytes_in: bytes(metrics::BYTES_IN_TOTAL),
+            bytes_out: bytes(metrics::BYTES_OUT_TOTAL),
+        }
+    }
+
+    fn transferred(&self, name: &'static str, n: usize) {
+        if name == metrics::BYTES_IN_TOTAL {
+            self.bytes_in.add(n as i64);
+        } else {
+            self.bytes_out.add(n as i64);
+        }
+    }
+}
+
+/// What is known about a relayed connection, for metrics and the admin API.
+struct ConnRecord {
+    id: u64,
+    relayed: bool,
+    started: time::Instant,
+    rule: String,
+    upstream: String,
+    connect_start: Option<time::Instant>,
+    up_bytes: u64,
+    down_bytes: u64,
+    capture: Option<TcpCapture>,
+    metrics: ConnMetrics,
+}
+
+impl ConnRecord {
+    fn new() -> Self {
+        ConnRecord {
+            id: admin::next_id(),
+            relayed: false,
+            started: time::Instant::now(),
+            rule: String::new(),
+            upstream: String::new(),
+            connect_start: None,
+            up_bytes: 0,
+            down_bytes: 0,
+            capture: None,
+            metrics: ConnMetrics::default(),
+        }
+    }
+
+    fn info(&self, client: &Endpoint) -> admin::SessionInfo {
+        let known = |s: &String| if s.is_empty() { None } else { Some(s.clone()) };
+        admin::SessionInfo {
+            id: self.id,
+            kind: "tcp",
+            client: client.clone(),
+            upstream: known(&self.upstream),
+            rule: known(&self.rule),
+            state: None,
+            up_bytes: self.up_bytes,
+            down_bytes: self.down_bytes,
+            age_secs: self.started.elapsed().as_secs_f64(),
+        }
+    }
+
+    fn connected(
+        &mut self,
+        fwd: &str,
+        rule: &str,
+        upstream: &Endpoint,
+        client: &Endpoint,
+        capture: Option<&Capture>,
+    ) {
+        self.relayed = true;
+        self.capture = capture
+            .filter(|c| c.wants(rule, client))
+            .map(|c| c.tcp(client, upstream));
+        self.rule = rule.to_string();
+        self.upstream = upstream.to_string();
+        self.connect_start = Some(time::Instant::now());
+        self.metrics = ConnMetrics::new(fwd, rule, &self.upstream);
+        self.metrics.active.add(1);
+    }
+
+    /// Count the connection once its upstream accepted it.
+    fn established(&self, fwd: &str, start: time::Instant) {
+        metrics::observe(
+            metrics::CONNECT_DURATION_SECONDS,
+            &[("forwarder", fwd), ("upstream", self.upstream.as_str())],
+            start.elapsed(),
+        );
+        metrics::counter_add(
+            metrics::CONNECTIONS_TOTAL,
+            &[
+                ("forwarder", fwd),
+                ("rule", self.rule.as_str()),
+                ("upstream", self.upstream.as_str()),
+            ],
+            1,
+        );
+        if let Ok(addr) = self.upstream.parse() {
+            upstream::connect_succeeded(addr);
+        }
+    }
+
+    fn finish(&self, fwd: &str, client: &Endpoint, reason: &str) {
+        access_log::write(&access_log::AccessRecord {
+            forwarder: fwd,
+            protocol: "tcp",
+            client,
+            pattern: Some(self.rule.as_str()).filter(|s| !s.is_empty()),
+            upstream: Some(self.upstream.as_str()).filter(|s| !s.is_empty()),
+            started: self.started,
+            up_bytes: self.up_bytes,
+            down_bytes: self.down_bytes,
+            close_reason: reason,
+        });
+    }
+
+    fn transferred(&mut self, name: &'static str, data: &[u8]) {
+        let n = data.len();
+        if name == metrics::BYTES_IN_TOTAL {
+            self.up_bytes += n as u64;
+            if let Some(c) = self.capture.as_mut() {
+                c.client_data(data);
+            }
+        } else {
+            self.down_bytes += n as u64;
+            if let Some(c) = self.capture.as_mut() {
+                c.upstream_data(data);
+            }
+        }
+        self.metrics.transferred(name, n);
+    }
 }
 
-fn SafeAddr(addr: &std::io::Result<SocketAddr>) -> String {
+fn connect_failed(fwd: &str, upstream: &str) {
+    metrics::counter_add(
+        metrics::CONNECT_FAILURES_TOTAL,
+        &[("forwarder", fwd), ("upstream", upstream)],
+        1,
+    );
+    if let Ok(addr) = upstream.parse() {
+        upstream::connect_failed(addr);
+    }
+}
+
+pub struct TcpForwarder {
+    local: Endpoint,
+    fd_name: Option<String>,
+    ipv6_only: bool,
+    unix_mode: Option<u32>,
+    listen_socket: SocketConfig,
+    settings: Shared<TcpSettings>,
+    /// The listener, bound by `from` or handed on by the forwarder this one
+    /// took over, until `listen` takes it.
+    listener: Mutex<Option<mpsc::Receiver<Listener>>>,
+    /// Where a closed loop hands its listener on to instead of closing it.
+    successor: Mutex<Option<mpsc::Sender<Listener>>>,
+    /// Set while a closed loop keeps its connections until they end rather
+    /// than for `drain_timeout`, as when a reload removed it.
+    drain_unbounded: Arc<AtomicBool>,
+}
+
+fn SafeAddr(addr: &std::io::Result<Endpoint>) -> String {
     match addr {
         Ok(addr) => addr.to_string(),
         Err(_) => "unknown".to_string(),
@@ -39,7 +236,7 @@ fn nextToken(token: &mut Token) -> Token {
 
 fn set_readable(
     poll: &mut Poll,
-    source: &mut TcpStream,
+    source: &mut Stream,
     token: &Token,
     stateMap: &mut HashMap<Token, Interest>,
 ) {
@@ -60,7 +257,7 @@ fn set_readable(
 }
 fn set_writable(
     poll: &mut Poll,
-    source: &mut TcpStream,
+    source: &mut Stream,
     token: &Token,
     stateMap: &mut HashMap<Token, Interest>,
 ) {
@@ -81,7 +278,7 @@ fn set_writable(
 }
 fn clear_readable(
     poll: &mut Poll,
-    source: &mut TcpStream,
+    source: &mut Stream,
     token: &Token,
     stateMap: &mut HashMap<Token, Interest>,
 ) {
@@ -103,7 +300,7 @@ fn clear_readable(
 }
 fn clear_writable(
     poll: &mut Poll,
-    source: &mut TcpStream,
+    source: &mut Stream,
     token: &Token,
     stateMap: &mut HashMap<Token, Interest>,
 ) {
@@ -242,9 +439,11 @@ fn proxy_protocol_tag(protocol: ProxyProtocol) -> &'static str {
 }
 
 struct Socks5Session {
-    client: TcpStream,
+    id: u64,
+    started: time::Instant,
+    client: Stream,
     remote: Option<TcpStream>,
-    client_addr: SocketAddr,
+    client_addr: Endpoint,
     target: Option<SocketAddr>,
     target_label: Option<String>,
     protocol: ProxyProtocol,
@@ -257,6 +456,10 @@ struct Socks5Session {
     r2c_queue: VecDeque<(Vec<u8>, usize)>,
     up_bytes: u64,
     down_bytes: u64,
+    connect_start: Option<time::Instant>,
+    capture: Option<TcpCapture>,
+    /// Looked up for the target the handles were made for.
+    metrics: Option<(Option<SocketAddr>, ConnMetrics)>,
     client_eof: bool,
     remote_eof: bool,
     client_write_shutdown: bool,
@@ -264,8 +467,10 @@ struct Socks5Session {
 }
 
 impl Socks5Session {
-    fn new(client: TcpStream, client_addr: SocketAddr) -> Self {
+    fn new(client: Stream, client_addr: Endpoint) -> Self {
         Self {
+            id: admin::next_id(),
+            started: time::Instant::now(),
             client,
             remote: None,
             client_addr,
@@ -281,29 +486,88 @@ impl Socks5Session {
             r2c_queue: VecDeque::new(),
             up_bytes: 0,
             down_bytes: 0,
+            connect_start: None,
+            capture: None,
+            metrics: None,
             client_eof: false,
             remote_eof: false,
             client_write_shutdown: false,
             remote_write_shutdown: false,
         }
     }
+
+    fn info(&self) -> admin::SessionInfo {
+        admin::SessionInfo {
+            id: self.id,
+            kind: "proxy",
+            client: self.client_addr.clone(),
+            upstream: self
+                .target_label
+                .clone()
+                .or_else(|| self.target.map(|a| a.to_string())),
+            rule: Some(proxy_protocol_tag(self.protocol).to_string()),
+            state: Some(format!("{:?}", self.state)),
+            up_bytes: self.up_bytes,
+            down_bytes: self.down_bytes,
+            age_secs: self.started.elapsed().as_secs_f64(),
+        }
+    }
+
+    fn upstream_label(&self) -> String {
+        self.target.map(|a| a.to_string()).unwrap_or_default()
+    }
+
+    /// Start capturing once the upstream is known, requests queued so far included.
+    fn start_capture(&mut self, capture: Option<&Capture>) {
+        let rule = proxy_protocol_tag(self.protocol);
+        let target = Endpoint::Inet(self.target.unwrap());
+        if let Some(c) = capture.filter(|c| c.wants(rule, &self.client_addr)) {
+            let mut stream = c.tcp(&self.client_addr, &target);
+            for (data, _) in &self.c2r_queue {
+                stream.client_data(data);
+            }
+            self.capture = Some(stream);
+        }
+    }
+
+    fn transferred(&mut self, fwd: &str, name: &'static str, data: &[u8]) {
+        let n = data.len();
+        if name == metrics::BYTES_IN_TOTAL {
+            self.up_bytes += n as u64;
+            if let Some(c) = self.capture.as_mut() {
+                c.client_data(data);
+            }
+        } else {
+            self.down_bytes += n as u64;
+            if let Some(c) = self.capture.as_mut() {
+                c.upstream_data(data);
+            }
+        }
+        self.conn_metrics(fwd).transferred(name, n);
+    }
+
+    fn conn_metrics(&mut self, fwd: &str) -> &ConnMetrics {
+        if self.metrics.as_ref().is_none_or(|(t, _)| *t != self.target) {
+            let rule = proxy_protocol_tag(self.protocol);
+            let m = ConnMetrics::new(fwd, rule, &self.upstream_label());
+            self.metrics = Some((self.target, m));
+        }
+        &self.metrics.as_ref().unwrap().1
+    }
 }
 
-impl TcpForwarder {
-    pub fn from<T: ToSocketAddrs>(
-        config: &ForwardSessionConfig<T>,
-    ) -> std::io::Result<TcpForwarder> {
+impl TcpSettings {
+    fn from<T: ToEndpoint>(config: &ForwardSessionConfig<T>) -> std::io::Result<TcpSettings> {
+        let ip_matcher = IpAddrMatcher::from_rules(&config.acl_rules())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
         let mode = match config.tcp_mode {
-            TcpMode::Forward => TcpForwarderMode::Forward(Box::new(RegexMultiplexer::from((
-                config.remoteMap.clone(),
-                config.allow_nets.clone(),
-            )))),
-            TcpMode::Socks5Server => {
-                TcpForwarderMode::Socks5Server(IpAddrMatcher::from(&config.allow_nets))
-            }
+            TcpMode::Forward => TcpForwarderMode::Forward(Box::new(
+                RegexMultiplexer::new(config.remoteMap.clone(), ip_matcher)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?,
+            )),
+            TcpMode::Socks5Server => TcpForwarderMode::Socks5Server(ip_matcher),
         };
         Ok(Self {
-            local_addr: toSockAddr(&config.local),
             mode,
             max_connections: if config.max_connections >= 0 {
                 Some(config.max_connections as u64)
@@ -311,37 +575,186 @@ impl TcpForwarder {
                 None
             },
             cache_size: config.conn_bufsize,
+            drain_timeout: config.drain_timeout,
+            capture: match &config.capture {
+                Some(c) => {
+                    Some(Capture::from(c).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?)
+                }
+                None => None,
+            },
+            unmatched_log: match &config.unmatched_log {
+                Some(c) => Some(UnmatchedLog::open(c)?),
+                None => None,
+            },
+            listen_socket: config.listen_socket.clone(),
+            upstream_socket: config.upstream_socket.clone(),
+            rule_sockets: config.upstream_sockets(),
         })
     }
 
-    pub fn listen(self: &Self, closed: Arc<AtomicBool>) -> std::io::Result<()> {
-        if let TcpForwarderMode::Socks5Server(ip_matcher) = &self.mode {
-            return self.listen_socks5(closed, ip_matcher.clone());
+    /// Options of the upstream socket of a connection that matched `rule`.
+    fn upstream_socket(&self, rule: usize) -> &SocketConfig {
+        self.rule_sockets.get(rule).unwrap_or(&self.upstream_socket)
+    }
+}
+
+impl TcpForwarder {
+    /// A forwarder for `config` with its listener bound, so that a busy
+    /// address is reported here rather than by `listen`.
+    pub fn from<T: ToEndpoint>(
+        config: &ForwardSessionConfig<T>,
+    ) -> std::io::Result<TcpForwarder> {
+        let forwarder = Self::unbound(config)?;
+        let listener = forwarder.bind().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "fail to bind {}: make sure the address is not in use and you have permission to bind\n  {}",
+                    forwarder.local.url("tcp"),
+                    e
+                ),
+            )
+        })?;
+        let (tx, rx) = mpsc::channel();
+        tx.send(listener).unwrap();
+        *forwarder.listener.lock().unwrap() = Some(rx);
+        Ok(forwarder)
+    }
+
+    fn unbound<T: ToEndpoint>(config: &ForwardSessionConfig<T>) -> std::io::Result<TcpForwarder> {
+        Ok(Self {
+            local: config.local.to_endpoint()?,
+            fd_name: config.fd_name.clone(),
+            ipv6_only: config.ipv6_only,
+            unix_mode: config.unix_mode,
+            listen_socket: config.listen_socket.clone(),
+            settings: Shared::new(TcpSettings::from(config)?),
+            listener: Mutex::new(None),
+            successor: Mutex::new(None),
+            drain_unbounded: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// A forwarder for a changed configuration of the same listener, eg. in
+    /// another `tcp_mode`. Its `listen` waits until the loop of this one is
+    /// closed and takes the listener over, the connections of this one drain.
+    pub fn take_over<T: ToEndpoint>(
+        &self,
+        config: &ForwardSessionConfig<T>,
+    ) -> std::io::Result<TcpForwarder> {
+        let forwarder = Self::unbound(config)?;
+        let mut listener = self.listener.lock().unwrap();
+        if listener.is_some() {
+            // not listening yet
+            *forwarder.listener.lock().unwrap() = listener.take();
+            return Ok(forwarder);
         }
+        let (tx, rx) = mpsc::channel();
+        *forwarder.listener.lock().unwrap() = Some(rx);
+        *self.successor.lock().unwrap() = Some(tx);
+        Ok(forwarder)
+    }
 
-        let plugin = match &self.mode {
-            TcpForwarderMode::Forward(plugin) => plugin,
-            TcpForwarderMode::Socks5Server(_) => unreachable!(),
-        };
+    /// The flag that lets a closed loop drain without `drain_timeout`.
+    pub(crate) fn drain_unbounded(&self) -> &Arc<AtomicBool> {
+        &self.drain_unbounded
+    }
 
-        let mut pollIns = Poll::new().unwrap();
-        let mut listener = match TcpListener::bind(self.local_addr) {
-            Ok(l) => l,
-            Err(e) => {
-                panic!(
-                    "fail to bind tcp://{}: make sure the address is not in use and you have permission to bind\n  {}",
-                    self.local_addr, e
-                );
+    /// When a closed loop with connections left gives them up, `None` while
+    /// they may stay until they end.
+    fn drain_deadline(&self, settings: &TcpSettings) -> Option<time::Instant> {
+        if self.drain_unbounded.load(std::sync::atomic::Ordering::SeqCst) {
+            None
+        } else {
+            Some(time::Instant::now() + settings.drain_timeout)
+        }
+    }
+
+    fn take_listener(&self) -> io::Result<Listener> {
+        let rx = self
+            .listener
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| io::Error::other(format!("{} is already listening", self.local)))?;
+        rx.recv().map_err(|_| {
+            io::Error::other(format!("the listener of {} was not handed over", self.local))
+        })
+    }
+
+    /// Close `listener`, or hand it on to the forwarder that took this one over.
+    fn release(&self, listener: Listener) {
+        if let Some(tx) = self.successor.lock().unwrap().take() {
+            let _ = tx.send(listener);
+        }
+    }
+
+    /// The socket systemd passed for this forwarder, or a new one.
+    fn bind(&self) -> io::Result<Listener> {
+        let fd_name = self.fd_name.as_deref();
+        let addr = match &self.local {
+            Endpoint::Inet(addr) => *addr,
+            #[cfg(unix)]
+            Endpoint::Unix(name) => {
+                return match systemd::take_unix_listener(name, fd_name) {
+                    Some(listener) => Listener::inherited_unix(listener, name),
+                    None => Listener::bind_unix(name, self.unix_mode),
+                };
             }
+            #[cfg(not(unix))]
+            Endpoint::Unix(name) => return Listener::bind_unix(name, self.unix_mode),
+        };
+        let listener = match systemd::take_tcp_listener(addr, fd_name) {
+            Some(listener) => inherited_tcp(listener, &self.listen_socket)?,
+            None => bind_tcp(addr, self.ipv6_only, &self.listen_socket)?,
         };
+        Ok(Listener::Tcp(TcpListener::from_std(listener)))
+    }
+
+    /// Replace routing rules, access rules and limits, live connections are kept.
+    /// Switching `tcp_mode` needs a new listener and is rejected.
+    pub fn update<T: ToEndpoint>(&self, config: &ForwardSessionConfig<T>) -> std::io::Result<()> {
+        self.prepare_update(config)?();
+        Ok(())
+    }
+
+    /// Check an `update`, which is made when the returned function is called.
+    pub(crate) fn prepare_update<T: ToEndpoint>(
+        &self,
+        config: &ForwardSessionConfig<T>,
+    ) -> std::io::Result<impl FnOnce() + '_> {
+        let settings = TcpSettings::from(config)?;
+        let same_mode = matches!(
+            (&settings.mode, &self.settings.get().mode),
+            (TcpForwarderMode::Forward(_), TcpForwarderMode::Forward(_))
+                | (TcpForwarderMode::Socks5Server(_), TcpForwarderMode::Socks5Server(_))
+        );
+        if !same_mode {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "tcp_mode can't be changed without restarting the listener",
+            ));
+        }
+        Ok(move || self.settings.set(settings))
+    }
+
+    pub fn listen(self: &Self, closed: Arc<AtomicBool>) -> std::io::Result<()> {
+        let settings = self.settings.get();
+        if let TcpForwarderMode::Socks5Server(_) = &settings.mode {
+            return self.listen_socks5(closed);
+        }
+
+        let mut pollIns = Poll::new().unwrap();
+        let mut listener = Some(self.take_listener()?);
         let listener_token = Token(0);
         pollIns
             .registry()
-            .register(&mut listener, listener_token, Interest::READABLE)
+            .register(listener.as_mut().unwrap(), listener_token, Interest::READABLE)
             .unwrap();
-        info!("listen at tcp://{}", listener.local_addr().unwrap());
+        info!("listen at {}", self.local.url("tcp"));
+        let admin = admin::ForwarderHandle::register(self.local.clone(), "tcp", &pollIns)?;
 
-        let capacity = if let Some(mx) = self.max_connections {
+        let capacity = if let Some(mx) = settings.max_connections {
             std::cmp::min(mx as usize, 1024)
         } else {
             1024
@@ -353,9 +766,17 @@ impl TcpForwarder {
         let mut outGoingPeerRecieveBytes: u64 = 0;
         let mut outGoingPeerSendBytes: u64 = 0;
 
+        let fwd = self.local.to_string();
+        // first packets that matched no rule and that matched one
+        let sniff = ["no_match", "match"].map(|result| {
+            metrics::series(
+                metrics::SNIFF_TOTAL,
+                &[("forwarder", fwd.as_str()), ("protocol", "tcp"), ("result", result)],
+            )
+        });
         let mut conn_token = Token(1);
-        let mut token2stream: HashMap<Token, (Rc<RefCell<TcpStream>>, SocketAddr)> = HashMap::new();
-        let mut token2connss: HashMap<Token, Rc<RefCell<TcpStream>>> = HashMap::new();
+        let mut token2stream: HashMap<Token, (Rc<RefCell<Stream>>, Endpoint, ConnRecord)> = HashMap::new();
+        let mut token2connss: HashMap<Token, Rc<RefCell<Stream>>> = HashMap::new();
         let mut token2stat = HashMap::new();
         let mut token2buffer: HashMap<Token, (Vec<_>, usize)> = HashMap::new();
         let mut shutdownMe: HashSet<Token> = HashSet::new();
@@ -363,10 +784,11 @@ impl TcpForwarder {
 
         let removeConn =
             |tk: Token,
+             reason: &str,
              pollIns: &mut Poll,
-             token2stream: &mut HashMap<Token, (Rc<RefCell<TcpStream>>, SocketAddr)>,
+             token2stream: &mut HashMap<Token, (Rc<RefCell<Stream>>, Endpoint, ConnRecord)>,
              token2stat: &mut _,
-             token2connss: &mut HashMap<Token, Rc<RefCell<TcpStream>>>,
+             token2connss: &mut HashMap<Token, Rc<RefCell<Stream>>>,
              token2buffer: &mut HashMap<Token, (Vec<_>, usize)>,
              shutdownMe: &mut HashSet<Token>,
              alreadyShutdown: &mut HashSet<Token>| {
@@ -413,7 +835,11 @@ impl TcpForwarder {
                         token2stat,
                     );
                 }
-                token2stream.remove(&t1).unwrap();
+                let (_, client, record) = token2stream.remove(&t1).unwrap();
+                record.finish(&fwd, &client, reason);
+                if record.relayed {
+                    record.metrics.active.add(-1);
+                }
                 token2connss.remove(&t2);
                 token2buffer.remove(&t1);
                 token2buffer.remove(&t2);
@@ -423,53 +849,171 @@ impl TcpForwarder {
                 alreadyShutdown.remove(&t2);
             };
 
+        let mut drain_deadline: Option<time::Instant> = None;
+        let mut ruled: Option<Arc<TcpSettings>> = None;
         loop {
-            if closed.load(std::sync::atomic::Ordering::SeqCst) {
-                log::debug!(
-                    "tcp forwarder closed: inComingPeerRecieveBytes = {inComingPeerRecieveBytes}, inComingPeerSendBytes = {inComingPeerSendBytes}, outGoingPeerRecieveBytes = {outGoingPeerRecieveBytes}, outGoingPeerSendBytes = {outGoingPeerSendBytes}"
+            let settings = self.settings.get();
+            let plugin = match &settings.mode {
+                TcpForwarderMode::Forward(plugin) => plugin,
+                TcpForwarderMode::Socks5Server(_) => unreachable!(),
+            };
+            if !ruled.as_ref().is_some_and(|r| Arc::ptr_eq(r, &settings)) {
+                admin.set_rules(admin::rule_keys(plugin.as_ref()));
+                ruled = Some(settings.clone());
+            }
+            if admin.pending() {
+                for id in admin.take_close_requests() {
+                    let found = token2stream
+                        .iter()
+                        .find(|(_, v)| v.2.id == id)
+                        .map(|(tk, _)| *tk);
+                    if let Some(tk) = found {
+                        info!("close connection {} by admin request", id);
+                        removeConn(
+                            tk,
+                            "closed by admin request",
+                            &mut pollIns,
+                            &mut token2stream,
+                            &mut token2stat,
+                            &mut token2connss,
+                            &mut token2buffer,
+                            &mut shutdownMe,
+                            &mut alreadyShutdown,
+                        );
+                    }
+                }
+                admin.publish(
+                    token2stream
+                        .values()
+                        .map(|(_, addr, record)| record.info(addr))
+                        .collect(),
                 );
-                return Ok(());
+            }
+            let draining = closed.load(std::sync::atomic::Ordering::SeqCst);
+            if draining {
+                if let Some(mut l) = listener.take() {
+                    pollIns.registry().deregister(&mut l).unwrap_or(());
+                    self.release(l);
+                    drain_deadline = self.drain_deadline(&settings);
+                    let lingers = drain_deadline.is_none() || !settings.drain_timeout.is_zero();
+                    if !token2stream.is_empty() && lingers {
+                        info!(
+                            "stop listening at {}, draining {} connections",
+                            self.local.url("tcp"),
+                            token2stream.len()
+                        );
+                    }
+                }
+                // bounded again by shutdown
+                if drain_deadline.is_none() {
+                    drain_deadline = self.drain_deadline(&settings);
+                }
+                if token2stream.is_empty()
+                    || drain_deadline.is_some_and(|d| time::Instant::now() >= d)
+                {
+                    if !token2stream.is_empty() && !settings.drain_timeout.is_zero() {
+                        info!(
+                            "drain timeout at {}, drop {} connections",
+                            self.local.url("tcp"),
+                            token2stream.len()
+                        );
+                    }
+                    let remaining: Vec<Token> = token2stream.keys().copied().collect();
+                    for tk in remaining {
+                        removeConn(
+                            tk,
+                            "drain timeout",
+                            &mut pollIns,
+                            &mut token2stream,
+                            &mut token2stat,
+                            &mut token2connss,
+                            &mut token2buffer,
+                            &mut shutdownMe,
+                            &mut alreadyShutdown,
+                        );
+                    }
+                    log::debug!(
+                        "tcp forwarder closed: inComingPeerRecieveBytes = {inComingPeerRecieveBytes}, inComingPeerSendBytes = {inComingPeerSendBytes}, outGoingPeerRecieveBytes = {outGoingPeerRecieveBytes}, outGoingPeerSendBytes = {outGoingPeerSendBytes}"
+                    );
+                    return Ok(());
+                }
             }
 
-            pollIns
-                .poll(&mut events, Some(time::Duration::from_secs(1)))
-                .unwrap();
+            let poll_timeout = if draining {
+                time::Duration::from_millis(100)
+            } else {
+                time::Duration::from_secs(1)
+            };
+            pollIns.poll(&mut events, Some(poll_timeout)).unwrap();
             for event in &events {
                 let tk = event.token();
                 if tk == listener_token {
                     if !event.is_readable() {
                         continue;
                     }
+                    let listener = match listener.as_mut() {
+                        Some(l) => l,
+                        None => continue,
+                    };
 
                     loop {
                         match listener.accept() {
                             Ok((mut stream, addr)) => {
-                                if let Some(mx) = self.max_connections {
+                                if let Some(mx) = settings.max_connections {
                                     if token2stream.len() as u64 >= mx {
                                         info!("drop TCP connection from {} for quota", addr);
                                         break;
                                     }
                                 }
 
-                                if !plugin.testipaddr(&addr) {
+                                // a unix socket is guarded by its file mode instead
+                                if !addr.inet().is_none_or(|a| plugin.testipaddr(&a)) {
                                     info!("drop TCP connection from {}", addr);
                                     stream.shutdown(Shutdown::Both).unwrap_or(());
                                     continue;
                                 }
 
+                                if let Stream::Tcp(s) = &stream {
+                                    if let Err(e) = socket_options::accepted(s, &settings.listen_socket) {
+                                        info!("fail to set socket options of {}: {}", addr, e);
+                                    }
+                                }
+
+                                let singleRemote = plugin.onlySingleTarget();
+                                if singleRemote.is_some() && !admin.rule_enabled(0) {
+                                    info!("drop TCP connection from {}, rule is disabled", addr);
+                                    stream.shutdown(Shutdown::Both).unwrap_or(());
+                                    continue;
+                                }
+
                                 let t = nextToken(&mut conn_token);
                                 let nt = nextToken(&mut conn_token);
-                                let singleRemote = plugin.onlySingleTarget();
                                 if singleRemote.is_some() {
                                     let remote = singleRemote.unwrap();
-                                    match TcpStream::connect(remote) {
+                                    match Stream::connect(&remote, settings.upstream_socket(0)) {
                                         Ok(mut conn) => {
                                             pollIns
                                                 .registry()
                                                 .register(&mut stream, t, Interest::READABLE)
                                                 .unwrap();
-                                            token2stream
-                                                .insert(t, (Rc::new(RefCell::new(stream)), addr));
+                                            let mut record = ConnRecord::new();
+                                            record.connected(
+                                                &fwd,
+                                                plugin.rulePattern(0).unwrap_or(""),
+                                                &remote,
+                                                &addr,
+                                                settings.capture.as_ref(),
+                                            );
+                                            info!(
+                                                "accept connection from {} to {}, current connections: {}",
+                                                addr,
+                                                remote,
+                                                token2stream.len() + 1
+                                            );
+                                            token2stream.insert(
+                                                t,
+                                                (Rc::new(RefCell::new(stream)), addr, record),
+                                            );
                                             pollIns
                                                 .registry()
                                                 .register(&mut conn, nt, Interest::READABLE)
@@ -477,14 +1021,18 @@ impl TcpForwarder {
                                             token2connss.insert(nt, Rc::new(RefCell::new(conn)));
                                             token2stat.insert(t, Interest::READABLE);
                                             token2stat.insert(nt, Interest::READABLE);
-                                            info!(
-                                                "accept connection from {} to {}, current connections: {}",
-                                                addr,
-                                                remote,
-                                                token2stream.len()
-                                            );
                                         }
                                         Err(reason) => {
+                                            connect_failed(&fwd, &remote.to_string());
+                                            let mut record = ConnRecord::new();
+                                            record.rule =
+                                                plugin.rulePattern(0).unwrap_or("").to_string();
+                                            record.upstream = remote.to_string();
+                                            record.finish(
+                                                &fwd,
+                                                &addr,
+                                                &format!("connect failed: {}", reason),
+                                            );
                                             info!(
                                                 "close connection from {} because failed to connect remote address {}: {}",
                                                 addr, remote, reason
@@ -496,7 +1044,10 @@ impl TcpForwarder {
                                         .registry()
                                         .register(&mut stream, t, Interest::READABLE)
                                         .unwrap();
-                                    token2stream.insert(t, (Rc::new(RefCell::new(stream)), addr));
+                                    token2stream.insert(
+                                        t,
+                                        (Rc::new(RefCell::new(stream)), addr, ConnRecord::new()),
+                                    );
                                     token2stat.insert(t, Interest::READABLE);
                                 }
                             }
@@ -532,16 +1083,22 @@ impl TcpForwarder {
 
                 let mut peerConnOpt = conn.clone();
 
+                if tk.0 % 2 == 1 {
+                    let record = &mut token2stream.get_mut(&tk2).unwrap().2;
+                    if let Some(start) = record.connect_start.take() {
+                        match sss.borrow().take_error() {
+                            Ok(None) if !event.is_error() => record.established(&fwd, start),
+                            _ => connect_failed(&fwd, &record.upstream),
+                        }
+                    }
+                }
+
                 if event.is_readable() {
                     let mut buf = [0; 1 << 16];
-                    let mut n = 0;
                     loop {
                         let mut sss_mut = sss.borrow_mut();
                         match sss_mut.read(&mut buf[..]) {
                             Ok(s) => {
-                                if n > 0 && s == 0 {
-                                    break;
-                                }
                                 if s == 0 {
                                     if token2buffer.get(&tk2).is_none() {
                                         if peerConnOpt.is_some() {
@@ -553,6 +1110,7 @@ impl TcpForwarder {
                                                 drop(conn_mut);
                                                 removeConn(
                                                     tk,
+                                                    "closed",
                                                     &mut pollIns,
                                                     &mut token2stream,
                                                     &mut token2stat,
@@ -575,6 +1133,7 @@ impl TcpForwarder {
                                             drop(sss_mut);
                                             removeConn(
                                                 tk,
+                                                "client closed",
                                                 &mut pollIns,
                                                 &mut token2stream,
                                                 &mut token2stat,
@@ -588,24 +1147,44 @@ impl TcpForwarder {
                                     } else {
                                         shutdownMe.insert(tk2);
                                     }
+                                    // EOF may come in the same event as the last data
+                                    break;
                                 } else {
                                     log::debug!(
                                         "read buffer[{}] from {}",
                                         s,
                                         SafeAddr(&sss_mut.peer_addr())
                                     );
-                                    if tk.0 % 2 == 0 {
-                                        inComingPeerRecieveBytes += s as u64;
-                                    } else {
-                                        outGoingPeerRecieveBytes += s as u64;
-                                    }
                                     let vbuf = Vec::from(&buf[0..s]);
+                                    let mut close_reason = String::from("no matching rule");
                                     let trueconn = if peerConnOpt.is_none() {
-                                        match plugin
-                                            .decideTarget(&vbuf, sss_mut.peer_addr().unwrap())
+                                        // unix clients have no address to match
+                                        let client = token2stream[&tk].1.inet().unwrap_or_else(|| {
+                                            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+                                        });
+                                        let matched = plugin.matchRule(&vbuf, client, &|i| {
+                                            admin.rule_enabled(i)
+                                        });
+                                        sniff[matched.is_some() as usize].add(1);
+                                        if let (None, Some(l)) =
+                                            (&matched, settings.unmatched_log.as_ref())
                                         {
-                                            Some(addr) => match TcpStream::connect(addr) {
+                                            l.record(&fwd, "tcp", &token2stream[&tk].1, &vbuf);
+                                        }
+                                        match matched {
+                                            Some((index, addr)) => match Stream::connect(
+                                                &addr,
+                                                settings.upstream_socket(index),
+                                            ) {
                                                 Ok(mut ccc) => {
+                                                    let entry = token2stream.get_mut(&tk).unwrap();
+                                                    entry.2.connected(
+                                                        &fwd,
+                                                        plugin.rulePattern(index).unwrap_or(""),
+                                                        &addr,
+                                                        &entry.1,
+                                                        settings.capture.as_ref(),
+                                                    );
                                                     pollIns
                                                         .registry()
                                                         .register(&mut ccc, tk2, Interest::READABLE)
@@ -620,6 +1199,16 @@ impl TcpForwarder {
                                                     Some(token2connss.get(&tk2).unwrap().clone())
                                                 }
                                                 Err(reason) => {
+                                                    connect_failed(&fwd, &addr.to_string());
+                                                    let record =
+                                                        &mut token2stream.get_mut(&tk).unwrap().2;
+                                                    record.rule = plugin
+                                                        .rulePattern(index)
+                                                        .unwrap_or("")
+                                                        .to_string();
+                                                    record.upstream = addr.to_string();
+                                                    close_reason =
+                                                        format!("connect failed: {}", reason);
                                                     info!(
                                                         "fail to create connection to {} '{}', so release resources",
                                                         addr, reason
@@ -632,10 +1221,28 @@ impl TcpForwarder {
                                     } else {
                                         Some(peerConnOpt.as_ref().unwrap().clone())
                                     };
+                                    if tk.0 % 2 == 0 {
+                                        inComingPeerRecieveBytes += s as u64;
+                                        if let Some((_, _, record)) = token2stream.get_mut(&tk) {
+                                            record.transferred(
+                                                metrics::BYTES_IN_TOTAL,
+                                                &vbuf,
+                                            );
+                                        }
+                                    } else {
+                                        outGoingPeerRecieveBytes += s as u64;
+                                        if let Some((_, _, record)) = token2stream.get_mut(&tk2) {
+                                            record.transferred(
+                                                metrics::BYTES_OUT_TOTAL,
+                                                &vbuf,
+                                            );
+                                        }
+                                    }
                                     if trueconn.is_none() {
                                         drop(sss_mut);
                                         removeConn(
                                             tk,
+                                            &close_reason,
                                             &mut pollIns,
                                             &mut token2stream,
                                             &mut token2stat,
@@ -649,7 +1256,7 @@ impl TcpForwarder {
                                             Some(bb) => {
                                                 bb.0.push(vbuf);
                                                 bb.1 += s;
-                                                if bb.1 >= self.cache_size {
+                                                if bb.1 >= settings.cache_size {
                                                     clear_readable(
                                                         &mut pollIns,
                                                         &mut sss_mut,
@@ -666,7 +1273,7 @@ impl TcpForwarder {
                                                     &tk2,
                                                     &mut token2stat,
                                                 );
-                                                if s >= self.cache_size {
+                                                if s >= settings.cache_size {
                                                     clear_readable(
                                                         &mut pollIns,
                                                         &mut sss_mut,
@@ -687,6 +1294,7 @@ impl TcpForwarder {
                                 drop(sss_mut);
                                 removeConn(
                                     tk,
+                                    &format!("read error: {}", _e),
                                     &mut pollIns,
                                     &mut token2stream,
                                     &mut token2stat,
@@ -698,7 +1306,6 @@ impl TcpForwarder {
                                 break;
                             }
                         }
-                        n += 1;
                     }
                 }
 
@@ -737,8 +1344,8 @@ impl TcpForwarder {
                                 nwrited += s;
                                 if s < bb.len() {
                                     bufstat.0.insert(0, bb.as_slice()[s..bb.len()].to_vec());
-                                    if bufstat.1 >= self.cache_size
-                                        && (bufstat.1 - nwrited) < self.cache_size
+                                    if bufstat.1 >= settings.cache_size
+                                        && (bufstat.1 - nwrited) < settings.cache_size
                                     {
                                         set_readable(
                                             &mut pollIns,
@@ -752,8 +1359,8 @@ impl TcpForwarder {
                                 }
 
                                 if bufstat.0.is_empty() {
-                                    if bufstat.1 >= self.cache_size
-                                        && (bufstat.1 - nwrited) < self.cache_size
+                                    if bufstat.1 >= settings.cache_size
+                                        && (bufstat.1 - nwrited) < settings.cache_size
                                     {
                                         set_readable(
                                             &mut pollIns,
@@ -775,6 +1382,7 @@ impl TcpForwarder {
                                             drop(conn_mut);
                                             removeConn(
                                                 tk,
+                                                "closed",
                                                 &mut pollIns,
                                                 &mut token2stream,
                                                 &mut token2stat,
@@ -800,8 +1408,8 @@ impl TcpForwarder {
                             }
                             Err(r) => {
                                 if r.kind() == std::io::ErrorKind::WouldBlock {
-                                    if bufstat.1 >= self.cache_size
-                                        && (bufstat.1 - nwrited) < self.cache_size
+                                    if bufstat.1 >= settings.cache_size
+                                        && (bufstat.1 - nwrited) < settings.cache_size
                                     {
                                         set_readable(
                                             &mut pollIns,
@@ -816,6 +1424,7 @@ impl TcpForwarder {
                                     drop(conn_mut);
                                     removeConn(
                                         tk,
+                                        &format!("write error: {}", r),
                                         &mut pollIns,
                                         &mut token2stream,
                                         &mut token2stat,
@@ -834,11 +1443,7 @@ impl TcpForwarder {
         }
     }
 
-    fn listen_socks5(
-        &self,
-        closed: Arc<AtomicBool>,
-        ip_matcher: IpAddrMatcher,
-    ) -> std::io::Result<()> {
+    fn listen_socks5(&self, closed: Arc<AtomicBool>) -> std::io::Result<()> {
         fn encode_socks5_reply(rep: u8, bound: SocketAddr) -> Vec<u8> {
             let mut response = Vec::with_capacity(22);
             response.push(0x05);
@@ -860,7 +1465,7 @@ impl TcpForwarder {
         }
 
         fn flush_queue(
-            stream: &mut TcpStream,
+            stream: &mut impl Write,
             queue: &mut VecDeque<(Vec<u8>, usize)>,
         ) -> io::Result<()> {
             while let Some((buf, off)) = queue.front_mut() {
@@ -1135,6 +1740,7 @@ impl TcpForwarder {
             sessions: &mut HashMap<Token, Socks5Session>,
             remote_to_client: &mut HashMap<Token, Token>,
             client_token: Token,
+            fwd: &str,
         ) {
             if let Some(mut sess) = sessions.remove(&client_token) {
                 let _ = poll.registry().deregister(&mut sess.client);
@@ -1144,21 +1750,34 @@ impl TcpForwarder {
                         let _ = poll.registry().deregister(remote);
                     }
                 }
-                let target = sess
+                let upstream = sess
                     .target_label
                     .clone()
-                    .or_else(|| sess.target.map(|a| a.to_string()))
-                    .unwrap_or_else(|| "unknown".to_string());
+                    .or_else(|| sess.target.map(|a| a.to_string()));
+                let target = upstream.clone().unwrap_or_else(|| "unknown".to_string());
                 let reason = sess
                     .close_reason
+                    .take()
                     .unwrap_or_else(|| "closed by peer".to_string());
                 let protocol = proxy_protocol_tag(sess.protocol);
                 if sess.state == Socks5SessionState::Relay {
+                    sess.conn_metrics(fwd).active.add(-1);
                     info!(
                         "{} {} relay finished to {}: up {} bytes, down {} bytes",
                         protocol, sess.client_addr, target, sess.up_bytes, sess.down_bytes
                     );
                 }
+                access_log::write(&access_log::AccessRecord {
+                    forwarder: fwd,
+                    protocol: &protocol.to_lowercase(),
+                    client: &sess.client_addr,
+                    pattern: None,
+                    upstream: upstream.as_deref(),
+                    started: sess.started,
+                    up_bytes: sess.up_bytes,
+                    down_bytes: sess.down_bytes,
+                    close_reason: &reason,
+                });
                 info!(
                     "{} session closed {}: {}",
                     protocol, sess.client_addr, reason
@@ -1168,48 +1787,131 @@ impl TcpForwarder {
         }
 
         let mut poll = Poll::new()?;
-        let mut listener = TcpListener::bind(self.local_addr)?;
+        let mut listener = Some(self.take_listener()?);
         let listener_token = Token(0);
         poll.registry()
-            .register(&mut listener, listener_token, Interest::READABLE)?;
-        info!("listen at socks5://{}", listener.local_addr().unwrap());
+            .register(listener.as_mut().unwrap(), listener_token, Interest::READABLE)?;
+        info!("listen at {}", self.local.url("socks5"));
+        let fwd = self.local.to_string();
+        let admin = admin::ForwarderHandle::register(self.local.clone(), "socks5", &poll)?;
 
         let mut events = Events::with_capacity(1024);
         let mut next_token = Token(1);
         let mut sessions: HashMap<Token, Socks5Session> = HashMap::new();
         let mut remote_to_client: HashMap<Token, Token> = HashMap::new();
         let mut to_close: Vec<Token> = vec![];
+        let mut drain_deadline: Option<time::Instant> = None;
 
         loop {
-            if closed.load(std::sync::atomic::Ordering::SeqCst) {
-                return Ok(());
+            let settings = self.settings.get();
+            let ip_matcher = match &settings.mode {
+                TcpForwarderMode::Socks5Server(ip_matcher) => ip_matcher,
+                TcpForwarderMode::Forward(_) => unreachable!(),
+            };
+            if admin.pending() {
+                for id in admin.take_close_requests() {
+                    let found = sessions
+                        .iter_mut()
+                        .find(|(_, s)| s.id == id)
+                        .map(|(tk, s)| {
+                            s.close_reason = Some("closed by admin request".to_string());
+                            *tk
+                        });
+                    if let Some(client_token) = found {
+                        close_session(
+                            &mut poll,
+                            &mut sessions,
+                            &mut remote_to_client,
+                            client_token,
+                            &fwd,
+                        );
+                    }
+                }
+                admin.publish(sessions.values().map(|s| s.info()).collect());
+            }
+            let draining = closed.load(std::sync::atomic::Ordering::SeqCst);
+            if draining {
+                if let Some(mut l) = listener.take() {
+                    let _ = poll.registry().deregister(&mut l);
+                    self.release(l);
+                    drain_deadline = self.drain_deadline(&settings);
+                    let lingers = drain_deadline.is_none() || !settings.drain_timeout.is_zero();
+                    if !sessions.is_empty() && lingers {
+                        info!(
+                            "stop listening at {}, draining {} connections",
+                            self.local.url("socks5"),
+                            sessions.len()
+                        );
+                    }
+                }
+                // bounded again by shutdown
+                if drain_deadline.is_none() {
+                    drain_deadline = self.drain_deadline(&settings);
+                }
+                if sessions.is_empty() || drain_deadline.is_some_and(|d| time::Instant::now() >= d)
+                {
+                    if !sessions.is_empty() && !settings.drain_timeout.is_zero() {
+                        info!(
+                            "drain timeout at {}, drop {} connections",
+                            self.local.url("socks5"),
+                            sessions.len()
+                        );
+                    }
+                    let remaining: Vec<Token> = sessions.keys().copied().collect();
+                    for client_token in remaining {
+                        sessions.get_mut(&client_token).unwrap().close_reason =
+                            Some("drain timeout".to_string());
+                        close_session(
+                            &mut poll,
+                            &mut sessions,
+                            &mut remote_to_client,
+                            client_token,
+                            &fwd,
+                        );
+                    }
+                    return Ok(());
+                }
             }
 
-            poll.poll(&mut events, Some(time::Duration::from_secs(1)))?;
+            let poll_timeout = if draining {
+                time::Duration::from_millis(100)
+            } else {
+                time::Duration::from_secs(1)
+            };
+            poll.poll(&mut events, Some(poll_timeout))?;
             for event in &events {
                 let tk = event.token();
                 if tk == listener_token {
                     if !event.is_readable() {
                         continue;
                     }
+                    let listener = match listener.as_mut() {
+                        Some(l) => l,
+                        None => continue,
+                    };
                     loop {
                         match listener.accept() {
                             Ok((mut stream, addr)) => {
-                                if !ip_matcher.testipaddr(&addr.ip()) {
+                                if !addr.inet().is_none_or(|a| ip_matcher.testipaddr(&a.ip())) {
                                     info!("drop PROXY connection from {}", addr);
                                     continue;
                                 }
-                                if let Some(mx) = self.max_connections {
+                                if let Some(mx) = settings.max_connections {
                                     if sessions.len() as u64 >= mx {
                                         info!("drop PROXY connection from {} for quota", addr);
                                         continue;
                                     }
                                 }
+                                if let Stream::Tcp(s) = &stream {
+                                    if let Err(e) = socket_options::accepted(s, &settings.listen_socket) {
+                                        info!("fail to set socket options of {}: {}", addr, e);
+                                    }
+                                }
                                 let ctk = nextToken(&mut next_token);
                                 poll.registry()
                                     .register(&mut stream, ctk, Interest::READABLE)?;
-                                sessions.insert(ctk, Socks5Session::new(stream, addr));
                                 info!("accept PROXY connection from {}", addr);
+                                sessions.insert(ctk, Socks5Session::new(stream, addr));
                                 info!("PROXY opened connections: {}", sessions.len());
                             }
                             Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
@@ -1241,7 +1943,7 @@ impl TcpForwarder {
                                     break;
                                 }
                                 Ok(n) => {
-                                    sess.down_bytes += n as u64;
+                                    sess.transferred(&fwd, metrics::BYTES_OUT_TOTAL, &buf[0..n]);
                                     sess.r2c_queue.push_back((buf[0..n].to_vec(), 0));
                                 }
                                 Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
@@ -1256,6 +1958,16 @@ impl TcpForwarder {
                     if event.is_writable() {
                         if sess.state == Socks5SessionState::Connecting {
                             let connect_err = sess.remote.as_mut().unwrap().take_error()?;
+                            let upstream = sess.upstream_label();
+                            if connect_err.is_some() {
+                                connect_failed(&fwd, &upstream);
+                            } else if let Some(start) = sess.connect_start.take() {
+                                metrics::observe(
+                                    metrics::CONNECT_DURATION_SECONDS,
+                                    &[("forwarder", fwd.as_str()), ("upstream", upstream.as_str())],
+                                    start.elapsed(),
+                                );
+                            }
                             if let Some(err) = connect_err {
                                 match sess.protocol {
                                     ProxyProtocol::Socks5 => {
@@ -1301,6 +2013,13 @@ impl TcpForwarder {
                                 }
                                 sess.state = Socks5SessionState::Relay;
                                 let protocol = proxy_protocol_tag(sess.protocol);
+                                let labels = [
+                                    ("forwarder", fwd.as_str()),
+                                    ("rule", protocol),
+                                    ("upstream", upstream.as_str()),
+                                ];
+                                metrics::counter_add(metrics::CONNECTIONS_TOTAL, &labels, 1);
+                                sess.conn_metrics(&fwd).active.add(1);
                                 info!(
                                     "{} {} relay established: client -> {} (bound {})",
                                     protocol,
@@ -1313,7 +2032,7 @@ impl TcpForwarder {
                                 );
                                 if !sess.client_in.is_empty() {
                                     let extra = std::mem::take(&mut sess.client_in);
-                                    sess.up_bytes += extra.len() as u64;
+                                    sess.transferred(&fwd, metrics::BYTES_IN_TOTAL, &extra);
                                     sess.c2r_queue.push_back((extra, 0));
                                 }
                             }
@@ -1366,7 +2085,7 @@ impl TcpForwarder {
                                 }
                                 Ok(n) => {
                                     if sess.state == Socks5SessionState::Relay {
-                                        sess.up_bytes += n as u64;
+                                        sess.transferred(&fwd, metrics::BYTES_IN_TOTAL, &buf[0..n]);
                                         sess.c2r_queue.push_back((buf[0..n].to_vec(), 0));
                                     } else {
                                         sess.client_in.extend_from_slice(&buf[0..n]);
@@ -1432,13 +2151,17 @@ impl TcpForwarder {
                                             protocol, sess.client_addr, target_label
                                         );
                                         if !upstream.is_empty() {
-                                            sess.up_bytes += upstream.len() as u64;
+                                            sess.transferred(
+                                                &fwd,
+                                                metrics::BYTES_IN_TOTAL,
+                                                &upstream,
+                                            );
                                             sess.c2r_queue.push_back((upstream, 0));
                                             if sess.protocol == ProxyProtocol::HttpForward {
                                                 sess.client_in.clear();
                                             }
                                         }
-                                        match TcpStream::connect(target) {
+                                        match socket_options::connect(target, &settings.upstream_socket) {
                                             Ok(mut remote) => {
                                                 let rtk = nextToken(&mut next_token);
                                                 poll.registry().register(
@@ -1448,10 +2171,13 @@ impl TcpForwarder {
                                                 )?;
                                                 sess.remote_token = Some(rtk);
                                                 sess.remote = Some(remote);
+                                                sess.connect_start = Some(time::Instant::now());
+                                                sess.start_capture(settings.capture.as_ref());
                                                 remote_to_client.insert(rtk, client_token);
                                                 sess.state = Socks5SessionState::Connecting;
                                             }
                                             Err(err) => {
+                                                connect_failed(&fwd, &target.to_string());
                                                 sess.r2c_queue.push_back((
                                                     b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n"
                                                         .to_vec(),
@@ -1519,7 +2245,7 @@ impl TcpForwarder {
                                             "{} {} CONNECT request to {}",
                                             protocol, sess.client_addr, target_label
                                         );
-                                        match TcpStream::connect(target) {
+                                        match socket_options::connect(target, &settings.upstream_socket) {
                                             Ok(mut remote) => {
                                                 let rtk = nextToken(&mut next_token);
                                                 poll.registry().register(
@@ -1529,10 +2255,13 @@ impl TcpForwarder {
                                                 )?;
                                                 sess.remote_token = Some(rtk);
                                                 sess.remote = Some(remote);
+                                                sess.connect_start = Some(time::Instant::now());
+                                                sess.start_capture(settings.capture.as_ref());
                                                 remote_to_client.insert(rtk, client_token);
                                                 sess.state = Socks5SessionState::Connecting;
                                             }
                                             Err(err) => {
+                                                connect_failed(&fwd, &target.to_string());
                                                 sess.r2c_queue.push_back((
                                                     encode_socks5_reply(
                                                         0x05,
@@ -1603,6 +2332,7 @@ impl TcpForwarder {
                     &mut sessions,
                     &mut remote_to_client,
                     client_token,
+                    &fwd,
                 );
             }
         }
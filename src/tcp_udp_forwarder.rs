@@ -1,71 +1,237 @@
-use crate::forward_config::ForwardSessionConfig;
+use crate::endpoint::ToEndpoint;
+use crate::forward_config::{ForwardSessionConfig, TcpMode};
 use crate::tcp_forwarder::TcpForwarder;
 use crate::udp_forwarder::UdpForwarder;
 use std::error::Error;
-use std::net::ToSocketAddrs;
 use std::result::Result;
-use std::sync::{Arc, atomic::AtomicBool};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Stops listening and returns once the live connections are drained.
+pub type CloseHandler = Box<dyn FnOnce() + Send>;
+
+type Running = (Arc<AtomicBool>, JoinHandle<()>);
+
+/// Loops a reload closed, which serve their connections until these end.
+pub struct Draining {
+    unbounded: Vec<Arc<AtomicBool>>,
+    thread: JoinHandle<()>,
+}
+
+impl Draining {
+    fn start(unbounded: Vec<Arc<AtomicBool>>, running: Vec<Running>) -> Draining {
+        for flag in &unbounded {
+            flag.store(true, Ordering::SeqCst);
+        }
+        Draining {
+            unbounded,
+            thread: std::thread::spawn(close_handler(running)),
+        }
+    }
+
+    /// Give the connections left `drain_timeout` from now, as on shutdown.
+    pub fn bound(&self) {
+        for flag in &self.unbounded {
+            flag.store(false, Ordering::SeqCst);
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    pub fn join(self) {
+        self.thread.join().unwrap_or_default();
+    }
+}
+
+/// The forwarder of one protocol and, once it listens, its loop.
+struct Half<F> {
+    forwarder: Arc<F>,
+    running: Option<Running>,
+}
+
+impl<F: Send + Sync + 'static> Half<F> {
+    fn new(forwarder: F) -> Self {
+        Half {
+            forwarder: Arc::new(forwarder),
+            running: None,
+        }
+    }
+
+    fn listen(&mut self, run: fn(&F, Arc<AtomicBool>)) {
+        if self.running.is_some() {
+            return;
+        }
+        let closed = Arc::new(AtomicBool::new(false));
+        let forwarder = self.forwarder.clone();
+        let loop_closed = closed.clone();
+        let thread = std::thread::spawn(move || run(&forwarder, loop_closed));
+        self.running = Some((closed, thread));
+    }
+}
+
+fn run_tcp(tcp: &TcpForwarder, closed: Arc<AtomicBool>) {
+    if let Err(e) = tcp.listen(closed) {
+        log::error!("tcp forwarder stopped: {}", e);
+    }
+}
+
+fn run_udp(udp: &UdpForwarder, closed: Arc<AtomicBool>) {
+    if let Err(e) = udp.listen(closed) {
+        log::error!("udp forwarder stopped: {}", e);
+    }
+}
+
+fn close_handler(running: Vec<Running>) -> CloseHandler {
+    Box::new(move || {
+        for (closed, _) in &running {
+            closed.store(true, Ordering::SeqCst);
+        }
+        for (_, thread) in running {
+            thread.join().unwrap_or_default();
+        }
+    })
+}
 
-#[derive(Clone)]
 pub struct TcpUdpForwarder {
-    udp: Arc<Option<UdpForwarder>>,
-    tcpe: Arc<Option<TcpForwarder>>,
+    udp: Option<Half<UdpForwarder>>,
+    tcpe: Option<Half<TcpForwarder>>,
+    tcp_mode: TcpMode,
 }
 
 impl TcpUdpForwarder {
+    /// The forwarders `config` enables, with their listeners bound.
     pub fn from<T>(config: &ForwardSessionConfig<T>) -> Result<TcpUdpForwarder, Box<dyn Error>>
     where
-        T: ToSocketAddrs,
+        T: ToEndpoint,
     {
-        assert!(config.enable_tcp || config.enable_udp);
+        if !config.enable_tcp && !config.enable_udp {
+            return Err(Box::from("both enable_tcp and enable_udp are false"));
+        }
 
         let mut udpi = None;
         let mut tcpei = None;
         if config.enable_tcp {
-            tcpei = Some(TcpForwarder::from(&config).unwrap());
+            tcpei = Some(Half::new(TcpForwarder::from(config)?));
         }
         if config.enable_udp {
-            udpi = Some(UdpForwarder::from(&config).unwrap());
+            udpi = Some(Half::new(UdpForwarder::from(config)?));
         }
 
         Ok(TcpUdpForwarder {
-            udp: Arc::from(udpi),
-            tcpe: Arc::from(tcpei),
+            udp: udpi,
+            tcpe: tcpei,
+            tcp_mode: config.tcp_mode.clone(),
         })
     }
 
-    pub fn listen(&self) -> Box<dyn FnOnce() -> ()> {
-        let mut tte = None;
-        let mut tu = None;
-        let closed = Arc::new(AtomicBool::from(false));
-        if self.tcpe.is_some() {
-            let m = self.tcpe.clone();
-            let tcp_closed = closed.clone();
-            tte = Some(std::thread::spawn(move || match m.as_ref() {
-                Some(l) => {
-                    l.listen(tcp_closed).unwrap_or_default();
-                }
-                None => {}
-            }));
-        }
-        if self.udp.is_some() {
-            let m = self.udp.clone();
-            let udp_closed = closed.clone();
-            tu = Some(std::thread::spawn(move || match m.as_ref() {
-                Some(l) => l.listen(udp_closed).unwrap_or_default(),
-                None => {}
-            }));
-        }
-
-        let close_handler = move || {
-            closed.store(true, std::sync::atomic::Ordering::SeqCst);
-            if tte.is_some() {
-                tte.unwrap().join().unwrap_or_default();
+    /// Apply a changed configuration of the same listener. Routing rules,
+    /// access rules and limits change in place. A protocol whose `enable_tcp`,
+    /// `enable_udp` or `tcp_mode` changed gets a new listener, a TCP one takes
+    /// the old listening socket over, and the old ones drain like those of
+    /// `retire`. Nothing changes when an error is returned.
+    pub fn update<T>(
+        &mut self,
+        config: &ForwardSessionConfig<T>,
+    ) -> Result<Option<Draining>, Box<dyn Error>>
+    where
+        T: ToEndpoint,
+    {
+        if !config.enable_tcp && !config.enable_udp {
+            return Err(Box::from("both enable_tcp and enable_udp are false"));
+        }
+        let restart_tcp =
+            config.enable_tcp != self.tcpe.is_some() || config.tcp_mode != self.tcp_mode;
+        let restart_udp = config.enable_udp != self.udp.is_some();
+
+        // everything that may fail before anything is changed
+        let new_udp = if restart_udp && config.enable_udp {
+            Some(UdpForwarder::from(config)?)
+        } else {
+            None
+        };
+        let mut updates: Vec<Box<dyn FnOnce() + '_>> = vec![];
+        if let Some(udp) = self.udp.as_ref().filter(|_| !restart_udp) {
+            updates.push(Box::new(udp.forwarder.prepare_update(config)?));
+        }
+        if let Some(tcp) = self.tcpe.as_ref().filter(|_| !restart_tcp) {
+            updates.push(Box::new(tcp.forwarder.prepare_update(config)?));
+        }
+        let new_tcp = match &self.tcpe {
+            _ if !restart_tcp || !config.enable_tcp => None,
+            Some(tcp) => Some(tcp.forwarder.take_over(config)?),
+            None => Some(TcpForwarder::from(config)?),
+        };
+
+        for apply in updates {
+            apply();
+        }
+        let mut unbounded = vec![];
+        let mut replaced = vec![];
+        if restart_tcp {
+            if let Some(old) = std::mem::replace(&mut self.tcpe, new_tcp.map(Half::new)) {
+                unbounded.push(old.forwarder.drain_unbounded().clone());
+                replaced.extend(old.running);
             }
-            if tu.is_some() {
-                tu.unwrap().join().unwrap_or_default();
+        }
+        if restart_udp {
+            if let Some(old) = std::mem::replace(&mut self.udp, new_udp.map(Half::new)) {
+                unbounded.push(old.forwarder.drain_unbounded().clone());
+                replaced.extend(old.running);
             }
-        };
-        return Box::new(close_handler);
+        }
+        self.tcp_mode = config.tcp_mode.clone();
+        self.listen();
+        if replaced.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(Draining::start(unbounded, replaced)))
+        }
+    }
+
+    /// Start the loops of the listeners that are not running yet.
+    pub fn listen(&mut self) {
+        if let Some(tcp) = self.tcpe.as_mut() {
+            tcp.listen(run_tcp);
+        }
+        if let Some(udp) = self.udp.as_mut() {
+            udp.listen(run_udp);
+        }
+    }
+
+    /// Whether no loop that was started has stopped.
+    pub fn is_alive(&self) -> bool {
+        let tcp = self.tcpe.as_ref().and_then(|h| h.running.as_ref());
+        let udp = self.udp.as_ref().and_then(|h| h.running.as_ref());
+        tcp.into_iter()
+            .chain(udp)
+            .all(|(_, thread)| !thread.is_finished())
+    }
+
+    /// Stop listening and serve the connections until they end, as when a
+    /// reload removed this forwarder.
+    pub fn retire(self) -> Draining {
+        let unbounded = vec![
+            self.tcpe.as_ref().map(|h| h.forwarder.drain_unbounded().clone()),
+            self.udp.as_ref().map(|h| h.forwarder.drain_unbounded().clone()),
+        ];
+        let running = vec![
+            self.tcpe.and_then(|h| h.running),
+            self.udp.and_then(|h| h.running),
+        ];
+        Draining::start(
+            unbounded.into_iter().flatten().collect(),
+            running.into_iter().flatten().collect(),
+        )
+    }
+
+    pub fn close(self) -> CloseHandler {
+        let running = vec![
+            self.tcpe.and_then(|h| h.running),
+            self.udp.and_then(|h| h.running),
+        ];
+        close_handler(running.into_iter().flatten().collect())
     }
 }
@@ -0,0 +1,455 @@
+//! Receiving and sending several datagrams per syscall, with `recvmmsg` and
+//! `sendmmsg` on Linux and one `recv_from`/`send_to` each elsewhere.
+//!
+//! On Linux the local address a datagram was sent to is taken from its
+//! `IP_PKTINFO`/`IPV6_PKTINFO` message, and a reply can be sent from it.
+use mio::net::UdpSocket;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+/// Datagrams received or sent by one call at most.
+pub const BATCH: usize = 32;
+
+/// Large enough for any UDP payload. A batch is allocated zeroed, so the
+/// system commits its pages only as far as datagrams fill them.
+const SLOT_SIZE: usize = 65535;
+
+/// Bytes of recycled vectors a `PacketPool` keeps at most.
+const POOL_BYTES: usize = 1 << 20;
+
+/// Room for the control message of a datagram, aligned for `cmsghdr`.
+#[cfg(target_os = "linux")]
+type Control = [u64; 8];
+
+/// A datagram to send: where to, `None` where the socket is connected, the
+/// local address to send it from, `None` for the one the kernel picks, and
+/// the payload.
+pub type Outgoing<'a> = (Option<SocketAddr>, Option<IpAddr>, &'a [u8]);
+
+/// A received datagram's slot, length, sender and the local address it was
+/// sent to when the socket reports it.
+type Received = (usize, usize, SocketAddr, Option<IpAddr>);
+
+/// The message headers of a batch and the names, buffers and control messages
+/// they point to, allocated once and pointed again by every call.
+#[cfg(target_os = "linux")]
+struct Headers {
+    names: Vec<socket2::SockAddrStorage>,
+    iovecs: Vec<libc::iovec>,
+    controls: Vec<Control>,
+    msgs: Vec<libc::mmsghdr>,
+}
+
+#[cfg(target_os = "linux")]
+impl Headers {
+    fn new() -> Self {
+        let iovec = libc::iovec {
+            iov_base: std::ptr::null_mut(),
+            iov_len: 0,
+        };
+        Headers {
+            names: (0..BATCH)
+                .map(|_| socket2::SockAddrStorage::zeroed())
+                .collect(),
+            iovecs: vec![iovec; BATCH],
+            controls: vec![[0; 8]; BATCH],
+            msgs: vec![unsafe { std::mem::zeroed() }; BATCH],
+        }
+    }
+}
+
+/// Buffers of a batch of received datagrams, reused by every `recv`.
+pub struct RecvBatch {
+    /// `BATCH` slots of `SLOT_SIZE` bytes.
+    bufs: Vec<u8>,
+    received: Vec<Received>,
+    #[cfg(target_os = "linux")]
+    headers: Headers,
+}
+
+impl RecvBatch {
+    pub fn new() -> Self {
+        RecvBatch {
+            bufs: vec![0; BATCH * SLOT_SIZE],
+            received: Vec::with_capacity(BATCH),
+            #[cfg(target_os = "linux")]
+            headers: Headers::new(),
+        }
+    }
+
+    /// Receive the datagrams `socket` has ready, up to `BATCH`. Returns how
+    /// many, or `WouldBlock` when there is none.
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.received.clear();
+        self.recv_slots(socket)?;
+        Ok(self.received.len())
+    }
+
+    /// The `i`th datagram of the last `recv` and where it came from.
+    pub fn get(&self, i: usize) -> (&[u8], SocketAddr) {
+        let (slot, len, from, _) = self.received[i];
+        (&self.bufs[slot * SLOT_SIZE..][..len], from)
+    }
+
+    /// The local address the `i`th datagram was sent to, if the socket
+    /// was set up with `socket_options::recv_pktinfo`.
+    pub fn local(&self, i: usize) -> Option<IpAddr> {
+        self.received[i].3
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_slots(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        use socket2::{SockAddr, SockAddrStorage};
+        use std::net::Ipv4Addr;
+        use std::os::unix::io::AsRawFd;
+
+        let h = &mut self.headers;
+        for (i, slot) in self.bufs.chunks_mut(SLOT_SIZE).enumerate() {
+            h.iovecs[i] = libc::iovec {
+                iov_base: slot.as_mut_ptr() as *mut libc::c_void,
+                iov_len: slot.len(),
+            };
+            let msg = &mut h.msgs[i].msg_hdr;
+            msg.msg_name = &mut h.names[i] as *mut SockAddrStorage as *mut libc::c_void;
+            msg.msg_namelen = h.names[i].size_of();
+            msg.msg_iov = &mut h.iovecs[i];
+            msg.msg_iovlen = 1;
+            msg.msg_control = h.controls[i].as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = std::mem::size_of::<Control>() as _;
+            msg.msg_flags = 0;
+        }
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                h.msgs.as_mut_ptr(),
+                BATCH as _,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for i in 0..n as usize {
+            let msg = &h.msgs[i];
+            let name = std::mem::replace(&mut h.names[i], SockAddrStorage::zeroed());
+            let from = unsafe { SockAddr::new(name, msg.msg_hdr.msg_namelen) };
+            // a connected socket may leave the name empty
+            let from = from
+                .as_socket()
+                .or_else(|| socket.peer_addr().ok())
+                .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+            self.received
+                .push((i, msg.msg_len as usize, from, pktinfo(&msg.msg_hdr)));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn recv_slots(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        for (i, slot) in self.bufs.chunks_mut(SLOT_SIZE).enumerate() {
+            match socket.recv_from(slot) {
+                Ok((len, from)) => self.received.push((i, len, from, None)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && !self.received.is_empty() => {
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for RecvBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Message headers of a batch of datagrams to send, reused by every `send`.
+pub struct SendBatch {
+    #[cfg(target_os = "linux")]
+    headers: Headers,
+}
+
+impl SendBatch {
+    pub fn new() -> Self {
+        SendBatch {
+            #[cfg(target_os = "linux")]
+            headers: Headers::new(),
+        }
+    }
+
+    /// Send the first `BATCH` of `datagrams`. Returns how many were sent, or
+    /// the error of the first.
+    #[cfg(target_os = "linux")]
+    pub fn send<'a, I>(&mut self, socket: &UdpSocket, datagrams: I) -> io::Result<usize>
+    where
+        I: IntoIterator<Item = Outgoing<'a>>,
+    {
+        use socket2::{SockAddr, SockAddrStorage};
+        use std::os::unix::io::AsRawFd;
+
+        let h = &mut self.headers;
+        let mut count = 0;
+        for (i, (to, from, data)) in datagrams.into_iter().take(BATCH).enumerate() {
+            h.iovecs[i] = libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            };
+            let msg = &mut h.msgs[i].msg_hdr;
+            *msg = unsafe { std::mem::zeroed() };
+            if let Some(to) = to {
+                let to = SockAddr::from(to);
+                msg.msg_namelen = to.len();
+                h.names[i] = to.as_storage();
+                msg.msg_name = &mut h.names[i] as *mut SockAddrStorage as *mut libc::c_void;
+            }
+            msg.msg_iov = &mut h.iovecs[i];
+            msg.msg_iovlen = 1;
+            if let Some(from) = from {
+                set_pktinfo(msg, &mut h.controls[i], from);
+            }
+            count = i + 1;
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+        let n = unsafe { libc::sendmmsg(socket.as_raw_fd(), h.msgs.as_mut_ptr(), count as _, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    /// Send the first `BATCH` of `datagrams` from the address the kernel
+    /// picks. Returns how many were sent, or the error of the first.
+    #[cfg(not(target_os = "linux"))]
+    pub fn send<'a, I>(&mut self, socket: &UdpSocket, datagrams: I) -> io::Result<usize>
+    where
+        I: IntoIterator<Item = Outgoing<'a>>,
+    {
+        let mut sent = 0;
+        for (to, _, buf) in datagrams.into_iter().take(BATCH) {
+            let result = match to {
+                Some(to) => socket.send_to(buf, to),
+                None => socket.send(buf),
+            };
+            match result {
+                Ok(_) => sent += 1,
+                Err(_) if sent > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sent)
+    }
+}
+
+impl Default for SendBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The destination address in the `IP_PKTINFO` or `IPV6_PKTINFO` message
+/// of a received datagram.
+#[cfg(target_os = "linux")]
+fn pktinfo(msg: &libc::msghdr) -> Option<IpAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+        match (header.cmsg_level, header.cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = unsafe { std::ptr::read_unaligned(data as *const libc::in_pktinfo) };
+                return Some(Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)).into());
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info = unsafe { std::ptr::read_unaligned(data as *const libc::in6_pktinfo) };
+                return Some(Ipv6Addr::from(info.ipi6_addr.s6_addr).into());
+            }
+            _ => {}
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+    None
+}
+
+/// Fill `control` with a message to send a datagram from `from`.
+#[cfg(target_os = "linux")]
+fn set_pktinfo(msg: &mut libc::msghdr, control: &mut Control, from: IpAddr) {
+    let (level, kind, len) = match from {
+        IpAddr::V4(_) => (
+            libc::IPPROTO_IP,
+            libc::IP_PKTINFO,
+            std::mem::size_of::<libc::in_pktinfo>(),
+        ),
+        IpAddr::V6(_) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_PKTINFO,
+            std::mem::size_of::<libc::in6_pktinfo>(),
+        ),
+    };
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(len as _) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(msg);
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = kind;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len as _) as _;
+        let data = libc::CMSG_DATA(cmsg);
+        match from {
+            IpAddr::V4(ip) => {
+                let mut info: libc::in_pktinfo = std::mem::zeroed();
+                info.ipi_spec_dst.s_addr = u32::from(ip).to_be();
+                std::ptr::write_unaligned(data as *mut libc::in_pktinfo, info);
+            }
+            IpAddr::V6(ip) => {
+                let mut info: libc::in6_pktinfo = std::mem::zeroed();
+                info.ipi6_addr.s6_addr = ip.octets();
+                std::ptr::write_unaligned(data as *mut libc::in6_pktinfo, info);
+            }
+        }
+    }
+}
+
+/// Vectors of sent datagrams, to copy the next received ones into without
+/// allocating.
+pub struct PacketPool {
+    free: Vec<Vec<u8>>,
+    /// Capacity of the vectors in `free`, at most `POOL_BYTES`.
+    bytes: usize,
+}
+
+impl PacketPool {
+    pub fn new() -> Self {
+        PacketPool {
+            free: vec![],
+            bytes: 0,
+        }
+    }
+
+    /// A vector holding a copy of `data`.
+    pub fn copy(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut buf = match self.free.pop() {
+            Some(buf) => {
+                self.bytes -= buf.capacity();
+                buf
+            }
+            None => vec![],
+        };
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    /// Give back a vector that is no longer needed.
+    pub fn recycle(&mut self, mut buf: Vec<u8>) {
+        if self.bytes + buf.capacity() <= POOL_BYTES {
+            self.bytes += buf.capacity();
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+}
+
+impl Default for PacketPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn recv_all(
+        batch: &mut RecvBatch,
+        socket: &UdpSocket,
+        count: usize,
+    ) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut datagrams = vec![];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while datagrams.len() < count && Instant::now() < deadline {
+            match batch.recv(socket) {
+                Ok(n) => {
+                    for i in 0..n {
+                        let (data, from) = batch.get(i);
+                        datagrams.push((data.to_vec(), from));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(5))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        datagrams
+    }
+
+    #[test]
+    fn batches_keep_order_and_addresses() {
+        let a = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let to = Some(b.local_addr().unwrap());
+        let payloads: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; i as usize]).collect();
+        let datagrams: Vec<_> = payloads.iter().map(|p| (to, None, p.as_slice())).collect();
+        let mut batch = SendBatch::new();
+        let mut sent = 0;
+        while sent < datagrams.len() {
+            sent += batch.send(&a, datagrams[sent..].iter().copied()).unwrap();
+        }
+
+        let mut recv_batch = RecvBatch::new();
+        let received = recv_all(&mut recv_batch, &b, payloads.len());
+        assert_eq!(received.len(), payloads.len());
+        for ((data, from), payload) in received.iter().zip(&payloads) {
+            assert_eq!(data, payload);
+            assert_eq!(*from, a.local_addr().unwrap());
+        }
+
+        // connected sockets send without an address
+        a.connect(b.local_addr().unwrap()).unwrap();
+        let two: [Outgoing; 2] = [(None, None, b"one"), (None, None, b"two")];
+        assert_eq!(batch.send(&a, two.iter().copied()).unwrap(), 2);
+        let received = recv_all(&mut recv_batch, &b, 2);
+        assert_eq!(received[1].0, b"two");
+        assert!(recv_batch.recv(&b).is_err());
+    }
+
+    #[test]
+    fn datagrams_of_any_size_are_received() {
+        let a = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let to = Some(b.local_addr().unwrap());
+        // the largest payload of IPv4
+        let large = vec![1; 65507];
+        let datagrams: [Outgoing; 2] = [(to, None, &large), (to, None, b"small")];
+        let mut send_batch = SendBatch::new();
+        assert_eq!(send_batch.send(&a, datagrams.iter().copied()).unwrap(), 2);
+
+        let mut batch = RecvBatch::new();
+        let received = recv_all(&mut batch, &b, 2);
+        assert_eq!(received[0].0, large);
+        assert_eq!(received[1].0, b"small");
+    }
+
+    #[test]
+    fn pool_reuses_vectors() {
+        let mut pool = PacketPool::new();
+        let buf = pool.copy(&[1; 100]);
+        let ptr = buf.as_ptr();
+        pool.recycle(buf);
+        let buf = pool.copy(b"ab");
+        assert_eq!(buf, b"ab");
+        assert_eq!(buf.as_ptr(), ptr);
+
+        // no more than `POOL_BYTES` are kept
+        pool.recycle(Vec::with_capacity(POOL_BYTES));
+        pool.recycle(buf);
+        assert_eq!(pool.free.len(), 1);
+        assert_eq!(pool.bytes, POOL_BYTES);
+    }
+}
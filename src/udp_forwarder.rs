@@ -1,29 +1,60 @@
 extern crate mio;
 
 use log::info;
-use queues::*;
 use std::cmp;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::collections::hash_map::Entry;
 use std::error::Error;
 use std::io;
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::time;
 
+use crate::access_log;
+use crate::address_matcher::IpAddrMatcher;
+use crate::admin;
+use crate::capture::Capture;
 use crate::connection_plugin::{ConnectionPlugin, RegexMultiplexer};
-use crate::forward_config::ForwardSessionConfig;
+use crate::endpoint::{Endpoint, ToEndpoint};
+use crate::forward_config::{DropPolicy, ForwardSessionConfig, SocketConfig, UdpQueueConfig};
+use crate::metrics;
+use crate::socket_options;
+use crate::systemd;
+use crate::udp_batch::{PacketPool, RecvBatch, SendBatch};
+use crate::unmatched_log::UnmatchedLog;
 use crate::utils;
 
 use mio::net::UdpSocket;
 use mio::{Events, Interest, Poll, Token};
 
-pub struct UdpForwarder {
-    bindAddr: SocketAddr,
+struct UdpSettings {
     plugin: Box<dyn ConnectionPlugin + Send + Sync>,
     max_connections: Option<u64>,
+    drain_timeout: time::Duration,
+    capture: Option<Capture>,
+    unmatched_log: Option<UnmatchedLog>,
+    upstream_socket: SocketConfig,
+    /// `upstream_socket` with the options of each rule over it.
+    rule_sockets: Vec<SocketConfig>,
+    idle_timeout: time::Duration,
+    /// `idle_timeout` of each rule.
+    rule_idle_timeouts: Vec<time::Duration>,
+    evict_lru: bool,
+    queue: UdpQueueConfig,
+}
+
+pub struct UdpForwarder {
+    bindAddr: SocketAddr,
+    settings: utils::Shared<UdpSettings>,
+    /// Bound by `from`, until `listen` takes it.
+    socket: Mutex<Option<std::net::UdpSocket>>,
+    /// Set while a closed loop keeps its sessions until they idle out rather
+    /// than flushing them for `drain_timeout`, as when a reload removed it.
+    drain_unbounded: Arc<AtomicBool>,
 }
 
 fn next(token: &mut Token) -> Token {
@@ -32,6 +63,265 @@ fn next(token: &mut Token) -> Token {
     ans
 }
 
+/// Bookkeeping of a UDP session for the admin API.
+struct SessionMeta {
+    id: u64,
+    started: time::Instant,
+    up_bytes: u64,
+    down_bytes: u64,
+    /// Datagrams either way dropped by a full queue.
+    dropped: u64,
+    bytes_in: metrics::Series,
+    bytes_out: metrics::Series,
+}
+
+/// Handles on the series a UDP loop updates, looked up once.
+struct LoopMetrics {
+    sessions_active: metrics::Series,
+    /// First packets that matched no rule and that matched one.
+    sniff: [metrics::Series; 2],
+    /// Datagrams and bytes dropped on the way to upstreams.
+    dropped_upstream: [metrics::Series; 2],
+    /// Datagrams and bytes dropped on the way to clients.
+    dropped_client: [metrics::Series; 2],
+}
+
+impl LoopMetrics {
+    fn new(fwd: &str) -> Self {
+        let sniff = |result| {
+            metrics::series(
+                metrics::SNIFF_TOTAL,
+                &[("forwarder", fwd), ("protocol", "udp"), ("result", result)],
+            )
+        };
+        let dropped = |direction| {
+            let labels = [("forwarder", fwd), ("direction", direction)];
+            [
+                metrics::series(metrics::UDP_DROPPED_TOTAL, &labels),
+                metrics::series(metrics::UDP_DROPPED_BYTES_TOTAL, &labels),
+            ]
+        };
+        LoopMetrics {
+            sessions_active: metrics::series(metrics::UDP_SESSIONS_ACTIVE, &[("forwarder", fwd)]),
+            sniff: [sniff("no_match"), sniff("match")],
+            dropped_upstream: dropped("upstream"),
+            dropped_client: dropped("client"),
+        }
+    }
+}
+
+/// The byte counters of a session to `upstream` chosen by `rule`.
+fn session_bytes(fwd: &str, rule: &str, upstream: &SocketAddr) -> [metrics::Series; 2] {
+    if !metrics::enabled() {
+        return Default::default();
+    }
+    let upstream = upstream.to_string();
+    let labels = |name| {
+        metrics::series(
+            name,
+            &[
+                ("forwarder", fwd),
+                ("protocol", "udp"),
+                ("rule", rule),
+                ("upstream", upstream.as_str()),
+            ],
+        )
+    };
+    [labels(metrics::BYTES_IN_TOTAL), labels(metrics::BYTES_OUT_TOTAL)]
+}
+
+/// Datagrams waiting for a socket to become writable, and their bytes.
+struct Backlog<T> {
+    queue: VecDeque<(T, Vec<u8>)>,
+    bytes: usize,
+}
+
+impl<T> Backlog<T> {
+    fn new() -> Self {
+        Backlog {
+            queue: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn front(&self) -> Option<&(T, Vec<u8>)> {
+        self.queue.front()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &(T, Vec<u8>)> {
+        self.queue.iter()
+    }
+
+    fn pop_front(&mut self) -> Option<(T, Vec<u8>)> {
+        let item = self.queue.pop_front()?;
+        self.bytes -= item.1.len();
+        Some(item)
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+        self.bytes = 0;
+    }
+
+    /// Queue a datagram if it fits in `packets` and `bytes`, otherwise `drop`
+    /// decides what is given up for it. Returns the dropped datagrams.
+    fn push(
+        &mut self,
+        to: T,
+        buf: Vec<u8>,
+        packets: usize,
+        bytes: usize,
+        drop: DropPolicy,
+    ) -> Vec<(T, Vec<u8>)> {
+        let fits = |b: &Self| b.len() < packets && b.bytes + buf.len() <= bytes;
+        let mut dropped = vec![];
+        if drop == DropPolicy::Oldest && buf.len() <= bytes {
+            while !fits(self) && !self.is_empty() {
+                dropped.extend(self.pop_front());
+            }
+        }
+        if fits(self) {
+            self.bytes += buf.len();
+            self.queue.push_back((to, buf));
+        } else {
+            dropped.push((to, buf));
+        }
+        dropped
+    }
+}
+
+/// When each session was last active, in unique microseconds, and when it
+/// goes idle: by activity to evict the least recently used, by deadline so
+/// that expiring looks at expired sessions only.
+#[derive(Default)]
+struct Expiry {
+    by_activity: BTreeMap<u128, Token>,
+    by_deadline: BTreeSet<(u128, Token)>,
+    /// last activity and idle timeout of each session
+    sessions: HashMap<Token, (u128, u128)>,
+}
+
+impl Expiry {
+    fn insert(&mut self, t: Token, at: u128, idle: u128) {
+        self.by_activity.insert(at, t);
+        self.by_deadline.insert((at + idle, t));
+        self.sessions.insert(t, (at, idle));
+    }
+
+    fn remove(&mut self, t: Token) -> Option<(u128, u128)> {
+        let (at, idle) = self.sessions.remove(&t)?;
+        self.by_activity.remove(&at);
+        self.by_deadline.remove(&(at + idle, t));
+        Some((at, idle))
+    }
+
+    /// Record activity of `t` at `at`.
+    fn touch(&mut self, t: Token, at: u128) {
+        if let Some((_, idle)) = self.remove(t) {
+            self.insert(t, at, idle);
+        }
+    }
+
+    /// Give `t` the idle timeout of the rule it matched.
+    fn set_idle(&mut self, t: Token, idle: u128) {
+        if let Some((at, _)) = self.remove(t) {
+            self.insert(t, at, idle);
+        }
+    }
+
+    fn latest_activity(&self) -> Option<u128> {
+        self.by_activity.keys().next_back().copied()
+    }
+
+    fn least_recent(&self) -> impl Iterator<Item = &Token> {
+        self.by_activity.values()
+    }
+
+    /// Sessions idle for their timeout at `now`.
+    fn expired(&self, now: u128) -> impl Iterator<Item = Token> + '_ {
+        self.by_deadline
+            .range(..=(now, Token(usize::MAX)))
+            .map(|(_, t)| *t)
+    }
+}
+
+/// Count datagrams a full queue dropped, to clients or to upstreams.
+fn count_dropped<T>(direction: &str, series: &[metrics::Series; 2], dropped: &[(T, Vec<u8>)]) {
+    if dropped.is_empty() {
+        return;
+    }
+    let bytes: usize = dropped.iter().map(|d| d.1.len()).sum();
+    log::debug!("drop {} datagrams of {} bytes to {}", dropped.len(), bytes, direction);
+    series[0].add(dropped.len() as i64);
+    series[1].add(bytes as i64);
+}
+
+fn finish_session(
+    fwd: &str,
+    client: SocketAddr,
+    dst: Option<&(SocketAddr, String)>,
+    meta: &SessionMeta,
+    reason: &str,
+) {
+    if meta.dropped > 0 {
+        info!(
+            "UDP session of {} dropped {} datagrams on full queues",
+            client, meta.dropped
+        );
+    }
+    let upstream = dst.map(|d| d.0.to_string());
+    access_log::write(&access_log::AccessRecord {
+        forwarder: fwd,
+        protocol: "udp",
+        client: &Endpoint::Inet(client),
+        pattern: dst.map(|d| d.1.as_str()),
+        upstream: upstream.as_deref(),
+        started: meta.started,
+        up_bytes: meta.up_bytes,
+        down_bytes: meta.down_bytes,
+        close_reason: reason,
+    });
+}
+
+/// Make the placeholder socket of a session one of `target`'s family, which
+/// may differ from the listener's, bind it with `options` and connect it.
+fn open_upstream(
+    poll: &Poll,
+    sock: &mut UdpSocket,
+    token: Token,
+    target: &SocketAddr,
+    options: &SocketConfig,
+) -> io::Result<()> {
+    let ipv6 = target.is_ipv6();
+    if sock.local_addr()?.is_ipv6() != ipv6 {
+        poll.registry().deregister(sock)?;
+        *sock = socket_options::udp_socket(ipv6)?;
+        poll.registry()
+            .register(sock, token, Interest::READABLE.add(Interest::WRITABLE))?;
+    }
+    socket_options::bind_udp(sock, options, ipv6)?;
+    sock.connect(*target)
+}
+
+/// Where to reply to `client` from a listener at `local`, an IPv4 client of
+/// a dual-stack listener is reached at its IPv4-mapped address.
+fn reply_addr(local: &SocketAddr, client: SocketAddr) -> SocketAddr {
+    match client {
+        SocketAddr::V4(v4) if local.is_ipv6() => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        _ => client,
+    }
+}
+
 fn reset_readable(poll: &mut Poll, source: &mut UdpSocket, token: &Token) {
     poll.registry().deregister(source).unwrap();
     poll.registry()
@@ -45,29 +335,112 @@ fn reset_readable_writable(poll: &mut Poll, source: &mut UdpSocket, token: &Toke
         .unwrap();
 }
 
-impl UdpForwarder {
-    pub fn from<T: ToSocketAddrs>(
+impl UdpSettings {
+    fn from<T: ToEndpoint>(
         config: &ForwardSessionConfig<T>,
-    ) -> Result<UdpForwarder, Box<dyn Error>> {
-        let baddr = utils::toSockAddr(&config.local);
+    ) -> Result<UdpSettings, Box<dyn Error>> {
+        let q = &config.udp_queue;
+        if [q.session_packets, q.session_bytes, q.packets, q.bytes].contains(&0) {
+            return Err(Box::from("udp_queue limits must not be 0"));
+        }
+        let ip_matcher = IpAddrMatcher::from_rules(&config.acl_rules())?;
+        let capture = match &config.capture {
+            Some(c) => Some(Capture::from(c)?),
+            None => None,
+        };
+        let unmatched_log = match &config.unmatched_log {
+            Some(c) => Some(UnmatchedLog::open(c)?),
+            None => None,
+        };
 
-        Ok(UdpForwarder {
-            bindAddr: baddr,
-            plugin: Box::new(RegexMultiplexer::from((
-                config.remoteMap.clone(),
-                config.allow_nets.clone(),
-            ))),
+        Ok(UdpSettings {
+            plugin: Box::new(RegexMultiplexer::new(config.remoteMap.clone(), ip_matcher)?),
             max_connections: if config.max_connections >= 0 {
                 Some(config.max_connections as u64)
             } else {
                 None
             },
+            drain_timeout: config.drain_timeout,
+            capture,
+            unmatched_log,
+            upstream_socket: config.upstream_socket.clone(),
+            rule_sockets: config.upstream_sockets(),
+            idle_timeout: config.udp_idle_timeout,
+            rule_idle_timeouts: config.udp_idle_timeouts(),
+            evict_lru: config.udp_evict_lru,
+            queue: config.udp_queue.clone(),
         })
     }
 
+    /// Options of the upstream socket of a session that matched `rule`.
+    fn upstream_socket(&self, rule: usize) -> &SocketConfig {
+        self.rule_sockets.get(rule).unwrap_or(&self.upstream_socket)
+    }
+
+    /// Idle timeout of a session that matched `rule`, in microseconds.
+    fn idle_timeout_us(&self, rule: usize) -> u128 {
+        self.rule_idle_timeouts
+            .get(rule)
+            .unwrap_or(&self.idle_timeout)
+            .as_micros()
+    }
+}
+
+impl UdpForwarder {
+    pub fn from<T: ToEndpoint>(
+        config: &ForwardSessionConfig<T>,
+    ) -> Result<UdpForwarder, Box<dyn Error>> {
+        let baddr = utils::resolveSockAddr(&config.local)?;
+        let settings = UdpSettings::from(config)?;
+
+        let inherited = systemd::take_udp_socket(baddr, config.fd_name.as_deref());
+        let bound = match inherited {
+            Some(socket) => utils::inherited_udp(socket, &config.listen_socket),
+            None => utils::bind_udp(baddr, config.ipv6_only, &config.listen_socket),
+        };
+        let socket = bound.map_err(|e| {
+            format!(
+                "fail to bind udp://{}: make sure the address is not in use and you have permission to bind\n  {}",
+                baddr, e
+            )
+        })?;
+
+        Ok(UdpForwarder {
+            bindAddr: baddr,
+            settings: utils::Shared::new(settings),
+            socket: Mutex::new(Some(socket)),
+            drain_unbounded: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// The flag that lets a closed loop drain without `drain_timeout`.
+    pub(crate) fn drain_unbounded(&self) -> &Arc<AtomicBool> {
+        &self.drain_unbounded
+    }
+
+    /// Replace routing rules, access rules and limits, established sessions
+    /// keep their target.
+    pub fn update<T: ToEndpoint>(
+        &self,
+        config: &ForwardSessionConfig<T>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.prepare_update(config)?();
+        Ok(())
+    }
+
+    /// Check an `update`, which is made when the returned function is called.
+    pub(crate) fn prepare_update<T: ToEndpoint>(
+        &self,
+        config: &ForwardSessionConfig<T>,
+    ) -> Result<impl FnOnce() + '_, Box<dyn Error>> {
+        let settings = UdpSettings::from(config)?;
+        Ok(move || self.settings.set(settings))
+    }
+
     pub fn listen(self: &UdpForwarder, closed: Arc<AtomicBool>) -> Result<(), Box<dyn Error>> {
+        let settings = self.settings.get();
         let mut poll = Poll::new().unwrap();
-        let capacity = if let Some(mx) = self.max_connections {
+        let capacity = if let Some(mx) = settings.max_connections {
             std::cmp::min(mx as usize, 1024)
         } else {
             1024
@@ -78,60 +451,134 @@ impl UdpForwarder {
         let mut addr2token: HashMap<SocketAddr, Token> = HashMap::new();
         let mut token2addr: HashMap<Token, SocketAddr> = HashMap::new();
         let mut token2socket: HashMap<Token, UdpSocket> = HashMap::new();
-        let mut tokenWaitWrite: HashMap<Token, Vec<Vec<u8>>> = HashMap::new();
-        let mut life2token: BTreeMap<u128, Token> = BTreeMap::new();
-        let mut token2life: HashMap<Token, u128> = HashMap::new();
-        let mut token2dst: HashMap<Token, SocketAddr> = HashMap::new();
-        let mut writeBackQueue: Queue<(SocketAddr, Vec<u8>)> = Queue::new();
-
-        let mut udpfd = match UdpSocket::bind(self.bindAddr) {
-            Ok(l) => l,
-            Err(e) => {
-                panic!(
-                    "fail to bind udp://{}: make sure the address is not in use and you have permission to bind\n  {}",
-                    self.bindAddr, e
-                );
-            }
+        let mut tokenWaitWrite: HashMap<Token, Backlog<()>> = HashMap::new();
+        // the idle timeout of a session is its rule's once one matched
+        let mut expiry = Expiry::default();
+        // upstream and the pattern of the rule that chose it
+        let mut token2dst: HashMap<Token, (SocketAddr, String)> = HashMap::new();
+        let mut token2meta: HashMap<Token, SessionMeta> = HashMap::new();
+        // the local address a session's client sent to, to reply from it
+        let mut token2local: HashMap<Token, IpAddr> = HashMap::new();
+        // replies to clients, with the local address to send them from
+        let mut writeBackQueue: Backlog<(SocketAddr, Option<IpAddr>)> = Backlog::new();
+
+        let socket = self.socket.lock().unwrap().take();
+        let mut udpfd = match socket {
+            Some(socket) => UdpSocket::from_std(socket),
+            None => return Err(format!("udp://{} is already listening", self.bindAddr).into()),
         };
 
         poll.registry()
             .register(&mut udpfd, t1, Interest::READABLE)
             .unwrap();
         log::info!("listen incomming udp://{}", udpfd.local_addr().unwrap());
+        let local = udpfd.local_addr()?;
+        if local.ip().is_unspecified() {
+            // a multi-homed host otherwise replies from the address of its route
+            if let Err(e) = socket_options::recv_pktinfo(&udpfd, local.is_ipv6()) {
+                info!("udp://{} replies from any address: {}", local, e);
+            }
+        }
 
-        let mut read_buf = vec![0; 1 << 16];
-        let mut waiting_to_close: Vec<Token> = vec![];
-        let lifespan_us = 3 * 60 * 1000 * 1000;
+        let admin = admin::ForwarderHandle::register(Endpoint::Inet(self.bindAddr), "udp", &poll)?;
+        let mut recv_batch = RecvBatch::new();
+        let mut send_batch = SendBatch::new();
+        let mut pool = PacketPool::new();
+        let mut waiting_to_close: Vec<(Token, &str)> = vec![];
+        let fwd = self.bindAddr.to_string();
+        let loop_metrics = LoopMetrics::new(&fwd);
+        let mut drain_deadline: Option<time::Instant> = None;
+        let mut ruled: Option<Arc<UdpSettings>> = None;
         loop {
+            let settings = self.settings.get();
+            if !ruled.as_ref().is_some_and(|r| Arc::ptr_eq(r, &settings)) {
+                admin.set_rules(admin::rule_keys(settings.plugin.as_ref()));
+                ruled = Some(settings.clone());
+            }
             // now as KEY of BTreeMap should be unique for every connections
             let mut now = time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_micros();
-            let lastkv = life2token.iter().next();
-            if lastkv.is_some() {
-                now = cmp::max(now, *lastkv.unwrap().0);
+            if let Some(latest) = expiry.latest_activity() {
+                now = cmp::max(now, latest + 1);
             }
-            for outdate in life2token.range(0..now - lifespan_us) {
-                waiting_to_close.push(outdate.1.clone());
+            for t in expiry.expired(now) {
+                waiting_to_close.push((t, "idle timeout"));
             }
-            for t in &waiting_to_close {
-                let k = token2life.remove(&t).unwrap();
-                life2token.remove(&k).unwrap();
+            let admin_pending = admin.pending();
+            if admin_pending {
+                for id in admin.take_close_requests() {
+                    if let Some((t, _)) = token2meta.iter().find(|(_, m)| m.id == id) {
+                        info!("close UDP session {} by admin request", id);
+                        waiting_to_close.push((*t, "closed by admin request"));
+                    }
+                }
+            }
+            waiting_to_close.sort_by_key(|(t, _)| *t);
+            waiting_to_close.dedup_by_key(|(t, _)| *t);
+            for (t, reason) in &waiting_to_close {
+                finish_session(&fwd, token2addr[t], token2dst.get(t), &token2meta[t], reason);
+                expiry.remove(*t).unwrap();
                 let addr = *token2addr.get(&t).unwrap();
                 addr2token.remove(&addr);
                 token2addr.remove(&t);
                 token2socket.remove(&t).unwrap();
                 tokenWaitWrite.remove(&t);
                 token2dst.remove(&t);
+                token2local.remove(t);
+                token2meta.remove(&t);
+                loop_metrics.sessions_active.add(-1);
             }
             waiting_to_close.clear();
+            if admin_pending {
+                let sessions = token2meta
+                    .iter()
+                    .map(|(t, m)| {
+                        let dst = token2dst.get(t);
+                        admin::SessionInfo {
+                            id: m.id,
+                            kind: "udp",
+                            client: Endpoint::Inet(token2addr[t]),
+                            upstream: dst.map(|d| d.0.to_string()),
+                            rule: dst.map(|d| d.1.clone()),
+                            state: None,
+                            up_bytes: m.up_bytes,
+                            down_bytes: m.down_bytes,
+                            age_secs: m.started.elapsed().as_secs_f64(),
+                        }
+                    })
+                    .collect();
+                admin.publish(sessions);
+            }
 
-            let rs = poll.poll(&mut events, Some(time::Duration::from_millis(1000)));
-            if closed.load(Ordering::SeqCst) {
-                return Ok(());
+            let draining = closed.load(Ordering::SeqCst);
+            if draining && self.drain_unbounded.load(Ordering::SeqCst) {
+                // sessions are served until they idle out, no new one is created
+                if token2meta.is_empty() {
+                    return Ok(());
+                }
+            } else if draining {
+                let deadline = *drain_deadline
+                    .get_or_insert_with(|| time::Instant::now() + settings.drain_timeout);
+                // pending datagrams are flushed before exit, no new session is created
+                if (writeBackQueue.is_empty() && tokenWaitWrite.is_empty())
+                    || time::Instant::now() >= deadline
+                {
+                    for (t, meta) in &token2meta {
+                        finish_session(&fwd, token2addr[t], token2dst.get(t), meta, "shutdown");
+                    }
+                    return Ok(());
+                }
             }
 
+            let poll_timeout = if draining {
+                time::Duration::from_millis(100)
+            } else {
+                time::Duration::from_millis(1000)
+            };
+            let rs = poll.poll(&mut events, Some(poll_timeout));
+
             if rs.is_err() {
                 let err = rs.unwrap_err();
                 if err.kind() == io::ErrorKind::WouldBlock {
@@ -145,135 +592,217 @@ impl UdpForwarder {
                 match event.token() {
                     token if token == t1 => {
                         if event.is_readable() {
-                            let mut cont = true;
-                            while cont {
-                                match udpfd.recv_from(&mut read_buf) {
-                                    Ok((size, end)) => {
-                                        if !self.plugin.testipaddr(&end) {
-                                            info!("drop UDP package from {}", end.ip());
+                            // sessions about to close make room already
+                            let mut closing: HashSet<Token> =
+                                waiting_to_close.iter().map(|(t, _)| *t).collect();
+                            loop {
+                                let count = match recv_batch.recv(&udpfd) {
+                                    Ok(n) => n,
+                                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                                    Err(err) => return Err(Box::from(err)),
+                                };
+                                for i in 0..count {
+                                    let (datagram, end) = recv_batch.get(i);
+                                    let size = datagram.len();
+                                    // IPv4 clients of `[::]` as themselves for rules and logs
+                                    let end = utils::canonical(end);
+                                    if !settings.plugin.testipaddr(&end) {
+                                        info!("drop UDP package from {}", end.ip());
+                                        continue;
+                                    }
+
+                                    log::debug!("listen: read {} bytes from {}", size, end);
+                                    if addr2token.get(&end).is_none() {
+                                        if draining {
                                             continue;
                                         }
-
-                                        log::debug!("listen: read {} bytes from {}", size, end);
-                                        if addr2token.get(&end).is_none() {
-                                            if let Some(mx) = self.max_connections {
-                                                if addr2token.len() as u64 >= mx {
-                                                    info!(
-                                                        "drop UDP package from {} because quota is meeted",
-                                                        end.ip()
-                                                    );
-                                                    continue;
+                                        if let Some(mx) = settings.max_connections {
+                                            if (addr2token.len() - closing.len()) as u64 >= mx {
+                                                let lru = if settings.evict_lru {
+                                                    expiry
+                                                        .least_recent()
+                                                        .find(|t| !closing.contains(t))
+                                                } else {
+                                                    None
+                                                };
+                                                match lru {
+                                                    Some(t) => {
+                                                        info!(
+                                                            "evict UDP session of {} for {} because quota is meeted",
+                                                            token2addr[t], end
+                                                        );
+                                                        closing.insert(*t);
+                                                        waiting_to_close.push((*t, "evicted"));
+                                                    }
+                                                    None => {
+                                                        info!(
+                                                            "drop UDP package from {} because quota is meeted",
+                                                            end.ip()
+                                                        );
+                                                        continue;
+                                                    }
                                                 }
                                             }
-                                            info!("create session, new message from {}", end);
-
-                                            let new_socket = if self.bindAddr.is_ipv4() {
-                                                UdpSocket::bind("0.0.0.0:0".parse().unwrap())
-                                                    .unwrap()
-                                            } else {
-                                                UdpSocket::bind("[::]:0".parse().unwrap()).unwrap()
-                                            };
-                                            let t = next(&mut tx);
-                                            addr2token.insert(end, t);
-                                            token2addr.insert(t, end);
-                                            token2socket.insert(t, new_socket);
-                                            token2life.insert(t, now);
-                                            life2token.insert(now, t);
-                                            now += 1;
-
-                                            poll.registry()
-                                                .register(
-                                                    token2socket.get_mut(&t).unwrap(),
-                                                    t,
-                                                    Interest::READABLE,
-                                                )
-                                                .unwrap();
                                         }
+                                        info!("create session, new message from {}", end);
+
+                                        // replaced by a socket of the upstream's family and
+                                        // bound once the rule and its socket options are known
+                                        let new_socket =
+                                            socket_options::udp_socket(self.bindAddr.is_ipv6())?;
+                                        let t = next(&mut tx);
+                                        addr2token.insert(end, t);
+                                        token2addr.insert(t, end);
+                                        token2socket.insert(t, new_socket);
+                                        expiry.insert(t, now, settings.idle_timeout.as_micros());
+                                        now += 1;
+                                        token2meta.insert(
+                                            t,
+                                            SessionMeta {
+                                                id: admin::next_id(),
+                                                started: time::Instant::now(),
+                                                up_bytes: 0,
+                                                down_bytes: 0,
+                                                dropped: 0,
+                                                bytes_in: Default::default(),
+                                                bytes_out: Default::default(),
+                                            },
+                                        );
+                                        loop_metrics.sessions_active.add(1);
 
-                                        let t = addr2token.get(&end).unwrap().clone();
-                                        if tokenWaitWrite.get(&t).is_none() {
-                                            tokenWaitWrite.insert(t, vec![]);
-                                            reset_readable_writable(
-                                                &mut poll,
+                                        poll.registry()
+                                            .register(
                                                 token2socket.get_mut(&t).unwrap(),
-                                                &t,
-                                            );
-                                        }
+                                                t,
+                                                Interest::READABLE,
+                                            )
+                                            .unwrap();
+                                    }
 
-                                        let write_queue = tokenWaitWrite.get_mut(&t).unwrap();
-                                        write_queue.push(Vec::from(&read_buf[0..size]));
+                                    let t = addr2token.get(&end).unwrap().clone();
+                                    if let Some(local) = recv_batch.local(i) {
+                                        token2local.insert(t, local);
                                     }
-                                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                                        cont = false;
+                                    if tokenWaitWrite.get(&t).is_none() {
+                                        tokenWaitWrite.insert(t, Backlog::new());
+                                        reset_readable_writable(
+                                            &mut poll,
+                                            token2socket.get_mut(&t).unwrap(),
+                                            &t,
+                                        );
                                     }
-                                    Err(err) => {
-                                        return Err(Box::from(err));
+
+                                    let write_queue = tokenWaitWrite.get_mut(&t).unwrap();
+                                    let limits = &settings.queue;
+                                    let dropped = write_queue.push(
+                                        (),
+                                        pool.copy(datagram),
+                                        limits.session_packets,
+                                        limits.session_bytes,
+                                        limits.drop,
+                                    );
+                                    count_dropped("upstream", &loop_metrics.dropped_upstream, &dropped);
+                                    if let Some(meta) = token2meta.get_mut(&t) {
+                                        meta.dropped += dropped.len() as u64;
+                                    }
+                                    for (_, buf) in dropped {
+                                        pool.recycle(buf);
                                     }
                                 }
                             }
                         }
                         if event.is_writable() {
-                            assert!(writeBackQueue.size() > 0);
-                            let mut cont = true;
-                            while writeBackQueue.size() > 0 && cont {
-                                let (addr, buf) = writeBackQueue.remove().unwrap();
-                                match udpfd.send_to(&buf, addr) {
-                                    Ok(n) => {
-                                        log::debug!(
-                                            "send back {}/{n} bytes to {}, remain {}",
-                                            buf.len(),
-                                            addr,
-                                            writeBackQueue.size()
-                                        );
-                                    }
-                                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                                        cont = false;
-                                    }
-                                    Err(_) => {}
+                            assert!(!writeBackQueue.is_empty());
+                            while !writeBackQueue.is_empty() {
+                                let batch = writeBackQueue.iter().map(|((addr, local), buf)| {
+                                    let to = reply_addr(&self.bindAddr, *addr);
+                                    (Some(to), *local, buf.as_slice())
+                                });
+                                let sent = match send_batch.send(&udpfd, batch) {
+                                    Ok(n) => n,
+                                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                                    // the first one can't be sent, skip it
+                                    Err(_) => 1,
+                                };
+                                for _ in 0..sent {
+                                    let ((addr, _), buf) = writeBackQueue.pop_front().unwrap();
+                                    log::debug!(
+                                        "send back {} bytes to {}, remain {}",
+                                        buf.len(),
+                                        addr,
+                                        writeBackQueue.len()
+                                    );
+                                    pool.recycle(buf);
                                 }
                             }
 
-                            if writeBackQueue.size() == 0 {
+                            if writeBackQueue.is_empty() {
                                 reset_readable(&mut poll, &mut udpfd, &t1);
                             }
                         }
                     }
+                    admin::WAKER_TOKEN => {}
                     token => {
                         let sock = token2socket.get_mut(&token).unwrap();
 
                         if event.is_readable() {
-                            let mut cont = true;
-                            while cont {
-                                match sock.recv_from(&mut read_buf) {
-                                    Ok((size, peerAddr)) if size > 0 => {
-                                        let addr = token2addr.get(&token).unwrap().clone();
-                                        log::debug!(
-                                            "midpoint {}: read {} bytes from {}",
-                                            sock.local_addr().unwrap(),
-                                            size,
-                                            peerAddr
-                                        );
-                                        if writeBackQueue.size() == 0 {
-                                            reset_readable_writable(&mut poll, &mut udpfd, &t1);
+                            loop {
+                                let count = match recv_batch.recv(sock) {
+                                    Ok(n) => n,
+                                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                                    Err(_) => {
+                                        waiting_to_close.push((token, "upstream receive error"));
+                                        break;
+                                    }
+                                };
+                                for i in 0..count {
+                                    let (datagram, peerAddr) = recv_batch.get(i);
+                                    let size = datagram.len();
+                                    if size == 0 {
+                                        continue;
+                                    }
+                                    let addr = token2addr.get(&token).unwrap().clone();
+                                    log::debug!(
+                                        "midpoint {}: read {} bytes from {}",
+                                        sock.local_addr().unwrap(),
+                                        size,
+                                        peerAddr
+                                    );
+                                    let was_empty = writeBackQueue.is_empty();
+                                    let limits = &settings.queue;
+                                    let dropped = writeBackQueue.push(
+                                        (addr, token2local.get(&token).copied()),
+                                        pool.copy(datagram),
+                                        limits.packets,
+                                        limits.bytes,
+                                        limits.drop,
+                                    );
+                                    if was_empty && !writeBackQueue.is_empty() {
+                                        reset_readable_writable(&mut poll, &mut udpfd, &t1);
+                                    }
+                                    count_dropped("client", &loop_metrics.dropped_client, &dropped);
+                                    for ((client, _), buf) in dropped {
+                                        if let Some(t) = addr2token.get(&client) {
+                                            if let Some(meta) = token2meta.get_mut(t) {
+                                                meta.dropped += 1;
+                                            }
                                         }
-                                        writeBackQueue
-                                            .add((addr, Vec::from(&read_buf[0..size])))
-                                            .unwrap();
-
-                                        let oldLife = token2life.get(&token).unwrap();
-                                        life2token.remove(oldLife);
-                                        token2life.insert(token, now);
-                                        life2token.insert(now, token);
-                                        now += 1;
+                                        pool.recycle(buf);
                                     }
-                                    Ok(_) => {}
-                                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                                        cont = false;
+                                    if let Some(meta) = token2meta.get_mut(&token) {
+                                        meta.down_bytes += size as u64;
+                                        meta.bytes_out.add(size as i64);
                                     }
-                                    Err(_) => {
-                                        cont = false;
-                                        waiting_to_close.push(token)
+                                    if let Some((_, rule)) = token2dst.get(&token) {
+                                        if let Some(c) = settings.capture.as_ref() {
+                                            if c.wants(rule, &Endpoint::Inet(addr)) {
+                                                c.udp(peerAddr, addr, datagram);
+                                            }
+                                        }
                                     }
+
+                                    expiry.touch(token, now);
+                                    now += 1;
                                 }
                             }
                         }
@@ -288,57 +817,104 @@ impl UdpForwarder {
                             let mut cont = true;
                             let mut nwritten = 0;
                             while !bufs.is_empty() && cont {
-                                let buf = bufs[0].clone();
-                                let dst = if token2dst.contains_key(&token) {
-                                    Some(*token2dst.get(&token).unwrap())
-                                } else {
-                                    let target = self
+                                if let Entry::Vacant(entry) = token2dst.entry(token) {
+                                    let first = &bufs.front().unwrap().1;
+                                    let matched = settings
                                         .plugin
-                                        .decideTarget(&buf, *token2addr.get(&token).unwrap());
-                                    if target.is_some() {
+                                        .matchRule(first, *token2addr.get(&token).unwrap(), &|i| {
+                                            admin.rule_enabled(i)
+                                        })
+                                        // validation keeps unix remotes away from udp
+                                        .and_then(|(i, target)| target.inet().map(|a| (i, a)));
+                                    loop_metrics.sniff[matched.is_some() as usize].add(1);
+                                    if let Some((index, target)) = matched {
+                                        let options = settings.upstream_socket(index);
+                                        if let Err(e) = open_upstream(&poll, sock, token, &target, options) {
+                                            info!(
+                                                "fail to set up the socket from {} to {}: {}",
+                                                token2addr[&token], target, e
+                                            );
+                                            bufs.clear();
+                                            waiting_to_close.push((token, "upstream socket error"));
+                                            break;
+                                        }
                                         log::debug!(
                                             "forward udp packet from {} to {}",
                                             token2addr.get(&token).unwrap(),
-                                            target.unwrap()
+                                            target
                                         );
-                                        token2dst.insert(token, target.unwrap());
+                                        let rule = settings.plugin.rulePattern(index).unwrap_or("");
+                                        metrics::counter_add(
+                                            metrics::UDP_SESSIONS_TOTAL,
+                                            &[
+                                                ("forwarder", fwd.as_str()),
+                                                ("rule", rule),
+                                                ("upstream", target.to_string().as_str()),
+                                            ],
+                                            1,
+                                        );
+                                        if let Some(meta) = token2meta.get_mut(&token) {
+                                            [meta.bytes_in, meta.bytes_out] = session_bytes(&fwd, rule, &target);
+                                        }
+                                        entry.insert((target, rule.to_string()));
+                                        expiry.set_idle(token, settings.idle_timeout_us(index));
                                     }
-                                    target
-                                };
-                                match dst {
-                                    Some(remote) => match sock.send_to(&buf, remote) {
-                                        Ok(s) => {
-                                            bufs.remove(0);
+                                }
+                                match token2dst.get(&token) {
+                                    Some((remote, rule)) => {
+                                        // the socket is connected to `remote`
+                                        let batch = bufs
+                                            .iter()
+                                            .map(|(_, buf)| (None, None, buf.as_slice()));
+                                        let sent = match send_batch.send(sock, batch) {
+                                            Ok(n) => n,
+                                            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                                                // Keep buffer head for next writable event.
+                                                cont = false;
+                                                0
+                                            }
+                                            Err(_) => {
+                                                bufs.pop_front();
+                                                cont = false;
+                                                waiting_to_close.push((token, "upstream send error"));
+                                                0
+                                            }
+                                        };
+                                        let client = token2addr[&token];
+                                        for _ in 0..sent {
+                                            let (_, buf) = bufs.pop_front().unwrap();
+                                            let s = buf.len();
+                                            if let Some(c) = settings.capture.as_ref() {
+                                                if c.wants(rule, &Endpoint::Inet(client)) {
+                                                    c.udp(client, *remote, &buf);
+                                                }
+                                            }
+                                            if let Some(meta) = token2meta.get_mut(&token) {
+                                                meta.up_bytes += s as u64;
+                                                meta.bytes_in.add(s as i64);
+                                            }
                                             log::debug!(
                                                 "sent {} bytes to {}, data packet come from {}",
                                                 s,
                                                 remote,
-                                                token2addr.get(&token).unwrap()
+                                                client
                                             );
                                             nwritten += s;
+                                            pool.recycle(buf);
                                         }
-                                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                                            // Keep buffer head for next writable event.
-                                            cont = false;
-                                        }
-                                        Err(_) => {
-                                            bufs.remove(0);
-                                            cont = false;
-                                            waiting_to_close.push(token);
-                                        }
-                                    },
+                                    }
                                     None => {
-                                        bufs.remove(0);
-                                        waiting_to_close.push(token);
+                                        let (_, buf) = bufs.pop_front().unwrap();
+                                        if let Some(l) = settings.unmatched_log.as_ref() {
+                                            l.record(&fwd, "udp", &Endpoint::Inet(token2addr[&token]), &buf);
+                                        }
+                                        waiting_to_close.push((token, "no matching rule"));
                                         break;
                                     }
                                 }
                             }
                             if nwritten > 0 {
-                                let oldLife = token2life.get(&token).unwrap();
-                                life2token.remove(oldLife);
-                                token2life.insert(token, now);
-                                life2token.insert(now, token);
+                                expiry.touch(token, now);
                                 now += 1;
                             }
                             if bufs.is_empty() {
@@ -347,7 +923,7 @@ impl UdpForwarder {
                             }
                         }
                         if event.is_error() {
-                            waiting_to_close.push(token);
+                            waiting_to_close.push((token, "socket error"));
                         }
                     }
                 }
@@ -355,3 +931,69 @@ impl UdpForwarder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Backlog, Expiry};
+    use crate::forward_config::DropPolicy;
+    use mio::Token;
+
+    fn contents(backlog: &Backlog<u8>) -> Vec<u8> {
+        backlog.queue.iter().map(|d| d.0).collect()
+    }
+
+    #[test]
+    fn backlog_drops_newest_or_oldest() {
+        for (drop, kept, lost) in [
+            (DropPolicy::Newest, vec![0, 1, 2], vec![3, 4]),
+            (DropPolicy::Oldest, vec![2, 3, 4], vec![0, 1]),
+        ] {
+            let mut backlog = Backlog::new();
+            let mut dropped = vec![];
+            for i in 0..5 {
+                dropped.extend(backlog.push(i, vec![0; 10], 3, 100, drop));
+            }
+            assert_eq!(contents(&backlog), kept);
+            assert_eq!(dropped.iter().map(|d| d.0).collect::<Vec<_>>(), lost);
+            assert_eq!(backlog.bytes, 30);
+        }
+    }
+
+    #[test]
+    fn backlog_bounds_bytes() {
+        let mut backlog = Backlog::new();
+        assert!(backlog.push(0, vec![0; 60], 10, 100, DropPolicy::Oldest).is_empty());
+        // the oldest makes room
+        assert_eq!(backlog.push(1, vec![0; 50], 10, 100, DropPolicy::Oldest).len(), 1);
+        assert_eq!(contents(&backlog), [1]);
+        // one that can never fit is dropped by itself
+        assert_eq!(backlog.push(2, vec![0; 101], 10, 100, DropPolicy::Oldest)[0].0, 2);
+        assert_eq!(backlog.push(3, vec![0; 60], 10, 100, DropPolicy::Newest)[0].0, 3);
+        assert_eq!(backlog.pop_front().unwrap().0, 1);
+        assert_eq!(backlog.bytes, 0);
+        // no room at all drops the datagram instead of looping
+        assert_eq!(backlog.push(4, vec![0; 10], 0, 100, DropPolicy::Oldest)[0].0, 4);
+    }
+
+    #[test]
+    fn expiry_by_deadline() {
+        let mut expiry = Expiry::default();
+        expiry.insert(Token(1), 100, 1000);
+        expiry.insert(Token(2), 200, 1000);
+        expiry.insert(Token(3), 300, 1000);
+        // a shorter timeout of its rule expires the newest first
+        expiry.set_idle(Token(3), 10);
+        assert_eq!(expiry.expired(310).collect::<Vec<_>>(), [Token(3)]);
+        expiry.touch(Token(1), 400);
+        assert_eq!(
+            expiry.expired(1200).collect::<Vec<_>>(),
+            [Token(3), Token(2)]
+        );
+        assert_eq!(
+            expiry.least_recent().collect::<Vec<_>>(),
+            [&Token(2), &Token(3), &Token(1)]
+        );
+        assert_eq!(expiry.remove(Token(2)), Some((200, 1000)));
+        assert_eq!(expiry.latest_activity(), Some(400));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::endpoint::Endpoint;
+use crate::forward_config::UnmatchedLogConfig;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const MAX_PREFIXES: usize = 4096;
+
+/// Output and limiter state, shared by every forwarder logging to the same path
+/// so that a reload keeps the counters.
+struct Sink {
+    out: Box<dyn Write + Send>,
+    window_start: Option<Instant>,
+    in_window: u32,
+    rate_limited: u64,
+    seen: HashMap<(String, Vec<u8>), (Instant, u64)>,
+}
+
+fn open_sinks() -> &'static Mutex<HashMap<String, Weak<Mutex<Sink>>>> {
+    static SINKS: OnceLock<Mutex<HashMap<String, Weak<Mutex<Sink>>>>> = OnceLock::new();
+    SINKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Samples of first packets that matched no rule.
+pub struct UnmatchedLog {
+    sink: Arc<Mutex<Sink>>,
+    config: UnmatchedLogConfig,
+}
+
+/// Classic `offset  hex  |ascii|` dump, 16 bytes per line.
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", i * 16);
+        for j in 0..16 {
+            if j == 8 {
+                out.push(' ');
+            }
+            match line.get(j) {
+                Some(b) => {
+                    let _ = write!(out, " {:02x}", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+impl Sink {
+    fn new(out: Box<dyn Write + Send>) -> Sink {
+        Sink {
+            out,
+            window_start: None,
+            in_window: 0,
+            rate_limited: 0,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Whether a sample may be written now, with the number of similar samples
+    /// suppressed since the last one and of samples dropped by the rate limit.
+    fn admit(
+        &mut self,
+        key: (String, Vec<u8>),
+        now: Instant,
+        config: &UnmatchedLogConfig,
+    ) -> Option<(u64, u64)> {
+        if let Some((logged, suppressed)) = self.seen.get_mut(&key) {
+            if now.duration_since(*logged) < config.dedup_window {
+                *suppressed += 1;
+                return None;
+            }
+        }
+        match self.window_start {
+            Some(start) if now.duration_since(start) < RATE_WINDOW => {
+                if self.in_window >= config.per_minute {
+                    self.rate_limited += 1;
+                    return None;
+                }
+            }
+            _ => {
+                self.window_start = Some(now);
+                self.in_window = 0;
+            }
+        }
+        self.in_window += 1;
+        if self.seen.len() >= MAX_PREFIXES {
+            self.seen
+                .retain(|_, (logged, _)| now.duration_since(*logged) < config.dedup_window);
+            if self.seen.len() >= MAX_PREFIXES {
+                self.seen.clear();
+            }
+        }
+        let suppressed = self.seen.insert(key, (now, 0)).map(|(_, n)| n).unwrap_or(0);
+        Some((suppressed, std::mem::take(&mut self.rate_limited)))
+    }
+}
+
+impl UnmatchedLog {
+    /// Open `config.file` for appending, or share the writer if it is already open.
+    pub fn open(config: &UnmatchedLogConfig) -> io::Result<UnmatchedLog> {
+        let mut sinks = open_sinks().lock().unwrap();
+        let sink = match sinks.get(&config.file).and_then(|s| s.upgrade()) {
+            Some(sink) => sink,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.file)?;
+                log::info!("log unmatched first packets to {}", config.file);
+                let sink = Arc::new(Mutex::new(Sink::new(Box::new(file))));
+                sinks.insert(config.file.clone(), Arc::downgrade(&sink));
+                sink
+            }
+        };
+        Ok(UnmatchedLog {
+            sink,
+            config: config.clone(),
+        })
+    }
+
+    /// Log the first packet of a connection or UDP session no rule matched.
+    pub fn record(&self, forwarder: &str, protocol: &str, client: &Endpoint, data: &[u8]) {
+        let prefix = &data[..data.len().min(self.config.dedup_prefix)];
+        let mut sink = self.sink.lock().unwrap();
+        let (suppressed, rate_limited) = match sink.admit(
+            (protocol.to_string(), prefix.to_vec()),
+            Instant::now(),
+            &self.config,
+        ) {
+            Some(counts) => counts,
+            None => return,
+        };
+        let text = format_sample(
+            SystemTime::now(),
+            forwarder,
+            protocol,
+            client,
+            &data[..data.len().min(self.config.bytes)],
+            data.len(),
+            suppressed,
+            rate_limited,
+        );
+        if let Err(e) = sink
+            .out
+            .write_all(text.as_bytes())
+            .and_then(|_| sink.out.flush())
+        {
+            log::warn!("fail to write unmatched log: {}", e);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn format_sample(
+    now: SystemTime,
+    forwarder: &str,
+    protocol: &str,
+    client: &Endpoint,
+    dump: &[u8],
+    len: usize,
+    suppressed: u64,
+    rate_limited: u64,
+) -> String {
+    let mut out = format!(
+        "{} {} {} from {}, {} bytes",
+        humantime::format_rfc3339_millis(now),
+        protocol,
+        forwarder,
+        client,
+        len
+    );
+    if suppressed > 0 {
+        let _ = write!(out, ", {} similar suppressed", suppressed);
+    }
+    if rate_limited > 0 {
+        let _ = write!(out, ", {} dropped by rate limit", rate_limited);
+    }
+    out.push('\n');
+    out.push_str(&hexdump(dump));
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_lines() {
+        let dump = hexdump(b"GET / HTTP/1.1\r\nHost: a\r\n");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|"
+        );
+        assert_eq!(
+            lines[1],
+            "00000010  48 6f 73 74 3a 20 61 0d  0a                       |Host: a..|"
+        );
+    }
+
+    #[test]
+    fn dedup_and_rate_limit() {
+        let config = UnmatchedLogConfig {
+            per_minute: 2,
+            dedup_window: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let mut sink = Sink::new(Box::new(io::sink()));
+        let key = |p: &[u8]| ("tcp".to_string(), p.to_vec());
+        let t0 = Instant::now();
+        assert_eq!(sink.admit(key(b"\x16\x03"), t0, &config), Some((0, 0)));
+        assert_eq!(sink.admit(key(b"\x16\x03"), t0, &config), None);
+        assert_eq!(sink.admit(key(b"GET "), t0, &config), Some((0, 0)));
+        assert_eq!(sink.admit(key(b"SSH-"), t0, &config), None);
+        assert_eq!(sink.admit(key(b"RDP"), t0, &config), None);
+
+        let t1 = t0 + Duration::from_secs(61);
+        assert_eq!(sink.admit(key(b"SSH-"), t1, &config), Some((0, 2)));
+        assert_eq!(sink.admit(key(b"\x16\x03"), t1, &config), None);
+
+        let t2 = t0 + Duration::from_secs(601);
+        assert_eq!(sink.admit(key(b"\x16\x03"), t2, &config), Some((2, 0)));
+    }
+}
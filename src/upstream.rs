@@ -0,0 +1,401 @@
+use crate::forward_config::UpstreamConfig;
+use crate::metrics;
+use crate::utils::resolveSockAddr;
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Instant;
+
+struct Server {
+    addr: SocketAddr,
+    weight: i64,
+    /// Smooth weighted round-robin counter.
+    current: i64,
+    /// Result of the health checks, servers start healthy.
+    healthy: bool,
+    checks_in_row: u32,
+    fails_in_row: u32,
+    skip_until: Option<Instant>,
+}
+
+impl Server {
+    fn available(&self) -> bool {
+        self.healthy && self.skip_until.is_none()
+    }
+}
+
+struct GroupState {
+    config: UpstreamConfig,
+    servers: Vec<Server>,
+    checking: bool,
+}
+
+/// A named set of servers, shared by every forwarder whose `remote` names it.
+pub struct UpstreamGroup {
+    name: String,
+    state: Mutex<GroupState>,
+}
+
+/// The configured groups by name.
+#[derive(Default)]
+struct Registry {
+    groups: Mutex<HashMap<String, Arc<UpstreamGroup>>>,
+}
+
+fn registry() -> &'static Registry {
+    static GROUPS: OnceLock<Registry> = OnceLock::new();
+    GROUPS.get_or_init(Registry::default)
+}
+
+static CHECKING: AtomicBool = AtomicBool::new(false);
+
+fn set_available(group: &str, addr: SocketAddr, available: bool) {
+    metrics::gauge_add(
+        metrics::UPSTREAM_AVAILABLE,
+        &[("group", group), ("upstream", addr.to_string().as_str())],
+        if available { 1 } else { -1 },
+    );
+}
+
+impl GroupState {
+    /// Put back servers whose `fail_timeout` is over.
+    fn expire(&mut self, name: &str, now: Instant) {
+        for s in self.servers.iter_mut() {
+            if s.skip_until.is_some_and(|t| now >= t) {
+                s.skip_until = None;
+                if s.healthy {
+                    set_available(name, s.addr, true);
+                }
+            }
+        }
+    }
+}
+
+impl UpstreamGroup {
+    fn new(config: &UpstreamConfig) -> UpstreamGroup {
+        let group = UpstreamGroup {
+            name: config.name.clone(),
+            state: Mutex::new(GroupState {
+                config: config.clone(),
+                servers: vec![],
+                checking: false,
+            }),
+        };
+        group.apply(config);
+        group
+    }
+
+    /// Take the servers of `config`, keeping the state of those still listed.
+    fn apply(&self, config: &UpstreamConfig) {
+        let mut state = self.state.lock().unwrap();
+        let mut old: Vec<Server> = state.servers.drain(..).collect();
+        for server in &config.servers {
+            let addr = match resolveSockAddr(server.address.as_str()).ok() {
+                Some(addr) => addr,
+                None => {
+                    log::warn!("upstream {}: can't resolve {}", self.name, server.address);
+                    continue;
+                }
+            };
+            let kept = old
+                .iter()
+                .position(|s| s.addr == addr)
+                .map(|i| old.remove(i));
+            let mut server = match kept {
+                Some(mut s) => {
+                    s.weight = server.weight as i64;
+                    s
+                }
+                None => {
+                    set_available(&self.name, addr, true);
+                    Server {
+                        addr,
+                        weight: server.weight as i64,
+                        current: 0,
+                        healthy: true,
+                        checks_in_row: 0,
+                        fails_in_row: 0,
+                        skip_until: None,
+                    }
+                }
+            };
+            // without checks nothing would ever bring the server back
+            if config.health_check.is_none() && !server.healthy {
+                server.healthy = true;
+                server.checks_in_row = 0;
+                if server.available() {
+                    set_available(&self.name, addr, true);
+                }
+            }
+            state.servers.push(server);
+        }
+        for s in old {
+            if s.available() {
+                set_available(&self.name, s.addr, false);
+            }
+        }
+        state.config = config.clone();
+    }
+
+    /// The next server by weight among the available ones, or among all of
+    /// them when none is.
+    pub fn pick(&self) -> Option<SocketAddr> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(&self.name, Instant::now());
+        let any_available = state.servers.iter().any(|s| s.available());
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for i in 0..state.servers.len() {
+            let s = &mut state.servers[i];
+            if any_available && !s.available() {
+                continue;
+            }
+            s.current += s.weight;
+            total += s.weight;
+            let current = s.current;
+            if best.is_none_or(|b| current > state.servers[b].current) {
+                best = Some(i);
+            }
+        }
+        let best = best?;
+        state.servers[best].current -= total;
+        Some(state.servers[best].addr)
+    }
+}
+
+impl Registry {
+    fn configure(&self, upstreams: &[UpstreamConfig]) {
+        let mut groups = self.groups.lock().unwrap();
+        groups.retain(|name, group| {
+            let keep = upstreams.iter().any(|u| &u.name == name);
+            if !keep {
+                group.apply(&UpstreamConfig::default());
+            }
+            keep
+        });
+        for config in upstreams {
+            match groups.get(&config.name) {
+                Some(group) => group.apply(config),
+                None => {
+                    let group = Arc::new(UpstreamGroup::new(config));
+                    groups.insert(config.name.clone(), group);
+                }
+            }
+            let group = &groups[&config.name];
+            if CHECKING.load(Ordering::SeqCst) {
+                spawn_checker(group);
+            }
+        }
+    }
+
+    fn group(&self, name: &str) -> Option<Arc<UpstreamGroup>> {
+        self.groups.lock().unwrap().get(name).cloned()
+    }
+
+    fn is_registered(&self, group: &Arc<UpstreamGroup>) -> bool {
+        self.groups
+            .lock()
+            .unwrap()
+            .get(&group.name)
+            .is_some_and(|g| Arc::ptr_eq(g, group))
+    }
+
+    /// Record the outcome of connecting to `addr` in every group that has it.
+    fn connected(&self, addr: SocketAddr, ok: bool) {
+        let groups: Vec<Arc<UpstreamGroup>> =
+            self.groups.lock().unwrap().values().cloned().collect();
+        for group in groups {
+            group.connected(addr, ok);
+        }
+    }
+}
+
+/// Create, update or remove groups to match `upstreams`. Health and failure
+/// state of servers that stay is kept.
+pub fn configure(upstreams: &[UpstreamConfig]) {
+    registry().configure(upstreams);
+}
+
+/// The group named `name`, if configured.
+pub fn group(name: &str) -> Option<Arc<UpstreamGroup>> {
+    registry().group(name)
+}
+
+/// Run the health checks of every group with `health_check`, now and after
+/// each `configure`.
+pub fn start_health_checks() {
+    CHECKING.store(true, Ordering::SeqCst);
+    for group in registry().groups.lock().unwrap().values() {
+        spawn_checker(group);
+    }
+}
+
+fn spawn_checker(group: &Arc<UpstreamGroup>) {
+    {
+        let mut state = group.state.lock().unwrap();
+        if state.checking || state.config.health_check.is_none() {
+            return;
+        }
+        state.checking = true;
+    }
+    let weak = Arc::downgrade(group);
+    std::thread::spawn(move || check_loop(weak));
+}
+
+/// Connect to every server once per interval until the group is removed or
+/// loses its `health_check`.
+fn check_loop(weak: Weak<UpstreamGroup>) {
+    loop {
+        let group = match weak.upgrade() {
+            Some(g) => g,
+            None => return,
+        };
+        // the registry is locked before any group, as `configure` does
+        let registered = registry().is_registered(&group);
+        let (check, addrs) = {
+            let mut state = group.state.lock().unwrap();
+            match state.config.health_check.clone() {
+                Some(check) if registered => (
+                    check,
+                    state.servers.iter().map(|s| s.addr).collect::<Vec<_>>(),
+                ),
+                _ => {
+                    state.checking = false;
+                    return;
+                }
+            }
+        };
+        for addr in addrs {
+            let passed = TcpStream::connect_timeout(&addr, check.timeout).is_ok();
+            let mut state = group.state.lock().unwrap();
+            state.expire(&group.name, Instant::now());
+            if let Some(s) = state.servers.iter_mut().find(|s| s.addr == addr) {
+                let was = s.available();
+                if passed == s.healthy {
+                    s.checks_in_row = 0;
+                } else {
+                    s.checks_in_row += 1;
+                    let needed = if s.healthy { check.fall } else { check.rise };
+                    if s.checks_in_row >= needed.max(1) {
+                        s.healthy = passed;
+                        s.checks_in_row = 0;
+                        if passed {
+                            log::info!("upstream {}: {} is healthy again", group.name, addr);
+                        } else {
+                            log::warn!("upstream {}: {} failed health checks", group.name, addr);
+                        }
+                    }
+                }
+                if was != s.available() {
+                    set_available(&group.name, addr, !was);
+                }
+            }
+        }
+        drop(group);
+        std::thread::sleep(check.interval);
+    }
+}
+
+impl UpstreamGroup {
+    /// Record the outcome of connecting to `addr`, if it is one of ours.
+    fn connected(&self, addr: SocketAddr, ok: bool) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.expire(&self.name, now);
+        let (max_fails, fail_timeout) = (state.config.max_fails, state.config.fail_timeout);
+        for s in state.servers.iter_mut().filter(|s| s.addr == addr) {
+            if ok {
+                s.fails_in_row = 0;
+                continue;
+            }
+            s.fails_in_row += 1;
+            if max_fails > 0 && s.fails_in_row >= max_fails {
+                let was = s.available();
+                s.fails_in_row = 0;
+                s.skip_until = Some(now + fail_timeout);
+                log::warn!(
+                    "upstream {}: {} failed {} connects, skip it for {:?}",
+                    self.name,
+                    addr,
+                    max_fails,
+                    fail_timeout
+                );
+                if was {
+                    set_available(&self.name, addr, false);
+                }
+            }
+        }
+    }
+}
+
+/// A connection to `addr` was established.
+pub fn connect_succeeded(addr: SocketAddr) {
+    registry().connected(addr, true);
+}
+
+/// Connecting to `addr` failed.
+pub fn connect_failed(addr: SocketAddr) {
+    registry().connected(addr, false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forward_config::UpstreamServer;
+    use std::time::Duration;
+
+    fn config(name: &str, servers: &[(&str, u32)]) -> UpstreamConfig {
+        UpstreamConfig {
+            name: name.to_string(),
+            servers: servers
+                .iter()
+                .map(|(address, weight)| UpstreamServer {
+                    address: address.to_string(),
+                    weight: *weight,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn picks_by_weight() {
+        let group = UpstreamGroup::new(&config(
+            "weighted",
+            &[("127.0.0.1:1001", 1), ("127.0.0.1:1002", 3)],
+        ));
+        let picks: Vec<u16> = (0..8).map(|_| group.pick().unwrap().port()).collect();
+        assert_eq!(picks, [1002, 1001, 1002, 1002, 1002, 1001, 1002, 1002]);
+    }
+
+    #[test]
+    fn failed_servers_are_skipped_for_a_while() {
+        let mut pool = config("skipping", &[("127.0.0.1:1011", 1), ("127.0.0.1:1012", 1)]);
+        pool.max_fails = 2;
+        pool.fail_timeout = Duration::from_millis(200);
+        // a registry of its own, other tests configure the global one
+        let registry = Registry::default();
+        registry.configure(&[pool.clone()]);
+        let group = registry.group("skipping").unwrap();
+
+        let failing: SocketAddr = "127.0.0.1:1011".parse().unwrap();
+        registry.connected(failing, false);
+        registry.connected(failing, true);
+        registry.connected(failing, false);
+        assert!((0..4).any(|_| group.pick() == Some(failing)));
+
+        registry.connected(failing, false);
+        assert!((0..4).all(|_| group.pick() != Some(failing)));
+        // the state survives a reload of the same group
+        registry.configure(&[pool]);
+        assert!(Arc::ptr_eq(&group, &registry.group("skipping").unwrap()));
+        assert!((0..4).all(|_| group.pick() != Some(failing)));
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert!((0..4).any(|_| group.pick() == Some(failing)));
+
+        registry.configure(&[]);
+        assert!(registry.group("skipping").is_none());
+    }
+}
@@ -1,5 +1,9 @@
+use crate::forward_config::SocketConfig;
+use crate::socket_options;
+use std::io;
+use socket2::{Domain, Socket, Type};
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 
@@ -13,9 +17,100 @@ impl<'a> Drop for ArcRel<'a> {
     }
 }
 
-pub fn toSockAddr<T>(addr: &T) -> SocketAddr
-where
-    T: ToSocketAddrs,
-{
-    addr.to_socket_addrs().unwrap().next().unwrap()
+/// The first address `addr` resolves to.
+pub fn resolveSockAddr<T: ToSocketAddrs + ?Sized>(addr: &T) -> io::Result<SocketAddr> {
+    let mut addrs = addr
+        .to_socket_addrs()
+        .map_err(|e| io::Error::new(e.kind(), format!("can't resolve: {}", e)))?;
+    addrs
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "resolves to no address"))
+}
+
+/// `addr` with an IPv4-mapped IPv6 address as the IPv4 address it stands
+/// for, which is how IPv4 clients of a dual-stack listener show up.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+fn socket(addr: SocketAddr, kind: Type, ipv6_only: bool) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// A non-blocking listener at `addr`, only taking IPv6 clients when `ipv6_only`.
+pub fn bind_tcp(
+    addr: SocketAddr,
+    ipv6_only: bool,
+    options: &SocketConfig,
+) -> io::Result<std::net::TcpListener> {
+    let socket = socket(addr, Type::STREAM, ipv6_only)?;
+    socket_options::tcp_listener(&socket, options, addr.is_ipv6())?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Like `bind_tcp`, for datagrams.
+pub fn bind_udp(
+    addr: SocketAddr,
+    ipv6_only: bool,
+    options: &SocketConfig,
+) -> io::Result<std::net::UdpSocket> {
+    let socket = socket(addr, Type::DGRAM, ipv6_only)?;
+    socket_options::udp_listener(&socket, options, addr.is_ipv6())?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// A listener systemd passed, made non-blocking and given the `options` a
+/// bound socket still takes.
+pub fn inherited_tcp(
+    listener: std::net::TcpListener,
+    options: &SocketConfig,
+) -> io::Result<std::net::TcpListener> {
+    let ipv6 = listener.local_addr()?.is_ipv6();
+    let socket = Socket::from(listener);
+    socket_options::tcp_listener(&socket, options, ipv6)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Like `inherited_tcp`, for datagrams.
+pub fn inherited_udp(
+    socket: std::net::UdpSocket,
+    options: &SocketConfig,
+) -> io::Result<std::net::UdpSocket> {
+    let ipv6 = socket.local_addr()?.is_ipv6();
+    let socket = Socket::from(socket);
+    socket_options::udp_listener(&socket, options, ipv6)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// A value that can be swapped while other threads keep using the old one.
+pub struct Shared<T> {
+    v: RwLock<Arc<T>>,
+}
+
+impl<T> Shared<T> {
+    pub fn new(v: T) -> Self {
+        Shared {
+            v: RwLock::new(Arc::new(v)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.v.read().unwrap().clone()
+    }
+
+    pub fn set(&self, v: T) {
+        *self.v.write().unwrap() = Arc::new(v);
+    }
 }
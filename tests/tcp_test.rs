@@ -1,8 +1,14 @@
 use mio::net::{TcpListener, TcpStream};
 use mio::{Events, Interest, Poll, Token};
 use ntest::timeout;
-use portforwarder::forward_config::{ForwardSessionConfig, TcpMode};
+use portforwarder::forward_config::{
+    CaptureConfig, ForwardSessionConfig, TcpMode, UnmatchedLogConfig, UpstreamConfig,
+    UpstreamServer,
+};
+use portforwarder::config;
 use portforwarder::tcp_forwarder::TcpForwarder;
+use portforwarder::tcp_udp_forwarder::TcpUdpForwarder;
+use portforwarder::upstream;
 use rand::Rng;
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
@@ -231,6 +237,7 @@ fn run_tcp_forwarder_at(local: &'static str, remote: &'static str, finished: Arc
         allow_nets: vec!["127.0.0.1/24".to_string(), "::1/128".to_string()],
         max_connections: 256,
         tcp_mode: TcpMode::Forward,
+        ..Default::default()
     };
     let forwarder = TcpForwarder::from(&config).unwrap();
     forwarder.listen(finished).unwrap();
@@ -365,3 +372,745 @@ fn test_tcp_forwarder_congestion_multi_clients() {
     backend_thread.join().unwrap();
     forwarder_thread.join().unwrap();
 }
+
+#[test]
+#[timeout(30000)]
+fn test_tcp_forwarder_drains_on_close() {
+    let _guard = test_lock();
+    init_log();
+
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let backend = std::net::TcpListener::bind("127.0.0.1:32357").unwrap();
+    let backend_thread = std::thread::spawn(move || {
+        let (mut stream, _) = backend.accept().unwrap();
+        let mut buf = [0u8; 1024];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => stream.write_all(&buf[..n]).unwrap(),
+            }
+        }
+    });
+
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:33843",
+        remoteMap: vec![(".*".to_string(), "127.0.0.1:32357".to_string())],
+        enable_udp: false,
+        drain_timeout: Duration::from_secs(10),
+        ..Default::default()
+    };
+    let forwarder = TcpForwarder::from(&config).unwrap();
+    let p1 = finished.clone();
+    let forwarder_thread = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut client = std::net::TcpStream::connect("127.0.0.1:33843").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0u8; 5];
+    client.write_all(b"hello").unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    finished.store(true, Ordering::SeqCst);
+    std::thread::sleep(Duration::from_millis(1500));
+    assert!(std::net::TcpStream::connect("127.0.0.1:33843").is_err());
+    assert!(!forwarder_thread.is_finished());
+
+    client.write_all(b"world").unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"world");
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
+
+    forwarder_thread.join().unwrap();
+    backend_thread.join().unwrap();
+}
+
+#[test]
+#[timeout(30000)]
+fn test_tcp_forwarder_update_keeps_connections() {
+    let _guard = test_lock();
+    init_log();
+
+    fn tagged_backend(addr: &'static str, tag: u8) -> std::thread::JoinHandle<()> {
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1];
+            while let Ok(1) = stream.read(&mut buf) {
+                stream.write_all(&[tag]).unwrap();
+            }
+        })
+    }
+    let backend_a = tagged_backend("127.0.0.1:22358", b'a');
+    let backend_b = tagged_backend("127.0.0.1:22359", b'b');
+
+    let mut config = ForwardSessionConfig {
+        local: "127.0.0.1:23844",
+        remoteMap: vec![(".*".to_string(), "127.0.0.1:22358".to_string())],
+        enable_udp: false,
+        ..Default::default()
+    };
+    let forwarder = Arc::new(TcpForwarder::from(&config).unwrap());
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let (f1, p1) = (forwarder.clone(), finished.clone());
+    let forwarder_thread = std::thread::spawn(move || f1.listen(p1).unwrap());
+    std::thread::sleep(Duration::from_millis(200));
+
+    let ask = |client: &mut std::net::TcpStream| {
+        let mut buf = [0u8; 1];
+        client.write_all(b"?").unwrap();
+        client.read_exact(&mut buf).unwrap();
+        buf[0]
+    };
+    let mut old_client = std::net::TcpStream::connect("127.0.0.1:23844").unwrap();
+    assert_eq!(ask(&mut old_client), b'a');
+
+    config.remoteMap = vec![(".*".to_string(), "127.0.0.1:22359".to_string())];
+    forwarder.update(&config).unwrap();
+    std::thread::sleep(Duration::from_millis(1200));
+
+    let mut new_client = std::net::TcpStream::connect("127.0.0.1:23844").unwrap();
+    assert_eq!(ask(&mut new_client), b'b');
+    assert_eq!(ask(&mut old_client), b'a');
+
+    drop(old_client);
+    drop(new_client);
+    finished.store(true, Ordering::SeqCst);
+    forwarder_thread.join().unwrap();
+    backend_a.join().unwrap();
+    backend_b.join().unwrap();
+}
+
+fn admin_request(addr: &str, method: &str, path: &str) -> serde_json::Value {
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    serde_json::from_str(body).unwrap()
+}
+
+#[test]
+#[timeout(30000)]
+fn test_admin_api_controls_tcp_connections() {
+    let _guard = test_lock();
+    init_log();
+
+    fn tagged_backend(addr: &'static str, tag: u8) -> std::thread::JoinHandle<()> {
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1];
+            while let Ok(1) = stream.read(&mut buf) {
+                stream.write_all(&[tag]).unwrap();
+            }
+        })
+    }
+    let backend_a = tagged_backend("127.0.0.1:22360", b'a');
+    let backend_b = tagged_backend("127.0.0.1:22362", b'b');
+    let admin_addr = "127.0.0.1:22361";
+    portforwarder::admin::serve(admin_addr).unwrap();
+
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:23845",
+        remoteMap: vec![
+            ("^x".to_string(), "127.0.0.1:22360".to_string()),
+            (".*".to_string(), "127.0.0.1:22362".to_string()),
+        ],
+        enable_udp: false,
+        ..Default::default()
+    };
+    let forwarder = TcpForwarder::from(&config).unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let p1 = finished.clone();
+    let forwarder_thread = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+    std::thread::sleep(Duration::from_millis(200));
+
+    let exchange = |client: &mut std::net::TcpStream| {
+        let mut buf = [0u8; 1];
+        client.write_all(b"x").unwrap();
+        client.read_exact(&mut buf).unwrap();
+        buf[0]
+    };
+    let mut first = std::net::TcpStream::connect("127.0.0.1:23845").unwrap();
+    first.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(exchange(&mut first), b'a');
+
+    let forwarders = admin_request(admin_addr, "GET", "/forwarders");
+    let fwd = forwarders
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|f| f["local"] == "127.0.0.1:23845")
+        .unwrap();
+    assert_eq!(fwd["rules"][0]["pattern"], "^x");
+    let sessions = fwd["sessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0]["kind"], "tcp");
+    assert_eq!(sessions[0]["rule"], "^x");
+    assert_eq!(sessions[0]["upstream"], "127.0.0.1:22360");
+    assert_eq!(sessions[0]["up_bytes"], 1);
+    assert_eq!(sessions[0]["down_bytes"], 1);
+
+    let path = format!("/forwarders/{}/rules/0/disable?seconds=60", fwd["id"]);
+    admin_request(admin_addr, "POST", &path);
+    let mut second = std::net::TcpStream::connect("127.0.0.1:23845").unwrap();
+    second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(exchange(&mut second), b'b');
+
+    let path = format!("/sessions/{}/close", sessions[0]["id"]);
+    admin_request(admin_addr, "POST", &path);
+    let mut buf = [0u8; 1];
+    assert!(matches!(first.read(&mut buf), Ok(0) | Err(_)));
+    let remaining = admin_request(admin_addr, "GET", "/sessions");
+    assert_eq!(remaining.as_array().unwrap().len(), 1);
+
+    drop(second);
+    finished.store(true, Ordering::SeqCst);
+    forwarder_thread.join().unwrap();
+    backend_a.join().unwrap();
+    backend_b.join().unwrap();
+}
+
+#[test]
+#[timeout(30000)]
+fn test_access_log_records_connections() {
+    let _guard = test_lock();
+    init_log();
+
+    let path = std::env::temp_dir().join(format!("portfd-access-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    portforwarder::access_log::open(path.to_str().unwrap()).unwrap();
+
+    let backend = std::net::TcpListener::bind("127.0.0.1:22363").unwrap();
+    let backend_thread = std::thread::spawn(move || {
+        let (mut stream, _) = backend.accept().unwrap();
+        let mut buf = [0u8; 16];
+        let n = stream.read(&mut buf).unwrap();
+        stream.write_all(&buf[..n]).unwrap();
+        stream.write_all(b"!").unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    });
+
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:23846",
+        remoteMap: vec![("^x".to_string(), "127.0.0.1:22363".to_string())],
+        enable_udp: false,
+        ..Default::default()
+    };
+    let forwarder = TcpForwarder::from(&config).unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let p1 = finished.clone();
+    let forwarder_thread = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut client = std::net::TcpStream::connect("127.0.0.1:23846").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"xyz").unwrap();
+    let mut reply = [0u8; 4];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"xyz!");
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    assert_eq!(client.read(&mut reply).unwrap(), 0);
+
+    let mut unmatched = std::net::TcpStream::connect("127.0.0.1:23846").unwrap();
+    unmatched.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    unmatched.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 1];
+    assert!(matches!(unmatched.read(&mut buf), Ok(0) | Err(_)));
+    std::thread::sleep(Duration::from_millis(200));
+
+    finished.store(true, Ordering::SeqCst);
+    forwarder_thread.join().unwrap();
+    backend_thread.join().unwrap();
+
+    let content = std::fs::read_to_string(&path).unwrap();
+    let records: Vec<serde_json::Value> = content
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .filter(|r: &serde_json::Value| r["forwarder"] == "127.0.0.1:23846")
+        .collect();
+    assert_eq!(records.len(), 2, "{}", content);
+    assert_eq!(records[0]["protocol"], "tcp");
+    assert_eq!(records[0]["pattern"], "^x");
+    assert_eq!(records[0]["upstream"], "127.0.0.1:22363");
+    assert_eq!(records[0]["up_bytes"], 3);
+    assert_eq!(records[0]["down_bytes"], 4);
+    assert!(records[0]["start"].as_str().unwrap() <= records[0]["end"].as_str().unwrap());
+    assert!(records[1]["upstream"].is_null());
+    assert_eq!(records[1]["close_reason"], "no matching rule");
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[timeout(30000)]
+fn test_capture_writes_pcapng() {
+    let _guard = test_lock();
+    init_log();
+
+    let path = std::env::temp_dir().join(format!("portfd-capture-{}.pcapng", std::process::id()));
+    let backend = std::net::TcpListener::bind("127.0.0.1:22364").unwrap();
+    let backend_thread = std::thread::spawn(move || {
+        let (mut stream, _) = backend.accept().unwrap();
+        let mut buf = [0u8; 16];
+        let n = stream.read(&mut buf).unwrap();
+        stream.write_all(&buf[..n]).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    });
+
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:23847",
+        remoteMap: vec![("^x".to_string(), "127.0.0.1:22364".to_string())],
+        enable_udp: false,
+        capture: Some(CaptureConfig {
+            file: path.to_str().unwrap().to_string(),
+            rules: vec!["^x".to_string()],
+            clients: vec!["127.0.0.0/8".to_string()],
+        }),
+        ..Default::default()
+    };
+    let forwarder = TcpForwarder::from(&config).unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let p1 = finished.clone();
+    let forwarder_thread = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut client = std::net::TcpStream::connect("127.0.0.1:23847").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.write_all(b"xyz").unwrap();
+    let mut reply = [0u8; 3];
+    client.read_exact(&mut reply).unwrap();
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    assert_eq!(client.read(&mut reply).unwrap(), 0);
+    let client_port = client.local_addr().unwrap().port();
+
+    finished.store(true, Ordering::SeqCst);
+    forwarder_thread.join().unwrap();
+    backend_thread.join().unwrap();
+
+    let bytes = std::fs::read(&path).unwrap();
+    let le32 =
+        |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+    let mut packets = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let kind = le32(offset);
+        let len = le32(offset + 4) as usize;
+        if kind == 6 {
+            let caplen = le32(offset + 20);
+            packets.push(bytes[offset + 28..offset + 28 + caplen as usize].to_vec());
+        }
+        offset += len;
+    }
+    // handshake, one segment each way, then FINs
+    assert_eq!(packets.len(), 8);
+    let data: Vec<&Vec<u8>> = packets.iter().filter(|p| p.len() > 40).collect();
+    assert_eq!(data.len(), 2);
+    assert_eq!(&data[0][40..], b"xyz");
+    assert_eq!(u16::from_be_bytes([data[0][20], data[0][21]]), client_port);
+    assert_eq!(u16::from_be_bytes([data[0][22], data[0][23]]), 22364);
+    assert_eq!(u16::from_be_bytes([data[1][20], data[1][21]]), 22364);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[timeout(30000)]
+fn test_unmatched_log_samples_first_packets() {
+    let _guard = test_lock();
+    init_log();
+
+    let path = std::env::temp_dir().join(format!("portfd-unmatched-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:23848",
+        remoteMap: vec![("^x".to_string(), "127.0.0.1:22365".to_string())],
+        enable_udp: false,
+        unmatched_log: Some(UnmatchedLogConfig {
+            file: path.to_str().unwrap().to_string(),
+            dedup_prefix: 4,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let forwarder = TcpForwarder::from(&config).unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let p1 = finished.clone();
+    let forwarder_thread = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+    std::thread::sleep(Duration::from_millis(200));
+
+    for payload in [&b"GET /a"[..], b"GET /b", b"hello"] {
+        let mut client = std::net::TcpStream::connect("127.0.0.1:23848").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(payload).unwrap();
+        let mut buf = [0u8; 4];
+        assert!(matches!(client.read(&mut buf), Ok(0) | Err(_)));
+    }
+
+    finished.store(true, Ordering::SeqCst);
+    forwarder_thread.join().unwrap();
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(log.matches(" tcp 127.0.0.1:23848 from 127.0.0.1:").count(), 2);
+    assert!(log.contains("47 45 54 20 2f 61"));
+    assert!(log.contains("|GET /a|"));
+    assert!(!log.contains("|GET /b|"));
+    assert!(log.contains("5 bytes\n00000000  68 65 6c 6c 6f"));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[timeout(30000)]
+fn test_upstream_group_balances_and_skips_failed_servers() {
+    let _guard = test_lock();
+    init_log();
+
+    // each backend answers with its name and closes
+    for (addr, name) in [("127.0.0.1:22366", b'a'), ("127.0.0.1:22367", b'b')] {
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.write_all(&[name]);
+            }
+        });
+    }
+    let servers = ["127.0.0.1:22366", "127.0.0.1:22367", "127.0.0.1:22368"]
+        .iter()
+        .map(|address| UpstreamServer {
+            address: address.to_string(),
+            weight: 1,
+        })
+        .collect();
+    upstream::configure(&[UpstreamConfig {
+        name: "pool".to_string(),
+        servers,
+        ..Default::default()
+    }]);
+
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:23849",
+        remoteMap: vec![(".*".to_string(), "pool".to_string())],
+        enable_udp: false,
+        ..Default::default()
+    };
+    let forwarder = TcpForwarder::from(&config).unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let p1 = finished.clone();
+    let forwarder_thread = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut answers = vec![];
+    for _ in 0..6 {
+        let mut client = std::net::TcpStream::connect("127.0.0.1:23849").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut answer = vec![];
+        let _ = client.read_to_end(&mut answer);
+        answers.push(String::from_utf8(answer).unwrap());
+    }
+    // nothing listens on the third server, it is skipped after failing once
+    assert_eq!(answers, ["a", "b", "", "a", "b", "a"]);
+
+    finished.store(true, Ordering::SeqCst);
+    forwarder_thread.join().unwrap();
+    upstream::configure(&[]);
+}
+
+#[test]
+#[timeout(30000)]
+fn test_port_range_maps_to_remote_ports() {
+    let _guard = test_lock();
+    init_log();
+
+    for (addr, name) in [("127.0.0.1:22393", b'a'), ("127.0.0.1:22394", b'b')] {
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.write_all(&[name]);
+            }
+        });
+    }
+    // the IPv6 wildcard shares 22373 with 127.0.0.1 as an IPv6-only socket
+    let configs = config::parse(
+        "
+forwarders:
+  - local: [127.0.0.1:22373-22374, \"[::]:22373\"]
+    remote: 127.0.0.1:${port+20}
+    enable_udp: false
+",
+        config::Format::Yaml,
+    )
+    .unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let mut threads = vec![];
+    for config in &configs.forwarders {
+        let forwarder = TcpForwarder::from(config).unwrap();
+        let p1 = finished.clone();
+        threads.push(std::thread::spawn(move || forwarder.listen(p1).unwrap()));
+    }
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut answers = vec![];
+    for addr in ["127.0.0.1:22373", "127.0.0.1:22374", "[::1]:22373"] {
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut answer = vec![];
+        let _ = client.read_to_end(&mut answer);
+        answers.push(String::from_utf8(answer).unwrap());
+    }
+    assert_eq!(answers, ["a", "b", "a"]);
+
+    finished.store(true, Ordering::SeqCst);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+#[timeout(30000)]
+fn test_unix_socket_local_and_remote() {
+    use std::os::unix::fs::PermissionsExt;
+    let _guard = test_lock();
+    init_log();
+
+    let path = std::env::temp_dir().join(format!("portfd-tcp-test-{}.sock", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let listener = std::net::TcpListener::bind("127.0.0.1:22396").unwrap();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut request = vec![];
+            let _ = stream.read_to_end(&mut request);
+            let _ = stream.write_all(&request);
+        }
+    });
+    // tcp -> unix socket -> tcp
+    let configs = config::parse(
+        &format!(
+            "
+forwarders:
+  - local: 127.0.0.1:22395
+    remote: unix:{path}
+    enable_udp: false
+  - local: unix:{path}
+    remote: 127.0.0.1:22396
+    enable_udp: false
+    unix_mode: \"0600\"
+",
+            path = path
+        ),
+        config::Format::Yaml,
+    )
+    .unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let mut threads = vec![];
+    for config in &configs.forwarders {
+        let forwarder = TcpForwarder::from(config).unwrap();
+        let p1 = finished.clone();
+        threads.push(std::thread::spawn(move || forwarder.listen(p1).unwrap()));
+    }
+    std::thread::sleep(Duration::from_millis(200));
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut client = std::net::TcpStream::connect("127.0.0.1:22395").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"over a unix socket").unwrap();
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut answer = vec![];
+    let _ = client.read_to_end(&mut answer);
+    assert_eq!(answer, b"over a unix socket");
+
+    finished.store(true, Ordering::SeqCst);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(!std::path::Path::new(&path).exists());
+}
+
+#[test]
+#[timeout(30000)]
+fn test_upstream_socket_options() {
+    let _guard = test_lock();
+    init_log();
+
+    // answers with the address the connection came from
+    let listener = std::net::TcpListener::bind("127.0.0.1:22398").unwrap();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let peer = stream.peer_addr().unwrap();
+            // unread data would turn the close into a reset
+            let _ = stream.read(&mut [0; 16]);
+            let _ = stream.write_all(peer.ip().to_string().as_bytes());
+        }
+    });
+    let configs = config::parse(
+        "
+forwarders:
+  - local: 127.0.0.1:22397
+    enable_udp: false
+    listen_socket:
+      nodelay: true
+      keepalive: 30s
+    upstream_socket:
+      bind_address: 127.0.0.2
+      nodelay: true
+      send_buffer: 64KB
+    remoteMap:
+      - pattern: ^a
+        remote: 127.0.0.1:22398
+        upstream_socket:
+          bind_address: 127.0.0.3
+      - pattern: .*
+        remote: 127.0.0.1:22398
+",
+        config::Format::Yaml,
+    )
+    .unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let forwarder = TcpForwarder::from(&configs.forwarders[0]).unwrap();
+    let p1 = finished.clone();
+    let thread = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut answers = vec![];
+    for first in ["a", "b"] {
+        let mut client = std::net::TcpStream::connect("127.0.0.1:22397").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(first.as_bytes()).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut answer = vec![];
+        let _ = client.read_to_end(&mut answer);
+        answers.push(String::from_utf8(answer).unwrap());
+    }
+    assert_eq!(answers, ["127.0.0.3", "127.0.0.2"]);
+
+    finished.store(true, Ordering::SeqCst);
+    thread.join().unwrap();
+}
+
+#[test]
+#[timeout(30000)]
+fn test_reload_restarts_listener_on_mode_change() {
+    let _guard = test_lock();
+    init_log();
+
+    let backend = std::net::TcpListener::bind("127.0.0.1:22413").unwrap();
+    std::thread::spawn(move || {
+        for mut stream in backend.incoming().flatten() {
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 64];
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    let _ = stream.write_all(&buf[..n]);
+                }
+            });
+        }
+    });
+    let echo = |client: &mut std::net::TcpStream, data: &[u8]| {
+        let mut buf = vec![0u8; data.len()];
+        client.write_all(data).unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    };
+
+    let mut config = ForwardSessionConfig {
+        local: "127.0.0.1:22414",
+        remoteMap: vec![(".*".to_string(), "127.0.0.1:22413".to_string())],
+        enable_udp: false,
+        drain_timeout: Duration::from_secs(10),
+        ..Default::default()
+    };
+    let busy = std::net::TcpListener::bind("127.0.0.1:22414").unwrap();
+    assert!(TcpUdpForwarder::from(&config).is_err());
+    drop(busy);
+    let mut forwarder = TcpUdpForwarder::from(&config).unwrap();
+    forwarder.listen();
+
+    let mut client = std::net::TcpStream::connect("127.0.0.1:22414").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    echo(&mut client, b"hello");
+
+    // nothing changes when the udp half can't bind
+    config.tcp_mode = TcpMode::Socks5Server;
+    config.enable_udp = true;
+    let busy = std::net::UdpSocket::bind("127.0.0.1:22414").unwrap();
+    assert!(forwarder.update(&config).is_err());
+    drop(busy);
+    let mut other = std::net::TcpStream::connect("127.0.0.1:22414").unwrap();
+    other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    echo(&mut other, b"still forwarding");
+    drop(other);
+
+    config.enable_udp = false;
+    let draining = forwarder.update(&config).unwrap().unwrap();
+    std::thread::sleep(Duration::from_millis(1500));
+
+    // the old connection drains, new ones are served as socks5
+    echo(&mut client, b"world");
+    let mut proxied = std::net::TcpStream::connect("127.0.0.1:22414").unwrap();
+    proxied.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reply = [0u8; 2];
+    proxied.write_all(&[5, 1, 0]).unwrap();
+    proxied.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, 0]);
+    assert!(!draining.is_finished());
+
+    drop(client);
+    draining.join();
+    config.drain_timeout = Duration::ZERO;
+    assert!(forwarder.update(&config).unwrap().is_none());
+    drop(proxied);
+    forwarder.close()();
+}
+
+#[test]
+#[timeout(30000)]
+fn test_retire_keeps_connections_until_they_end() {
+    let _guard = test_lock();
+    init_log();
+
+    let backend = std::net::TcpListener::bind("127.0.0.1:22419").unwrap();
+    std::thread::spawn(move || {
+        for mut stream in backend.incoming().flatten() {
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 64];
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    let _ = stream.write_all(&buf[..n]);
+                }
+            });
+        }
+    });
+    let echo = |client: &mut std::net::TcpStream, data: &[u8]| {
+        let mut buf = vec![0u8; data.len()];
+        client.write_all(data).unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    };
+
+    // the default drain_timeout of zero must not cut connections a reload leaves
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:22420",
+        remoteMap: vec![(".*".to_string(), "127.0.0.1:22419".to_string())],
+        enable_udp: false,
+        ..Default::default()
+    };
+    let mut forwarder = TcpUdpForwarder::from(&config).unwrap();
+    forwarder.listen();
+
+    let mut client = std::net::TcpStream::connect("127.0.0.1:22420").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    echo(&mut client, b"hello");
+
+    let draining = forwarder.retire();
+    std::thread::sleep(Duration::from_millis(1500));
+    assert!(std::net::TcpStream::connect("127.0.0.1:22420").is_err());
+    echo(&mut client, b"world");
+    assert!(!draining.is_finished());
+
+    drop(client);
+    draining.join();
+}
@@ -1,7 +1,7 @@
 use mio::net::UdpSocket;
 use mio::{Events, Interest, Poll, Token};
 use ntest::timeout;
-use portforwarder::forward_config::{ForwardSessionConfig, TcpMode};
+use portforwarder::forward_config::{ForwardSessionConfig, SocketConfig, TcpMode};
 use portforwarder::udp_forwarder::UdpForwarder;
 use rand::Rng;
 use std::collections::HashSet;
@@ -158,6 +158,7 @@ fn run_udp_forwarder_at(local: &'static str, remote: &'static str, finished: Arc
         allow_nets: ["127.0.0.1/24".to_string(), "::1/128".to_string()].to_vec(),
         max_connections: 256,
         tcp_mode: TcpMode::Forward,
+        ..Default::default()
     };
     let forwarder_wrap = UdpForwarder::from(&config);
     assert!(forwarder_wrap.is_ok());
@@ -309,3 +310,296 @@ fn test_udp_forwarder_congestion_burst() {
     echo_thread.join().unwrap();
     fd.join().unwrap();
 }
+
+#[test]
+#[timeout(30000)]
+fn test_udp_upstream_bind_address() {
+    let _guard = test_lock();
+    init_log();
+
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:22399",
+        remoteMap: vec![(".*".to_string(), "127.0.0.1:22400".to_string())],
+        enable_tcp: false,
+        listen_socket: SocketConfig {
+            recv_buffer: Some(256 * 1024),
+            ..Default::default()
+        },
+        upstream_socket: SocketConfig {
+            bind_address: Some("127.0.0.2".parse().unwrap()),
+            tos: Some(0x10),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let forwarder = UdpForwarder::from(&config).unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let p1 = finished.clone();
+    let fd = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+
+    // answers with the address the datagram came from
+    let backend = std::net::UdpSocket::bind("127.0.0.1:22400").unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        while let Ok((_, peer)) = backend.recv_from(&mut buf) {
+            let _ = backend.send_to(peer.ip().to_string().as_bytes(), peer);
+        }
+    });
+    std::thread::sleep(Duration::from_millis(200));
+
+    let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.send_to(b"hi", "127.0.0.1:22399").unwrap();
+    let mut buf = [0u8; 64];
+    let n = client.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"127.0.0.2");
+
+    finished.store(true, Ordering::SeqCst);
+    fd.join().unwrap();
+}
+
+#[test]
+#[timeout(30000)]
+fn test_udp_across_address_families() {
+    let _guard = test_lock();
+    init_log();
+
+    // an IPv4 listener with an IPv6 upstream, and a dual-stack one whose
+    // IPv4 clients are matched against IPv4 networks
+    let configs = [
+        ForwardSessionConfig {
+            local: "127.0.0.1:22401",
+            remoteMap: vec![(".*".to_string(), "[::1]:22402".to_string())],
+            enable_tcp: false,
+            ..Default::default()
+        },
+        ForwardSessionConfig {
+            local: "[::]:22403",
+            remoteMap: vec![(".*".to_string(), "127.0.0.1:22404".to_string())],
+            enable_tcp: false,
+            allow_nets: vec!["127.0.0.1".to_string()],
+            ..Default::default()
+        },
+    ];
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let mut threads = vec![];
+    for config in &configs {
+        let forwarder = UdpForwarder::from(config).unwrap();
+        let p1 = finished.clone();
+        threads.push(std::thread::spawn(move || forwarder.listen(p1).unwrap()));
+    }
+    for backend in ["[::1]:22402", "127.0.0.1:22404"] {
+        let backend = std::net::UdpSocket::bind(backend).unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while let Ok((n, peer)) = backend.recv_from(&mut buf) {
+                let _ = backend.send_to(&buf[..n], peer);
+            }
+        });
+    }
+    std::thread::sleep(Duration::from_millis(200));
+
+    for local in ["127.0.0.1:22401", "127.0.0.1:22403"] {
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send_to(b"across", local).unwrap();
+        let mut buf = [0u8; 64];
+        let (n, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"across");
+        assert_eq!(from, local.parse().unwrap());
+    }
+
+    finished.store(true, Ordering::SeqCst);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+#[timeout(30000)]
+fn test_udp_idle_timeout_and_lru_eviction() {
+    let _guard = test_lock();
+    init_log();
+
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:22405",
+        remoteMap: vec![(".*".to_string(), "127.0.0.1:22406".to_string())],
+        enable_tcp: false,
+        max_connections: 1,
+        rule_idle_timeouts: vec![(0, Duration::from_secs(1))],
+        udp_evict_lru: true,
+        ..Default::default()
+    };
+    let forwarder = UdpForwarder::from(&config).unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let p1 = finished.clone();
+    let fd = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+
+    // answers with the port the datagram came from, one per session
+    let backend = std::net::UdpSocket::bind("127.0.0.1:22406").unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        while let Ok((_, peer)) = backend.recv_from(&mut buf) {
+            let _ = backend.send_to(peer.port().to_string().as_bytes(), peer);
+        }
+    });
+    std::thread::sleep(Duration::from_millis(200));
+
+    let session = |client: &std::net::UdpSocket| {
+        client.send_to(b"hi", "127.0.0.1:22405").unwrap();
+        let mut buf = [0u8; 64];
+        let n = client.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    };
+    let a = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let b = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    a.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    b.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let first = session(&a);
+    assert_eq!(session(&a), first);
+    // the quota is full, a's session makes room for b
+    session(&b);
+    let second = session(&a);
+    assert_ne!(second, first);
+    std::thread::sleep(Duration::from_millis(2500));
+    assert_ne!(session(&a), second);
+
+    finished.store(true, Ordering::SeqCst);
+    fd.join().unwrap();
+}
+
+#[test]
+#[timeout(30000)]
+fn test_udp_session_ignores_other_senders() {
+    let _guard = test_lock();
+    init_log();
+
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:22407",
+        remoteMap: vec![(".*".to_string(), "127.0.0.1:22408".to_string())],
+        enable_tcp: false,
+        ..Default::default()
+    };
+    let forwarder = UdpForwarder::from(&config).unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let p1 = finished.clone();
+    let fd = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+
+    let backend = std::net::UdpSocket::bind("127.0.0.1:22408").unwrap();
+    backend.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+
+    let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    client.send_to(b"hi", "127.0.0.1:22407").unwrap();
+    let mut buf = [0u8; 128];
+    let (_, session) = backend.recv_from(&mut buf).unwrap();
+
+    // the session's socket only takes datagrams of its upstream
+    let stranger = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    stranger.send_to(b"spoofed", session).unwrap();
+    assert!(client.recv(&mut buf).is_err());
+    let burst: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 100]).collect();
+    for datagram in &burst {
+        backend.send_to(datagram, session).unwrap();
+    }
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    for datagram in &burst {
+        let n = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &datagram[..]);
+    }
+
+    finished.store(true, Ordering::SeqCst);
+    fd.join().unwrap();
+}
+
+#[test]
+#[timeout(30000)]
+fn test_udp_replies_from_the_address_sent_to() {
+    let _guard = test_lock();
+    init_log();
+
+    let configs = [
+        ("0.0.0.0:22409", "127.0.0.1:22410"),
+        ("[::]:22411", "127.0.0.1:22412"),
+    ]
+    .map(|(local, remote)| ForwardSessionConfig {
+        local,
+        remoteMap: vec![(".*".to_string(), remote.to_string())],
+        enable_tcp: false,
+        ..Default::default()
+    });
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let mut threads = vec![];
+    for config in &configs {
+        let forwarder = UdpForwarder::from(config).unwrap();
+        let p1 = finished.clone();
+        threads.push(std::thread::spawn(move || forwarder.listen(p1).unwrap()));
+    }
+    for backend in ["127.0.0.1:22410", "127.0.0.1:22412"] {
+        let backend = std::net::UdpSocket::bind(backend).unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while let Ok((n, peer)) = backend.recv_from(&mut buf) {
+                let _ = backend.send_to(&buf[..n], peer);
+            }
+        });
+    }
+    std::thread::sleep(Duration::from_millis(200));
+
+    // the route back to 127.0.0.1 would pick 127.0.0.1 as the source
+    for local in ["127.0.0.5:22409", "127.0.0.5:22411"] {
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send_to(b"multi-homed", local).unwrap();
+        let mut buf = [0u8; 64];
+        let (n, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"multi-homed");
+        assert_eq!(from, local.parse().unwrap());
+    }
+
+    finished.store(true, Ordering::SeqCst);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+#[timeout(30000)]
+fn test_udp_large_datagrams() {
+    let _guard = test_lock();
+    init_log();
+
+    let config = ForwardSessionConfig {
+        local: "127.0.0.1:22417",
+        remoteMap: vec![(".*".to_string(), "127.0.0.1:22418".to_string())],
+        enable_tcp: false,
+        ..Default::default()
+    };
+    let forwarder = UdpForwarder::from(&config).unwrap();
+    let finished: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let p1 = finished.clone();
+    let fd = std::thread::spawn(move || forwarder.listen(p1).unwrap());
+
+    let backend = std::net::UdpSocket::bind("127.0.0.1:22418").unwrap();
+    backend.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+
+    // larger than a jumbo frame either way
+    let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let request = vec![1u8; 20000];
+    client.send_to(&request, "127.0.0.1:22417").unwrap();
+    let mut buf = vec![0u8; 65536];
+    let (n, session) = backend.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &request[..]);
+
+    let reply = vec![2u8; 65507];
+    backend.send_to(&reply, session).unwrap();
+    let n = client.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &reply[..]);
+
+    finished.store(true, Ordering::SeqCst);
+    fd.join().unwrap();
+}